@@ -1,21 +1,125 @@
-use anyhow::{Error, Result};
-use sqlx::{postgres::PgPool, Pool, Postgres};
-use std::env;
+use anyhow::{anyhow, Error, Result};
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use std::{
+    env,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
 use tracing::info;
 
 use crate::{
-    models::{LeaderboardEntry, Wallet},
-    utils::Currency,
+    models::{
+        CurrencyRake, CurrencyReconciliation, LeaderboardEntry, MatchHistoryEntry,
+        PendingWithdrawal, Timeframe, Wallet,
+    },
+    payout::{GameResult, PayoutPolicy, Settlement},
+    utils::{Currency, TxType, WalletType, WithdrawalStatus},
 };
 
+/// Pool sizing and timeouts. The game server and the wallet share one database,
+/// so each service's share of its connections has to be capped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    /// How long a query waits for a free connection before failing
+    pub acquire_timeout: Duration,
+    /// Idle connections are closed after this long
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Reads `DB_MAX_CONNECTIONS`, `DB_ACQUIRE_TIMEOUT_SECS` and `DB_IDLE_TIMEOUT_SECS`,
+    /// falling back to the defaults for unset variables.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let read = |key: &str, default: u64| -> Result<u64> {
+            match lookup(key) {
+                Some(value) => value
+                    .parse()
+                    .ok()
+                    .filter(|&value| value > 0)
+                    .ok_or_else(|| anyhow!("{} must be a positive integer, got {:?}", key, value)),
+                None => Ok(default),
+            }
+        };
+        let default = Self::default();
+
+        Ok(Self {
+            max_connections: read("DB_MAX_CONNECTIONS", default.max_connections as u64)?
+                .try_into()?,
+            acquire_timeout: Duration::from_secs(read(
+                "DB_ACQUIRE_TIMEOUT_SECS",
+                default.acquire_timeout.as_secs(),
+            )?),
+            idle_timeout: Duration::from_secs(read(
+                "DB_IDLE_TIMEOUT_SECS",
+                default.idle_timeout.as_secs(),
+            )?),
+        })
+    }
+
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+    }
+}
+
+static POOLS_ESTABLISHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Opens a new pool. Each pool holds its own connections, so services should call
+/// this once at startup and share the result.
 pub async fn establish_connection() -> Pool<Postgres> {
     let db_url = env::var("DATABASE_URL").unwrap();
     info!("Db url: {:?} ", db_url);
-    PgPool::connect(&db_url)
+    let config = PoolConfig::from_env().expect("Invalid database pool config");
+    info!("Db pool: {:?}", config);
+    POOLS_ESTABLISHED.fetch_add(1, Ordering::Relaxed);
+    config
+        .pool_options()
+        .connect(&db_url)
         .await
         .expect("Failed to create pool")
 }
 
+/// How many pools `establish_connection` has opened in this process.
+pub fn pools_established() -> usize {
+    POOLS_ESTABLISHED.load(Ordering::Relaxed)
+}
+
+/// Returns true if the error comes from a query that matched no rows.
+pub fn is_not_found(err: &Error) -> bool {
+    matches!(
+        err.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::RowNotFound)
+    )
+}
+
+// Postgres' SQLSTATE for unique_violation
+const UNIQUE_VIOLATION: &str = "23505";
+
+/// Returns true if the error comes from a query that broke a unique constraint.
+pub fn is_unique_violation(err: &Error) -> bool {
+    err.downcast_ref::<sqlx::Error>()
+        .and_then(sqlx::Error::as_database_error)
+        .and_then(|err| err.code())
+        .is_some_and(|code| code == UNIQUE_VIOLATION)
+}
+
 pub async fn get_user_wallet(
     pool: &Pool<Postgres>,
     user_id: i32,
@@ -29,6 +133,66 @@ pub async fn get_user_wallet(
         .map_err(Error::from)
 }
 
+pub async fn get_user_wallets(pool: &Pool<Postgres>, user_id: i32) -> Result<Vec<Wallet>> {
+    sqlx::query_as::<_, Wallet>("SELECT * FROM wallet WHERE user_id = $1 ORDER BY id")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(Error::from)
+}
+
+/// Creates a zero-balance wallet for every currency the user doesn't hold yet
+/// and returns all of the user's wallets.
+pub async fn provision_user_wallets_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    user_id: i32,
+    currencies: &[Currency],
+    wallet_type: WalletType,
+) -> Result<Vec<Wallet>> {
+    for currency in currencies {
+        sqlx::query(
+            "INSERT INTO wallet (user_id, currency, balance, wallet_type) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (user_id, currency) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(currency.to_string())
+        .bind(0.0)
+        .bind(wallet_type.to_string())
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    sqlx::query_as::<_, Wallet>("SELECT * FROM wallet WHERE user_id = $1 ORDER BY id")
+        .bind(user_id)
+        .fetch_all(&mut **tx)
+        .await
+        .map_err(Error::from)
+}
+
+/// Records `address` as the wallet the user deposits `currency` from and returns the
+/// updated wallet. Deposits are only credited from that wallet, so the first address
+/// recorded is kept; `None` if another one already was, or the user holds no such
+/// wallet. Fails with a unique violation if another user registered `address`.
+pub async fn register_wallet_address_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    user_id: i32,
+    currency: Currency,
+    address: &str,
+) -> Result<Option<Wallet>> {
+    sqlx::query_as::<_, Wallet>(
+        "UPDATE wallet SET wallet_address = $1, updated_at = NOW()
+         WHERE user_id = $2 AND currency = $3
+         AND (wallet_address IS NULL OR wallet_address = $1)
+         RETURNING *",
+    )
+    .bind(address)
+    .bind(user_id)
+    .bind(currency.to_string())
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(Error::from)
+}
+
 pub async fn update_user_wallet(
     pool: &Pool<Postgres>,
     user_id: i32,
@@ -48,6 +212,478 @@ pub async fn update_user_wallet(
     Ok(())
 }
 
+/// Credits a deposit identified by `reference` (tx hash or payment id) exactly once.
+/// Returns false if the deposit was already recorded. Relies on a unique index over
+/// the deposit references of `currency`.
+pub async fn credit_deposit_once_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    user_id: i32,
+    currency: Currency,
+    amount: f64,
+    reference: &str,
+) -> Result<bool> {
+    let recorded = sqlx::query(
+        "INSERT INTO transactions (user_id, amount, currency, tx_type, tx_hash) VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(user_id)
+    .bind(amount)
+    .bind(currency.to_string())
+    .bind(TxType::DEPOSIT.to_string())
+    .bind(reference)
+    .execute(&mut **tx)
+    .await?;
+
+    if recorded.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let credited = sqlx::query(
+        "UPDATE wallet SET balance = balance + $1, updated_at = NOW()
+         WHERE user_id = $2 AND currency = $3",
+    )
+    .bind(amount)
+    .bind(user_id)
+    .bind(currency.to_string())
+    .execute(&mut **tx)
+    .await?;
+
+    if credited.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound.into());
+    }
+    Ok(true)
+}
+
+/// Links a new user to the owner of `referral_code`. Returns the referrer, or None
+/// if no user has that code.
+pub async fn record_referral_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    referee_id: i32,
+    referral_code: &str,
+) -> Result<Option<i32>> {
+    sqlx::query_scalar(
+        "INSERT INTO referrals (referrer_id, referee_id)
+         SELECT id, $2 FROM users WHERE referral_code = upper($1) AND id <> $2
+         RETURNING referrer_id",
+    )
+    .bind(referral_code.trim())
+    .bind(referee_id)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(Error::from)
+}
+
+/// Credits the referrer of `referee_id` a BONUS of `amount`, once per referee.
+/// Returns the referrer if this call paid the bonus, None if the user wasn't
+/// referred or the bonus was already paid.
+pub async fn credit_referral_bonus_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    referee_id: i32,
+    currency: Currency,
+    amount: f64,
+) -> Result<Option<i32>> {
+    // Claiming the referral row first means concurrent deposits can't both pay out
+    let referrer_id: Option<i32> = sqlx::query_scalar(
+        "UPDATE referrals SET bonus_credited_at = NOW()
+         WHERE referee_id = $1 AND bonus_credited_at IS NULL
+         RETURNING referrer_id",
+    )
+    .bind(referee_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(referrer_id) = referrer_id else {
+        return Ok(None);
+    };
+
+    let credited = sqlx::query(
+        "UPDATE wallet SET balance = balance + $1, updated_at = NOW()
+         WHERE user_id = $2 AND currency = $3",
+    )
+    .bind(amount)
+    .bind(referrer_id)
+    .bind(currency.to_string())
+    .execute(&mut **tx)
+    .await?;
+    if credited.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound.into());
+    }
+
+    sqlx::query(
+        "INSERT INTO transactions (user_id, amount, currency, tx_type, tx_hash) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(referrer_id)
+    .bind(amount)
+    .bind(currency.to_string())
+    .bind(TxType::BONUS.to_string())
+    .bind(format!("referral-{}", referee_id))
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(Some(referrer_id))
+}
+
+/// Debits a refund of an INR deposit identified by its Razorpay payment id.
+/// `amount` defaults to whatever hasn't been refunded yet; partial refunds may
+/// not add up to more than the original deposit. Returns the user and the amount debited.
+pub async fn refund_deposit_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    payment_id: &str,
+    amount: Option<f64>,
+) -> Result<(i32, f64)> {
+    // Locking the deposit row serialises concurrent refunds of the same payment
+    let (user_id, deposited): (i32, f64) = sqlx::query_as(
+        "SELECT user_id, amount FROM transactions
+         WHERE tx_hash = $1 AND currency = $2 AND tx_type = $3
+         FOR UPDATE",
+    )
+    .bind(payment_id)
+    .bind(Currency::INR.to_string())
+    .bind(TxType::DEPOSIT.to_string())
+    .fetch_one(&mut **tx)
+    .await?;
+
+    let refunded: f64 = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(amount), 0) FROM transactions
+         WHERE tx_hash = $1 AND currency = $2 AND tx_type = $3",
+    )
+    .bind(payment_id)
+    .bind(Currency::INR.to_string())
+    .bind(TxType::REFUND.to_string())
+    .fetch_one(&mut **tx)
+    .await?;
+
+    let refundable = deposited - refunded;
+    let amount = amount.unwrap_or(refundable);
+    if amount <= 0.0 || amount > refundable {
+        return Err(anyhow::anyhow!(
+            "Refund of {} exceeds the refundable amount of {}",
+            amount,
+            refundable
+        ));
+    }
+
+    let debited = sqlx::query(
+        "UPDATE wallet SET balance = balance - $1, updated_at = NOW()
+         WHERE user_id = $2 AND currency = $3 AND balance >= $1",
+    )
+    .bind(amount)
+    .bind(user_id)
+    .bind(Currency::INR.to_string())
+    .execute(&mut **tx)
+    .await?;
+
+    if debited.rows_affected() == 0 {
+        return Err(anyhow::anyhow!("Insufficient balance"));
+    }
+
+    sqlx::query(
+        "INSERT INTO transactions (user_id, amount, currency, tx_type, tx_hash) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(user_id)
+    .bind(amount)
+    .bind(Currency::INR.to_string())
+    .bind(TxType::REFUND.to_string())
+    .bind(payment_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok((user_id, amount))
+}
+
+/// Holds `amount` from the user's wallet and queues the withdrawal for the worker.
+/// Fails without touching the wallet if the balance doesn't cover the amount.
+pub async fn enqueue_withdrawal_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    user_id: i32,
+    currency: Currency,
+    amount: f64,
+    fee: f64,
+    withdraw_address: &str,
+) -> Result<PendingWithdrawal> {
+    let held = sqlx::query(
+        "UPDATE wallet SET balance = balance - $1, updated_at = NOW()
+         WHERE user_id = $2 AND currency = $3 AND balance >= $1",
+    )
+    .bind(amount)
+    .bind(user_id)
+    .bind(currency.to_string())
+    .execute(&mut **tx)
+    .await?;
+
+    if held.rows_affected() == 0 {
+        return Err(anyhow::anyhow!("Insufficient balance"));
+    }
+
+    sqlx::query_as::<_, PendingWithdrawal>(
+        "INSERT INTO pending_withdrawals (user_id, currency, amount, fee, withdraw_address, status)
+         VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+    )
+    .bind(user_id)
+    .bind(currency.to_string())
+    .bind(amount)
+    .bind(fee)
+    .bind(withdraw_address)
+    .bind(WithdrawalStatus::PENDING.to_string())
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(Error::from)
+}
+
+/// The response to the user's withdrawal with `idempotency_key`, if one was requested
+/// within `window`. Locks the key until the transaction ends, so a concurrent retry
+/// waits for the first request to commit and then sees its response.
+pub async fn idempotent_withdrawal_response_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    user_id: i32,
+    idempotency_key: &str,
+    window: Duration,
+) -> Result<Option<String>> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(format!("withdrawal:{}:{}", user_id, idempotency_key))
+        .execute(&mut **tx)
+        .await?;
+
+    let response: Option<Option<String>> = sqlx::query_scalar(
+        "SELECT response FROM pending_withdrawals
+         WHERE user_id = $1 AND idempotency_key = $2
+           AND created_at > NOW() - make_interval(secs => $3)
+         ORDER BY id DESC LIMIT 1",
+    )
+    .bind(user_id)
+    .bind(idempotency_key)
+    .bind(window.as_secs_f64())
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(response.flatten())
+}
+
+/// Stores the idempotency key of a queued withdrawal and the response it was given.
+pub async fn record_withdrawal_response_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    withdrawal_id: i32,
+    idempotency_key: &str,
+    response: &str,
+) -> Result<()> {
+    sqlx::query("UPDATE pending_withdrawals SET idempotency_key = $1, response = $2 WHERE id = $3")
+        .bind(idempotency_key)
+        .bind(response)
+        .bind(withdrawal_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Totals per currency of what users hold in their wallets plus withdrawals held but
+/// not yet sent. Game bets stay in the wallets until settlement, so they're part of
+/// the balances; the stakes of running games are reported alongside, not added to the
+/// liabilities. One statement, so the totals come from a single snapshot.
+pub async fn reconciliation_report<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+) -> Result<Vec<CurrencyReconciliation>> {
+    sqlx::query_as::<_, CurrencyReconciliation>(
+        "SELECT currency,
+                SUM(balance) AS wallet_balances,
+                SUM(held) AS pending_withdrawals,
+                SUM(staked) AS in_flight_stakes,
+                SUM(balance + held) AS liabilities
+         FROM (
+            SELECT currency, balance, 0::DOUBLE PRECISION AS held, 0::DOUBLE PRECISION AS staked
+            FROM wallet
+            UNION ALL
+            SELECT currency, 0, amount, 0 FROM pending_withdrawals WHERE status IN ($1, $2)
+            UNION ALL
+            SELECT currency, 0, 0, single_bet_size * CARDINALITY(user_ids) FROM running_games
+         ) AS owed
+         GROUP BY currency
+         ORDER BY currency",
+    )
+    .bind(WithdrawalStatus::PENDING.to_string())
+    .bind(WithdrawalStatus::PROCESSING.to_string())
+    .fetch_all(executor)
+    .await
+    .map_err(Error::from)
+}
+
+/// Rake per currency from the RAKE transactions settlement records, over `timeframe`.
+/// A transaction counts when it was recorded at or after `from` and before `to`. The
+/// rows of one settlement share its game id and, written in one transaction, its
+/// timestamp; a rematch keeps the game id but settles separately, so it counts again.
+pub async fn rake_totals<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    timeframe: Timeframe,
+) -> Result<Vec<CurrencyRake>> {
+    sqlx::query_as::<_, CurrencyRake>(
+        "SELECT currency, SUM(amount) AS rake, COUNT(DISTINCT (tx_hash, created_at)) AS games
+         FROM transactions
+         WHERE tx_type = $1
+           AND ($2::TIMESTAMPTZ IS NULL OR created_at >= $2)
+           AND ($3::TIMESTAMPTZ IS NULL OR created_at < $3)
+         GROUP BY currency
+         ORDER BY currency",
+    )
+    .bind(TxType::RAKE.to_string())
+    .bind(timeframe.from)
+    .bind(timeframe.to)
+    .fetch_all(executor)
+    .await
+    .map_err(Error::from)
+}
+
+pub async fn get_pending_withdrawal(pool: &Pool<Postgres>, id: i32) -> Result<PendingWithdrawal> {
+    sqlx::query_as::<_, PendingWithdrawal>("SELECT * FROM pending_withdrawals WHERE id = $1")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .map_err(Error::from)
+}
+
+/// Marks the oldest pending withdrawal as processing and returns it.
+pub async fn claim_next_withdrawal(pool: &Pool<Postgres>) -> Result<Option<PendingWithdrawal>> {
+    sqlx::query_as::<_, PendingWithdrawal>(
+        "UPDATE pending_withdrawals SET status = $1, updated_at = NOW()
+         WHERE id = (
+            SELECT id FROM pending_withdrawals WHERE status = $2
+            ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED
+         )
+         RETURNING *",
+    )
+    .bind(WithdrawalStatus::PROCESSING.to_string())
+    .bind(WithdrawalStatus::PENDING.to_string())
+    .fetch_optional(pool)
+    .await
+    .map_err(Error::from)
+}
+
+/// Records the hash of a withdrawal's transfer before it is sent, so the transfer can be
+/// found on chain if the worker stops before recording the outcome. Along with it goes
+/// what tells when the transfer can no longer land: the nonce of an EVM transfer, or
+/// the last valid block height of a Solana one.
+pub async fn record_withdrawal_tx_hash(
+    pool: &Pool<Postgres>,
+    withdrawal_id: i32,
+    tx_hash: &str,
+    nonce: Option<i64>,
+    last_valid_block_height: Option<i64>,
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE pending_withdrawals
+         SET tx_hash = $1, nonce = $2, last_valid_block_height = $3, updated_at = NOW()
+         WHERE id = $4",
+    )
+    .bind(tx_hash)
+    .bind(nonce)
+    .bind(last_valid_block_height)
+    .bind(withdrawal_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Withdrawals still processing with no progress for `older_than`, which a worker
+/// claimed but never recorded the outcome of.
+pub async fn stuck_withdrawals(
+    pool: &Pool<Postgres>,
+    older_than: Duration,
+) -> Result<Vec<PendingWithdrawal>> {
+    sqlx::query_as::<_, PendingWithdrawal>(
+        "SELECT * FROM pending_withdrawals
+         WHERE status = $1 AND updated_at < NOW() - make_interval(secs => $2)
+         ORDER BY id",
+    )
+    .bind(WithdrawalStatus::PROCESSING.to_string())
+    .bind(older_than.as_secs_f64())
+    .fetch_all(pool)
+    .await
+    .map_err(Error::from)
+}
+
+/// Marks a withdrawal as sent and records it, and its fee, in the transaction ledger.
+/// Fails if the withdrawal was already completed or failed.
+pub async fn complete_withdrawal(
+    pool: &Pool<Postgres>,
+    withdrawal: &PendingWithdrawal,
+    tx_hash: &str,
+) -> Result<()> {
+    info!("Completing withdrawal {}: {}", withdrawal.id, tx_hash);
+    let mut tx = pool.begin().await?;
+
+    let settled = sqlx::query(
+        "UPDATE pending_withdrawals SET status = $1, tx_hash = $2, updated_at = NOW()
+         WHERE id = $3 AND status IN ($4, $5)",
+    )
+    .bind(WithdrawalStatus::COMPLETED.to_string())
+    .bind(tx_hash)
+    .bind(withdrawal.id)
+    .bind(WithdrawalStatus::PENDING.to_string())
+    .bind(WithdrawalStatus::PROCESSING.to_string())
+    .execute(&mut *tx)
+    .await?;
+    if settled.rows_affected() == 0 {
+        return Err(anyhow!("Withdrawal {} is already settled", withdrawal.id));
+    }
+
+    // The hold covers both, so together they make up the amount taken from the wallet
+    let mut entries = vec![(withdrawal.amount - withdrawal.fee, TxType::WITHDRAWAL)];
+    if withdrawal.fee > 0.0 {
+        entries.push((withdrawal.fee, TxType::FEE));
+    }
+    for (amount, tx_type) in entries {
+        sqlx::query(
+            "INSERT INTO transactions (user_id, amount, currency, tx_type, tx_hash) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(withdrawal.user_id)
+        .bind(amount)
+        .bind(&withdrawal.currency)
+        .bind(tx_type.to_string())
+        .bind(tx_hash)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Marks a withdrawal as failed and returns the held amount to the user's wallet.
+/// Fails if the withdrawal was already completed or failed.
+pub async fn fail_withdrawal(
+    pool: &Pool<Postgres>,
+    withdrawal: &PendingWithdrawal,
+    error: &str,
+) -> Result<()> {
+    info!("Failing withdrawal {}: {}", withdrawal.id, error);
+    let mut tx = pool.begin().await?;
+
+    let settled = sqlx::query(
+        "UPDATE pending_withdrawals SET status = $1, error = $2, updated_at = NOW()
+         WHERE id = $3 AND status IN ($4, $5)",
+    )
+    .bind(WithdrawalStatus::FAILED.to_string())
+    .bind(error)
+    .bind(withdrawal.id)
+    .bind(WithdrawalStatus::PENDING.to_string())
+    .bind(WithdrawalStatus::PROCESSING.to_string())
+    .execute(&mut *tx)
+    .await?;
+    // Refunding twice would credit the user money they were already paid back
+    if settled.rows_affected() == 0 {
+        return Err(anyhow!("Withdrawal {} is already settled", withdrawal.id));
+    }
+
+    sqlx::query(
+        "UPDATE wallet SET balance = balance + $1, updated_at = NOW()
+         WHERE user_id = $2 AND currency = $3",
+    )
+    .bind(withdrawal.amount)
+    .bind(withdrawal.user_id)
+    .bind(&withdrawal.currency)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
 // pub async fn create_user_and_wallet(
 //     pool: &Pool<Postgres>,
 //     user: &User,
@@ -72,52 +708,158 @@ pub async fn update_user_wallet(
 //     Ok(())
 // }
 
+/// A finished game to settle
+#[derive(Debug, Clone, Copy)]
+pub struct FinishedGame<'a> {
+    pub game_id: &'a str,
+    pub user_ids: &'a [i32],
+    pub result: &'a GameResult,
+    pub single_bet_size: f64,
+    /// The losers left the game rather than hitting a bomb
+    pub abandoned: bool,
+    /// Hex SHA3-256 of the seed the board was drawn from
+    pub seed_hash: &'a str,
+}
+
+/// Moves the pot from the losers to the winners as `policy` splits it. The rake is
+/// recorded as a RAKE transaction against each loser who paid it, keyed by the game id,
+/// and the game itself is recorded in `games` along with the balance changes.
 pub async fn update_player_balances(
     pool: &Pool<Postgres>,
-    user_ids: &[i32],
-    loser_idx: usize,
-    single_bet_size: f64,
-    winning_amount: f64,
+    game: &FinishedGame<'_>,
     currency: Currency,
+    policy: &PayoutPolicy,
 ) -> Result<()> {
-    info!("Updating player balances for user_ids: {:?}", user_ids);
+    info!("Updating player balances for user_ids: {:?}", game.user_ids);
     let mut tx = pool.begin().await?;
     // Default to SOLANA network if none is provided
     let currency_str = currency.to_string();
 
-    for (i, user_id) in user_ids.iter().enumerate() {
-        info!("Currency: {:?}, user_id: {:?}", currency_str, user_id);
-        let current_balance: f64 =
-            sqlx::query_scalar("SELECT balance FROM wallet WHERE user_id = $1 AND currency = $2")
-                .bind(user_id)
-                .bind(currency_str.clone())
-                .fetch_one(&mut *tx)
-                .await?;
-        info!("Current balance: {:?}", current_balance);
+    let mut balances = Vec::with_capacity(game.user_ids.len());
+    for user_id in game.user_ids {
+        let balance: f64 = sqlx::query_scalar(
+            "SELECT balance FROM wallet WHERE user_id = $1 AND currency = $2 FOR UPDATE",
+        )
+        .bind(user_id)
+        .bind(&currency_str)
+        .fetch_one(&mut *tx)
+        .await?;
+        balances.push(balance);
+    }
+    info!("Current balances: {:?}", balances);
 
-        let (new_balance, profit) = if i == loser_idx {
-            (current_balance - single_bet_size, -single_bet_size)
-        } else {
-            (current_balance + winning_amount, winning_amount)
-        };
+    let settlement = policy.settle(game.result, game.single_bet_size, &balances, game.abandoned);
+    record_finished_game_tx(&mut tx, game, &currency_str, &settlement).await?;
+    remove_running_game(&mut *tx, game.game_id).await?;
 
+    for ((user_id, balance), profit) in game.user_ids.iter().zip(balances).zip(settlement.deltas) {
         sqlx::query(
             "UPDATE wallet SET balance = $1, updated_at = CURRENT_TIMESTAMP 
              WHERE user_id = $2 AND currency = $3",
         )
-        .bind(new_balance)
+        .bind(balance + profit)
         .bind(user_id)
-        .bind(currency_str.clone())
+        .bind(&currency_str)
         .execute(&mut *tx)
         .await?;
 
         record_game_result_tx(&mut tx, *user_id, &currency_str, profit).await?;
     }
 
+    for (user_id, rake) in game.user_ids.iter().zip(settlement.rakes) {
+        if rake <= 0.0 {
+            continue;
+        }
+        sqlx::query(
+            "INSERT INTO transactions (user_id, amount, currency, tx_type, tx_hash) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(user_id)
+        .bind(rake)
+        .bind(&currency_str)
+        .bind(TxType::RAKE.to_string())
+        .bind(game.game_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
     tx.commit().await?;
     Ok(())
 }
 
+/// Records a game that just started running, so reconciliation counts its stakes as in
+/// flight until it settles. A rematch keeps its game id and replaces the row.
+pub async fn record_running_game<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    game_id: &str,
+    user_ids: &[i32],
+    currency: Currency,
+    single_bet_size: f64,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO running_games (game_id, user_ids, currency, single_bet_size)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (game_id) DO UPDATE
+         SET user_ids = $2, currency = $3, single_bet_size = $4, started_at = CURRENT_TIMESTAMP",
+    )
+    .bind(game_id)
+    .bind(user_ids)
+    .bind(currency.to_string())
+    .bind(single_bet_size)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Forgets a running game, once it settles or ends without settling.
+pub async fn remove_running_game<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    game_id: &str,
+) -> Result<()> {
+    sqlx::query("DELETE FROM running_games WHERE game_id = $1")
+        .bind(game_id)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Keeps a settled game for match history and disputes. `pot` is what the losers paid in,
+/// before the rake was taken out of it.
+pub async fn record_finished_game_tx(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    game: &FinishedGame<'_>,
+    currency: &str,
+    settlement: &Settlement,
+) -> Result<()> {
+    let loser_ids: Vec<i32> = game
+        .result
+        .losers()
+        .iter()
+        .filter_map(|&idx| game.user_ids.get(idx).copied())
+        .collect();
+    let pot: f64 = settlement
+        .deltas
+        .iter()
+        .map(|delta| (-delta).max(0.0))
+        .sum();
+
+    sqlx::query(
+        "INSERT INTO games (game_id, seed_hash, user_ids, loser_ids, currency, single_bet_size, pot, rake, abandoned)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+    )
+    .bind(game.game_id)
+    .bind(game.seed_hash)
+    .bind(game.user_ids)
+    .bind(&loser_ids)
+    .bind(currency)
+    .bind(game.single_bet_size)
+    .bind(pot)
+    .bind(settlement.rake())
+    .bind(game.abandoned)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
 pub async fn record_game_result_tx(
     tx: &mut sqlx::Transaction<'_, Postgres>,
     user_id: i32,
@@ -154,6 +896,41 @@ pub async fn record_game_result_tx(
     Ok(())
 }
 
+/// `user_id`'s settled games, most recent first
+pub async fn get_match_history(
+    pool: &Pool<Postgres>,
+    user_id: i32,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<MatchHistoryEntry>, Error> {
+    sqlx::query_as(
+        "SELECT g.game_id,
+                ARRAY(
+                    SELECT COALESCE(u.name, '')
+                    FROM unnest(g.user_ids) WITH ORDINALITY AS p(user_id, seat)
+                    LEFT JOIN users u ON u.id = p.user_id
+                    WHERE p.user_id <> $1
+                    ORDER BY p.seat
+                ) AS opponents,
+                CASE
+                    WHEN $1 = ANY(g.loser_ids) THEN 'lost'
+                    WHEN cardinality(g.loser_ids) = 0 THEN 'draw'
+                    ELSE 'won'
+                END AS outcome,
+                g.single_bet_size, g.currency, g.finished_at
+         FROM games g
+         WHERE $1 = ANY(g.user_ids)
+         ORDER BY g.finished_at DESC, g.id DESC
+         LIMIT $2 OFFSET $3",
+    )
+    .bind(user_id)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+    .map_err(Error::from)
+}
+
 pub async fn get_leaderboard_24h(
     pool: &Pool<Postgres>,
     currency: &str,
@@ -179,3 +956,634 @@ pub async fn get_leaderboard_all_time(
         .await
         .map_err(Error::from)
 }
+
+/// Inserts a user with a unique privy id and email and returns its id, for tests run
+/// against a real database.
+#[cfg(any(test, feature = "test-utils"))]
+pub async fn create_test_user(tx: &mut sqlx::Transaction<'_, Postgres>) -> Result<i32> {
+    // Tests in one process may create users within the same microsecond
+    static CREATED: AtomicUsize = AtomicUsize::new(0);
+    let privy_id = format!(
+        "test-{}-{}",
+        chrono::Utc::now().timestamp_micros(),
+        CREATED.fetch_add(1, Ordering::Relaxed)
+    );
+    sqlx::query_scalar("INSERT INTO users (privy_id, email, name) VALUES ($1, $2, $3) RETURNING id")
+        .bind(&privy_id)
+        .bind(format!("{}@example.com", privy_id))
+        .bind("test")
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_config_from_env() {
+        let config = PoolConfig::from_lookup(|_| None).unwrap();
+        assert_eq!(config, PoolConfig::default());
+
+        let config = PoolConfig::from_lookup(|key| match key {
+            "DB_MAX_CONNECTIONS" => Some("25".to_string()),
+            "DB_IDLE_TIMEOUT_SECS" => Some("60".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(config.max_connections, 25);
+        assert_eq!(
+            config.acquire_timeout,
+            PoolConfig::default().acquire_timeout
+        );
+        assert_eq!(config.idle_timeout, Duration::from_secs(60));
+
+        assert!(PoolConfig::from_lookup(|_| Some("0".to_string())).is_err());
+        assert!(PoolConfig::from_lookup(|_| Some("ten".to_string())).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_pool_config_is_applied() -> Result<()> {
+        dotenv::dotenv().ok();
+        let config = PoolConfig {
+            max_connections: 1,
+            acquire_timeout: Duration::from_millis(200),
+            ..PoolConfig::default()
+        };
+        let pool = config
+            .pool_options()
+            .connect(&env::var("DATABASE_URL")?)
+            .await?;
+
+        let _held = pool.acquire().await?;
+        assert_eq!(pool.size(), 1);
+        // The only connection is taken, so the next acquire gives up after the timeout
+        assert!(matches!(
+            pool.acquire().await,
+            Err(sqlx::Error::PoolTimedOut)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        assert!(is_not_found(&Error::from(sqlx::Error::RowNotFound)));
+        assert!(!is_not_found(&Error::from(sqlx::Error::PoolTimedOut)));
+        assert!(!is_not_found(&anyhow::anyhow!("no rows")));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_provision_user_wallets() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        // Everything runs inside a transaction that is rolled back on drop
+        let mut tx = pool.begin().await?;
+
+        let user_id = create_test_user(&mut tx).await?;
+
+        let currencies = [Currency::SOL, Currency::MON];
+        let wallets =
+            provision_user_wallets_tx(&mut tx, user_id, &currencies, WalletType::PDA).await?;
+        assert_eq!(wallets.len(), 2);
+
+        sqlx::query("UPDATE wallet SET balance = $1 WHERE user_id = $2 AND currency = $3")
+            .bind(1.5)
+            .bind(user_id)
+            .bind(Currency::SOL.to_string())
+            .execute(&mut *tx)
+            .await?;
+
+        // Provisioning again must not duplicate or reset existing wallets
+        let wallets =
+            provision_user_wallets_tx(&mut tx, user_id, &currencies, WalletType::PDA).await?;
+        assert_eq!(wallets.len(), 2);
+        let balance_of = |currency: Currency| {
+            wallets
+                .iter()
+                .find(|w| w.currency == currency.to_string())
+                .map(|w| w.balance)
+        };
+        assert_eq!(balance_of(Currency::SOL), Some(1.5));
+        assert_eq!(balance_of(Currency::MON), Some(0.0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_first_wallet_address_is_kept() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let user_id = create_test_user(&mut tx).await?;
+        provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+
+        let wallet = register_wallet_address_tx(&mut tx, user_id, Currency::SOL, "first").await?;
+        assert_eq!(wallet.unwrap().wallet_address.as_deref(), Some("first"));
+        // Deposits from the first wallet can't be redirected to another
+        assert!(
+            register_wallet_address_tx(&mut tx, user_id, Currency::SOL, "second")
+                .await?
+                .is_none()
+        );
+        assert!(
+            register_wallet_address_tx(&mut tx, user_id, Currency::MON, "0x1")
+                .await?
+                .is_none()
+        );
+        let wallets = provision_user_wallets_tx(&mut tx, user_id, &[], WalletType::PDA).await?;
+        assert_eq!(wallets[0].wallet_address.as_deref(), Some("first"));
+        // Registering it again is harmless
+        let wallet = register_wallet_address_tx(&mut tx, user_id, Currency::SOL, "first").await?;
+        assert_eq!(wallet.unwrap().wallet_address.as_deref(), Some("first"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_wallet_address_belongs_to_one_user() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let owner = create_test_user(&mut tx).await?;
+        let other = create_test_user(&mut tx).await?;
+        for user_id in [owner, other] {
+            provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        }
+        let address = format!("wallet-of-{}", owner);
+        register_wallet_address_tx(&mut tx, owner, Currency::SOL, &address).await?;
+
+        // Another user can't have the owner's deposits credited to them
+        let err = register_wallet_address_tx(&mut tx, other, Currency::SOL, &address)
+            .await
+            .unwrap_err();
+        assert!(is_unique_violation(&err), "{:?}", err);
+        assert!(!is_not_found(&err));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_withdrawal_queue() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+
+        let mut tx = pool.begin().await?;
+        let user_id = create_test_user(&mut tx).await?;
+        provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        sqlx::query("UPDATE wallet SET balance = 2.0 WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        // Enqueueing holds the full amount
+        let sent = enqueue_withdrawal_tx(&mut tx, user_id, Currency::SOL, 1.0, 0.1, "addr").await?;
+        let failed =
+            enqueue_withdrawal_tx(&mut tx, user_id, Currency::SOL, 0.5, 0.0, "addr").await?;
+        assert!(
+            enqueue_withdrawal_tx(&mut tx, user_id, Currency::SOL, 5.0, 0.0, "addr")
+                .await
+                .is_err()
+        );
+        tx.commit().await?;
+        assert_eq!(sent.status, WithdrawalStatus::PENDING.to_string());
+        assert_eq!(
+            get_user_wallet(&pool, user_id, Currency::SOL)
+                .await?
+                .balance,
+            0.5
+        );
+
+        // A successful transfer keeps the hold and records the ledger entries
+        complete_withdrawal(&pool, &sent, "tx-hash").await?;
+        let sent = get_pending_withdrawal(&pool, sent.id).await?;
+        assert_eq!(sent.status, WithdrawalStatus::COMPLETED.to_string());
+        assert_eq!(sent.tx_hash.as_deref(), Some("tx-hash"));
+        let entries: Vec<(String, f64)> = sqlx::query_as(
+            "SELECT tx_type, amount FROM transactions WHERE user_id = $1 ORDER BY tx_type",
+        )
+        .bind(user_id)
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(
+            entries,
+            [
+                (TxType::FEE.to_string(), 0.1),
+                (TxType::WITHDRAWAL.to_string(), 0.9)
+            ]
+        );
+        // Together they add up to what was taken from the wallet
+        let ledger: f64 = entries.iter().map(|(_, amount)| amount).sum();
+        assert!((ledger - sent.amount).abs() < 1e-9);
+
+        // A failed transfer refunds the hold
+        fail_withdrawal(&pool, &failed, "rpc down").await?;
+        let failed = get_pending_withdrawal(&pool, failed.id).await?;
+        assert_eq!(failed.status, WithdrawalStatus::FAILED.to_string());
+        assert_eq!(
+            get_user_wallet(&pool, user_id, Currency::SOL)
+                .await?
+                .balance,
+            1.0
+        );
+
+        // Settling again changes nothing
+        assert!(fail_withdrawal(&pool, &failed, "rpc down").await.is_err());
+        assert!(complete_withdrawal(&pool, &failed, "tx-hash")
+            .await
+            .is_err());
+        assert_eq!(
+            get_user_wallet(&pool, user_id, Currency::SOL)
+                .await?
+                .balance,
+            1.0
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_settlement_records_rake() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let loser = create_test_user(&mut tx).await?;
+        let winner = create_test_user(&mut tx).await?;
+        for user_id in [loser, winner] {
+            provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+            sqlx::query("UPDATE wallet SET balance = 1.0 WHERE user_id = $1")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        let game_id = format!("game-{}", loser);
+        let policy = PayoutPolicy {
+            rake_percent: 10.0,
+            ..PayoutPolicy::default()
+        };
+        let game = FinishedGame {
+            game_id: &game_id,
+            user_ids: &[loser, winner],
+            result: &GameResult::Loser(0),
+            single_bet_size: 0.5,
+            abandoned: false,
+            seed_hash: "seed",
+        };
+        update_player_balances(&pool, &game, Currency::SOL, &policy).await?;
+
+        let balance = |user_id| get_user_wallet(&pool, user_id, Currency::SOL);
+        assert_eq!(balance(loser).await?.balance, 0.5);
+        assert_eq!(balance(winner).await?.balance, 1.45);
+        let rake: f64 = sqlx::query_scalar(
+            "SELECT amount FROM transactions WHERE user_id = $1 AND tx_type = $2 AND tx_hash = $3",
+        )
+        .bind(loser)
+        .bind(TxType::RAKE.to_string())
+        .bind(&game_id)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(rake, 0.05);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_match_history() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let mut users = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            let user_id = create_test_user(&mut tx).await?;
+            sqlx::query("UPDATE users SET name = $1 WHERE id = $2")
+                .bind(name)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+            provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+            users.push(user_id);
+        }
+        tx.commit().await?;
+        let (alice, bob, carol) = (users[0], users[1], users[2]);
+
+        let games = [
+            (vec![alice, bob], GameResult::Loser(0)),
+            (vec![bob, carol], GameResult::Loser(1)),
+            (vec![bob, alice], GameResult::Loser(0)),
+            (vec![alice, bob], GameResult::Draw),
+        ];
+        for (i, (user_ids, result)) in games.iter().enumerate() {
+            let game_id = format!("game-{}-{}", alice, i);
+            let game = FinishedGame {
+                game_id: &game_id,
+                user_ids,
+                result,
+                single_bet_size: 0.1,
+                abandoned: false,
+                seed_hash: "seed",
+            };
+            update_player_balances(&pool, &game, Currency::SOL, &PayoutPolicy::default()).await?;
+        }
+
+        // Most recent first, and only games alice played
+        let history = get_match_history(&pool, alice, 10, 0).await?;
+        let games: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.game_id.as_str(), entry.outcome.as_str()))
+            .collect();
+        let game_id = |i| format!("game-{}-{}", alice, i);
+        assert_eq!(
+            games,
+            [
+                (game_id(3).as_str(), "draw"),
+                (game_id(2).as_str(), "won"),
+                (game_id(0).as_str(), "lost"),
+            ]
+        );
+        for entry in &history {
+            assert_eq!(entry.opponents, ["bob"]);
+            assert_eq!(entry.single_bet_size, 0.1);
+            assert_eq!(entry.currency, Currency::SOL.to_string());
+        }
+        let carol_history = get_match_history(&pool, carol, 10, 0).await?;
+        assert_eq!(carol_history.len(), 1);
+        assert_eq!(carol_history[0].outcome, "lost");
+
+        // Pages follow on from each other
+        let first = get_match_history(&pool, alice, 2, 0).await?;
+        let second = get_match_history(&pool, alice, 2, 2).await?;
+        assert_eq!(first.len(), 2);
+        assert_eq!(second.len(), 1);
+        assert_eq!(first[0].game_id, game_id(3));
+        assert_eq!(first[1].game_id, game_id(2));
+        assert_eq!(second[0].game_id, game_id(0));
+        assert!(get_match_history(&pool, alice, 2, 4).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_rake_totals_accumulate() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let since = Timeframe {
+            from: Some(chrono::Utc::now()),
+            to: None,
+        };
+        let mon_rake = |totals: Vec<CurrencyRake>| {
+            totals
+                .into_iter()
+                .find(|row| row.currency == Currency::MON.to_string())
+                .map(|row| (row.rake, row.games))
+                .unwrap_or_default()
+        };
+        let before = mon_rake(rake_totals(&pool, since).await?);
+
+        let mut tx = pool.begin().await?;
+        let loser = create_test_user(&mut tx).await?;
+        let winner = create_test_user(&mut tx).await?;
+        for user_id in [loser, winner] {
+            provision_user_wallets_tx(&mut tx, user_id, &[Currency::MON], WalletType::PDA).await?;
+            sqlx::query("UPDATE wallet SET balance = 10.0 WHERE user_id = $1")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        let policy = PayoutPolicy {
+            rake_percent: 10.0,
+            ..PayoutPolicy::default()
+        };
+        for (game, bet) in [0.5, 1.0, 2.0].into_iter().enumerate() {
+            let game_id = format!("game-{}-{}", loser, game);
+            let game = FinishedGame {
+                game_id: &game_id,
+                user_ids: &[loser, winner],
+                result: &GameResult::Loser(0),
+                single_bet_size: bet,
+                abandoned: false,
+                seed_hash: "seed",
+            };
+            update_player_balances(&pool, &game, Currency::MON, &policy).await?;
+        }
+        // A draw pays no rake
+        let draw_id = format!("game-{}-draw", loser);
+        let draw = FinishedGame {
+            game_id: &draw_id,
+            user_ids: &[loser, winner],
+            result: &GameResult::Draw,
+            single_bet_size: 1.0,
+            abandoned: false,
+            seed_hash: "seed",
+        };
+        update_player_balances(&pool, &draw, Currency::MON, &policy).await?;
+        // A rematch keeps the first game's id but is a game of its own
+        let rematch_id = format!("game-{}-0", loser);
+        let rematch = FinishedGame {
+            game_id: &rematch_id,
+            user_ids: &[loser, winner],
+            result: &GameResult::Loser(1),
+            single_bet_size: 0.5,
+            abandoned: false,
+            seed_hash: "seed",
+        };
+        update_player_balances(&pool, &rematch, Currency::MON, &policy).await?;
+
+        let after = mon_rake(rake_totals(&pool, since).await?);
+        assert!((after.0 - before.0 - 0.4).abs() < 1e-9);
+        assert_eq!(after.1 - before.1, 4);
+
+        // An empty window has no rake in it
+        let none_yet = Timeframe {
+            from: since.from,
+            to: since.from,
+        };
+        assert!(rake_totals(&pool, none_yet).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_credit_deposit_once() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let user_id = create_test_user(&mut tx).await?;
+        provision_user_wallets_tx(&mut tx, user_id, &[Currency::INR], WalletType::DIRECT).await?;
+
+        let payment_id = format!("pay_{}", user_id);
+        assert!(credit_deposit_once_tx(&mut tx, user_id, Currency::INR, 100.0, &payment_id).await?);
+        assert!(
+            !credit_deposit_once_tx(&mut tx, user_id, Currency::INR, 100.0, &payment_id).await?
+        );
+
+        let balance: f64 =
+            sqlx::query_scalar("SELECT balance FROM wallet WHERE user_id = $1 AND currency = $2")
+                .bind(user_id)
+                .bind(Currency::INR.to_string())
+                .fetch_one(&mut *tx)
+                .await?;
+        assert_eq!(balance, 100.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_refund_deposit() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let user_id = create_test_user(&mut tx).await?;
+        provision_user_wallets_tx(&mut tx, user_id, &[Currency::INR], WalletType::DIRECT).await?;
+
+        let payment_id = format!("pay_{}", user_id);
+        credit_deposit_once_tx(&mut tx, user_id, Currency::INR, 100.0, &payment_id).await?;
+
+        // Partial refund, then the remainder
+        assert_eq!(
+            refund_deposit_tx(&mut tx, &payment_id, Some(30.0)).await?,
+            (user_id, 30.0)
+        );
+        assert!(refund_deposit_tx(&mut tx, &payment_id, Some(80.0))
+            .await
+            .is_err());
+        assert_eq!(
+            refund_deposit_tx(&mut tx, &payment_id, None).await?,
+            (user_id, 70.0)
+        );
+        assert!(refund_deposit_tx(&mut tx, &payment_id, None).await.is_err());
+
+        let balance: f64 =
+            sqlx::query_scalar("SELECT balance FROM wallet WHERE user_id = $1 AND currency = $2")
+                .bind(user_id)
+                .bind(Currency::INR.to_string())
+                .fetch_one(&mut *tx)
+                .await?;
+        assert_eq!(balance, 0.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_referral_bonus_is_credited_once() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let referrer = create_test_user(&mut tx).await?;
+        let referee = create_test_user(&mut tx).await?;
+        for user_id in [referrer, referee] {
+            provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        }
+        let code: String = sqlx::query_scalar("SELECT referral_code FROM users WHERE id = $1")
+            .bind(referrer)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        assert_eq!(
+            record_referral_tx(&mut tx, referee, "no-such-code").await?,
+            None
+        );
+        assert_eq!(
+            record_referral_tx(&mut tx, referee, &code.to_lowercase()).await?,
+            Some(referrer)
+        );
+
+        // Only the first deposit pays the referrer
+        for (i, expected) in [Some(referrer), None].into_iter().enumerate() {
+            credit_deposit_once_tx(&mut tx, referee, Currency::SOL, 1.0, &format!("tx-{}", i))
+                .await?;
+            assert_eq!(
+                credit_referral_bonus_tx(&mut tx, referee, Currency::SOL, 0.05).await?,
+                expected
+            );
+        }
+        // Nobody referred the referrer
+        assert_eq!(
+            credit_referral_bonus_tx(&mut tx, referrer, Currency::SOL, 0.05).await?,
+            None
+        );
+
+        let balance: f64 =
+            sqlx::query_scalar("SELECT balance FROM wallet WHERE user_id = $1 AND currency = $2")
+                .bind(referrer)
+                .bind(Currency::SOL.to_string())
+                .fetch_one(&mut *tx)
+                .await?;
+        assert_eq!(balance, 0.05);
+        let bonuses: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM transactions WHERE user_id = $1 AND tx_type = $2",
+        )
+        .bind(referrer)
+        .bind(TxType::BONUS.to_string())
+        .fetch_one(&mut *tx)
+        .await?;
+        assert_eq!(bonuses, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_reconciliation_report() -> Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let totals = |report: Vec<CurrencyReconciliation>, currency: Currency| {
+            report
+                .into_iter()
+                .find(|row| row.currency == currency.to_string())
+                .map(|row| {
+                    (
+                        row.wallet_balances,
+                        row.pending_withdrawals,
+                        row.in_flight_stakes,
+                        row.liabilities,
+                    )
+                })
+                .unwrap_or_default()
+        };
+        let before = totals(reconciliation_report(&mut *tx).await?, Currency::SOL);
+
+        let user_id = create_test_user(&mut tx).await?;
+        provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        credit_deposit_once_tx(
+            &mut tx,
+            user_id,
+            Currency::SOL,
+            2.0,
+            &format!("tx-{}", user_id),
+        )
+        .await?;
+        // Held from the wallet while the worker hasn't sent it
+        enqueue_withdrawal_tx(&mut tx, user_id, Currency::SOL, 0.5, 0.0, "address").await?;
+        // Two players each staking 0.25 in a game that hasn't settled
+        let opponent = create_test_user(&mut tx).await?;
+        record_running_game(
+            &mut *tx,
+            &format!("game-{}", user_id),
+            &[user_id, opponent],
+            Currency::SOL,
+            0.25,
+        )
+        .await?;
+
+        let after = totals(reconciliation_report(&mut *tx).await?, Currency::SOL);
+        assert!((after.0 - before.0 - 1.5).abs() < 1e-9);
+        assert!((after.1 - before.1 - 0.5).abs() < 1e-9);
+        assert!((after.2 - before.2 - 0.5).abs() < 1e-9);
+        // The stakes are still in the wallets, so they add nothing owed
+        assert!((after.3 - before.3 - 2.0).abs() < 1e-9);
+
+        remove_running_game(&mut *tx, &format!("game-{}", user_id)).await?;
+        let settled = totals(reconciliation_report(&mut *tx).await?, Currency::SOL);
+        assert!((settled.2 - before.2).abs() < 1e-9);
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use std::{future::Future, time::Duration};
+
+use serde_json::{json, Map, Value};
+use sqlx::{Pool, Postgres};
+
+/// A dependency check that hasn't answered by now counts as failed, so a hung
+/// dependency can't hang the probe too.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Outcome of the readiness checks, one entry per dependency.
+#[derive(Debug, Default)]
+pub struct HealthReport {
+    checks: Vec<(&'static str, Result<(), String>)>,
+}
+
+impl HealthReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `check` with `CHECK_TIMEOUT` and records the result under `name`.
+    pub async fn check<F, E>(mut self, name: &'static str, check: F) -> Self
+    where
+        F: Future<Output = Result<(), E>>,
+        E: ToString,
+    {
+        let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err(format!("timed out after {:?}", CHECK_TIMEOUT)),
+        };
+        self.checks.push((name, result));
+        self
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|(_, result)| result.is_ok())
+    }
+
+    /// `{"status": "ok" | "unavailable", "checks": {"<name>": "ok" | "<error>"}}`
+    pub fn to_json(&self) -> Value {
+        let checks: Map<String, Value> = self
+            .checks
+            .iter()
+            .map(|(name, result)| {
+                let status = match result {
+                    Ok(()) => "ok".to_string(),
+                    Err(e) => e.clone(),
+                };
+                (name.to_string(), Value::String(status))
+            })
+            .collect();
+        json!({
+            "status": if self.is_healthy() { "ok" } else { "unavailable" },
+            "checks": checks,
+        })
+    }
+}
+
+pub async fn check_postgres(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1").execute(pool).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_all_healthy() {
+        let report = HealthReport::new()
+            .check("postgres", async { Ok::<_, String>(()) })
+            .await
+            .check("redis", async { Ok::<_, String>(()) })
+            .await;
+
+        assert!(report.is_healthy());
+        assert_eq!(
+            report.to_json(),
+            json!({ "status": "ok", "checks": { "postgres": "ok", "redis": "ok" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_one_dependency_down() {
+        let report = HealthReport::new()
+            .check("postgres", async { Ok::<_, String>(()) })
+            .await
+            .check("redis", async { Err("connection refused") })
+            .await;
+
+        assert!(!report.is_healthy());
+        assert_eq!(
+            report.to_json(),
+            json!({
+                "status": "unavailable",
+                "checks": { "postgres": "ok", "redis": "connection refused" }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hung_check_times_out() {
+        let report = HealthReport::new()
+            .check("rpc", std::future::pending::<Result<(), String>>())
+            .await;
+        assert!(!report.is_healthy());
+    }
+}
@@ -7,29 +7,41 @@ macro_rules! agg_mod {
     };
 }
 
+/// Parses a variant from its name, case-insensitively. A variant can be
+/// followed by `| "alias"` to accept extra spellings, e.g. `SOL | "solana"`.
 #[macro_export]
 macro_rules! impl_from_str_for_enum {
-    ($enum_name:ident, $( $variant:ident ),*) => {
+    ($enum_name:ident, $( $variant:ident $( | $alias:literal )* ),*) => {
         impl std::str::FromStr for $enum_name {
             type Err = anyhow::Error;
 
             fn from_str(s: &str) -> Result<Self, Self::Err> {
-                match s.to_uppercase().as_str() {
-                    $(stringify!($variant) => Ok($enum_name::$variant),)*
-                    _ => Err(anyhow::anyhow!("Invalid variant: {}", s)),
-                }
+                $(
+                    if s.eq_ignore_ascii_case(stringify!($variant))
+                        $( || s.eq_ignore_ascii_case($alias) )*
+                    {
+                        return Ok($enum_name::$variant);
+                    }
+                )*
+                Err(anyhow::anyhow!("Invalid variant: {}", s))
             }
         }
     };
 }
 
+/// Formats a variant as its name. Also generates `VARIANTS`, which the
+/// exhaustive match guarantees lists every variant.
 #[macro_export]
 macro_rules! impl_to_string_for_enum {
     ($enum_name:ident, $( $variant:ident ),*) => {
-        impl ToString for $enum_name {
-            fn to_string(&self) -> String {
+        impl $enum_name {
+            pub const VARIANTS: &'static [$enum_name] = &[$( $enum_name::$variant ),*];
+        }
+
+        impl std::fmt::Display for $enum_name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
                 match self {
-                    $( $enum_name::$variant => stringify!($variant).to_string(), )*
+                    $( $enum_name::$variant => f.write_str(stringify!($variant)), )*
                 }
             }
         }
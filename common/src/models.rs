@@ -12,6 +12,7 @@ pub struct User {
     pub created_at: chrono::DateTime<chrono::Utc>, // Use proper timestamp type
     pub updated_at: chrono::DateTime<chrono::Utc>,
     pub gif_ids: Vec<i32>,
+    pub referral_code: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
@@ -38,6 +39,26 @@ pub struct Transaction {
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
+pub struct PendingWithdrawal {
+    pub id: i32,
+    pub user_id: i32,
+    pub currency: String,
+    // Full amount held from the wallet, the user receives `amount - fee`
+    pub amount: f64,
+    pub fee: f64,
+    pub withdraw_address: String,
+    pub status: String,
+    pub tx_hash: Option<String>,
+    // Treasury nonce of a MON transfer
+    pub nonce: Option<i64>,
+    // Last block height a Solana transfer can land at
+    pub last_valid_block_height: Option<i64>,
+    pub error: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
 #[derive(Deserialize, Serialize, sqlx::FromRow)]
 pub struct GamePnl {
     pub id: i32,
@@ -67,3 +88,51 @@ pub struct LeaderboardEntry {
     pub total_matches: i64,
     pub rank: i64,
 }
+
+/// What the wallet owes users in one currency, to reconcile against treasury holdings
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
+pub struct CurrencyReconciliation {
+    pub currency: String,
+    pub wallet_balances: f64,
+    // Already debited from wallets but not yet sent by the withdrawal worker
+    pub pending_withdrawals: f64,
+    // Bet by players of games still running, already within `wallet_balances`
+    pub in_flight_stakes: f64,
+    pub liabilities: f64,
+}
+
+/// Rake the house kept in one currency
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
+pub struct CurrencyRake {
+    pub currency: String,
+    pub rake: f64,
+    // Settled games that paid any rake
+    pub games: i64,
+}
+
+/// A window of time; an unset bound leaves that side open
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize)]
+pub struct Timeframe {
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// A settled game as one of its players saw it
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
+pub struct MatchHistoryEntry {
+    pub game_id: String,
+    /// The other players' names, in seat order
+    pub opponents: Vec<String>,
+    /// "won", "lost" or "draw"
+    pub outcome: String,
+    pub single_bet_size: f64,
+    pub currency: String,
+    pub finished_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// The `limit` and `offset` query parameters of a paginated listing
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize)]
+pub struct Page {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
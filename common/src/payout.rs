@@ -0,0 +1,335 @@
+use std::env;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Who lost a finished game, by player index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameResult {
+    Loser(usize),
+    /// Several players were eliminated, each of them pays their bet
+    Losers(Vec<usize>),
+    /// Nobody lost, so nobody pays
+    Draw,
+}
+
+impl GameResult {
+    pub fn losers(&self) -> &[usize] {
+        match self {
+            GameResult::Loser(loser_idx) => std::slice::from_ref(loser_idx),
+            GameResult::Losers(losers) => losers,
+            GameResult::Draw => &[],
+        }
+    }
+}
+
+/// How a finished game's pot is split. The pot is what the losers pay in;
+/// the house keeps the rake and the winners share the rest equally.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PayoutPolicy {
+    /// Share of the pot kept by the house, in percent
+    pub rake_percent: f64,
+    /// Whether a loser may be charged more than their balance
+    pub allow_negative_balance: bool,
+    /// Charged on top of the bet to a player who abandons a game, in percent of the bet
+    pub abandonment_penalty_percent: f64,
+}
+
+impl Default for PayoutPolicy {
+    fn default() -> Self {
+        Self {
+            rake_percent: 0.0,
+            allow_negative_balance: true,
+            abandonment_penalty_percent: 0.0,
+        }
+    }
+}
+
+/// Balance changes of one settled game
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settlement {
+    /// Change of each player's balance, in player order
+    pub deltas: Vec<f64>,
+    /// Kept by the house out of each player's charge, in player order
+    pub rakes: Vec<f64>,
+}
+
+impl Settlement {
+    /// Everything the house keeps
+    pub fn rake(&self) -> f64 {
+        self.rakes.iter().sum()
+    }
+}
+
+impl PayoutPolicy {
+    /// Reads `PAYOUT_RAKE_PERCENT`, `PAYOUT_ALLOW_NEGATIVE_BALANCE` and
+    /// `PAYOUT_ABANDONMENT_PENALTY_PERCENT`, falling back to the defaults for unset variables.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let percent = |key: &str, default: f64, max: f64| -> Result<f64> {
+            match lookup(key) {
+                Some(value) => value
+                    .parse()
+                    .ok()
+                    .filter(|&value: &f64| (0.0..max).contains(&value))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "{} must be a percentage below {}, got {:?}",
+                            key,
+                            max,
+                            value
+                        )
+                    }),
+                None => Ok(default),
+            }
+        };
+        let default = Self::default();
+
+        Ok(Self {
+            // A 100% rake would leave the winners nothing
+            rake_percent: percent("PAYOUT_RAKE_PERCENT", default.rake_percent, 100.0)?,
+            allow_negative_balance: match lookup("PAYOUT_ALLOW_NEGATIVE_BALANCE") {
+                Some(value) => value.parse().map_err(|_| {
+                    anyhow!(
+                        "PAYOUT_ALLOW_NEGATIVE_BALANCE must be true or false, got {:?}",
+                        value
+                    )
+                })?,
+                None => default.allow_negative_balance,
+            },
+            abandonment_penalty_percent: percent(
+                "PAYOUT_ABANDONMENT_PENALTY_PERCENT",
+                default.abandonment_penalty_percent,
+                f64::INFINITY,
+            )?,
+        })
+    }
+
+    /// Splits a finished game between its players. `balances` are the players'
+    /// balances before settlement, which cap each loser's charge when balances
+    /// may not go negative. A draw, or a game everyone lost, is settled with no
+    /// money changing hands.
+    pub fn settle(
+        &self,
+        result: &GameResult,
+        single_bet_size: f64,
+        balances: &[f64],
+        abandoned: bool,
+    ) -> Settlement {
+        let players = balances.len();
+        let is_loser = |i: usize| result.losers().contains(&i);
+        let winners = (0..players).filter(|&i| !is_loser(i)).count();
+        if winners == 0 || winners == players {
+            return Settlement {
+                deltas: vec![0.0; players],
+                rakes: vec![0.0; players],
+            };
+        }
+
+        let charges: Vec<f64> = balances
+            .iter()
+            .enumerate()
+            .map(|(i, &balance)| {
+                if !is_loser(i) {
+                    return 0.0;
+                }
+                let mut charge = single_bet_size;
+                if abandoned {
+                    charge += single_bet_size * self.abandonment_penalty_percent / 100.0;
+                }
+                if !self.allow_negative_balance {
+                    charge = charge.min(balance.max(0.0));
+                }
+                charge
+            })
+            .collect();
+
+        let rakes: Vec<f64> = charges
+            .iter()
+            .map(|charge| charge * self.rake_percent / 100.0)
+            .collect();
+        let pot: f64 = charges
+            .iter()
+            .zip(&rakes)
+            .map(|(charge, rake)| charge - rake)
+            .sum();
+        let winning_amount = pot / winners as f64;
+        let deltas = charges
+            .iter()
+            .enumerate()
+            .map(
+                |(i, charge)| {
+                    if is_loser(i) {
+                        -charge
+                    } else {
+                        winning_amount
+                    }
+                },
+            )
+            .collect();
+
+        Settlement { deltas, rakes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn assert_conserves(settlement: &Settlement) {
+        let paid_out: f64 = settlement.deltas.iter().sum();
+        assert!(
+            (paid_out + settlement.rake()).abs() < 1e-12,
+            "{:?} does not conserve the pot",
+            settlement
+        );
+    }
+
+    #[test]
+    fn test_rake_is_deducted_and_the_pot_conserves() {
+        for rake_percent in [0.0, 1.0, 2.5, 10.0, 50.0] {
+            for players in [2, 3, 4] {
+                let policy = PayoutPolicy {
+                    rake_percent,
+                    ..PayoutPolicy::default()
+                };
+                let settlement =
+                    policy.settle(&GameResult::Loser(1), 0.2, &vec![1.0; players], false);
+
+                assert!((settlement.rake() - 0.2 * rake_percent / 100.0).abs() < 1e-12);
+                assert_eq!(settlement.deltas[1], -0.2);
+                let winning_amount = (0.2 - settlement.rake()) / (players - 1) as f64;
+                for (i, delta) in settlement.deltas.iter().enumerate() {
+                    if i != 1 {
+                        assert!((delta - winning_amount).abs() < 1e-12);
+                    }
+                }
+                assert_conserves(&settlement);
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_policy_pays_the_whole_bet() {
+        let settlement =
+            PayoutPolicy::default().settle(&GameResult::Loser(0), 0.3, &[0.0, 0.0, 0.0], false);
+
+        assert_eq!(settlement.rake(), 0.0);
+        assert_eq!(settlement.deltas, vec![-0.3, 0.15, 0.15]);
+    }
+
+    #[test]
+    fn test_charge_is_capped_at_the_balance() {
+        let policy = PayoutPolicy {
+            rake_percent: 10.0,
+            allow_negative_balance: false,
+            ..PayoutPolicy::default()
+        };
+        let settlement = policy.settle(&GameResult::Loser(0), 1.0, &[0.4, 1.0], false);
+
+        assert_eq!(settlement.deltas[0], -0.4);
+        assert!((settlement.rake() - 0.04).abs() < 1e-12);
+        assert_conserves(&settlement);
+
+        // A loser who is already negative pays nothing
+        let settlement = policy.settle(&GameResult::Loser(0), 1.0, &[-0.5, 1.0], false);
+        assert_eq!(settlement.deltas, vec![0.0, 0.0]);
+        assert_eq!(settlement.rake(), 0.0);
+    }
+
+    #[test]
+    fn test_abandonment_penalty_goes_into_the_pot() {
+        let policy = PayoutPolicy {
+            rake_percent: 5.0,
+            abandonment_penalty_percent: 50.0,
+            ..PayoutPolicy::default()
+        };
+
+        let settlement = policy.settle(&GameResult::Loser(1), 0.2, &[1.0, 1.0], true);
+        assert!((settlement.deltas[1] + 0.3).abs() < 1e-12);
+        assert!((settlement.rake() - 0.015).abs() < 1e-12);
+        assert_conserves(&settlement);
+
+        // Only abandonment is penalised
+        let settlement = policy.settle(&GameResult::Loser(1), 0.2, &[1.0, 1.0], false);
+        assert_eq!(settlement.deltas[1], -0.2);
+    }
+
+    #[test]
+    fn test_draw_refunds_everyone() {
+        let policy = PayoutPolicy {
+            rake_percent: 5.0,
+            ..PayoutPolicy::default()
+        };
+
+        let settlement = policy.settle(&GameResult::Draw, 0.2, &[1.0, 1.0, 1.0], false);
+        assert_eq!(settlement.deltas, vec![0.0; 3]);
+        assert_eq!(settlement.rake(), 0.0);
+
+        // With nobody left to win, a game everyone lost is a draw too
+        let settlement = policy.settle(&GameResult::Losers(vec![0, 1]), 0.2, &[1.0, 1.0], false);
+        assert_eq!(settlement.deltas, vec![0.0; 2]);
+        assert_eq!(settlement.rake(), 0.0);
+    }
+
+    #[test]
+    fn test_multiple_losers_split_between_winners() {
+        let policy = PayoutPolicy {
+            rake_percent: 10.0,
+            allow_negative_balance: false,
+            ..PayoutPolicy::default()
+        };
+        let settlement = policy.settle(
+            &GameResult::Losers(vec![0, 2]),
+            0.2,
+            &[1.0, 1.0, 0.1, 1.0],
+            false,
+        );
+
+        // The second loser can only cover their balance
+        assert_eq!(settlement.deltas[0], -0.2);
+        assert_eq!(settlement.deltas[2], -0.1);
+        assert!((settlement.rakes[0] - 0.02).abs() < 1e-12);
+        assert!((settlement.rakes[2] - 0.01).abs() < 1e-12);
+        assert_eq!(settlement.rakes[1], 0.0);
+        // Both winners share what's left of the pot
+        assert!((settlement.deltas[1] - 0.135).abs() < 1e-12);
+        assert!((settlement.deltas[3] - 0.135).abs() < 1e-12);
+        assert_conserves(&settlement);
+    }
+
+    #[test]
+    fn test_from_lookup() {
+        let lookup = |vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            PayoutPolicy::from_lookup(move |key| vars.get(key).cloned())
+        };
+
+        assert_eq!(lookup(&[]).unwrap(), PayoutPolicy::default());
+        assert_eq!(
+            lookup(&[
+                ("PAYOUT_RAKE_PERCENT", "2.5"),
+                ("PAYOUT_ALLOW_NEGATIVE_BALANCE", "false"),
+                ("PAYOUT_ABANDONMENT_PENALTY_PERCENT", "150"),
+            ])
+            .unwrap(),
+            PayoutPolicy {
+                rake_percent: 2.5,
+                allow_negative_balance: false,
+                abandonment_penalty_percent: 150.0,
+            }
+        );
+        assert!(lookup(&[("PAYOUT_RAKE_PERCENT", "100")]).is_err());
+        assert!(lookup(&[("PAYOUT_RAKE_PERCENT", "-1")]).is_err());
+        assert!(lookup(&[("PAYOUT_ALLOW_NEGATIVE_BALANCE", "no")]).is_err());
+        assert!(lookup(&[("PAYOUT_ABANDONMENT_PENALTY_PERCENT", "NaN")]).is_err());
+    }
+}
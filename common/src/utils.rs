@@ -10,20 +10,34 @@ pub enum Currency {
     MON,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum TxType {
     DEPOSIT,
     WITHDRAWAL,
     MINT,
+    FEE,
+    REFUND,
+    // The house's cut of a game's pot
+    RAKE,
+    // Paid to a referrer on their referee's first deposit
+    BONUS,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawalStatus {
+    PENDING,
+    PROCESSING,
+    COMPLETED,
+    FAILED,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum Network {
     SOLANA,
     MONAD,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum WalletType {
     PDA,
     DIRECT,
@@ -34,8 +48,18 @@ pub struct UserDetailsRequest {
     pub name: String,
     pub email: String,
     pub privy_id: String,
-    pub wallet_address: Option<String>,
     pub currency: Option<Currency>,
+    /// Code of the user who referred a new user; ignored for existing users
+    pub referral_code: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RegisterWalletRequest {
+    pub user_id: i32,
+    pub currency: Currency,
+    pub address: String,
+    /// The wallet's signature of the message that registers it to the user
+    pub signature: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -70,6 +94,16 @@ pub struct WithdrawRequest {
     pub amount: f64,
     pub currency: Currency,
     pub withdraw_address: String,
+    // A retry with the same key gets the first request's response instead of a second withdrawal
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefundRequest {
+    pub payment_id: String,
+    // In rupees; refunds whatever is left of the payment when omitted
+    pub amount: Option<f64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -81,11 +115,70 @@ pub struct MintNftRequest {
     pub tx_hash: String,
 }
 
-impl_from_str_for_enum!(Currency, INR, SOL, USDC, MON);
+impl_from_str_for_enum!(Currency, INR, SOL | "solana", USDC, MON | "monad");
 impl_to_string_for_enum!(Currency, INR, SOL, USDC, MON);
-impl_from_str_for_enum!(TxType, DEPOSIT, WITHDRAWAL, MINT);
-impl_to_string_for_enum!(TxType, DEPOSIT, WITHDRAWAL, MINT);
+impl_from_str_for_enum!(TxType, DEPOSIT, WITHDRAWAL, MINT, FEE, REFUND, RAKE, BONUS);
+impl_to_string_for_enum!(TxType, DEPOSIT, WITHDRAWAL, MINT, FEE, REFUND, RAKE, BONUS);
+impl_from_str_for_enum!(WithdrawalStatus, PENDING, PROCESSING, COMPLETED, FAILED);
+impl_to_string_for_enum!(WithdrawalStatus, PENDING, PROCESSING, COMPLETED, FAILED);
 impl_from_str_for_enum!(Network, SOLANA, MONAD);
 impl_to_string_for_enum!(Network, SOLANA, MONAD);
 impl_from_str_for_enum!(WalletType, PDA, DIRECT);
 impl_to_string_for_enum!(WalletType, PDA, DIRECT);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_currency_round_trip() {
+        for &currency in Currency::VARIANTS {
+            assert_eq!(Currency::from_str(&currency.to_string()).unwrap(), currency);
+            assert_eq!(
+                Currency::from_str(&currency.to_string().to_lowercase()).unwrap(),
+                currency
+            );
+        }
+    }
+
+    #[test]
+    fn test_currency_aliases() {
+        assert_eq!(Currency::from_str("solana").unwrap(), Currency::SOL);
+        assert_eq!(Currency::from_str("Solana").unwrap(), Currency::SOL);
+        assert_eq!(Currency::from_str("MONAD").unwrap(), Currency::MON);
+        // Aliases only parse, they never change how a currency is written
+        assert_eq!(Currency::SOL.to_string(), "SOL");
+    }
+
+    #[test]
+    fn test_unknown_variants() {
+        assert!(Currency::from_str("").is_err());
+        assert!(Currency::from_str("BTC").is_err());
+        assert!(Currency::from_str(" SOL").is_err());
+        assert!(TxType::from_str("DEPOSITS").is_err());
+    }
+
+    #[test]
+    fn test_enum_round_trips() {
+        for &tx_type in TxType::VARIANTS {
+            assert_eq!(TxType::from_str(&tx_type.to_string()).unwrap(), tx_type);
+        }
+        for &status in WithdrawalStatus::VARIANTS {
+            assert_eq!(
+                WithdrawalStatus::from_str(&status.to_string()).unwrap(),
+                status
+            );
+        }
+        for &network in Network::VARIANTS {
+            assert_eq!(Network::from_str(&network.to_string()).unwrap(), network);
+        }
+        for &wallet_type in WalletType::VARIANTS {
+            assert_eq!(
+                WalletType::from_str(&wallet_type.to_string()).unwrap(),
+                wallet_type
+            );
+        }
+    }
+}
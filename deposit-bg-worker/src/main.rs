@@ -1,43 +1,117 @@
-// use std::{str::FromStr, time::Duration};
-
-// use common::{db::establish_connection, models::User};
-// use deposits::sol::DepositService;
-// use dotenv::dotenv;
-// use solana_sdk::pubkey::Pubkey;
-// use tokio::time::sleep;
-// use tracing::info;
-
-// #[tokio::main]
-// async fn main() -> anyhow::Result<()> {
-//     dotenv().ok();
-
-//     info!("Starting the deposit background service");
-//     let program_id = std::env::var("PROGRAM_ID").unwrap();
-
-//     let cwd = std::env::current_dir().unwrap();
-//     let service = DepositService::new(cwd.join("treasury-keypair.json"), program_id.to_string());
-
-//     let pool = establish_connection();
-//     let mut conn = pool.await.acquire().await.expect("DB conn failed");
-
-//     loop {
-//         info!("Hello");
-//         let users: Vec<User> = sqlx::query_as("SELECT * FROM users")
-//             .fetch_all(&mut conn)
-//             .await
-//             .expect("Fqailed to fetch users");
-
-//         let users_pubkeys: Vec<_> = users
-//             .iter()
-//             .filter_map(|user| {
-//                 user.user_pda
-//                     .as_ref()
-//                     .map(|pda| Pubkey::from_str(pda).unwrap())
-//             })
-//             .collect();
-
-//         service.check_deposits(users_pubkeys).await.unwrap();
-
-//         sleep(Duration::from_secs(10)).await;
-//     }
-// }
+use std::{env, future::Future, time::Duration};
+
+use common::db::establish_connection;
+use deposits::sol::{self, DepositService};
+use dotenv::dotenv;
+use sqlx::{Pool, Postgres};
+use tokio::time::sleep;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv().ok();
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    info!("Starting the deposit background worker");
+    let pool = establish_connection().await;
+
+    let program_id = sol::program_id_from_env()?;
+    let treasury = sol::treasury_keypair_from_env()?;
+    let service = DepositService::new(treasury, program_id);
+
+    let poll_interval = Duration::from_secs(
+        env::var("DEPOSIT_POLL_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(10),
+    );
+
+    poll_forever(|| check_deposits(&pool, &service), poll_interval).await
+}
+
+/// Runs `poll` every `interval`. A failed poll is logged and retried on the next
+/// tick, so a dropped database connection or RPC hiccup never stops the worker.
+async fn poll_forever<F, Fut>(mut poll: F, interval: Duration) -> !
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    loop {
+        if let Err(err) = poll().await {
+            warn!("Deposit check failed, retrying: {:?}", err);
+        }
+        sleep(interval).await;
+    }
+}
+
+async fn check_deposits(pool: &Pool<Postgres>, service: &DepositService) -> anyhow::Result<()> {
+    // Runs on a fresh connection from the pool, which replaces connections that dropped
+    let user_pdas: Vec<String> =
+        sqlx::query_scalar("SELECT user_pda FROM users WHERE user_pda IS NOT NULL")
+            .fetch_all(pool)
+            .await?;
+
+    let pubkeys = user_pdas
+        .iter()
+        .filter_map(|pda| match sol::parse_address(pda) {
+            Ok(pubkey) => Some(pubkey),
+            Err(_) => {
+                warn!("Skipping malformed deposit address {:?}", pda);
+                None
+            }
+        })
+        .collect();
+
+    service.check_deposits(pubkeys).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use anyhow::anyhow;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_poll_survives_failures() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (succeeded, mut successes) = mpsc::unbounded_channel();
+
+        let worker = tokio::spawn({
+            let attempts = attempts.clone();
+            poll_forever(
+                move || {
+                    let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                    let succeeded = succeeded.clone();
+                    async move {
+                        // The first polls fail like a query on a dropped connection would
+                        if attempt < 2 {
+                            return Err(anyhow!("connection closed"));
+                        }
+                        succeeded.send(attempt).unwrap();
+                        Ok(())
+                    }
+                },
+                Duration::from_millis(1),
+            )
+        });
+
+        let attempt = tokio::time::timeout(Duration::from_secs(5), successes.recv())
+            .await
+            .expect("the worker stopped polling")
+            .unwrap();
+        assert_eq!(attempt, 2);
+        assert!(!worker.is_finished());
+        worker.abort();
+    }
+}
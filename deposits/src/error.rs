@@ -0,0 +1,28 @@
+use solana_client::client_error::ClientError;
+use solana_sdk::program_error::ProgramError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DepositError {
+    #[error("Invalid address: {0}")]
+    InvalidAddress(String),
+    #[error("Invalid transaction signature: {0}")]
+    InvalidSignature(String),
+    #[error("Deposit address {0} is already assigned")]
+    AddressAlreadyAssigned(String),
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    // Boxed, the client error is large enough to bloat every Result carrying it
+    #[error("Solana RPC error: {0}")]
+    Rpc(Box<ClientError>),
+    #[error("Failed to build instruction: {0}")]
+    Instruction(#[from] ProgramError),
+    #[error("Background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl From<ClientError> for DepositError {
+    fn from(err: ClientError) -> Self {
+        DepositError::Rpc(Box::new(err))
+    }
+}
@@ -1 +1,3 @@
+pub mod error;
+pub mod rpc_pool;
 pub mod sol;
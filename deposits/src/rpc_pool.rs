@@ -0,0 +1,237 @@
+// The calls hand back the client's own error, which is boxed into a DepositError once
+// a node has answered
+#![allow(clippy::result_large_err)]
+
+use std::{
+    env,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::anyhow;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    rpc_client::RpcClient,
+    rpc_config::RpcTransactionConfig,
+    rpc_request::RpcError,
+};
+use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::Signature,
+    transaction::{Result as TransactionResult, Transaction},
+};
+use solana_transaction_status::EncodedConfirmedTransactionWithStatusMeta;
+use tracing::warn;
+
+use crate::error::DepositError;
+
+/// Parses a comma separated list of RPC URLs, as `SOLANA_RPC_URL` holds them.
+pub fn parse_rpc_urls(urls: &str) -> anyhow::Result<Vec<String>> {
+    let urls: Vec<String> = urls
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect();
+    if urls.is_empty() {
+        return Err(anyhow!("Expected at least one Solana RPC URL"));
+    }
+    Ok(urls)
+}
+
+// Errors that say nothing about the request itself, so another node may well answer it.
+// The client reports a node it couldn't ask for its version ahead of a call, or that sent
+// back nonsense, as an RpcRequestError.
+fn is_unreachable(err: &ClientError) -> bool {
+    match err.kind() {
+        ClientErrorKind::Io(_) => true,
+        ClientErrorKind::Reqwest(err) => err.is_connect() || err.is_timeout(),
+        ClientErrorKind::RpcError(RpcError::RpcRequestError(_)) => true,
+        _ => false,
+    }
+}
+
+/// Solana RPC nodes to fail over between, the first one being the primary. A call that
+/// can't get an answer out of a node, e.g. by a connection error or a timeout, is retried
+/// on the next one; any other error is the call's own and is returned as is.
+pub struct RpcPool {
+    clients: Vec<RpcClient>,
+    // The node calls start with: the last one that answered
+    healthy: AtomicUsize,
+}
+
+impl RpcPool {
+    /// # Panics
+    /// If `clients` is empty.
+    pub fn new(clients: Vec<RpcClient>) -> Self {
+        assert!(!clients.is_empty(), "An RpcPool needs at least one node");
+        Self {
+            clients,
+            healthy: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_commitment(urls: &[String], commitment: CommitmentConfig) -> Self {
+        Self::new(
+            urls.iter()
+                .map(|url| RpcClient::new_with_commitment(url.clone(), commitment))
+                .collect(),
+        )
+    }
+
+    /// Connects to the nodes listed in `SOLANA_RPC_URL`, comma separated, in order of preference.
+    pub fn from_env(commitment: CommitmentConfig) -> anyhow::Result<Self> {
+        let urls = env::var("SOLANA_RPC_URL").map_err(|_| anyhow!("SOLANA_RPC_URL is not set"))?;
+        let urls = parse_rpc_urls(&urls).map_err(|e| anyhow!("Invalid SOLANA_RPC_URL: {}", e))?;
+        Ok(Self::with_commitment(&urls, commitment))
+    }
+
+    pub fn commitment(&self) -> CommitmentConfig {
+        self.clients[0].commitment()
+    }
+
+    /// URL of the node calls currently go to first.
+    pub fn healthy_url(&self) -> String {
+        self.clients[self.healthy.load(Ordering::Relaxed)].url()
+    }
+
+    // Runs `call` against the node that last answered, then each of the others in turn
+    // while the node tried gives no answer. Blocks like the calls it makes.
+    fn call<T>(&self, call: impl Fn(&RpcClient) -> ClientResult<T>) -> Result<T, DepositError> {
+        let start = self.healthy.load(Ordering::Relaxed);
+        let mut tried = 0;
+        loop {
+            let index = (start + tried) % self.clients.len();
+            let client = &self.clients[index];
+            match call(client) {
+                Err(err) if is_unreachable(&err) && tried + 1 < self.clients.len() => {
+                    warn!(rpc_url = %client.url(), "Solana RPC node unreachable, trying the next: {}", err);
+                    tried += 1;
+                }
+                result => {
+                    if result.is_ok() && index != start {
+                        warn!(rpc_url = %client.url(), "Failed over to Solana RPC node");
+                        self.healthy.store(index, Ordering::Relaxed);
+                    }
+                    return Ok(result?);
+                }
+            }
+        }
+    }
+
+    pub fn get_latest_blockhash(&self) -> Result<Hash, DepositError> {
+        self.call(|rpc| rpc.get_latest_blockhash())
+    }
+
+    /// The latest blockhash and the last block height a transaction signed against it
+    /// can land at.
+    pub fn get_latest_blockhash_with_expiry(&self) -> Result<(Hash, u64), DepositError> {
+        self.call(|rpc| rpc.get_latest_blockhash_with_commitment(rpc.commitment()))
+    }
+
+    pub fn get_block_height(&self) -> Result<u64, DepositError> {
+        self.call(|rpc| rpc.get_block_height())
+    }
+
+    /// Sends `transaction` and waits for it to reach our commitment level. A transaction
+    /// sent again through another node keeps its signature, so it can't land twice.
+    pub fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Signature, DepositError> {
+        self.call(|rpc| rpc.send_and_confirm_transaction(transaction))
+    }
+
+    pub fn get_multiple_accounts(
+        &self,
+        pubkeys: &[Pubkey],
+    ) -> Result<Vec<Option<Account>>, DepositError> {
+        self.call(|rpc| rpc.get_multiple_accounts(pubkeys))
+    }
+
+    /// Status of the transaction `signature` at our commitment level.
+    pub fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<TransactionResult<()>>, DepositError> {
+        self.call(|rpc| rpc.get_signature_status_with_commitment(signature, rpc.commitment()))
+    }
+
+    pub fn get_transaction_with_config(
+        &self,
+        signature: &Signature,
+        config: RpcTransactionConfig,
+    ) -> Result<EncodedConfirmedTransactionWithStatusMeta, DepositError> {
+        self.call(|rpc| rpc.get_transaction_with_config(signature, config))
+    }
+
+    /// Asks the first node that answers whether it considers itself healthy (`getHealth`).
+    pub fn get_health(&self) -> Result<(), DepositError> {
+        self.call(|rpc| rpc.get_health())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+    use solana_client::rpc_request::RpcRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_rpc_urls() {
+        assert_eq!(
+            parse_rpc_urls("https://a.example.com").unwrap(),
+            ["https://a.example.com"]
+        );
+        assert_eq!(
+            parse_rpc_urls(" https://a.example.com, https://b.example.com ,").unwrap(),
+            ["https://a.example.com", "https://b.example.com"]
+        );
+        assert!(parse_rpc_urls("").is_err());
+        assert!(parse_rpc_urls(" , ").is_err());
+    }
+
+    #[test]
+    fn test_unreachable_primary_fails_over() {
+        // Nothing listens on port 1, so the primary refuses every connection
+        let pool = RpcPool::new(vec![
+            RpcClient::new("http://127.0.0.1:1".to_string()),
+            RpcClient::new_mock("succeeds".to_string()),
+        ]);
+        assert_eq!(pool.healthy_url(), "http://127.0.0.1:1");
+
+        pool.get_latest_blockhash().unwrap();
+        assert_eq!(pool.healthy_url(), "MockSender: succeeds");
+        // Later calls go straight to the node that answered
+        pool.call(|rpc| rpc.get_slot()).unwrap();
+        assert_eq!(pool.healthy_url(), "MockSender: succeeds");
+    }
+
+    #[test]
+    fn test_answers_dont_fail_over() {
+        // The primary answers, just not with a slot
+        let mocks = HashMap::from([(RpcRequest::GetSlot, json!("not a slot"))]);
+        let pool = RpcPool::new(vec![
+            RpcClient::new_mock_with_mocks("succeeds".to_string(), mocks),
+            RpcClient::new_mock("succeeds".to_string()),
+        ]);
+        let err = pool.call(|rpc| rpc.get_slot()).unwrap_err();
+        assert!(matches!(err, DepositError::Rpc(_)));
+        assert_eq!(pool.healthy.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_every_node_unreachable() {
+        let pool = RpcPool::new(vec![
+            RpcClient::new("http://127.0.0.1:1".to_string()),
+            RpcClient::new("http://127.0.0.1:2".to_string()),
+        ]);
+        assert!(pool.get_latest_blockhash().is_err());
+        assert_eq!(pool.healthy_url(), "http://127.0.0.1:1");
+    }
+}
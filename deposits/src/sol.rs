@@ -1,169 +1,764 @@
-// use redis::Client;
-// use solana_client::rpc_client::RpcClient;
-// use solana_sdk::{
-//     commitment_config::CommitmentConfig, instruction::AccountMeta, pubkey::Pubkey,
-//     signature::Keypair, signer::Signer, system_instruction, system_program,
-//     transaction::Transaction,
-// };
-// use std::{env, path::Path, str::FromStr, sync::Arc};
-
-// async fn handle_deposit(
-//     connection: Arc<RpcClient>,
-//     treasury: Arc<Keypair>,
-//     program_id: Pubkey,
-//     redis: Arc<Client>,
-//     deposit_address: Pubkey,
-//     amount: u64,
-// ) -> anyhow::Result<()> {
-//     let mut conn = redis.get_connection()?;
-//     let user_id: String = redis::cmd("HGET")
-//         .arg("deposit_addresses")
-//         .arg(deposit_address.to_string())
-//         .query(&mut conn)?;
-
-//     let user_pubkey = Pubkey::from_str(&user_id)?;
-
-//     let instruction = anchor_lang::solana_program::instruction::Instruction {
-//         program_id,
-//         accounts: vec![
-//             AccountMeta::new(deposit_address, false), // PDA is not a signer
-//             AccountMeta::new(user_pubkey, false),
-//             AccountMeta::new(treasury.pubkey(), true), // Treasury is signer
-//             AccountMeta::new_readonly(system_program::id(), false),
-//         ],
-//         data: {
-//             let mut data = vec![91, 60, 51, 162, 44, 140, 96, 24]; // discriminator for forward deposit
-//             data.extend_from_slice(&amount.to_le_bytes());
-//             data
-//         },
-//     };
-
-//     let recent_blockhash = connection.get_latest_blockhash()?;
-//     let transaction = Transaction::new_signed_with_payer(
-//         &[instruction],
-//         Some(&treasury.pubkey()),
-//         &[treasury.as_ref()], // Only treasury signs
-//         recent_blockhash,
-//     );
-
-//     let signature = connection.send_and_confirm_transaction(&transaction)?;
-
-//     println!("Confirmation sent: {:?}", signature);
-//     Ok(())
-// }
-
-// #[derive(Clone)]
-// pub struct DepositService {
-//     redis: Arc<Client>,
-//     connection: Arc<RpcClient>,
-//     treasury: Arc<Keypair>,
-//     program_id: Pubkey,
-// }
-
-// impl DepositService {
-//     pub fn new<P: AsRef<Path>>(treasury_keypair_path: P, program_id: String) -> Self {
-//         println!("Creating DepositService");
-//         let program_id = Pubkey::from_str(&program_id).unwrap();
-//         let connection = RpcClient::new_with_commitment(
-//             std::env::var("SOLANA_RPC_URL").unwrap(),
-//             CommitmentConfig::confirmed(),
-//         );
-
-//         let treasury_data = std::fs::read_to_string(treasury_keypair_path).unwrap();
-//         let treasury_bytes: Vec<u8> = serde_json::from_str(&treasury_data).unwrap();
-//         let treasury = Keypair::from_bytes(&treasury_bytes).unwrap();
-//         let redis_url = env::var("REDIS_URL").unwrap();
-//         let client = Client::open(redis_url.clone()).expect("Failed to create Redis client");
-
-//         Self {
-//             redis: Arc::new(client),
-//             connection: Arc::new(connection),
-//             treasury: Arc::new(treasury),
-//             //program_id: Pubkey::from_str("FFT8CyM7DnNoWG2AukQqCEyNtZRLJvxN9WK6S7mC5kLP").unwrap(),
-//             program_id,
-//         }
-//     }
-//     pub fn generate_deposit_address(&self) -> anyhow::Result<Pubkey> {
-//         // let program_id = Pubkey::from_str(&program_id)?;
-//         let new_keypair = Keypair::new();
-//         let user_pubkey = new_keypair.pubkey();
-//         let (pda, _) =
-//             Pubkey::find_program_address(&[b"deposit", user_pubkey.as_ref()], &self.program_id);
-
-//         println!("PDA: {:?}", pda);
-//         let mut conn = self.redis.get_connection()?;
-//         let result = redis::cmd("HSET")
-//             .arg("deposit_addresses")
-//             .arg(pda.to_string())
-//             .arg(user_pubkey.to_string())
-//             .exec(&mut conn);
-
-//         if let Err(err) = result {
-//             eprintln!("Error executing HSET: {:?}", err);
-//         }
-//         Ok(pda)
-//     }
-
-//     pub async fn check_deposits(&self, pubkeys: Vec<Pubkey>) -> anyhow::Result<()> {
-//         if let Ok(accounts) = self.connection.get_multiple_accounts(&pubkeys) {
-//             for (i, account) in accounts.iter().enumerate() {
-//                 // check if account lamport is > 0, initiate fund transfer to the treasury
-//                 if let Some(account) = account {
-//                     if account.lamports > 0 {
-//                         // handle deposit
-//                         println!("Account: {:?}", account);
-//                         let conn = self.connection.clone();
-//                         let treasury = self.treasury.clone();
-//                         let redis = self.redis.clone();
-//                         let program_id = self.program_id.clone();
-//                         let pubkey = pubkeys[i].clone();
-//                         let amount = account.lamports.clone();
-//                         tokio::spawn(async move {
-//                             if let Err(err) =
-//                                 handle_deposit(conn, treasury, program_id, redis, pubkey, amount)
-//                                     .await
-//                             {
-//                                 eprintln!("Error: {:?}", err);
-//                             }
-//                         });
-//                     }
-//                 }
-//             }
-//         }
-
-//         Ok(())
-//     }
-
-//     pub async fn withdraw_to_user_from_treasury(
-//         &self,
-//         withdrawal_address: String,
-//         amount: u64,
-//     ) -> anyhow::Result<String> {
-//         let to_pubkey = Pubkey::from_str(&withdrawal_address)?;
-
-//         let treasury_pubkey = self.treasury.pubkey();
-//         let treasury_keypair = self.treasury.clone();
-//         let rpc_client = self.connection.clone();
-
-//         let signature = tokio::task::spawn_blocking(move || {
-//             let instruction = system_instruction::transfer(&treasury_pubkey, &to_pubkey, amount);
-//             let recent_blockhash = rpc_client.get_latest_blockhash()?; // Blocking
-//             let transaction = Transaction::new_signed_with_payer(
-//                 &[instruction],
-//                 Some(&treasury_pubkey),
-//                 &[treasury_keypair.as_ref()],
-//                 recent_blockhash,
-//             );
-
-//             let signature = rpc_client.send_and_confirm_transaction(&transaction)?; // Blocking
-//             Ok::<_, anyhow::Error>(signature.to_string())
-//         })
-//         .await??;
-
-//         println!("Signature: {:?}", signature);
-//         Ok(signature)
-//     }
-// }
+use anyhow::anyhow;
+use redis::{Client, Commands, Connection};
+use serde::{Deserialize, Serialize};
+use solana_client::rpc_config::RpcTransactionConfig;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::Signer,
+    system_instruction, system_program,
+    transaction::Transaction,
+};
+use solana_transaction_status::{
+    EncodedConfirmedTransactionWithStatusMeta, UiTransactionEncoding, UiTransactionTokenBalance,
+};
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account_idempotent,
+};
+use std::{
+    collections::HashSet,
+    env, fs,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, error, info};
+
+use crate::{error::DepositError, rpc_pool::RpcPool};
+
+// Deposit PDA -> pubkey it was derived from
+const DEPOSIT_ADDRESSES_KEY: &str = "deposit_addresses";
+// Deposit PDA -> id of the user it belongs to
+const DEPOSIT_ADDRESS_OWNERS_KEY: &str = "deposit_address_owners";
+
+/// Redis channel a `DepositConfirmed` is published on after every sweep
+pub const DEPOSIT_CONFIRMED_CHANNEL: &str = "deposits:confirmed";
+
+// Anchor discriminator of the program's `forward_deposit` instruction, i.e. the
+// first 8 bytes of sha256("global:forward_deposit")
+const FORWARD_DEPOSIT_DISCRIMINATOR: [u8; 8] = [91, 60, 51, 162, 44, 140, 96, 24];
+
+// Where the treasury keypair is read from when no other source is configured
+pub const DEFAULT_TREASURY_KEYPAIR_PATH: &str = "treasury-keypair.json";
+
+// Circle's USDC mint on mainnet, used when `USDC_MINT` isn't set
+pub const DEFAULT_USDC_MINT: &str = "EPjFWJd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
+
+/// Smallest balance worth sweeping, used when `MIN_DEPOSIT_LAMPORTS` isn't set. Sweeping
+/// less would cost about as much in fees as it moves.
+pub const DEFAULT_MIN_DEPOSIT_LAMPORTS: u64 = 10_000;
+
+/// Decimals of the USDC mint; amounts on chain are in millionths of a dollar
+pub const USDC_DECIMALS: u8 = 6;
+
+// The deployed deposit program, used when `PROGRAM_ID` isn't set
+pub const DEFAULT_PROGRAM_ID: &str = "FFT8CyM7DnNoWG2AukQqCEyNtZRLJvxN9WK6S7mC5kLP";
+
+/// Reads the deposit program id from `PROGRAM_ID`, defaulting to `DEFAULT_PROGRAM_ID`.
+pub fn program_id_from_env() -> anyhow::Result<Pubkey> {
+    match env::var("PROGRAM_ID") {
+        Ok(program_id) => parse_program_id(&program_id),
+        Err(_) => parse_program_id(DEFAULT_PROGRAM_ID),
+    }
+}
+
+pub fn parse_program_id(program_id: &str) -> anyhow::Result<Pubkey> {
+    Pubkey::from_str(program_id.trim())
+        .map_err(|e| anyhow!("Invalid program id {:?}: {}", program_id, e))
+}
+
+/// Loads the treasury keypair from `TREASURY_KEYPAIR`, which holds the keypair's JSON
+/// byte array itself (e.g. injected by a secret manager), else from the file at
+/// `TREASURY_KEYPAIR_PATH`, else from `treasury-keypair.json` in the working directory.
+pub fn treasury_keypair_from_env() -> anyhow::Result<Keypair> {
+    treasury_keypair_from_lookup(|key| env::var(key).ok())
+}
+
+fn treasury_keypair_from_lookup(
+    lookup: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Keypair> {
+    if let Some(json) = lookup("TREASURY_KEYPAIR") {
+        return parse_keypair(&json).map_err(|e| anyhow!("Invalid TREASURY_KEYPAIR: {}", e));
+    }
+    let path = lookup("TREASURY_KEYPAIR_PATH")
+        .unwrap_or_else(|| DEFAULT_TREASURY_KEYPAIR_PATH.to_string());
+    let json = fs::read_to_string(&path)
+        .map_err(|e| anyhow!("Failed to read treasury keypair from {:?}: {}", path, e))?;
+    parse_keypair(&json).map_err(|e| anyhow!("Invalid treasury keypair in {:?}: {}", path, e))
+}
+
+/// Parses a keypair in the Solana CLI's format, a JSON array of its 64 bytes.
+pub fn parse_keypair(json: &str) -> anyhow::Result<Keypair> {
+    let bytes: Vec<u8> = serde_json::from_str(json.trim())
+        .map_err(|e| anyhow!("expected a JSON array of bytes: {}", e))?;
+    Keypair::from_bytes(&bytes).map_err(|e| anyhow!("not a keypair: {}", e))
+}
+
+/// Reads the USDC mint from `USDC_MINT`, defaulting to `DEFAULT_USDC_MINT`.
+pub fn usdc_mint_from_env() -> anyhow::Result<Pubkey> {
+    let mint = env::var("USDC_MINT").unwrap_or_else(|_| DEFAULT_USDC_MINT.to_string());
+    Pubkey::from_str(mint.trim()).map_err(|e| anyhow!("Invalid USDC mint {:?}: {}", mint, e))
+}
+
+/// Converts whole tokens into the mint's base units, e.g. 1.5 USDC into 1_500_000.
+pub fn to_base_units(amount: f64, decimals: u8) -> u64 {
+    (amount * 10f64.powi(decimals.into())).round() as u64
+}
+
+/// Reads the smallest deposit to sweep from `MIN_DEPOSIT_LAMPORTS`, defaulting to
+/// `DEFAULT_MIN_DEPOSIT_LAMPORTS`. Smaller balances wait until more is deposited.
+pub fn min_deposit_from_env() -> anyhow::Result<u64> {
+    match env::var("MIN_DEPOSIT_LAMPORTS") {
+        Ok(lamports) => lamports.trim().parse().map_err(|_| {
+            anyhow!(
+                "Invalid MIN_DEPOSIT_LAMPORTS {:?}, expected a whole number of lamports",
+                lamports
+            )
+        }),
+        Err(_) => Ok(DEFAULT_MIN_DEPOSIT_LAMPORTS),
+    }
+}
+
+/// Reads the commitment level from `SOLANA_COMMITMENT`, defaulting to `confirmed`.
+pub fn commitment_from_env() -> anyhow::Result<CommitmentConfig> {
+    match env::var("SOLANA_COMMITMENT") {
+        Ok(level) => parse_commitment(&level),
+        Err(_) => Ok(CommitmentConfig::confirmed()),
+    }
+}
+
+pub fn parse_commitment(level: &str) -> anyhow::Result<CommitmentConfig> {
+    match level.trim().to_lowercase().as_str() {
+        "processed" => Ok(CommitmentConfig::processed()),
+        "confirmed" => Ok(CommitmentConfig::confirmed()),
+        "finalized" => Ok(CommitmentConfig::finalized()),
+        _ => Err(anyhow!(
+            "Invalid commitment level {:?}, expected processed, confirmed or finalized",
+            level
+        )),
+    }
+}
+
+pub fn parse_address(address: &str) -> Result<Pubkey, DepositError> {
+    Pubkey::from_str(address.trim()).map_err(|_| DepositError::InvalidAddress(address.to_string()))
+}
+
+/// Whether `signature`, base58 encoded, is `signer`'s signature of `message`, as a
+/// wallet's `signMessage` makes them.
+pub fn is_signed_by(message: &str, signature: &str, signer: &str) -> bool {
+    let (Ok(signature), Ok(signer)) =
+        (Signature::from_str(signature.trim()), parse_address(signer))
+    else {
+        return false;
+    };
+    signature.verify(signer.as_ref(), message.as_bytes())
+}
+
+// Balance increase of `recipient` in a transaction. Only accounts listed in the message
+// itself count; transfers to the treasury never go through address lookup tables.
+fn lamports_received(
+    account_keys: &[Pubkey],
+    pre_balances: &[u64],
+    post_balances: &[u64],
+    recipient: &Pubkey,
+) -> u64 {
+    account_keys
+        .iter()
+        .position(|key| key == recipient)
+        .and_then(|i| Some(post_balances.get(i)?.saturating_sub(*pre_balances.get(i)?)))
+        .unwrap_or(0)
+}
+
+// Whether `signer` is among the first `num_signers` account keys, which signed the transaction
+fn is_signer(account_keys: &[Pubkey], num_signers: usize, signer: &Pubkey) -> bool {
+    account_keys
+        .iter()
+        .take(num_signers)
+        .any(|key| key == signer)
+}
+
+// Token balance `owner` holds of `mint` across the accounts in `balances`
+fn token_balance(balances: &[UiTransactionTokenBalance], owner: &Pubkey, mint: &Pubkey) -> u64 {
+    let (owner, mint) = (owner.to_string(), mint.to_string());
+    balances
+        .iter()
+        .filter(|balance| {
+            balance.mint == mint && Option::from(balance.owner.as_ref()) == Some(&owner)
+        })
+        .filter_map(|balance| balance.ui_token_amount.amount.parse::<u64>().ok())
+        .sum()
+}
+
+// Moves `amount` base units of `mint` from `owner`'s token account to `recipient`'s,
+// creating the recipient's associated token account if it doesn't exist yet.
+fn spl_transfer_instructions(
+    owner: &Pubkey,
+    recipient: &Pubkey,
+    mint: &Pubkey,
+    decimals: u8,
+    amount: u64,
+) -> Result<Vec<Instruction>, DepositError> {
+    let source = get_associated_token_address(owner, mint);
+    let destination = get_associated_token_address(recipient, mint);
+    Ok(vec![
+        create_associated_token_account_idempotent(owner, recipient, mint, &spl_token::id()),
+        spl_token::instruction::transfer_checked(
+            &spl_token::id(),
+            &source,
+            mint,
+            &destination,
+            owner,
+            &[],
+            amount,
+            decimals,
+        )?,
+    ])
+}
+
+/// Deposit addresses with a sweep underway. A sweep outlives the poll that started it
+/// while it confirms, and sweeping the same balance twice would double-credit the user.
+#[derive(Clone, Default)]
+struct InFlightSweeps(Arc<Mutex<HashSet<Pubkey>>>);
+
+impl InFlightSweeps {
+    /// Marks `address` as being swept until the guard is dropped, or returns `None`
+    /// if a sweep of it is already underway.
+    fn start(&self, address: Pubkey) -> Option<SweepGuard> {
+        let mut addresses = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        addresses.insert(address).then(|| SweepGuard {
+            sweeps: self.clone(),
+            address,
+        })
+    }
+
+    /// Sweeps to start for deposit addresses holding at least `min_lamports`, skipping
+    /// those already being swept
+    fn start_all(
+        &self,
+        balances: &[(Pubkey, u64)],
+        min_lamports: u64,
+    ) -> Vec<(Pubkey, u64, SweepGuard)> {
+        balances
+            .iter()
+            // Dust stays put until further deposits lift it over the threshold
+            .filter(|(_, lamports)| *lamports > 0 && *lamports >= min_lamports)
+            .filter_map(|&(address, lamports)| match self.start(address) {
+                Some(guard) => Some((address, lamports, guard)),
+                None => {
+                    debug!(deposit_address = %address, "Sweep already in flight");
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+struct SweepGuard {
+    sweeps: InFlightSweeps,
+    address: Pubkey,
+}
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        let mut addresses = self.sweeps.0.lock().unwrap_or_else(|e| e.into_inner());
+        addresses.remove(&self.address);
+    }
+}
+
+/// A deposit that has been swept into the treasury and can be credited to its user.
+/// The sweep signature identifies it, so crediting it more than once can be refused.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DepositConfirmed {
+    pub user_id: i32,
+    pub deposit_address: String,
+    pub lamports: u64,
+    pub signature: String,
+}
+
+// Announces a swept deposit to the wallet service and anyone else listening
+fn publish_deposit_confirmed(
+    conn: &mut Connection,
+    deposit_address: &Pubkey,
+    lamports: u64,
+    signature: &str,
+) -> anyhow::Result<DepositConfirmed> {
+    let user_id: Option<i32> =
+        conn.hget(DEPOSIT_ADDRESS_OWNERS_KEY, deposit_address.to_string())?;
+    let user_id =
+        user_id.ok_or_else(|| anyhow!("Deposit address {} has no owner", deposit_address))?;
+    let event = DepositConfirmed {
+        user_id,
+        deposit_address: deposit_address.to_string(),
+        lamports,
+        signature: signature.to_string(),
+    };
+    let _: () = conn.publish(DEPOSIT_CONFIRMED_CHANNEL, serde_json::to_string(&event)?)?;
+    Ok(event)
+}
+
+fn user_deposit_addresses_key(user_id: i32) -> String {
+    format!("user_deposit_addresses:{}", user_id)
+}
+
+/// Maps a deposit PDA to its seed pubkey and owning user.
+/// Returns false, leaving the existing mapping untouched, if the PDA is already registered.
+fn register_deposit_address(
+    conn: &mut Connection,
+    user_id: i32,
+    pda: &Pubkey,
+    seed_pubkey: &Pubkey,
+) -> Result<bool, DepositError> {
+    let created: bool = conn.hset_nx(
+        DEPOSIT_ADDRESSES_KEY,
+        pda.to_string(),
+        seed_pubkey.to_string(),
+    )?;
+    if !created {
+        return Ok(false);
+    }
+
+    let _: () = redis::pipe()
+        .atomic()
+        .hset(DEPOSIT_ADDRESS_OWNERS_KEY, pda.to_string(), user_id)
+        .sadd(user_deposit_addresses_key(user_id), pda.to_string())
+        .query(conn)?;
+    Ok(true)
+}
+
+fn list_deposit_addresses(
+    conn: &mut Connection,
+    user_id: i32,
+) -> Result<Vec<Pubkey>, DepositError> {
+    let addresses: Vec<String> = conn.smembers(user_deposit_addresses_key(user_id))?;
+    addresses
+        .iter()
+        .map(|address| parse_address(address))
+        .collect()
+}
+
+async fn handle_deposit(
+    connection: Arc<RpcPool>,
+    treasury: Arc<Keypair>,
+    program_id: Pubkey,
+    redis: Arc<Client>,
+    deposit_address: Pubkey,
+    amount: u64,
+) -> anyhow::Result<()> {
+    let mut conn = redis.get_connection()?;
+    let user_id: String = redis::cmd("HGET")
+        .arg(DEPOSIT_ADDRESSES_KEY)
+        .arg(deposit_address.to_string())
+        .query(&mut conn)?;
+
+    let user_pubkey = Pubkey::from_str(&user_id)?;
+
+    let instruction = Instruction {
+        program_id,
+        accounts: vec![
+            AccountMeta::new(deposit_address, false), // PDA is not a signer
+            AccountMeta::new(user_pubkey, false),
+            AccountMeta::new(treasury.pubkey(), true), // Treasury is signer
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data: {
+            let mut data = FORWARD_DEPOSIT_DISCRIMINATOR.to_vec();
+            data.extend_from_slice(&amount.to_le_bytes());
+            data
+        },
+    };
+
+    let recent_blockhash = connection.get_latest_blockhash()?;
+    let transaction = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&treasury.pubkey()),
+        &[treasury.as_ref()], // Only treasury signs
+        recent_blockhash,
+    );
+
+    let signature = connection.send_and_confirm_transaction(&transaction)?;
+
+    info!(%signature, %deposit_address, lamports = amount, "Deposit swept to treasury");
+
+    let event =
+        publish_deposit_confirmed(&mut conn, &deposit_address, amount, &signature.to_string())?;
+    debug!(user_id = event.user_id, %signature, "Published confirmed deposit");
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct DepositService {
+    redis: Arc<Client>,
+    connection: Arc<RpcPool>,
+    treasury: Arc<Keypair>,
+    program_id: Pubkey,
+    usdc_mint: Pubkey,
+    sweeps: InFlightSweeps,
+    min_deposit_lamports: u64,
+}
+
+impl DepositService {
+    pub fn new(treasury: Keypair, program_id: Pubkey) -> Self {
+        debug!("Creating DepositService");
+        // Used for both deposit sweeps and withdrawals
+        let commitment = commitment_from_env().expect("Invalid SOLANA_COMMITMENT");
+        let connection = RpcPool::from_env(commitment).expect("Invalid SOLANA_RPC_URL");
+
+        let redis_url = env::var("REDIS_URL").unwrap();
+        let client = Client::open(redis_url.clone()).expect("Failed to create Redis client");
+        let usdc_mint = usdc_mint_from_env().expect("Invalid USDC_MINT");
+        let min_deposit_lamports = min_deposit_from_env().expect("Invalid MIN_DEPOSIT_LAMPORTS");
+
+        Self {
+            redis: Arc::new(client),
+            connection: Arc::new(connection),
+            treasury: Arc::new(treasury),
+            program_id,
+            usdc_mint,
+            sweeps: InFlightSweeps::default(),
+            min_deposit_lamports,
+        }
+    }
+    /// Derives a fresh deposit PDA for `user_id` and records it in Redis.
+    pub fn generate_deposit_address(&self, user_id: i32) -> Result<Pubkey, DepositError> {
+        let new_keypair = Keypair::new();
+        let seed_pubkey = new_keypair.pubkey();
+        let (pda, _) =
+            Pubkey::find_program_address(&[b"deposit", seed_pubkey.as_ref()], &self.program_id);
+
+        debug!(user_id, deposit_address = %pda, "Generated deposit address");
+        let mut conn = self.redis.get_connection()?;
+        if !register_deposit_address(&mut conn, user_id, &pda, &seed_pubkey)? {
+            return Err(DepositError::AddressAlreadyAssigned(pda.to_string()));
+        }
+        Ok(pda)
+    }
+
+    pub fn get_user_deposit_addresses(&self, user_id: i32) -> Result<Vec<Pubkey>, DepositError> {
+        let mut conn = self.redis.get_connection()?;
+        list_deposit_addresses(&mut conn, user_id)
+    }
+
+    /// Address deposits are swept to and withdrawals are paid from.
+    pub fn treasury_address(&self) -> Pubkey {
+        self.treasury.pubkey()
+    }
+
+    /// Mint of the USDC deposits and withdrawals are made in.
+    pub fn usdc_mint(&self) -> Pubkey {
+        self.usdc_mint
+    }
+
+    // The transaction `signature` if it succeeded at our commitment level
+    fn successful_transaction(
+        rpc_client: &RpcPool,
+        signature: &Signature,
+    ) -> Result<Option<EncodedConfirmedTransactionWithStatusMeta>, DepositError> {
+        let commitment = rpc_client.commitment();
+        let status = rpc_client.get_signature_status(signature)?;
+        if !matches!(status, Some(Ok(()))) {
+            return Ok(None);
+        }
+
+        let confirmed = rpc_client.get_transaction_with_config(
+            signature,
+            RpcTransactionConfig {
+                encoding: Some(UiTransactionEncoding::Base64),
+                commitment: Some(commitment),
+                max_supported_transaction_version: Some(0),
+            },
+        )?;
+        Ok(Some(confirmed))
+    }
+
+    fn parse_signature(signature: &str) -> Result<Signature, DepositError> {
+        Signature::from_str(signature.trim())
+            .map_err(|_| DepositError::InvalidSignature(signature.to_string()))
+    }
+
+    /// Lamports `recipient` gained in the transaction `signature`. `None` if the
+    /// transaction is unknown, not yet at our commitment level, or failed.
+    pub async fn received_by(
+        &self,
+        signature: &str,
+        recipient: &Pubkey,
+    ) -> Result<Option<u64>, DepositError> {
+        let signature = Self::parse_signature(signature)?;
+        let rpc_client = self.connection.clone();
+        let recipient = *recipient;
+
+        tokio::task::spawn_blocking(move || {
+            let Some(confirmed) = Self::successful_transaction(&rpc_client, &signature)? else {
+                return Ok(None);
+            };
+            let (Some(meta), Some(transaction)) = (
+                confirmed.transaction.meta,
+                confirmed.transaction.transaction.decode(),
+            ) else {
+                return Ok(None);
+            };
+            Ok(Some(lamports_received(
+                transaction.message.static_account_keys(),
+                &meta.pre_balances,
+                &meta.post_balances,
+                &recipient,
+            )))
+        })
+        .await?
+    }
+
+    /// Whether `signer` signed the transaction `signature`, so the lamports or tokens it
+    /// moved came from their wallet. `false` under the same conditions as
+    /// [`Self::received_by`] returns `None`.
+    pub async fn signed_by(&self, signature: &str, signer: &Pubkey) -> Result<bool, DepositError> {
+        let signature = Self::parse_signature(signature)?;
+        let rpc_client = self.connection.clone();
+        let signer = *signer;
+
+        tokio::task::spawn_blocking(move || {
+            let Some(confirmed) = Self::successful_transaction(&rpc_client, &signature)? else {
+                return Ok(false);
+            };
+            let Some(transaction) = confirmed.transaction.transaction.decode() else {
+                return Ok(false);
+            };
+            let message = transaction.message;
+            Ok(is_signer(
+                message.static_account_keys(),
+                message.header().num_required_signatures.into(),
+                &signer,
+            ))
+        })
+        .await?
+    }
+
+    /// Base units of `mint` that token accounts owned by `owner` gained in the transaction
+    /// `signature`. `None` under the same conditions as [`Self::received_by`].
+    pub async fn tokens_received_by(
+        &self,
+        signature: &str,
+        owner: &Pubkey,
+        mint: &Pubkey,
+    ) -> Result<Option<u64>, DepositError> {
+        let signature = Self::parse_signature(signature)?;
+        let rpc_client = self.connection.clone();
+        let (owner, mint) = (*owner, *mint);
+
+        tokio::task::spawn_blocking(move || {
+            let Some(confirmed) = Self::successful_transaction(&rpc_client, &signature)? else {
+                return Ok(None);
+            };
+            let Some(meta) = confirmed.transaction.meta else {
+                return Ok(None);
+            };
+            let pre: Option<Vec<_>> = meta.pre_token_balances.into();
+            let post: Option<Vec<_>> = meta.post_token_balances.into();
+            let (pre, post) = (pre.unwrap_or_default(), post.unwrap_or_default());
+            Ok(Some(
+                token_balance(&post, &owner, &mint)
+                    .saturating_sub(token_balance(&pre, &owner, &mint)),
+            ))
+        })
+        .await?
+    }
+
+    /// Hands every confirmed deposit published after the call to `on_deposit`, until the
+    /// Redis connection fails. Blocks, so run it on a blocking thread.
+    pub fn listen_for_confirmed_deposits(
+        &self,
+        mut on_deposit: impl FnMut(DepositConfirmed),
+    ) -> Result<(), DepositError> {
+        let mut conn = self.redis.get_connection()?;
+        let mut pubsub = conn.as_pubsub();
+        pubsub.subscribe(DEPOSIT_CONFIRMED_CHANNEL)?;
+        loop {
+            let payload: String = pubsub.get_message()?.get_payload()?;
+            match serde_json::from_str(&payload) {
+                Ok(event) => on_deposit(event),
+                Err(err) => error!("Malformed confirmed deposit {:?}: {}", payload, err),
+            }
+        }
+    }
+
+    /// Round-trips a PING to the Redis instance holding the deposit addresses.
+    pub async fn ping_redis(&self) -> Result<(), DepositError> {
+        let redis = self.redis.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = redis.get_connection()?;
+            let _: String = redis::cmd("PING").query(&mut conn)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Asks the RPC node in use whether it considers itself healthy (`getHealth`).
+    pub async fn check_rpc_health(&self) -> Result<(), DepositError> {
+        let rpc_client = self.connection.clone();
+        tokio::task::spawn_blocking(move || rpc_client.get_health()).await?
+    }
+
+    pub async fn check_deposits(&self, pubkeys: Vec<Pubkey>) -> anyhow::Result<()> {
+        // Failing the poll lets the caller log and retry it
+        let accounts = self.connection.get_multiple_accounts(&pubkeys)?;
+        let balances: Vec<(Pubkey, u64)> = pubkeys
+            .iter()
+            .zip(&accounts)
+            .filter_map(|(pubkey, account)| Some((*pubkey, account.as_ref()?.lamports)))
+            .collect();
+
+        for (pubkey, amount, guard) in self.sweeps.start_all(&balances, self.min_deposit_lamports) {
+            info!(deposit_address = %pubkey, lamports = amount, "Deposit detected");
+            let conn = self.connection.clone();
+            let treasury = self.treasury.clone();
+            let redis = self.redis.clone();
+            let program_id = self.program_id;
+            tokio::spawn(async move {
+                if let Err(err) =
+                    handle_deposit(conn, treasury, program_id, redis, pubkey, amount).await
+                {
+                    error!(deposit_address = %pubkey, "Failed to sweep deposit: {:?}", err);
+                }
+                // The next poll may sweep the address again once this one has settled
+                drop(guard);
+            });
+        }
+
+        Ok(())
+    }
+
+    pub async fn withdraw_to_user_from_treasury(
+        &self,
+        withdrawal_address: String,
+        amount: u64,
+    ) -> Result<String, DepositError> {
+        let withdrawal = self.sign_withdrawal(withdrawal_address, amount).await?;
+        let signature = self.send_withdrawal(&withdrawal).await?;
+
+        // The destination is left out of the logs, the withdrawal row already has it
+        info!(%signature, lamports = amount, "Withdrawal sent");
+        Ok(signature)
+    }
+
+    /// Sends `amount` base units of `mint` from the treasury's token account to the
+    /// associated token account of `withdrawal_address`, which the treasury pays to open.
+    pub async fn withdraw_spl_to_user_from_treasury(
+        &self,
+        withdrawal_address: String,
+        mint: Pubkey,
+        decimals: u8,
+        amount: u64,
+    ) -> Result<String, DepositError> {
+        let withdrawal = self
+            .sign_spl_withdrawal(withdrawal_address, mint, decimals, amount)
+            .await?;
+        let signature = self.send_withdrawal(&withdrawal).await?;
+
+        info!(%signature, %mint, amount, "Token withdrawal sent");
+        Ok(signature)
+    }
+
+    /// Signs a transfer of `amount` lamports from the treasury to `withdrawal_address`
+    /// without sending it.
+    pub async fn sign_withdrawal(
+        &self,
+        withdrawal_address: String,
+        amount: u64,
+    ) -> Result<SignedWithdrawal, DepositError> {
+        let to_pubkey = parse_address(&withdrawal_address)?;
+        let instruction = system_instruction::transfer(&self.treasury.pubkey(), &to_pubkey, amount);
+        self.sign_from_treasury(vec![instruction]).await
+    }
+
+    /// Signs what [`Self::withdraw_spl_to_user_from_treasury`] sends, without sending it.
+    pub async fn sign_spl_withdrawal(
+        &self,
+        withdrawal_address: String,
+        mint: Pubkey,
+        decimals: u8,
+        amount: u64,
+    ) -> Result<SignedWithdrawal, DepositError> {
+        let to_pubkey = parse_address(&withdrawal_address)?;
+        let instructions = spl_transfer_instructions(
+            &self.treasury.pubkey(),
+            &to_pubkey,
+            &mint,
+            decimals,
+            amount,
+        )?;
+        self.sign_from_treasury(instructions).await
+    }
+
+    // Signs `instructions` with the treasury paying, against the latest blockhash
+    async fn sign_from_treasury(
+        &self,
+        instructions: Vec<Instruction>,
+    ) -> Result<SignedWithdrawal, DepositError> {
+        let treasury_keypair = self.treasury.clone();
+        let rpc_client = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let (recent_blockhash, last_valid_block_height) =
+                rpc_client.get_latest_blockhash_with_expiry()?; // Blocking
+            let transaction = Transaction::new_signed_with_payer(
+                &instructions,
+                Some(&treasury_keypair.pubkey()),
+                &[treasury_keypair.as_ref()],
+                recent_blockhash,
+            );
+            Ok(SignedWithdrawal {
+                transaction,
+                last_valid_block_height,
+            })
+        })
+        .await?
+    }
+
+    /// Whether the chain has moved past `last_valid_block_height`, so a transaction whose
+    /// blockhash expired there can no longer land.
+    pub async fn block_height_passed(
+        &self,
+        last_valid_block_height: u64,
+    ) -> Result<bool, DepositError> {
+        let rpc_client = self.connection.clone();
+        let height = tokio::task::spawn_blocking(move || rpc_client.get_block_height()).await??;
+        Ok(height > last_valid_block_height)
+    }
+
+    /// Sends a signed withdrawal and waits for it to reach our commitment level.
+    pub async fn send_withdrawal(
+        &self,
+        withdrawal: &SignedWithdrawal,
+    ) -> Result<String, DepositError> {
+        let rpc_client = self.connection.clone();
+        let transaction = withdrawal.transaction.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let signature = rpc_client.send_and_confirm_transaction(&transaction)?; // Blocking
+            Ok(signature.to_string())
+        })
+        .await?
+    }
+}
+
+/// A withdrawal signed by the treasury but not sent yet. Its signature is known before
+/// it goes out, so it can be recorded first and looked up on chain after a crash. It
+/// can only land until its blockhash expires, about a minute and a half later.
+#[derive(Debug, Clone)]
+pub struct SignedWithdrawal {
+    transaction: Transaction,
+    last_valid_block_height: u64,
+}
+
+impl SignedWithdrawal {
+    pub fn signature(&self) -> String {
+        self.transaction.signatures[0].to_string()
+    }
+
+    /// The last block height the withdrawal can land at.
+    pub fn last_valid_block_height(&self) -> u64 {
+        self.last_valid_block_height
+    }
+}
 
 // // // pub async fn read_account_updates(&self, account_pubkey: Pubkey) -> anyhow::Result<()> {
 // // //     let url = "wss://api.devnet.solana.com/";
@@ -259,3 +854,355 @@
 // //         tokio::time::sleep(Duration::from_secs(5)).await;
 // //     }
 // // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commitment() {
+        assert_eq!(
+            parse_commitment("processed").unwrap(),
+            CommitmentConfig::processed()
+        );
+        assert_eq!(
+            parse_commitment("Confirmed").unwrap(),
+            CommitmentConfig::confirmed()
+        );
+        assert_eq!(
+            parse_commitment(" finalized ").unwrap(),
+            CommitmentConfig::finalized()
+        );
+        assert!(parse_commitment("max").is_err());
+        assert!(parse_commitment("").is_err());
+    }
+
+    #[test]
+    fn test_forward_deposit_discriminator() {
+        use sha2::{Digest, Sha256};
+
+        let hash = Sha256::digest(b"global:forward_deposit");
+        assert_eq!(FORWARD_DEPOSIT_DISCRIMINATOR, hash[..8]);
+    }
+
+    #[test]
+    fn test_lamports_received() {
+        let payer = Pubkey::new_unique();
+        let treasury = Pubkey::new_unique();
+        let keys = [payer, treasury, system_program::id()];
+        let pre = [5_000_000_000, 1_000_000_000, 1];
+        let post = [3_999_995_000, 2_000_000_000, 1];
+
+        assert_eq!(
+            lamports_received(&keys, &pre, &post, &treasury),
+            1_000_000_000
+        );
+        // The payer lost lamports, which is not a deposit
+        assert_eq!(lamports_received(&keys, &pre, &post, &payer), 0);
+        // Accounts the transaction doesn't touch received nothing
+        assert_eq!(
+            lamports_received(&keys, &pre, &post, &Pubkey::new_unique()),
+            0
+        );
+    }
+
+    #[test]
+    fn test_is_signer() {
+        let payer = Pubkey::new_unique();
+        let treasury = Pubkey::new_unique();
+        let keys = [payer, treasury, system_program::id()];
+
+        assert!(is_signer(&keys, 1, &payer));
+        // Written to, but not a signer
+        assert!(!is_signer(&keys, 1, &treasury));
+        assert!(!is_signer(&keys, 1, &Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn test_is_signed_by() {
+        let wallet = Keypair::new();
+        let address = wallet.pubkey().to_string();
+        let signature = wallet.sign_message(b"hello").to_string();
+
+        assert!(is_signed_by("hello", &signature, &address));
+        assert!(!is_signed_by("goodbye", &signature, &address));
+        assert!(!is_signed_by(
+            "hello",
+            &signature,
+            &Pubkey::new_unique().to_string()
+        ));
+        assert!(!is_signed_by("hello", "not a signature", &address));
+    }
+
+    #[test]
+    fn test_spl_transfer_instructions() {
+        let treasury = Pubkey::new_unique();
+        let recipient = Pubkey::new_unique();
+        let mint = Pubkey::from_str(DEFAULT_USDC_MINT).unwrap();
+        let amount = to_base_units(12.5, USDC_DECIMALS);
+        assert_eq!(amount, 12_500_000);
+
+        let instructions =
+            spl_transfer_instructions(&treasury, &recipient, &mint, USDC_DECIMALS, amount).unwrap();
+        assert_eq!(instructions.len(), 2);
+        let recipient_ata = get_associated_token_address(&recipient, &mint);
+
+        // The recipient's token account is opened on demand, paid by the treasury
+        let create = &instructions[0];
+        assert_eq!(create.program_id, spl_associated_token_account::id());
+        assert_eq!(create.accounts[0].pubkey, treasury);
+        assert_eq!(create.accounts[1].pubkey, recipient_ata);
+        assert_eq!(create.accounts[2].pubkey, recipient);
+        assert_eq!(create.accounts[3].pubkey, mint);
+
+        let transfer = &instructions[1];
+        assert_eq!(transfer.program_id, spl_token::id());
+        let accounts: Vec<Pubkey> = transfer.accounts.iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(
+            accounts,
+            vec![
+                get_associated_token_address(&treasury, &mint),
+                mint,
+                recipient_ata,
+                treasury,
+            ]
+        );
+        assert!(transfer.accounts[3].is_signer);
+        match spl_token::instruction::TokenInstruction::unpack(&transfer.data).unwrap() {
+            spl_token::instruction::TokenInstruction::TransferChecked { amount, decimals } => {
+                assert_eq!(amount, 12_500_000);
+                assert_eq!(decimals, USDC_DECIMALS);
+            }
+            instruction => panic!("expected a checked transfer, got {:?}", instruction),
+        }
+    }
+
+    #[test]
+    fn test_to_base_units() {
+        assert_eq!(to_base_units(1.0, 9), 1_000_000_000);
+        assert_eq!(to_base_units(0.1, USDC_DECIMALS), 100_000);
+        // Float noise doesn't cost the user a base unit
+        assert_eq!(to_base_units(0.29, USDC_DECIMALS), 290_000);
+    }
+
+    #[test]
+    fn test_token_balance() {
+        let treasury = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let balance = |owner: &Pubkey, mint: &Pubkey, amount: u64| -> UiTransactionTokenBalance {
+            serde_json::from_value(serde_json::json!({
+                "accountIndex": 1,
+                "mint": mint.to_string(),
+                "owner": owner.to_string(),
+                "uiTokenAmount": {
+                    "uiAmount": null,
+                    "decimals": USDC_DECIMALS,
+                    "amount": amount.to_string(),
+                    "uiAmountString": "",
+                },
+            }))
+            .unwrap()
+        };
+        let balances = [
+            balance(&treasury, &mint, 2_000_000),
+            balance(&Pubkey::new_unique(), &mint, 5_000_000),
+            balance(&treasury, &Pubkey::new_unique(), 7_000_000),
+        ];
+
+        assert_eq!(token_balance(&balances, &treasury, &mint), 2_000_000);
+        assert_eq!(token_balance(&[], &treasury, &mint), 0);
+    }
+
+    #[test]
+    fn test_treasury_keypair_from_inline_bytes() {
+        let keypair = Keypair::new();
+        let json = serde_json::to_string(&keypair.to_bytes().to_vec()).unwrap();
+
+        let loaded = treasury_keypair_from_lookup(|key| match key {
+            "TREASURY_KEYPAIR" => Some(json.clone()),
+            // The inline secret wins over a path
+            "TREASURY_KEYPAIR_PATH" => Some("/nonexistent/keypair.json".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(loaded.pubkey(), keypair.pubkey());
+
+        let err = treasury_keypair_from_lookup(|key| {
+            (key == "TREASURY_KEYPAIR").then(|| "[1, 2, 3]".to_string())
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("TREASURY_KEYPAIR"), "{}", err);
+        assert!(treasury_keypair_from_lookup(|key| {
+            (key == "TREASURY_KEYPAIR").then(|| "not json".to_string())
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_treasury_keypair_from_path() {
+        let keypair = Keypair::new();
+        let path = env::temp_dir().join(format!("treasury-{}.json", keypair.pubkey()));
+        fs::write(
+            &path,
+            serde_json::to_string(&keypair.to_bytes().to_vec()).unwrap(),
+        )
+        .unwrap();
+        let lookup_path = |key: &str| {
+            (key == "TREASURY_KEYPAIR_PATH").then(|| path.to_string_lossy().into_owned())
+        };
+
+        assert_eq!(
+            treasury_keypair_from_lookup(lookup_path).unwrap().pubkey(),
+            keypair.pubkey()
+        );
+
+        fs::write(&path, "{}").unwrap();
+        let err = treasury_keypair_from_lookup(lookup_path).unwrap_err();
+        assert!(
+            err.to_string().contains("Invalid treasury keypair"),
+            "{}",
+            err
+        );
+        fs::remove_file(&path).unwrap();
+
+        let err = treasury_keypair_from_lookup(lookup_path).unwrap_err();
+        assert!(err.to_string().contains("Failed to read"), "{}", err);
+    }
+
+    #[test]
+    fn test_in_flight_address_is_not_swept_twice() {
+        let sweeps = InFlightSweeps::default();
+        let (funded, empty) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let balances = [(funded, 1_000_000), (empty, 0)];
+
+        let started = sweeps.start_all(&balances, 1);
+        assert_eq!(started.len(), 1);
+        assert_eq!((started[0].0, started[0].1), (funded, 1_000_000));
+
+        // The next poll sees the same balance while the first sweep is confirming
+        assert!(sweeps.start_all(&balances, 1).is_empty());
+
+        drop(started);
+        assert_eq!(sweeps.start_all(&balances, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_dust_is_not_swept() {
+        let sweeps = InFlightSweeps::default();
+        let (dust, funded) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let min = DEFAULT_MIN_DEPOSIT_LAMPORTS;
+
+        let started = sweeps.start_all(&[(dust, min - 1), (funded, min)], min);
+        let swept: Vec<Pubkey> = started.iter().map(|(address, ..)| *address).collect();
+        assert_eq!(swept, vec![funded]);
+        drop(started);
+
+        // Once more arrives, the accumulated balance is swept
+        let started = sweeps.start_all(&[(dust, 2 * min - 1)], min);
+        assert_eq!(started.len(), 1);
+        assert_eq!((started[0].0, started[0].1), (dust, 2 * min - 1));
+    }
+
+    #[test]
+    fn test_parse_program_id() {
+        assert_eq!(
+            parse_program_id(DEFAULT_PROGRAM_ID).unwrap().to_string(),
+            DEFAULT_PROGRAM_ID
+        );
+        assert!(parse_program_id("not-a-pubkey").is_err());
+        assert!(parse_program_id("").is_err());
+    }
+
+    fn redis_connection() -> Connection {
+        let redis_url = env::var("REDIS_URL").unwrap();
+        Client::open(redis_url).unwrap().get_connection().unwrap()
+    }
+
+    #[test]
+    #[ignore = "requires REDIS_URL"]
+    fn test_register_and_list_deposit_addresses() -> anyhow::Result<()> {
+        let mut conn = redis_connection();
+        let user_id = i32::MAX;
+        let _: () = conn.del(user_deposit_addresses_key(user_id))?;
+
+        let first = (Pubkey::new_unique(), Pubkey::new_unique());
+        let second = (Pubkey::new_unique(), Pubkey::new_unique());
+        assert!(register_deposit_address(
+            &mut conn, user_id, &first.0, &first.1
+        )?);
+        assert!(register_deposit_address(
+            &mut conn, user_id, &second.0, &second.1
+        )?);
+
+        let mut addresses = list_deposit_addresses(&mut conn, user_id)?;
+        addresses.sort();
+        let mut expected = vec![first.0, second.0];
+        expected.sort();
+        assert_eq!(addresses, expected);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires REDIS_URL"]
+    fn test_sweep_publishes_confirmed_deposit() -> anyhow::Result<()> {
+        let mut conn = redis_connection();
+        let mut subscriber = redis_connection();
+        let mut pubsub = subscriber.as_pubsub();
+        pubsub.subscribe(DEPOSIT_CONFIRMED_CHANNEL)?;
+        pubsub.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+
+        let pda = Pubkey::new_unique();
+        assert!(register_deposit_address(
+            &mut conn,
+            i32::MAX - 3,
+            &pda,
+            &Pubkey::new_unique()
+        )?);
+        let signature = Signature::new_unique().to_string();
+        publish_deposit_confirmed(&mut conn, &pda, 250_000_000, &signature)?;
+
+        let payload: String = pubsub.get_message()?.get_payload()?;
+        let event: DepositConfirmed = serde_json::from_str(&payload)?;
+        assert_eq!(
+            event,
+            DepositConfirmed {
+                user_id: i32::MAX - 3,
+                deposit_address: pda.to_string(),
+                lamports: 250_000_000,
+                signature,
+            }
+        );
+
+        // Nothing is announced for addresses nobody owns
+        assert!(publish_deposit_confirmed(&mut conn, &Pubkey::new_unique(), 1, "sig").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires REDIS_URL"]
+    fn test_existing_deposit_address_is_not_overwritten() -> anyhow::Result<()> {
+        let mut conn = redis_connection();
+        let pda = Pubkey::new_unique();
+        let seed = Pubkey::new_unique();
+
+        assert!(register_deposit_address(
+            &mut conn,
+            i32::MAX - 1,
+            &pda,
+            &seed
+        )?);
+        assert!(!register_deposit_address(
+            &mut conn,
+            i32::MAX - 2,
+            &pda,
+            &Pubkey::new_unique()
+        )?);
+
+        let stored: String = conn.hget(DEPOSIT_ADDRESSES_KEY, pda.to_string())?;
+        assert_eq!(stored, seed.to_string());
+        let owner: i32 = conn.hget(DEPOSIT_ADDRESS_OWNERS_KEY, pda.to_string())?;
+        assert_eq!(owner, i32::MAX - 1);
+        assert!(list_deposit_addresses(&mut conn, i32::MAX - 2)?.is_empty());
+        Ok(())
+    }
+}
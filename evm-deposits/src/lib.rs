@@ -1,44 +1,669 @@
-use alloy_network::TransactionBuilder;
-use alloy_primitives::{Address, U256};
-use alloy_provider::{Provider, ProviderBuilder};
+use alloy_consensus::{Transaction, TxEnvelope};
+use alloy_network::{EthereumWallet, TransactionBuilder};
+use alloy_primitives::{Address, PrimitiveSignature, TxHash, U256};
+use alloy_provider::{
+    fillers::FillProvider, utils::JoinedRecommendedFillers, PendingTransactionError, Provider,
+    ProviderBuilder, RootProvider, SendableTx,
+};
+use alloy_rpc_client::RpcClient;
 use alloy_rpc_types::TransactionRequest;
 use alloy_signer_local::PrivateKeySigner;
-use std::{env, str::FromStr};
+use alloy_sol_types::{sol, SolCall};
+use alloy_transport::{RpcError, TransportError};
+use alloy_transport_http::{reqwest, Http};
+use anyhow::anyhow;
+use std::{
+    env,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+use url::Url;
 
-pub async fn transfer_funds(to_address: &str, amount_in_eth: f64) -> anyhow::Result<String> {
-    let private_key = env::var("MONAD_ACCOUNT_PRIVATE_KEY").unwrap();
-    let wallet = PrivateKeySigner::from_str(&private_key)?;
-    let from_address = wallet.address();
-    let rpc_url = env::var("MONAD_RPC_URL").unwrap();
-    // Connect to an Ethereum node via RPC
-    let provider = ProviderBuilder::new()
-        .wallet(wallet)
-        .on_http(rpc_url.parse().unwrap());
+/// How long a request to a Monad RPC node may take, unless `MONAD_RPC_TIMEOUT_SECS` says otherwise
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
 
-    // Define the recipient address
-    let to_address = Address::from_str(to_address)?; // Replace with recipient address
+type MonadProvider = FillProvider<JoinedRecommendedFillers, RootProvider>;
 
-    // Build a transaction to send 100 wei from Alice to Bob
-    let tx = TransactionRequest::default()
-        .with_from(from_address)
-        .with_to(to_address)
-        .with_value(U256::from((amount_in_eth * 10_u64.pow(18) as f64) as u64));
+sol! {
+    /// ERC-20 `transfer`
+    function transfer(address to, uint256 amount) returns (bool);
+}
+
+/// Parses a comma separated list of RPC URLs, as `MONAD_RPC_URL` holds them.
+pub fn parse_rpc_urls(urls: &str) -> anyhow::Result<Vec<Url>> {
+    let urls = urls
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            url.parse()
+                .map_err(|e| anyhow!("Invalid RPC URL {:?}: {}", url, e))
+        })
+        .collect::<anyhow::Result<Vec<Url>>>()?;
+    if urls.is_empty() {
+        return Err(anyhow!("Expected at least one RPC URL"));
+    }
+    Ok(urls)
+}
+
+/// An address that isn't a valid EVM address.
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid address {0:?}")]
+pub struct InvalidAddress(pub String);
+
+pub fn parse_address(address: &str) -> Result<Address, InvalidAddress> {
+    Address::from_str(address.trim()).map_err(|_| InvalidAddress(address.to_string()))
+}
+
+/// Whether `signature`, hex encoded, is `signer`'s signature of `message`, as a wallet's
+/// `personal_sign` makes them (EIP-191).
+pub fn is_signed_by(message: &str, signature: &str, signer: &str) -> bool {
+    let (Ok(signature), Ok(signer)) = (
+        PrimitiveSignature::from_str(signature.trim()),
+        parse_address(signer),
+    ) else {
+        return false;
+    };
+    signature
+        .recover_address_from_msg(message)
+        .is_ok_and(|address| address == signer)
+}
+
+fn transport_error(err: &anyhow::Error) -> Option<&TransportError> {
+    match err.downcast_ref::<PendingTransactionError>() {
+        Some(PendingTransactionError::TransportError(err)) => Some(err),
+        _ => err.downcast_ref::<TransportError>(),
+    }
+}
+
+// Errors getting an answer out of the node, as opposed to the node's answer being an error
+fn is_node_error(err: &anyhow::Error) -> bool {
+    matches!(transport_error(err), Some(RpcError::Transport(_)))
+}
 
-    // Send the transaction and listen for the transaction to be included.
-    let tx_hash = provider.send_transaction(tx).await?.watch().await?;
+/// Why a transfer from the treasury failed, as far as the user and ops need to know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferFailure {
+    /// The destination isn't an address
+    InvalidAddress,
+    /// The treasury can't cover the amount and gas
+    InsufficientFunds,
+    /// No node answered
+    NodeUnavailable,
+    /// Anything else, e.g. a node rejecting the transaction
+    Other,
+}
 
-    println!("Sent transaction: {tx_hash}");
+impl TransferFailure {
+    /// Classifies an error returned by a [`MonadClient`] transfer.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<InvalidAddress>().is_some() {
+            return Self::InvalidAddress;
+        }
+        match transport_error(err) {
+            Some(RpcError::Transport(_)) => Self::NodeUnavailable,
+            // Nodes word it differently, but all say "insufficient funds"
+            Some(RpcError::ErrorResp(payload))
+                if payload
+                    .message
+                    .to_lowercase()
+                    .contains("insufficient funds") =>
+            {
+                Self::InsufficientFunds
+            }
+            _ => Self::Other,
+        }
+    }
 
-    Ok(tx_hash.to_string())
+    /// What to tell the user, without any detail of the treasury or the nodes.
+    pub fn user_message(self) -> &'static str {
+        match self {
+            Self::InvalidAddress => "Invalid withdrawal address",
+            Self::InsufficientFunds | Self::NodeUnavailable => {
+                "Withdrawals are temporarily unavailable, try again later"
+            }
+            Self::Other => "Withdrawal could not be sent",
+        }
+    }
+}
+
+struct Node {
+    url: Url,
+    provider: MonadProvider,
+}
+
+/// Monad RPC nodes to fail over between, the first one being the preferred, and the
+/// treasury account that pays withdrawals. Cheap to clone; clones share the connections.
+#[derive(Clone)]
+pub struct MonadClient {
+    nodes: Arc<[Node]>,
+    // The node calls start with: the last one that answered
+    healthy: Arc<AtomicUsize>,
+    treasury: Option<PrivateKeySigner>,
+}
+
+impl MonadClient {
+    /// Connects to `urls`, giving each request `timeout` to complete. Without a
+    /// `treasury` key the client can look transactions up but not send any.
+    pub fn new(
+        urls: &[Url],
+        timeout: Duration,
+        treasury: Option<PrivateKeySigner>,
+    ) -> anyhow::Result<Self> {
+        if urls.is_empty() {
+            return Err(anyhow!("Expected at least one RPC URL"));
+        }
+        let http = reqwest::Client::builder().timeout(timeout).build()?;
+        let nodes = urls
+            .iter()
+            .map(|url| Node {
+                url: url.clone(),
+                provider: ProviderBuilder::new().on_client(RpcClient::new(
+                    Http::with_client(http.clone(), url.clone()),
+                    false,
+                )),
+            })
+            .collect();
+        Ok(Self {
+            nodes,
+            healthy: Arc::new(AtomicUsize::new(0)),
+            treasury,
+        })
+    }
+
+    /// Reads the nodes from `MONAD_RPC_URL`, comma separated in order of preference, the
+    /// request timeout from `MONAD_RPC_TIMEOUT_SECS` and the treasury key, if any, from
+    /// `MONAD_ACCOUNT_PRIVATE_KEY`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let urls = env::var("MONAD_RPC_URL").map_err(|_| anyhow!("MONAD_RPC_URL is not set"))?;
+        let urls = parse_rpc_urls(&urls).map_err(|e| anyhow!("Invalid MONAD_RPC_URL: {}", e))?;
+        let timeout = match env::var("MONAD_RPC_TIMEOUT_SECS") {
+            Ok(secs) => match secs.trim().parse() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(anyhow!("Invalid MONAD_RPC_TIMEOUT_SECS {:?}", secs)),
+            },
+            Err(_) => DEFAULT_RPC_TIMEOUT,
+        };
+        let treasury = match env::var("MONAD_ACCOUNT_PRIVATE_KEY") {
+            Ok(private_key) => Some(
+                PrivateKeySigner::from_str(&private_key)
+                    .map_err(|e| anyhow!("Invalid MONAD_ACCOUNT_PRIVATE_KEY: {}", e))?,
+            ),
+            Err(_) => None,
+        };
+        Self::new(&urls, timeout, treasury)
+    }
+
+    /// URL of the node calls currently go to first.
+    pub fn healthy_url(&self) -> &Url {
+        &self.nodes[self.healthy.load(Ordering::Relaxed)].url
+    }
+
+    /// Address of the account that pays withdrawals, which is also where Monad deposits
+    /// go. `None` without a treasury key.
+    pub fn treasury_address(&self) -> Option<String> {
+        self.treasury
+            .as_ref()
+            .map(|treasury| treasury.address().to_string())
+    }
+
+    fn treasury(&self) -> anyhow::Result<&PrivateKeySigner> {
+        self.treasury
+            .as_ref()
+            .ok_or_else(|| anyhow!("MONAD_ACCOUNT_PRIVATE_KEY is not set"))
+    }
+
+    // Runs `call` against the node that last answered, then each of the others in turn
+    // while the node tried gives no answer
+    async fn call<T, F>(&self, call: impl Fn(MonadProvider) -> F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        let start = self.healthy.load(Ordering::Relaxed);
+        let mut tried = 0;
+        loop {
+            let index = (start + tried) % self.nodes.len();
+            let node = &self.nodes[index];
+            match call(node.provider.clone()).await {
+                Err(err) if is_node_error(&err) && tried + 1 < self.nodes.len() => {
+                    warn!(
+                        "Monad RPC node {} failed, trying the next: {}",
+                        node.url, err
+                    );
+                    tried += 1;
+                }
+                result => {
+                    if result.is_ok() && index != start {
+                        info!("Failed over to Monad RPC node {}", node.url);
+                        self.healthy.store(index, Ordering::Relaxed);
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    pub async fn transfer_funds(
+        &self,
+        to_address: &str,
+        amount_in_eth: f64,
+    ) -> anyhow::Result<String> {
+        let transfer = self.sign_transfer(to_address, amount_in_eth).await?;
+        self.send_transfer(&transfer).await
+    }
+
+    /// Signs a transfer of `amount_in_eth` to `to_address`, filling in the nonce and gas
+    /// from the chain, without sending it.
+    pub async fn sign_transfer(
+        &self,
+        to_address: &str,
+        amount_in_eth: f64,
+    ) -> anyhow::Result<SignedTransfer> {
+        let to_address = parse_address(to_address)?;
+        let tx = TransactionRequest::default()
+            .with_to(to_address)
+            .with_value(U256::from((amount_in_eth * 10_u64.pow(18) as f64) as u64));
+        self.sign(tx).await
+    }
+
+    pub async fn transfer_token(
+        &self,
+        token_address: &str,
+        to_address: &str,
+        amount: U256,
+    ) -> anyhow::Result<String> {
+        let transfer = self
+            .sign_token_transfer(token_address, to_address, amount)
+            .await?;
+        self.send_transfer(&transfer).await
+    }
+
+    /// Signs an ERC-20 transfer of `amount`, in the token's base units, of the token at
+    /// `token_address` to `to_address`, without sending it.
+    pub async fn sign_token_transfer(
+        &self,
+        token_address: &str,
+        to_address: &str,
+        amount: U256,
+    ) -> anyhow::Result<SignedTransfer> {
+        let token_address = parse_address(token_address)?;
+        let to_address = parse_address(to_address)?;
+        let tx = TransactionRequest::default()
+            .with_to(token_address)
+            .with_input(
+                transferCall {
+                    to: to_address,
+                    amount,
+                }
+                .abi_encode(),
+            );
+        self.sign(tx).await
+    }
+
+    // Fills in the nonce and gas of a transaction from the treasury and signs it
+    async fn sign(&self, tx: TransactionRequest) -> anyhow::Result<SignedTransfer> {
+        let treasury = self.treasury()?;
+        let tx = tx.with_from(treasury.address());
+        let tx = self
+            .call(|provider| {
+                let tx = tx.clone();
+                async move {
+                    match provider.fill(tx).await? {
+                        SendableTx::Builder(tx) => Ok(tx),
+                        SendableTx::Envelope(_) => Err(anyhow!("Transfer was signed unexpectedly")),
+                    }
+                }
+            })
+            .await?;
+        let envelope = tx
+            .build(&EthereumWallet::from(treasury.clone()))
+            .await
+            .map_err(|e| anyhow!("Transfer could not be signed: {}", e))?;
+        Ok(SignedTransfer { envelope })
+    }
+
+    /// Broadcasts a signed transfer and waits for it to be included. A transfer sent
+    /// again through another node keeps its hash, so it can't land twice.
+    pub async fn send_transfer(&self, transfer: &SignedTransfer) -> anyhow::Result<String> {
+        let tx_hash = self
+            .call(|provider| {
+                let envelope = transfer.envelope.clone();
+                async move { Ok(provider.send_tx_envelope(envelope).await?.watch().await?) }
+            })
+            .await?;
+
+        info!("Sent transaction: {tx_hash}");
+
+        Ok(tx_hash.to_string())
+    }
+
+    /// How much `recipient` received in `tx_hash`, in MON. `None` if the transaction
+    /// is unknown, still pending or reverted.
+    pub async fn received_by(&self, tx_hash: &str, recipient: &str) -> anyhow::Result<Option<f64>> {
+        let tx_hash = TxHash::from_str(tx_hash)?;
+        let recipient = Address::from_str(recipient)?;
+
+        self.call(|provider| async move {
+            let Some(receipt) = provider.get_transaction_receipt(tx_hash).await? else {
+                return Ok(None);
+            };
+            if !receipt.status() {
+                return Ok(None);
+            }
+            let Some(tx) = provider.get_transaction_by_hash(tx_hash).await? else {
+                return Ok(None);
+            };
+            if tx.to() != Some(recipient) {
+                return Ok(Some(0.0));
+            }
+            Ok(Some(f64::from(tx.value()) / 1e18))
+        })
+        .await
+    }
+
+    /// Whether the treasury transfer `tx_hash`, signed with `nonce`, can no longer land:
+    /// another transaction took its nonce, e.g. a replacement or cancellation, or it
+    /// reverted. `false` while the nonce is unspent, since the transfer may still be sent.
+    pub async fn transfer_dropped(&self, tx_hash: &str, nonce: u64) -> anyhow::Result<bool> {
+        let tx_hash = TxHash::from_str(tx_hash)?;
+        let treasury = self.treasury()?.address();
+
+        self.call(|provider| async move {
+            // Read before the receipt, so the transfer can't take the nonce in between
+            let spent = provider.get_transaction_count(treasury).latest().await?;
+            if spent <= nonce {
+                return Ok(false);
+            }
+            let receipt = provider.get_transaction_receipt(tx_hash).await?;
+            Ok(receipt.is_none_or(|receipt| !receipt.status()))
+        })
+        .await
+    }
+
+    /// Whether `tx_hash` was sent from `sender`. `false` if the transaction is unknown.
+    pub async fn sent_by(&self, tx_hash: &str, sender: &str) -> anyhow::Result<bool> {
+        let tx_hash = TxHash::from_str(tx_hash)?;
+        let sender = Address::from_str(sender)?;
+
+        self.call(|provider| async move {
+            let tx = provider.get_transaction_by_hash(tx_hash).await?;
+            Ok(tx.is_some_and(|tx| tx.inner.signer() == sender))
+        })
+        .await
+    }
+}
+
+/// A transfer from the treasury, signed but not broadcast yet.
+#[derive(Debug, Clone)]
+pub struct SignedTransfer {
+    envelope: TxEnvelope,
+}
+
+impl SignedTransfer {
+    /// The hash the transfer will have on chain once it is sent.
+    pub fn tx_hash(&self) -> String {
+        self.envelope.tx_hash().to_string()
+    }
+
+    /// The treasury nonce the transfer was signed with.
+    pub fn nonce(&self) -> u64 {
+        self.envelope.nonce()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Mutex;
+
+    use alloy_primitives::hex;
+    use alloy_signer::SignerSync;
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
     use super::*;
 
+    const TX_HASH: &str = "0x8e0b0d79e4d1b1f1d2c8c4ba1f4d4e8c0c4a7e2e6f1a3b5c7d9e0f1a2b3c4d5e";
+    const RECIPIENT: &str = "0x0BF493537Fa5b08836d7AE8750CFEA682a0f190C";
+    const TOKEN: &str = "0xf817257fed379853cDe0fa4F97AB987181B1E5Ea";
+
+    // An RPC node answering each request with what `reply` makes of it, the result or
+    // error without the id
+    async fn node(reply: impl Fn(&Value) -> Value + Send + 'static) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Requests are small enough to come in a read or two; the body is the
+                // last thing in them
+                let request = loop {
+                    let read = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                    let text = String::from_utf8_lossy(&request);
+                    let Some(body) = text.split("\r\n\r\n").nth(1) else {
+                        continue;
+                    };
+                    if let Ok(body) = serde_json::from_str::<Value>(body) {
+                        break body;
+                    }
+                };
+                let mut body = reply(&request);
+                body["jsonrpc"] = json!("2.0");
+                body["id"] = request["id"].clone();
+                let body = body.to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url.parse().unwrap()
+    }
+
+    // An RPC node that takes connections and never answers them
+    async fn silent_node() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            loop {
+                sockets.push(listener.accept().await.unwrap());
+            }
+        });
+        url.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_rpc_urls() {
+        let urls = parse_rpc_urls(" https://a.example.com, https://b.example.com ,").unwrap();
+        assert_eq!(urls.len(), 2);
+        assert_eq!(urls[1].as_str(), "https://b.example.com/");
+        assert!(parse_rpc_urls("").is_err());
+        assert!(parse_rpc_urls("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_primary_fails_over() {
+        let primary = silent_node().await;
+        // No receipt: the transaction is unknown
+        let secondary = node(|_| json!({ "result": null })).await;
+        let client = MonadClient::new(
+            &[primary.clone(), secondary.clone()],
+            Duration::from_millis(200),
+            None,
+        )
+        .unwrap();
+        assert_eq!(client.healthy_url(), &primary);
+
+        assert_eq!(client.received_by(TX_HASH, RECIPIENT).await.unwrap(), None);
+        assert_eq!(client.healthy_url(), &secondary);
+        // Later calls go straight to the node that answered
+        assert_eq!(client.received_by(TX_HASH, RECIPIENT).await.unwrap(), None);
+        assert_eq!(client.healthy_url(), &secondary);
+    }
+
+    #[tokio::test]
+    async fn test_error_answers_dont_fail_over() {
+        let primary = node(|_| json!({ "error": { "code": -32000, "message": "boom" } })).await;
+        let secondary = node(|_| json!({ "result": null })).await;
+        let client =
+            MonadClient::new(&[primary.clone(), secondary], Duration::from_secs(5), None).unwrap();
+
+        assert!(client.received_by(TX_HASH, RECIPIENT).await.is_err());
+        assert_eq!(client.healthy_url(), &primary);
+    }
+
+    #[tokio::test]
+    async fn test_client_is_reused_across_transfers() {
+        let methods = Arc::new(Mutex::new(Vec::new()));
+        let seen = methods.clone();
+        let url = node(move |request| {
+            let method = request["method"].as_str().unwrap().to_string();
+            let result = match method.as_str() {
+                "eth_chainId" => json!("0x279f"),
+                "eth_getTransactionCount" => json!("0x7"),
+                "eth_estimateGas" => json!("0x5208"),
+                "eth_feeHistory" => json!({
+                    "oldestBlock": "0x1",
+                    "baseFeePerGas": ["0x3b9aca00", "0x3b9aca00"],
+                    "gasUsedRatio": [0.5],
+                    "reward": [["0x3b9aca00"]]
+                }),
+                _ => return json!({ "error": { "code": -32601, "message": method } }),
+            };
+            seen.lock().unwrap().push(method);
+            json!({ "result": result })
+        })
+        .await;
+        // A well known development key
+        let treasury = PrivateKeySigner::from_str(
+            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+        )
+        .unwrap();
+        let client = MonadClient::new(&[url], Duration::from_secs(5), Some(treasury)).unwrap();
+
+        let first = client.sign_transfer(RECIPIENT, 0.01).await.unwrap();
+        let second = client.sign_transfer(RECIPIENT, 0.02).await.unwrap();
+        let token = client
+            .sign_token_transfer(TOKEN, RECIPIENT, U256::from(1_000_000))
+            .await
+            .unwrap();
+        assert_ne!(first.tx_hash(), second.tx_hash());
+        assert_ne!(second.tx_hash(), token.tx_hash());
+
+        // The chain id is only asked for once, then kept by the shared provider
+        let methods = methods.lock().unwrap();
+        let asked = |method| methods.iter().filter(|seen| *seen == method).count();
+        assert_eq!(asked("eth_chainId"), 1);
+        assert_eq!(asked("eth_getTransactionCount"), 3);
+    }
+
+    #[tokio::test]
+    async fn test_transfers_are_dropped_once_their_nonce_is_spent() {
+        // The treasury has sent 8 transactions, none of them `TX_HASH`
+        let url = node(|request| match request["method"].as_str() {
+            Some("eth_getTransactionCount") => json!({ "result": "0x8" }),
+            _ => json!({ "result": null }),
+        })
+        .await;
+        let treasury = PrivateKeySigner::from_str(
+            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+        )
+        .unwrap();
+        let client = MonadClient::new(&[url], Duration::from_secs(5), Some(treasury)).unwrap();
+
+        assert!(client.transfer_dropped(TX_HASH, 7).await.unwrap());
+        // Nonce 8 is still free, so the transfer may yet be sent
+        assert!(!client.transfer_dropped(TX_HASH, 8).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_failures_are_classified() {
+        let treasury = || {
+            Some(
+                PrivateKeySigner::from_str(
+                    "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+                )
+                .unwrap(),
+            )
+        };
+        let failure = |client: MonadClient, to: &'static str| async move {
+            TransferFailure::of(&client.sign_transfer(to, 1.0).await.unwrap_err())
+        };
+        let rejecting = |message: &'static str| {
+            node(move |_| json!({ "error": { "code": -32000, "message": message } }))
+        };
+
+        let url = rejecting("boom").await;
+        let client = MonadClient::new(&[url], Duration::from_secs(5), treasury()).unwrap();
+        assert_eq!(
+            failure(client.clone(), "not an address").await,
+            TransferFailure::InvalidAddress
+        );
+        assert_eq!(failure(client, RECIPIENT).await, TransferFailure::Other);
+
+        let url = rejecting("insufficient funds for gas * price + value").await;
+        let client = MonadClient::new(&[url], Duration::from_secs(5), treasury()).unwrap();
+        assert_eq!(
+            failure(client, RECIPIENT).await,
+            TransferFailure::InsufficientFunds
+        );
+
+        let url = silent_node().await;
+        let client = MonadClient::new(&[url], Duration::from_millis(200), treasury()).unwrap();
+        assert_eq!(
+            failure(client, RECIPIENT).await,
+            TransferFailure::NodeUnavailable
+        );
+    }
+
+    #[test]
+    fn test_is_signed_by() {
+        let wallet = PrivateKeySigner::random();
+        let address = wallet.address().to_string();
+        let signature =
+            hex::encode_prefixed(wallet.sign_message_sync(b"hello").unwrap().as_bytes());
+
+        assert!(is_signed_by("hello", &signature, &address));
+        // Addresses are compared, not their spelling
+        assert!(is_signed_by("hello", &signature, &address.to_lowercase()));
+        assert!(!is_signed_by("goodbye", &signature, &address));
+        assert!(!is_signed_by("hello", &signature, RECIPIENT));
+        assert!(!is_signed_by("hello", "0x12", &address));
+    }
+
+    #[test]
+    fn test_token_transfer_calldata() {
+        let calldata = transferCall {
+            to: Address::from_str(RECIPIENT).unwrap(),
+            amount: U256::from(1),
+        }
+        .abi_encode();
+        assert_eq!(calldata.len(), 4 + 32 + 32);
+        // transfer(address,uint256)
+        assert_eq!(calldata[..4], [0xa9, 0x05, 0x9c, 0xbb]);
+        assert_eq!(calldata[67], 1);
+    }
+
     #[tokio::test]
+    #[ignore = "requires a funded key and a live Monad RPC"]
     async fn test_transfer_funds() -> anyhow::Result<()> {
-        transfer_funds("0x0BF493537Fa5b08836d7AE8750CFEA682a0f190C", 0.01).await?;
+        MonadClient::from_env()?
+            .transfer_funds("0x0BF493537Fa5b08836d7AE8750CFEA682a0f190C", 0.01)
+            .await?;
         Ok(())
     }
 }
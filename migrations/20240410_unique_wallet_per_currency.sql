@@ -0,0 +1,11 @@
+-- Allow a user to hold one wallet per currency and make provisioning idempotent
+
+-- Drop any duplicate wallets, keeping the oldest row per (user_id, currency)
+DELETE FROM wallet w
+USING wallet d
+WHERE w.user_id = d.user_id
+AND w.currency = d.currency
+AND w.id > d.id;
+
+ALTER TABLE wallet
+ADD CONSTRAINT unique_user_currency UNIQUE (user_id, currency);
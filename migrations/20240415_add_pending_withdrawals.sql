@@ -0,0 +1,18 @@
+-- Queue of withdrawals drained by the withdrawal background worker
+
+CREATE TABLE pending_withdrawals (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    currency TEXT NOT NULL,
+    amount DOUBLE PRECISION NOT NULL,
+    fee DOUBLE PRECISION NOT NULL DEFAULT 0,
+    withdraw_address TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'PENDING',
+    tx_hash TEXT,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- The worker picks the oldest pending row first
+CREATE INDEX idx_pending_withdrawals_status ON pending_withdrawals(status, id);
@@ -0,0 +1,5 @@
+-- Each Razorpay payment may credit an INR wallet only once
+
+CREATE UNIQUE INDEX idx_transactions_unique_inr_deposit
+ON transactions(tx_hash)
+WHERE currency = 'INR' AND tx_type = 'DEPOSIT';
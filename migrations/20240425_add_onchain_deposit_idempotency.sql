@@ -0,0 +1,6 @@
+-- Each on-chain transaction may credit a deposit only once, whether it arrives through
+-- POST /deposit or a sweep announced by the deposit worker
+
+CREATE UNIQUE INDEX idx_transactions_unique_onchain_deposit
+ON transactions(currency, tx_hash)
+WHERE currency <> 'INR' AND tx_type = 'DEPOSIT';
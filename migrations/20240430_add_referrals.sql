@@ -0,0 +1,14 @@
+-- Every user gets a code to share. A new user who signs up with someone's code is
+-- linked to them here, and the referrer earns a bonus once on the referee's first deposit
+
+ALTER TABLE users ADD COLUMN referral_code TEXT NOT NULL UNIQUE
+    DEFAULT upper(substr(md5(random()::text), 1, 8));
+
+CREATE TABLE referrals (
+    id SERIAL PRIMARY KEY,
+    referrer_id INTEGER NOT NULL REFERENCES users(id),
+    referee_id INTEGER NOT NULL UNIQUE REFERENCES users(id),
+    -- Set when the referrer is paid, which happens at most once per referee
+    bonus_credited_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,10 @@
+-- A client retrying a withdrawal sends the same idempotency key, and gets the response
+-- stored with the first withdrawal instead of a second one. Keys are only honoured for
+-- a while, so they aren't unique.
+
+ALTER TABLE pending_withdrawals ADD COLUMN idempotency_key TEXT;
+ALTER TABLE pending_withdrawals ADD COLUMN response TEXT;
+
+CREATE INDEX idx_pending_withdrawals_idempotency_key
+ON pending_withdrawals(user_id, idempotency_key, created_at)
+WHERE idempotency_key IS NOT NULL;
@@ -0,0 +1,24 @@
+-- One row per settled game, for match history and resolving disputes. A rematch keeps its
+-- game id, so a game id can have several rows
+
+CREATE TABLE games (
+    id SERIAL PRIMARY KEY,
+    game_id TEXT NOT NULL,
+    -- Hex SHA3-256 of the seed the board was drawn from
+    seed_hash TEXT NOT NULL,
+    -- In seat order
+    user_ids INTEGER[] NOT NULL,
+    -- Empty for a draw
+    loser_ids INTEGER[] NOT NULL,
+    currency TEXT NOT NULL,
+    single_bet_size DOUBLE PRECISION NOT NULL,
+    -- What the losers paid in, rake included
+    pot DOUBLE PRECISION NOT NULL,
+    rake DOUBLE PRECISION NOT NULL,
+    -- The losers left the game rather than hitting a bomb
+    abandoned BOOLEAN NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX games_game_id_idx ON games (game_id);
+CREATE INDEX games_user_ids_idx ON games USING GIN (user_ids);
@@ -0,0 +1,12 @@
+-- Games running right now. Their bets stay in the players' wallets until settlement, so this
+-- only lets reconciliation report the stakes in flight. A row is removed when its game settles
+-- or ends unsettled; one left behind by a server that stopped mid-game has an old started_at
+
+CREATE TABLE running_games (
+    game_id TEXT PRIMARY KEY,
+    -- In seat order
+    user_ids INTEGER[] NOT NULL,
+    currency TEXT NOT NULL,
+    single_bet_size DOUBLE PRECISION NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,7 @@
+-- What tells when a withdrawal's transfer can no longer land: the treasury nonce a MON
+-- transfer was signed with, or the last block height a Solana transfer's blockhash is
+-- valid until. A signed transfer can be sent late, so a withdrawal that never confirmed
+-- is only refunded once that nonce is spent or that height has passed.
+
+ALTER TABLE pending_withdrawals ADD COLUMN nonce BIGINT;
+ALTER TABLE pending_withdrawals ADD COLUMN last_valid_block_height BIGINT;
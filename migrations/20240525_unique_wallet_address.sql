@@ -0,0 +1,17 @@
+-- A wallet address may be registered to deposit from by one user only. MON addresses
+-- are stored lowercase so differently checksummed spellings of one address collide.
+
+UPDATE wallet SET wallet_address = LOWER(wallet_address)
+WHERE currency = 'MON' AND wallet_address IS NOT NULL;
+
+-- Unregister addresses claimed by more than one user, keeping the oldest claim
+UPDATE wallet w
+SET wallet_address = NULL, updated_at = NOW()
+FROM wallet d
+WHERE w.currency = d.currency
+AND w.wallet_address = d.wallet_address
+AND w.id > d.id;
+
+CREATE UNIQUE INDEX idx_wallet_unique_address
+ON wallet(currency, wallet_address)
+WHERE wallet_address IS NOT NULL;
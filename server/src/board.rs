@@ -1,35 +1,94 @@
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tracing::info;
 
-use crate::seed_gen::get_bomb_coords;
+use crate::seed_gen::{get_bomb_coords_continuous_seeded, get_bomb_coords_seeded};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CellState {
     Mined,
     Hidden,
     Bomb,
+    Flagged,
+}
+
+/// How bombs are spread over the board
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BombLayout {
+    #[default]
+    Scattered,
+    Continuous,
+}
+
+impl CellState {
+    fn symbol(&self) -> char {
+        match self {
+            CellState::Hidden => '#',
+            CellState::Mined => '.',
+            CellState::Bomb => '*',
+            CellState::Flagged => 'F',
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Board {
     pub n: usize, // it would be nXn
     grid: Vec<Vec<CellState>>,
-    //TODO: It should be either continuous or scattered
     pub bomb_coordinates: Vec<u64>,
+    pub layout: BombLayout,
+    // Kept so a disputed board can be regenerated
+    pub seed: u64,
 }
 
 impl Board {
-    pub fn new(n: usize, bombs: usize) -> Board {
-        let bomb_coords = get_bomb_coords(bombs, n as u64);
+    /// Builds an `n`x`n` board, drawing a random seed when none is given.
+    pub fn new(n: usize, bombs: usize, layout: BombLayout, seed: Option<u64>) -> Board {
+        let seed = seed.unwrap_or_else(rand::random);
+        let bomb_coords = match layout {
+            BombLayout::Scattered => get_bomb_coords_seeded(bombs, n as u64, seed),
+            BombLayout::Continuous => get_bomb_coords_continuous_seeded(bombs, n as u64, seed),
+        };
 
         Board {
             n,
             grid: vec![vec![CellState::Hidden; n]; n],
             bomb_coordinates: bomb_coords,
+            layout,
+            seed,
         }
     }
 
+    /// Side length of the square grid.
+    pub fn dimension(&self) -> usize {
+        self.n
+    }
+
+    /// Number of bombs, without revealing where they are.
+    pub fn bomb_count(&self) -> usize {
+        self.bomb_coordinates.len()
+    }
+
+    /// Number of cells without a bomb.
+    pub fn safe_cells_total(&self) -> usize {
+        self.n * self.n - self.bomb_count()
+    }
+
+    /// Number of cells mined without hitting a bomb.
+    pub fn revealed_safe_count(&self) -> usize {
+        self.grid
+            .iter()
+            .flatten()
+            .filter(|cell| matches!(cell, CellState::Mined))
+            .count()
+    }
+
+    /// Whether every safe cell has been mined, leaving only bombs to reveal.
+    pub fn is_cleared(&self) -> bool {
+        self.revealed_safe_count() == self.safe_cells_total()
+    }
+
     pub fn mine(&mut self, x: usize, y: usize) -> bool {
         let position = x * self.n + y;
         if self.bomb_coordinates.contains(&(position as u64)) {
@@ -41,6 +100,89 @@ impl Board {
         }
     }
 
+    /// Flags or unflags a hidden cell, returning whether it is now flagged.
+    /// Flags are only markers, `mine` still reveals a flagged cell.
+    pub fn toggle_flag(&mut self, x: usize, y: usize) -> Result<bool, String> {
+        let cell = self
+            .grid
+            .get_mut(x)
+            .and_then(|row| row.get_mut(y))
+            .ok_or_else(|| format!("Cell ({}, {}) is outside the board", x, y))?;
+        match cell {
+            CellState::Hidden => {
+                *cell = CellState::Flagged;
+                Ok(true)
+            }
+            CellState::Flagged => {
+                *cell = CellState::Hidden;
+                Ok(false)
+            }
+            CellState::Mined | CellState::Bomb => Err("Cell is already revealed".to_string()),
+        }
+    }
+
+    /// State of the cell at (`x`, `y`), or `None` outside the board.
+    pub fn cell(&self, x: usize, y: usize) -> Option<&CellState> {
+        self.grid.get(x).and_then(|row| row.get(y))
+    }
+
+    /// Errors if the cell is outside the board or already revealed. Flagged cells
+    /// can still be mined.
+    pub fn check_hidden(&self, x: usize, y: usize) -> Result<(), String> {
+        let cell = self
+            .grid
+            .get(x)
+            .and_then(|row| row.get(y))
+            .ok_or_else(|| format!("Cell ({}, {}) is outside the board", x, y))?;
+        match cell {
+            CellState::Hidden | CellState::Flagged => Ok(()),
+            CellState::Mined | CellState::Bomb => Err("Cell is already revealed".to_string()),
+        }
+    }
+
+    /// Copy of the board with every bomb shown, for states where the game is over.
+    /// Mined cells stay as they are and flags on bombs are replaced by the bomb.
+    pub fn revealed_clone(&self) -> Board {
+        let mut board = self.clone();
+        for &position in &self.bomb_coordinates {
+            let position = position as usize;
+            board.grid[position / self.n][position % self.n] = CellState::Bomb;
+        }
+        board
+    }
+
+    /// The largest an `n`x`n` board with `bombs` bombs can serialize to: every cell
+    /// flagged and the bombs on the positions with the most digits. Only used to
+    /// size messages, never played on.
+    pub fn widest(n: usize, bombs: usize) -> Board {
+        let cells = (n * n) as u64;
+        Board {
+            n,
+            grid: vec![vec![CellState::Flagged; n]; n],
+            bomb_coordinates: (cells.saturating_sub(bombs as u64)..cells).collect(),
+            layout: BombLayout::Continuous,
+            seed: u64::MAX,
+        }
+    }
+
+    /// Plain-text grid, one row per line: `#` hidden, `.` mined, `*` bomb, `F` flagged.
+    pub fn render_ascii(&self) -> String {
+        self.grid
+            .iter()
+            .map(|row| row.iter().map(CellState::symbol).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// What a UI needs to draw the board. Only revealed state is included, never
+    /// the bomb coordinates.
+    pub fn to_client_json(&self) -> Value {
+        json!({
+            "n": self.n,
+            "cells": self.grid,
+        })
+    }
+
     pub fn display(&self) {
         info!("╔{}╗", "═".repeat(self.n * 5));
         for (row_idx, row) in self.grid.iter().enumerate() {
@@ -62,6 +204,9 @@ impl Board {
 
                         print!("{:<3} ", "💣".yellow());
                     }
+                    CellState::Flagged => {
+                        print!("{:<3} ", "🚩".red());
+                    }
                 }
             }
 
@@ -82,3 +227,152 @@ impl Board {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layouts() {
+        let scattered = Board::new(8, 10, BombLayout::Scattered, Some(7));
+        assert_eq!(scattered.bomb_coordinates, get_bomb_coords_seeded(10, 8, 7));
+
+        let continuous = Board::new(8, 10, BombLayout::Continuous, Some(7));
+        assert_eq!(
+            continuous.bomb_coordinates,
+            get_bomb_coords_continuous_seeded(10, 8, 7)
+        );
+        assert_eq!(continuous.layout, BombLayout::Continuous);
+    }
+
+    #[test]
+    fn test_dimension_and_bomb_count() {
+        let board = Board::new(8, 10, BombLayout::Scattered, Some(7));
+        assert_eq!(board.dimension(), 8);
+        assert_eq!(board.bomb_count(), 10);
+    }
+
+    #[test]
+    fn test_revealed_clone_shows_every_bomb() {
+        let mut board = Board::new(4, 3, BombLayout::Scattered, Some(7));
+        let safe = (0..16u64)
+            .find(|position| !board.bomb_coordinates.contains(position))
+            .unwrap() as usize;
+        board.mine(safe / 4, safe % 4);
+
+        let revealed = board.revealed_clone();
+        assert_eq!(revealed.render_ascii().matches('*').count(), 3);
+        assert_eq!(revealed.render_ascii().matches('.').count(), 1);
+        // The live board keeps its bombs hidden
+        assert_eq!(board.render_ascii().matches('*').count(), 0);
+    }
+
+    #[test]
+    fn test_widest_outgrows_any_played_board() {
+        let widest = serde_json::to_vec(&Board::widest(8, 10)).unwrap().len();
+        let mut board = Board::new(8, 10, BombLayout::Scattered, None);
+        board.toggle_flag(0, 0).unwrap();
+        assert!(serde_json::to_vec(&board).unwrap().len() <= widest);
+        assert!(serde_json::to_vec(&board.revealed_clone()).unwrap().len() <= widest);
+    }
+
+    fn cell(board: &Board, x: usize, y: usize) -> &CellState {
+        &board.grid[x][y]
+    }
+
+    #[test]
+    fn test_render_ascii() {
+        let mut board = Board::new(3, 1, BombLayout::Scattered, Some(0));
+        board.bomb_coordinates = vec![4];
+        board.mine(0, 0);
+        board.mine(1, 1);
+        board.toggle_flag(2, 2).unwrap();
+
+        assert_eq!(board.render_ascii(), ".##\n#*#\n##F");
+        assert!(board
+            .render_ascii()
+            .lines()
+            .all(|line| line.chars().count() == 3));
+    }
+
+    #[test]
+    fn test_to_client_json() {
+        let mut board = Board::new(2, 1, BombLayout::Scattered, Some(0));
+        board.bomb_coordinates = vec![3];
+        board.mine(0, 1);
+
+        assert_eq!(
+            board.to_client_json(),
+            json!({
+                "n": 2,
+                "cells": [["Hidden", "Mined"], ["Hidden", "Hidden"]],
+            })
+        );
+    }
+
+    #[test]
+    fn test_toggle_flag() {
+        let mut board = Board::new(4, 2, BombLayout::Scattered, Some(0));
+
+        assert_eq!(board.toggle_flag(1, 2), Ok(true));
+        assert!(matches!(cell(&board, 1, 2), CellState::Flagged));
+        assert_eq!(board.toggle_flag(1, 2), Ok(false));
+        assert!(matches!(cell(&board, 1, 2), CellState::Hidden));
+
+        assert!(board.toggle_flag(4, 0).is_err());
+    }
+
+    #[test]
+    fn test_flagged_cell_can_be_mined() {
+        let mut board = Board::new(4, 2, BombLayout::Scattered, Some(0));
+        let bomb = board.bomb_coordinates[0] as usize;
+        let (bx, by) = (bomb / 4, bomb % 4);
+        let safe = (0..16u64)
+            .find(|c| !board.bomb_coordinates.contains(c))
+            .unwrap() as usize;
+        let (sx, sy) = (safe / 4, safe % 4);
+
+        board.toggle_flag(bx, by).unwrap();
+        board.toggle_flag(sx, sy).unwrap();
+
+        assert!(board.mine(bx, by));
+        assert!(matches!(cell(&board, bx, by), CellState::Bomb));
+        assert!(!board.mine(sx, sy));
+        assert!(matches!(cell(&board, sx, sy), CellState::Mined));
+        // Revealed cells can't be flagged any more
+        assert!(board.toggle_flag(sx, sy).is_err());
+    }
+
+    #[test]
+    fn test_cleared_once_every_safe_cell_is_mined() {
+        let mut board = Board::new(3, 2, BombLayout::Scattered, Some(0));
+        assert_eq!(board.safe_cells_total(), 7);
+
+        let (bombs, safe): (Vec<usize>, Vec<usize>) =
+            (0..9).partition(|c| board.bomb_coordinates.contains(&(*c as u64)));
+        // Bombs and flags don't count towards clearing the board
+        board.mine(bombs[0] / 3, bombs[0] % 3);
+        board.toggle_flag(safe[0] / 3, safe[0] % 3).unwrap();
+        assert_eq!(board.revealed_safe_count(), 0);
+
+        for (i, cell) in safe.iter().enumerate() {
+            assert!(!board.is_cleared());
+            board.mine(cell / 3, cell % 3);
+            assert_eq!(board.revealed_safe_count(), i + 1);
+        }
+        assert!(board.is_cleared());
+    }
+
+    #[test]
+    fn test_check_hidden() {
+        let mut board = Board::new(4, 2, BombLayout::Scattered, Some(0));
+        let bomb = board.bomb_coordinates[0] as usize;
+        let (bx, by) = (bomb / 4, bomb % 4);
+
+        board.toggle_flag(bx, by).unwrap();
+        assert_eq!(board.check_hidden(bx, by), Ok(()));
+        board.mine(bx, by);
+        assert!(board.check_hidden(bx, by).is_err());
+        assert!(board.check_hidden(0, 4).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio_websockets::{ClientBuilder, MaybeTlsStream, Message, WebSocketStream};
+
+use crate::protocol::{GameMessage, GameState, ProtocolError};
+
+/// Typed WebSocket client for the game server, for end-to-end tests and tooling.
+///
+/// Messages are sent as `GameMessage`s and the client is a `Stream` of the
+/// messages the server sends back. Control frames are handled by the socket
+/// and never show up in the stream.
+pub struct GameClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl GameClient {
+    /// Connects to a game server, e.g. `ws://127.0.0.1:3000/`.
+    pub async fn connect(uri: &str) -> Result<Self> {
+        let uri: http::Uri = uri.parse()?;
+        let (ws, _) = ClientBuilder::from_uri(uri).connect().await?;
+        Ok(Self { ws })
+    }
+
+    pub async fn send(&mut self, message: &GameMessage) -> Result<()> {
+        self.send_frame(Message::binary(serde_json::to_vec(message)?))
+            .await
+    }
+
+    /// Sends a frame as is, e.g. to test how the server handles malformed input.
+    pub async fn send_frame(&mut self, frame: Message) -> Result<()> {
+        self.ws.send(frame).await?;
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for the next message from the server.
+    pub async fn recv(&mut self, timeout: Duration) -> Result<GameMessage> {
+        tokio::time::timeout(timeout, self.next())
+            .await
+            .map_err(|_| anyhow!("no message from the server within {:?}", timeout))?
+            .ok_or_else(|| anyhow!("connection closed"))?
+    }
+
+    /// Waits up to `timeout` for the next game state, skipping messages such as
+    /// blockchain updates. An `Error` from the server fails the wait with a
+    /// [`ProtocolError`] carrying its code.
+    pub async fn next_update(&mut self, timeout: Duration) -> Result<GameState> {
+        tokio::time::timeout(timeout, async {
+            while let Some(message) = self.next().await {
+                match message? {
+                    GameMessage::GameUpdate(state) => return Ok(state),
+                    GameMessage::Error { code, message } => {
+                        return Err(ProtocolError::new(code, message).into())
+                    }
+                    _ => continue,
+                }
+            }
+            Err(anyhow!("connection closed"))
+        })
+        .await
+        .map_err(|_| anyhow!("no game update within {:?}", timeout))?
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.ws.close().await?;
+        Ok(())
+    }
+}
+
+impl Stream for GameClient {
+    type Item = Result<GameMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let frame = match Pin::new(&mut self.ws).poll_next(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if frame.is_binary() || frame.is_text() {
+                return Poll::Ready(Some(
+                    serde_json::from_slice(frame.as_payload()).map_err(Into::into),
+                ));
+            }
+        }
+    }
+}
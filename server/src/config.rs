@@ -0,0 +1,446 @@
+use std::{env, net::SocketAddr, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use common::{impl_from_str_for_enum, payout::PayoutPolicy};
+
+use crate::{
+    game::{validate_name, AllowedGrids, BoardLimits, CreationLimit, DEFAULT_MAX_CONNECTIONS},
+    house_bot::HouseBotConfig,
+    router::ServerListRouter,
+};
+
+const DEFAULT_GAME_BIND_ADDR: &str = "0.0.0.0:3000";
+const DEFAULT_METRICS_PORT: u16 = 9092;
+// How long a player who drops out of a running game has to come back before losing it
+const DEFAULT_RECONNECT_GRACE: Duration = Duration::from_secs(30);
+// How long a lobby may wait for players before it's aborted; matches the discovery session TTL
+const DEFAULT_LOBBY_TIMEOUT: Duration = Duration::from_secs(120);
+// How long a full lobby waits for seed contributions; by default it starts right away,
+// folding in whatever was contributed while it filled
+const DEFAULT_SEED_CONTRIBUTION_TIMEOUT: Duration = Duration::ZERO;
+const DEFAULT_HOUSE_BOT_NAME: &str = "House";
+const DEFAULT_MOVES_API: &str = "https://xplode-moves.fly.dev/api/game";
+
+/// Where the server is deployed, from `ENVIRONMENT`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Environment {
+    #[default]
+    Development,
+    Staging,
+    Production,
+}
+
+impl_from_str_for_enum!(
+    Environment,
+    Development | "dev" | "local",
+    Staging,
+    Production | "prod"
+);
+
+/// The game server's settings, read once at startup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppConfig {
+    pub environment: Environment,
+    /// Whether new games are announced on Telegram and to the notify service
+    pub notifications_enabled: bool,
+    pub bind_addr: SocketAddr,
+    pub metrics_port: u16,
+    pub redis_url: String,
+    /// Connections served at once; anything past this is turned away with a 503
+    pub max_connections: usize,
+    /// Names this server in discovery and in `GAME_SERVERS`
+    pub server_id: String,
+    pub reconnect_grace: Duration,
+    pub lobby_timeout: Duration,
+    pub seed_contribution_timeout: Duration,
+    /// Takes the last seat of public lobbies that time out one player short, when enabled
+    pub house_bot: Option<HouseBotConfig>,
+    /// Base URL of the xplode-moves API that boards are generated by
+    pub moves_api: String,
+    pub board_limits: BoardLimits,
+    pub allowed_grids: AllowedGrids,
+    pub creation_limit: CreationLimit,
+    pub payout_policy: PayoutPolicy,
+    /// Where connections meant for another server go, unless Fly.io replays them
+    pub game_servers: Option<ServerListRouter>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            environment: Environment::default(),
+            notifications_enabled: false,
+            bind_addr: DEFAULT_GAME_BIND_ADDR.parse().unwrap(),
+            metrics_port: DEFAULT_METRICS_PORT,
+            redis_url: "redis://127.0.0.1:6379".to_string(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            server_id: "LocalServer".to_string(),
+            reconnect_grace: DEFAULT_RECONNECT_GRACE,
+            lobby_timeout: DEFAULT_LOBBY_TIMEOUT,
+            seed_contribution_timeout: DEFAULT_SEED_CONTRIBUTION_TIMEOUT,
+            house_bot: None,
+            moves_api: DEFAULT_MOVES_API.to_string(),
+            board_limits: BoardLimits::default(),
+            allowed_grids: AllowedGrids::default(),
+            creation_limit: CreationLimit::default(),
+            payout_policy: PayoutPolicy::default(),
+            game_servers: None,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Reads `ENVIRONMENT`, `NOTIFICATIONS_ENABLED` (on in production unless set),
+    /// `GAME_BIND_ADDR`, `METRICS_PORT`, `REDIS_URL` (required), `MAX_CONNECTIONS`,
+    /// `SERVER_ID` (falling back to `FLY_MACHINE_ID`)
+    /// the `*_SECS` timeouts, the `HOUSE_BOT_*` settings, `XPLODE_MOVES_API`, the board
+    /// and game creation limits, `ALLOWED_GRIDS`, the `PAYOUT_*` policy and
+    /// `GAME_SERVERS`. Timeouts that don't parse keep their defaults; anything else that
+    /// doesn't is an error.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    pub(crate) fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let default = Self::default();
+        let environment = match lookup("ENVIRONMENT") {
+            Some(value) => Environment::from_str(value.trim())
+                .map_err(|_| anyhow!("Invalid ENVIRONMENT {:?}", value))?,
+            None => default.environment,
+        };
+        let notifications_enabled = match lookup("NOTIFICATIONS_ENABLED") {
+            Some(value) => parse_flag(&value)
+                .ok_or_else(|| anyhow!("Invalid NOTIFICATIONS_ENABLED {:?}", value))?,
+            None => environment == Environment::Production,
+        };
+        let house_bot_enabled = match lookup("HOUSE_BOT_ENABLED") {
+            Some(value) => parse_flag(&value)
+                .ok_or_else(|| anyhow!("Invalid HOUSE_BOT_ENABLED {:?}", value))?,
+            None => false,
+        };
+        let house_bot = match house_bot_enabled {
+            true => Some(parse_house_bot(
+                lookup("HOUSE_BOT_USER_ID"),
+                lookup("HOUSE_BOT_NAME"),
+            )?),
+            false => None,
+        };
+        let secs = |var: &str, default: Duration| {
+            lookup(var)
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+        Ok(Self {
+            environment,
+            notifications_enabled,
+            bind_addr: parse_bind_addr(lookup("GAME_BIND_ADDR"))?,
+            metrics_port: parse_metrics_port(lookup("METRICS_PORT"))?,
+            redis_url: parse_redis_url(lookup("REDIS_URL"))?,
+            max_connections: parse_limit(
+                "MAX_CONNECTIONS",
+                lookup("MAX_CONNECTIONS"),
+                default.max_connections,
+            )?,
+            // Off Fly, SERVER_ID names this server as GAME_SERVERS does
+            server_id: lookup("SERVER_ID")
+                .or_else(|| lookup("FLY_MACHINE_ID"))
+                .unwrap_or(default.server_id),
+            reconnect_grace: secs("RECONNECT_GRACE_SECS", default.reconnect_grace),
+            lobby_timeout: secs("LOBBY_TIMEOUT_SECS", default.lobby_timeout),
+            seed_contribution_timeout: secs(
+                "SEED_CONTRIBUTION_TIMEOUT_SECS",
+                default.seed_contribution_timeout,
+            ),
+            house_bot,
+            moves_api: parse_moves_api(lookup("XPLODE_MOVES_API"))?,
+            board_limits: BoardLimits {
+                max_grid: parse_limit(
+                    "MAX_GRID",
+                    lookup("MAX_GRID"),
+                    default.board_limits.max_grid,
+                )?,
+                max_bombs: parse_limit(
+                    "MAX_BOMBS",
+                    lookup("MAX_BOMBS"),
+                    default.board_limits.max_bombs,
+                )?,
+                max_message_bytes: parse_limit(
+                    "MAX_MESSAGE_BYTES",
+                    lookup("MAX_MESSAGE_BYTES"),
+                    default.board_limits.max_message_bytes,
+                )?,
+            },
+            allowed_grids: match lookup("ALLOWED_GRIDS") {
+                Some(grids) => AllowedGrids::parse(&grids)?,
+                None => default.allowed_grids,
+            },
+            creation_limit: CreationLimit {
+                max_games: parse_limit(
+                    "MAX_GAMES_CREATED",
+                    lookup("MAX_GAMES_CREATED"),
+                    default.creation_limit.max_games,
+                )?,
+                window: Duration::from_secs(parse_limit(
+                    "GAME_CREATION_WINDOW_SECS",
+                    lookup("GAME_CREATION_WINDOW_SECS"),
+                    default.creation_limit.window.as_secs(),
+                )?),
+            },
+            payout_policy: PayoutPolicy::from_lookup(&lookup)?,
+            game_servers: lookup("GAME_SERVERS")
+                .map(|servers| ServerListRouter::parse(&servers))
+                .transpose()?,
+        })
+    }
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+// The bot settles like any player, so it needs a wallet user to play as
+fn parse_house_bot(user_id: Option<String>, name: Option<String>) -> Result<HouseBotConfig> {
+    let user_id = user_id.ok_or_else(|| anyhow!("HOUSE_BOT_ENABLED needs HOUSE_BOT_USER_ID"))?;
+    let user_id = match user_id.trim().parse() {
+        Ok(id) if id > 0 => id,
+        _ => return Err(anyhow!("Invalid HOUSE_BOT_USER_ID {:?}", user_id)),
+    };
+    let name = name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| DEFAULT_HOUSE_BOT_NAME.to_string());
+    validate_name(&name).map_err(|reason| anyhow!("Invalid HOUSE_BOT_NAME: {}", reason))?;
+    Ok(HouseBotConfig { user_id, name })
+}
+
+fn parse_redis_url(value: Option<String>) -> Result<String> {
+    let url = value.ok_or_else(|| anyhow!("REDIS_URL must be set"))?;
+    // Only parses the URL; nothing connects until the server starts
+    redis::Client::open(url.trim()).map_err(|e| anyhow!("Invalid REDIS_URL: {}", e))?;
+    Ok(url.trim().to_string())
+}
+
+fn parse_moves_api(value: Option<String>) -> Result<String> {
+    let Some(url) = value else {
+        return Ok(DEFAULT_MOVES_API.to_string());
+    };
+    match reqwest::Url::parse(url.trim()) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(url.trim().to_string()),
+        _ => Err(anyhow!("Invalid XPLODE_MOVES_API {:?}", url)),
+    }
+}
+
+// A count or size that has to be above 0
+fn parse_limit<T: FromStr + Default + PartialEq>(
+    var: &str,
+    value: Option<String>,
+    default: T,
+) -> Result<T> {
+    let Some(limit) = value else {
+        return Ok(default);
+    };
+    match limit.trim().parse() {
+        Ok(limit) if limit != T::default() => Ok(limit),
+        _ => Err(anyhow!("Invalid {} {:?}: must be above 0", var, limit)),
+    }
+}
+
+fn parse_bind_addr(value: Option<String>) -> Result<SocketAddr> {
+    let addr = value.unwrap_or_else(|| DEFAULT_GAME_BIND_ADDR.to_string());
+    addr.trim()
+        .parse()
+        .map_err(|e| anyhow!("Invalid GAME_BIND_ADDR {:?}: {}", addr, e))
+}
+
+fn parse_metrics_port(value: Option<String>) -> Result<u16> {
+    let Some(port) = value else {
+        return Ok(DEFAULT_METRICS_PORT);
+    };
+    match port.trim().parse() {
+        Ok(0) => Err(anyhow!("Invalid METRICS_PORT {:?}: must not be 0", port)),
+        Ok(port) => Ok(port),
+        Err(e) => Err(anyhow!("Invalid METRICS_PORT {:?}: {}", port, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    // Reads `vars`, with REDIS_URL pointing where the default config does unless given
+    fn lookup(vars: &[(&str, &str)]) -> Result<AppConfig> {
+        let mut vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        vars.entry("REDIS_URL".to_string())
+            .or_insert_with(|| AppConfig::default().redis_url);
+        AppConfig::from_lookup(move |key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_from_lookup() {
+        assert_eq!(lookup(&[]).unwrap(), AppConfig::default());
+
+        let config = lookup(&[
+            ("ENVIRONMENT", "Production"),
+            ("SERVER_ID", "game-1"),
+            ("FLY_MACHINE_ID", "machine-a"),
+            ("LOBBY_TIMEOUT_SECS", "60"),
+            ("RECONNECT_GRACE_SECS", "soon"),
+        ])
+        .unwrap();
+        assert_eq!(config.environment, Environment::Production);
+        assert!(config.notifications_enabled);
+        assert_eq!(config.server_id, "game-1");
+        assert_eq!(config.lobby_timeout, Duration::from_secs(60));
+        assert_eq!(config.reconnect_grace, DEFAULT_RECONNECT_GRACE);
+
+        assert_eq!(
+            lookup(&[("FLY_MACHINE_ID", "machine-a")])
+                .unwrap()
+                .server_id,
+            "machine-a"
+        );
+        assert!(lookup(&[("ENVIRONMENT", "prodution")]).is_err());
+    }
+
+    #[test]
+    fn test_notifications_follow_the_flag() {
+        // The flag wins over the environment either way
+        for environment in ["production", "PROD", "staging"] {
+            let config = lookup(&[
+                ("ENVIRONMENT", environment),
+                ("NOTIFICATIONS_ENABLED", "false"),
+            ])
+            .unwrap();
+            assert!(!config.notifications_enabled, "{}", environment);
+        }
+        assert!(
+            lookup(&[("NOTIFICATIONS_ENABLED", "true")])
+                .unwrap()
+                .notifications_enabled
+        );
+        assert!(
+            !lookup(&[("ENVIRONMENT", "staging")])
+                .unwrap()
+                .notifications_enabled
+        );
+        assert!(lookup(&[("NOTIFICATIONS_ENABLED", "maybe")]).is_err());
+    }
+
+    #[test]
+    fn test_house_bot_is_opt_in() {
+        assert_eq!(
+            lookup(&[("HOUSE_BOT_USER_ID", "7")]).unwrap().house_bot,
+            None
+        );
+        assert_eq!(
+            lookup(&[("HOUSE_BOT_ENABLED", "true"), ("HOUSE_BOT_USER_ID", "7")])
+                .unwrap()
+                .house_bot,
+            Some(HouseBotConfig {
+                user_id: 7,
+                name: "House".to_string(),
+            })
+        );
+        for user_id in [None, Some("0"), Some("house")] {
+            let mut vars = vec![("HOUSE_BOT_ENABLED", "1")];
+            vars.extend(user_id.map(|id| ("HOUSE_BOT_USER_ID", id)));
+            assert!(lookup(&vars).is_err(), "{:?}", user_id);
+        }
+        // The bot joins like any player, under the same name limit
+        assert!(lookup(&[
+            ("HOUSE_BOT_ENABLED", "1"),
+            ("HOUSE_BOT_USER_ID", "7"),
+            ("HOUSE_BOT_NAME", &"House".repeat(7)),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_game_settings_are_validated() {
+        let config = lookup(&[
+            ("XPLODE_MOVES_API", "http://localhost:3004/api/game"),
+            ("MAX_GRID", "8"),
+            ("MAX_GAMES_CREATED", "2"),
+            ("ALLOWED_GRIDS", "4,6"),
+            ("PAYOUT_RAKE_PERCENT", "5"),
+            ("GAME_SERVERS", "game-1=wss://game-1.example.com"),
+        ])
+        .unwrap();
+        assert_eq!(config.moves_api, "http://localhost:3004/api/game");
+        assert_eq!(config.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(config.board_limits.max_grid, 8);
+        assert_eq!(
+            config.board_limits.max_bombs,
+            BoardLimits::default().max_bombs
+        );
+        assert_eq!(config.creation_limit.max_games, 2);
+        assert_eq!(config.allowed_grids, AllowedGrids::parse("4,6").unwrap());
+        assert_eq!(config.payout_policy.rake_percent, 5.0);
+        assert_eq!(
+            config.game_servers,
+            Some(ServerListRouter::parse("game-1=wss://game-1.example.com").unwrap())
+        );
+
+        for (var, value) in [
+            ("REDIS_URL", "localhost:6379"),
+            ("MAX_CONNECTIONS", "0"),
+            ("XPLODE_MOVES_API", "xplode-moves.fly.dev"),
+            ("MAX_GRID", "0"),
+            ("MAX_BOMBS", "many"),
+            ("MAX_MESSAGE_BYTES", "-1"),
+            ("MAX_GAMES_CREATED", "0"),
+            ("GAME_CREATION_WINDOW_SECS", "soon"),
+            ("ALLOWED_GRIDS", "4,x"),
+            ("PAYOUT_RAKE_PERCENT", "100"),
+            ("GAME_SERVERS", "game-1"),
+        ] {
+            let err = lookup(&[(var, value)]).unwrap_err();
+            assert!(err.to_string().contains(var), "{}: {}", var, err);
+        }
+    }
+
+    #[test]
+    fn test_redis_url_is_required() {
+        let err = AppConfig::from_lookup(|_| None).unwrap_err();
+        assert!(err.to_string().contains("REDIS_URL"), "{}", err);
+        let config = lookup(&[
+            ("REDIS_URL", "rediss://cache.example.com:6380"),
+            ("MAX_CONNECTIONS", "50"),
+        ])
+        .unwrap();
+        assert_eq!(config.redis_url, "rediss://cache.example.com:6380");
+        assert_eq!(config.max_connections, 50);
+    }
+
+    #[test]
+    fn test_parse_bind_addr() {
+        assert_eq!(
+            parse_bind_addr(None).unwrap(),
+            "0.0.0.0:3000".parse().unwrap()
+        );
+        assert_eq!(
+            parse_bind_addr(Some("127.0.0.1:4000".to_string())).unwrap(),
+            "127.0.0.1:4000".parse().unwrap()
+        );
+        assert!(parse_bind_addr(Some("localhost".to_string())).is_err());
+        assert!(parse_bind_addr(Some("0.0.0.0:70000".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_parse_metrics_port() {
+        assert_eq!(parse_metrics_port(None).unwrap(), 9092);
+        assert_eq!(parse_metrics_port(Some("9100".to_string())).unwrap(), 9100);
+        assert!(parse_metrics_port(Some("0".to_string())).is_err());
+        assert!(parse_metrics_port(Some("metrics".to_string())).is_err());
+    }
+}
@@ -1,9 +1,22 @@
 use anyhow::Result;
-use redis::{AsyncCommands, Client};
+use common::utils::Currency;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client, Script};
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Instant};
+use std::{str::FromStr, sync::Arc, time::Instant};
 use tracing::{info, warn};
 
+// Persisted game snapshots are only useful for a short while after a shutdown
+const GAME_STATE_TTL_SECS: u64 = 60 * 60;
+
+// Live game servers keep refreshing their heartbeat key well within this
+const SERVER_HEARTBEAT_TTL_SECS: u64 = 30;
+
+// Sessions checked per lookup, so one full or stale session doesn't hide open ones
+const MATCHMAKING_CANDIDATES: usize = 10;
+
+// Outlasts any game, so only claims left behind by a crashed server expire
+const PLAYER_CLAIM_TTL_SECS: u64 = 60 * 60;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameSession {
     pub game_id: String,
@@ -12,6 +25,191 @@ pub struct GameSession {
     pub min_players: u32,
     pub current_players: u32,
     pub grid_size: u32,
+    // Rooms created to play with friends are joined through their link, never listed
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default = "default_currency")]
+    pub currency: Currency,
+}
+
+// Games have always been played for SOL, including those registered before sessions
+// recorded their currency
+pub(crate) fn default_currency() -> Currency {
+    Currency::SOL
+}
+
+// Fields of a `game_session:{id}` hash, in the order `parse_session` expects them
+const SESSION_FIELDS: [&str; 7] = [
+    "server_id",
+    "single_bet_size",
+    "min_players",
+    "current_players",
+    "grid_size",
+    "private",
+    "currency",
+];
+
+/// Totals across every game session known to discovery, for lobby screens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SessionStats {
+    pub players_online: u32,
+    pub active_games: u32,
+}
+
+impl SessionStats {
+    pub fn of(sessions: &[GameSession]) -> Self {
+        Self {
+            players_online: sessions.iter().map(|s| s.current_players).sum(),
+            active_games: sessions.len() as u32,
+        }
+    }
+}
+
+// What a lookup already knows about the sessions it reads, e.g. from their matchmaking
+// key, to fill in fields missing from a partially written hash
+#[derive(Debug, Clone, Copy, Default)]
+struct KnownFields {
+    currency: Option<Currency>,
+    single_bet_size: Option<f64>,
+    min_players: Option<u32>,
+    grid_size: Option<u32>,
+}
+
+// Whether nothing is left of a session's hash, i.e. it expired or was removed
+fn session_gone(values: &[Option<String>]) -> bool {
+    values.iter().all(Option::is_none)
+}
+
+// A session from its `SESSION_FIELDS`. A field missing from the hash is logged and
+// filled in from `known` or a safe default; `None` if that's impossible or a field is
+// malformed.
+fn parse_session(
+    game_id: &str,
+    values: &[Option<String>],
+    known: KnownFields,
+) -> Option<GameSession> {
+    let [server_id, single_bet_size, min_players, current_players, grid_size, private, currency] =
+        values
+    else {
+        return None;
+    };
+    if session_gone(values) {
+        return None;
+    }
+    Some(GameSession {
+        game_id: game_id.to_string(),
+        // Without it the game can't be reached
+        server_id: session_field(game_id, "server_id", server_id, None)?,
+        single_bet_size: session_field(
+            game_id,
+            "single_bet_size",
+            single_bet_size,
+            known.single_bet_size,
+        )?,
+        min_players: session_field(game_id, "min_players", min_players, known.min_players)?,
+        // Sessions are registered with their creator in them
+        current_players: session_field(game_id, "current_players", current_players, Some(1))?,
+        grid_size: session_field(game_id, "grid_size", grid_size, known.grid_size)?,
+        private: match private {
+            Some(private) => private.parse().ok()?,
+            None => false,
+        },
+        currency: match currency {
+            Some(currency) => currency.parse().ok()?,
+            None => known.currency.unwrap_or_else(default_currency),
+        },
+    })
+}
+
+// A field every session is registered with, or `fallback` if the hash lacks it
+fn session_field<T: FromStr>(
+    game_id: &str,
+    field: &str,
+    value: &Option<String>,
+    fallback: Option<T>,
+) -> Option<T> {
+    match value {
+        Some(value) => value.parse().ok(),
+        None => {
+            warn!(
+                game_id = %game_id,
+                field = %field,
+                recovered = %fallback.is_some(),
+                "Game session is missing a field"
+            );
+            fallback
+        }
+    }
+}
+
+// Bets are matched on their string form in matchmaking keys, so they're quantized
+// to a fixed number of decimals before being used anywhere
+pub(crate) const BET_SIZE_DECIMALS: i32 = 4;
+
+// A bet as it appears in matchmaking keys. Always BET_SIZE_DECIMALS decimals, so the
+// same bet gives the same key however its f64 was computed.
+fn bet_size_key(single_bet_size: f64) -> String {
+    format!("{:.*}", BET_SIZE_DECIMALS as usize, single_bet_size)
+}
+
+// The matchmaking set a session is listed in. Players are only matched with others
+// betting the same amount in the same currency, on the same board and player count.
+fn matchmaking_key(
+    currency: Currency,
+    single_bet_size: f64,
+    min_players: u32,
+    grid_size: u32,
+) -> String {
+    format!(
+        "matchmaking:{}:{}:{}:{}",
+        currency,
+        bet_size_key(single_bet_size),
+        min_players,
+        grid_size
+    )
+}
+
+// Picks up to ARGV[1] random ids from the matchmaking set KEYS[1] and reads the
+// session fields ARGV[2..] of each, so matchmaking takes one round trip and a
+// session can't be removed between being picked and being read
+const MATCHMAKING_CANDIDATES_SCRIPT: &str = r#"
+local candidates = {}
+for _, game_id in ipairs(redis.call('SRANDMEMBER', KEYS[1], ARGV[1])) do
+    local fields = redis.call('HMGET', 'game_session:' .. game_id, unpack(ARGV, 2))
+    table.insert(candidates, {game_id, fields})
+end
+return candidates
+"#;
+
+// Random members of a matchmaking set along with their `SESSION_FIELDS`
+async fn matchmaking_candidates(
+    conn: &mut MultiplexedConnection,
+    matchmaking_key: &str,
+) -> Result<Vec<(String, Vec<Option<String>>)>> {
+    Ok(Script::new(MATCHMAKING_CANDIDATES_SCRIPT)
+        .key(matchmaking_key)
+        .arg(MATCHMAKING_CANDIDATES)
+        .arg(&SESSION_FIELDS)
+        .invoke_async(conn)
+        .await?)
+}
+
+// Drops ids whose session is gone from a matchmaking set
+async fn remove_stale_members(
+    conn: &mut MultiplexedConnection,
+    matchmaking_key: &str,
+    stale: &[String],
+) -> Result<()> {
+    if stale.is_empty() {
+        return Ok(());
+    }
+    warn!(
+        matchmaking_key = %matchmaking_key,
+        stale = ?stale,
+        "Removing stale game sessions from matchmaking"
+    );
+    let _: () = conn.srem(matchmaking_key, stale).await?;
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -47,13 +245,17 @@ impl DiscoveryService {
                 ("min_players", session.min_players.to_string()),
                 ("current_players", session.current_players.to_string()),
                 ("grid_size", session.grid_size.to_string()),
+                ("private", session.private.to_string()),
+                ("currency", session.currency.to_string()),
             ],
         );
 
         // Add to matchmaking set
-        let matchmaking_key = format!(
-            "matchmaking:{}:{}:{}",
-            session.single_bet_size, session.min_players, session.grid_size
+        let matchmaking_key = matchmaking_key(
+            session.currency,
+            session.single_bet_size,
+            session.min_players,
+            session.grid_size,
         );
         pipe.sadd(matchmaking_key.clone(), session.game_id);
 
@@ -81,34 +283,11 @@ impl DiscoveryService {
         info!("Finding game session by id: {}", game_id);
         let mut conn = self.redis.get_multiplexed_async_connection().await?;
         let key = format!("game_session:{}", game_id);
-        let values: Option<Vec<String>> = conn
-            .hget(
-                &key,
-                &[
-                    "server_id",
-                    "single_bet_size",
-                    "min_players",
-                    "current_players",
-                    "grid_size",
-                ],
-            )
-            .await?;
+        let values: Vec<Option<String>> = conn.hget(&key, &SESSION_FIELDS).await?;
 
         info!("Here 1");
-        // Return None if values is None or doesn't have exactly 5 elements
-        let values = match values {
-            Some(v) if v.len() == 5 => v,
-            _ => return Ok(None),
-        };
-
-        // Parse values and create session
-        let session = GameSession {
-            game_id: game_id.to_string(),
-            server_id: values[0].clone(),
-            single_bet_size: values[1].parse()?,
-            min_players: values[2].parse()?,
-            current_players: values[3].parse()?,
-            grid_size: values[4].parse()?,
+        let Some(session) = parse_session(game_id, &values, KnownFields::default()) else {
+            return Ok(None);
         };
 
         info!("Here 2");
@@ -120,9 +299,20 @@ impl DiscoveryService {
         })
     }
 
+    // The server hosting the game, if it is still registered. Unlike
+    // find_game_session_by_id this also finds full sessions.
+    pub async fn game_server(&self, game_id: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let server_id: Option<String> = conn
+            .hget(format!("game_session:{}", game_id), "server_id")
+            .await?;
+        Ok(server_id)
+    }
+
     // Find best matching game session based on bet size and player count
     pub async fn find_game_session(
         &self,
+        currency: Currency,
         single_bet_size: f64,
         min_players: u32,
         grid_size: u32,
@@ -133,62 +323,45 @@ impl DiscoveryService {
         let conn_time = start.elapsed();
 
         // Get a random game ID from the matchmaking set
-        let matchmaking_key = format!(
-            "matchmaking:{}:{}:{}",
-            single_bet_size, min_players, grid_size
-        );
+        let matchmaking_key = matchmaking_key(currency, single_bet_size, min_players, grid_size);
 
-        let game_id: Option<String> = conn.srandmember(&matchmaking_key).await?;
-        let pipeline_time = start.elapsed();
+        // Candidates and their sessions come back together, take the first with room
+        let pipeline_start = Instant::now();
+        let candidates = matchmaking_candidates(&mut conn, &matchmaking_key).await?;
+        let pipeline_time = pipeline_start.elapsed();
 
-        // If we found a game, get its session info
         let session_fetch_start = Instant::now();
-        let result = if let Some(game_id) = game_id.as_ref() {
-            let key = format!("game_session:{}", game_id);
-
-            let values: Option<Vec<String>> = conn
-                .hget(
-                    &key,
-                    &[
-                        "server_id",
-                        "single_bet_size",
-                        "min_players",
-                        "current_players",
-                        "grid_size",
-                    ],
-                )
-                .await?;
-
-            if let Some(values) = values {
-                if values.len() == 5 {
-                    let session = GameSession {
-                        game_id: game_id.to_string(),
-                        server_id: values[0].clone(),
-                        single_bet_size: values[1].parse()?,
-                        min_players: values[2].parse()?,
-                        current_players: values[3].parse()?,
-                        grid_size: values[4].parse()?,
-                    };
-                    if session.current_players < min_players {
-                        Some(session)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        } else {
-            None
+        let known = KnownFields {
+            currency: Some(currency),
+            single_bet_size: Some(single_bet_size),
+            min_players: Some(min_players),
+            grid_size: Some(grid_size),
         };
+        let mut result = None;
+        let mut stale = Vec::new();
+        for (game_id, values) in &candidates {
+            if session_gone(values) {
+                // The session expired or was removed without leaving the set
+                stale.push(game_id.clone());
+                continue;
+            }
+            // A partial session stays in the set, only its unusable fields were lost
+            let Some(session) = parse_session(game_id, values, known) else {
+                continue;
+            };
+            if result.is_none() && session.current_players < min_players {
+                result = Some(session);
+            }
+        }
+        remove_stale_members(&mut conn, &matchmaking_key, &stale).await?;
         let session_fetch_time = session_fetch_start.elapsed();
         let total_time = start.elapsed();
 
         // Log timing information
         info!(
-            found_game = %game_id.is_some(),
+            found_game = %result.is_some(),
+            candidates = %candidates.len(),
+            currency = %currency,
             bet_size = %single_bet_size,
             min_players = %min_players,
             grid_size = %grid_size,
@@ -209,6 +382,98 @@ impl DiscoveryService {
         Ok(result)
     }
 
+    // Removes ids whose session hash has expired from every matchmaking set, so lookups
+    // don't keep drawing ghost games. Returns how many were removed.
+    pub async fn sweep_matchmaking(&self) -> Result<usize> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let mut matchmaking_keys: Vec<String> = Vec::new();
+        {
+            let mut keys = conn.scan_match::<_, String>("matchmaking:*").await?;
+            while let Some(key) = keys.next_item().await {
+                matchmaking_keys.push(key);
+            }
+        }
+
+        let mut removed = 0;
+        for matchmaking_key in matchmaking_keys {
+            let game_ids: Vec<String> = conn.smembers(&matchmaking_key).await?;
+            if game_ids.is_empty() {
+                continue;
+            }
+            let mut pipe = redis::pipe();
+            for game_id in &game_ids {
+                pipe.exists(format!("game_session:{}", game_id));
+            }
+            let live: Vec<bool> = pipe.query_async(&mut conn).await?;
+            let stale: Vec<String> = game_ids
+                .into_iter()
+                .zip(live)
+                .filter(|(_, live)| !live)
+                .map(|(game_id, _)| game_id)
+                .collect();
+            remove_stale_members(&mut conn, &matchmaking_key, &stale).await?;
+            removed += stale.len();
+        }
+        Ok(removed)
+    }
+
+    // Every live game session across all servers
+    pub async fn list_sessions(&self) -> Result<Vec<GameSession>> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let mut keys: Vec<String> = Vec::new();
+        {
+            let mut iter = conn.scan_match::<_, String>("game_session:*").await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.hget(key, &SESSION_FIELDS);
+        }
+        let values: Vec<Vec<Option<String>>> = pipe.query_async(&mut conn).await?;
+        // Sessions that expired between the scan and the fetch are skipped
+        Ok(keys
+            .iter()
+            .zip(values)
+            .filter_map(|(key, values)| {
+                parse_session(
+                    key.strip_prefix("game_session:")?,
+                    &values,
+                    KnownFields::default(),
+                )
+            })
+            .collect())
+    }
+
+    // Public sessions in `currency` on a `grid_size` board that still have room, for a
+    // lobby browser
+    pub async fn list_open_sessions(
+        &self,
+        currency: Currency,
+        grid_size: u32,
+    ) -> Result<Vec<GameSession>> {
+        Ok(self
+            .list_sessions()
+            .await?
+            .into_iter()
+            .filter(|session| {
+                !session.private
+                    && session.currency == currency
+                    && session.grid_size == grid_size
+                    && session.current_players < session.min_players
+            })
+            .collect())
+    }
+
+    pub async fn stats(&self) -> Result<SessionStats> {
+        Ok(SessionStats::of(&self.list_sessions().await?))
+    }
+
     // Update player count for a game session
     pub async fn update_player_count(&self, game_id: &str, current_players: u32) -> Result<()> {
         let mut conn = self.redis.get_multiplexed_async_connection().await?;
@@ -227,25 +492,32 @@ impl DiscoveryService {
 
         // Get session info first
         let key = format!("game_session:{}", game_id);
-        let values: Option<Vec<String>> = conn
+        let values: Vec<Option<String>> = conn
             .hget(
                 &key,
-                &[
-                    "server_id",
-                    "single_bet_size",
-                    "min_players",
-                    "current_players",
-                    "grid_size",
-                ],
+                &["single_bet_size", "min_players", "grid_size", "currency"],
             )
             .await?;
 
-        if let Some(values) = values {
-            if values.len() == 5 {
+        // A partial session is still deleted, it just can't be found in a matchmaking set
+        if let [Some(single_bet_size), Some(min_players), Some(grid_size), currency] =
+            values.as_slice()
+        {
+            let currency = match currency {
+                Some(currency) => currency.parse().ok(),
+                None => Some(default_currency()),
+            };
+            if let (Ok(single_bet_size), Ok(min_players), Ok(grid_size), Some(currency)) = (
+                single_bet_size.parse(),
+                min_players.parse(),
+                grid_size.parse(),
+                currency,
+            ) {
                 // Remove from matchmaking set
-                let matchmaking_key =
-                    format!("matchmaking:{}:{}:{}", values[1], values[2], values[4]);
-                pipe.srem(matchmaking_key, game_id);
+                pipe.srem(
+                    matchmaking_key(currency, single_bet_size, min_players, grid_size),
+                    game_id,
+                );
             }
         }
 
@@ -256,4 +528,461 @@ impl DiscoveryService {
         let _: () = pipe.query_async(&mut conn).await?;
         Ok(())
     }
+
+    // Claims the player for a game across all servers. Fails if they already hold a
+    // claim for another game; claiming the same game again succeeds.
+    pub async fn claim_player(&self, player_id: &str, game_id: &str) -> Result<bool> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("active_player:{}", player_id);
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(game_id)
+            .arg("NX")
+            .arg("EX")
+            .arg(PLAYER_CLAIM_TTL_SECS)
+            .query_async(&mut conn)
+            .await?;
+        if claimed.is_some() {
+            return Ok(true);
+        }
+        let current: Option<String> = conn.get(&key).await?;
+        Ok(current.as_deref() == Some(game_id))
+    }
+
+    // Counts a game the player just created, returning how many they have created in the
+    // window that their first counted creation opened. The window's key expires with it.
+    pub async fn record_game_creation(&self, player_id: &str, window_secs: u64) -> Result<u64> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("game_creations:{}", player_id);
+        let (created,): (u64,) = redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(&key)
+            .arg(0)
+            .arg("NX")
+            .arg("EX")
+            .arg(window_secs)
+            .ignore()
+            .incr(&key, 1)
+            .query_async(&mut conn)
+            .await?;
+        Ok(created)
+    }
+
+    // The game the player has claimed, if any
+    pub async fn player_game(&self, player_id: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let game_id: Option<String> = conn.get(format!("active_player:{}", player_id)).await?;
+        Ok(game_id)
+    }
+
+    // Frees players once they are out of their game
+    pub async fn release_players(&self, player_ids: &[String]) -> Result<()> {
+        if player_ids.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let keys: Vec<String> = player_ids
+            .iter()
+            .map(|player_id| format!("active_player:{}", player_id))
+            .collect();
+        let _: () = conn.del(keys).await?;
+        Ok(())
+    }
+
+    // Snapshot an unfinished game so it outlives this server, e.g. across a deploy
+    pub async fn persist_game_state(&self, game_id: &str, state: &str) -> Result<()> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("game_state:{}", game_id);
+        let _: () = conn.set_ex(&key, state, GAME_STATE_TTL_SECS).await?;
+        Ok(())
+    }
+
+    // Mark this server as live so other servers may redirect players to it
+    pub async fn heartbeat_server(&self, server_id: &str) -> Result<()> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("live_server:{}", server_id);
+        let _: () = conn.set_ex(&key, 1, SERVER_HEARTBEAT_TTL_SECS).await?;
+        Ok(())
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let _: String = redis::cmd("PING").query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    pub async fn unregister_server(&self, server_id: &str) -> Result<()> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let _: () = conn.del(format!("live_server:{}", server_id)).await?;
+        Ok(())
+    }
+
+    pub async fn is_server_live(&self, server_id: &str) -> Result<bool> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let live: bool = conn.exists(format!("live_server:{}", server_id)).await?;
+        Ok(live)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn session(single_bet_size: f64, current_players: u32) -> GameSession {
+        GameSession {
+            game_id: Uuid::new_v4().to_string(),
+            server_id: "test-server".to_string(),
+            single_bet_size,
+            min_players: 2,
+            current_players,
+            grid_size: 4,
+            private: false,
+            currency: Currency::SOL,
+        }
+    }
+
+    #[test]
+    fn test_stats_sum_player_counts() {
+        let stats = SessionStats::of(&[session(0.1, 1), session(0.2, 2)]);
+        assert_eq!(
+            stats,
+            SessionStats {
+                players_online: 3,
+                active_games: 2,
+            }
+        );
+        assert_eq!(SessionStats::of(&[]), SessionStats::default());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_candidates_match_separate_reads() -> Result<()> {
+        dotenv::dotenv().ok();
+        let discovery = DiscoveryService::new(Client::open(env::var("REDIS_URL")?)?);
+        let single_bet_size = 0.01 + f64::from(rand::random::<u16>()) / 1e4;
+        let (open, full) = (session(single_bet_size, 1), session(single_bet_size, 2));
+        discovery.register_game_session(open.clone()).await?;
+        discovery.register_game_session(full.clone()).await?;
+        let matchmaking_key = matchmaking_key(Currency::SOL, single_bet_size, 2, 4);
+        let mut conn = discovery.redis.get_multiplexed_async_connection().await?;
+        let _: () = conn.sadd(&matchmaking_key, "expired-session").await?;
+
+        // Fewer members than MATCHMAKING_CANDIDATES, so both reads draw all of them
+        let mut combined = matchmaking_candidates(&mut conn, &matchmaking_key).await?;
+        let game_ids: Vec<String> = conn
+            .srandmember_multiple(&matchmaking_key, MATCHMAKING_CANDIDATES)
+            .await?;
+        let mut separate = Vec::new();
+        for game_id in game_ids {
+            let values: Vec<Option<String>> = conn
+                .hget(format!("game_session:{}", game_id), &SESSION_FIELDS)
+                .await?;
+            separate.push((game_id, values));
+        }
+        combined.sort();
+        separate.sort();
+        assert_eq!(combined.len(), 3);
+        assert_eq!(combined, separate);
+
+        let found = discovery
+            .find_game_session(Currency::SOL, single_bet_size, 2, 4)
+            .await?
+            .unwrap();
+        assert_eq!(found.game_id, open.game_id);
+
+        discovery.remove_game_session(&open.game_id).await?;
+        discovery.remove_game_session(&full.game_id).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_list_sessions_includes_registered_sessions() -> Result<()> {
+        dotenv::dotenv().ok();
+        let discovery = DiscoveryService::new(Client::open(env::var("REDIS_URL")?)?);
+        let single_bet_size = 0.01 + f64::from(rand::random::<u16>()) / 1e4;
+        let (lobby, running) = (session(single_bet_size, 1), session(single_bet_size, 2));
+        discovery.register_game_session(lobby.clone()).await?;
+        discovery.register_game_session(running.clone()).await?;
+
+        let ours: Vec<GameSession> = discovery
+            .list_sessions()
+            .await?
+            .into_iter()
+            .filter(|s| s.game_id == lobby.game_id || s.game_id == running.game_id)
+            .collect();
+        assert_eq!(
+            SessionStats::of(&ours),
+            SessionStats {
+                players_online: 3,
+                active_games: 2,
+            }
+        );
+
+        discovery.remove_game_session(&lobby.game_id).await?;
+        discovery.remove_game_session(&running.game_id).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sessions_without_new_fields_are_public_sol() {
+        let values = ["server", "0.1", "2", "1", "4"]
+            .map(|value| Some(value.to_string()))
+            .into_iter()
+            .chain([None, None])
+            .collect::<Vec<_>>();
+        let session = parse_session("game", &values, KnownFields::default()).unwrap();
+        assert!(!session.private);
+        assert_eq!(session.currency, Currency::SOL);
+
+        let mut values = values;
+        values[5] = Some("true".to_string());
+        values[6] = Some("MON".to_string());
+        let session = parse_session("game", &values, KnownFields::default()).unwrap();
+        assert!(session.private);
+        assert_eq!(session.currency, Currency::MON);
+    }
+
+    #[test]
+    fn test_matchmaking_key_includes_currency() {
+        assert_eq!(
+            matchmaking_key(Currency::SOL, 0.1, 2, 4),
+            "matchmaking:SOL:0.1000:2:4"
+        );
+        // A bet computed with rounding error shares the key of the one typed in
+        assert_eq!(
+            matchmaking_key(Currency::SOL, 0.3 - 0.2, 2, 4),
+            matchmaking_key(Currency::SOL, 0.1, 2, 4)
+        );
+        assert_ne!(
+            matchmaking_key(Currency::SOL, 0.1, 2, 4),
+            matchmaking_key(Currency::MON, 0.1, 2, 4)
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_session_is_found_by_a_differently_computed_bet() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let discovery = DiscoveryService::new(redis.clone());
+        // A bucket of its own, on a grid nobody else uses
+        let grid_size = 100 + u32::from(rand::random::<u16>());
+        let registered = GameSession {
+            single_bet_size: 0.1 + 0.2,
+            grid_size,
+            ..session(0.0, 1)
+        };
+        discovery.register_game_session(registered.clone()).await?;
+
+        let mut conn = redis.get_multiplexed_async_connection().await?;
+        let members: Vec<String> = conn
+            .smembers(matchmaking_key(Currency::SOL, 0.3, 2, grid_size))
+            .await?;
+        assert_eq!(members, vec![registered.game_id.clone()]);
+        let found = discovery
+            .find_game_session(Currency::SOL, 0.3, 2, grid_size)
+            .await?;
+        assert_eq!(found.map(|s| s.game_id), Some(registered.game_id.clone()));
+
+        discovery.remove_game_session(&registered.game_id).await?;
+        assert!(discovery
+            .find_game_session(Currency::SOL, 0.3, 2, grid_size)
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_sessions() {
+        let values = ["server", "0.1", "2", "1", "4", "false", "SOL"]
+            .map(|value| Some(value.to_string()))
+            .to_vec();
+        let without = |field: &str| {
+            let mut values = values.clone();
+            values[SESSION_FIELDS.iter().position(|f| *f == field).unwrap()] = None;
+            values
+        };
+
+        let session =
+            parse_session("game", &without("current_players"), KnownFields::default()).unwrap();
+        assert_eq!(session.current_players, 1);
+        assert_eq!(session.server_id, "server");
+
+        // The lookup's own key fills in what it matched on
+        let known = KnownFields {
+            currency: Some(Currency::SOL),
+            single_bet_size: Some(0.1),
+            min_players: Some(2),
+            grid_size: Some(4),
+        };
+        assert!(parse_session("game", &without("grid_size"), KnownFields::default()).is_none());
+        assert_eq!(
+            parse_session("game", &without("grid_size"), known)
+                .unwrap()
+                .grid_size,
+            4
+        );
+
+        assert!(parse_session("game", &without("server_id"), known).is_none());
+        assert!(parse_session("game", &vec![None; 7], known).is_none());
+        let mut malformed = values.clone();
+        malformed[3] = Some("many".to_string());
+        assert!(parse_session("game", &malformed, known).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_session_missing_player_count_stays_findable() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let discovery = DiscoveryService::new(redis.clone());
+        let single_bet_size = 0.01 + f64::from(rand::random::<u16>()) / 1e4;
+        let open = session(single_bet_size, 1);
+        discovery.register_game_session(open.clone()).await?;
+        let mut conn = redis.get_multiplexed_async_connection().await?;
+        let _: () = conn
+            .hdel(format!("game_session:{}", open.game_id), "current_players")
+            .await?;
+
+        let found = discovery
+            .find_game_session(Currency::SOL, single_bet_size, 2, 4)
+            .await?
+            .unwrap();
+        assert_eq!(found.game_id, open.game_id);
+        assert_eq!(found.current_players, 1);
+        let members: Vec<String> = conn
+            .smembers(matchmaking_key(Currency::SOL, single_bet_size, 2, 4))
+            .await?;
+        assert!(members.contains(&open.game_id));
+        assert!(discovery
+            .find_game_session_by_id(&open.game_id)
+            .await?
+            .is_some());
+
+        discovery.remove_game_session(&open.game_id).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_list_open_sessions_only_lists_joinable_public_games() -> Result<()> {
+        dotenv::dotenv().ok();
+        let discovery = DiscoveryService::new(Client::open(env::var("REDIS_URL")?)?);
+        // A grid nobody else uses keeps other tests' sessions out of the listing
+        let grid_size = 100 + u32::from(rand::random::<u16>());
+        let on_grid = |current_players| GameSession {
+            grid_size,
+            ..session(0.1, current_players)
+        };
+
+        let open = on_grid(1);
+        let full = on_grid(2);
+        let private = GameSession {
+            private: true,
+            ..on_grid(1)
+        };
+        let other_currency = GameSession {
+            currency: Currency::MON,
+            ..on_grid(1)
+        };
+        let other_grid = session(0.1, 1);
+        let sessions = [&open, &full, &private, &other_currency, &other_grid];
+        for session in sessions {
+            discovery.register_game_session(session.clone()).await?;
+        }
+
+        let listed = discovery
+            .list_open_sessions(Currency::SOL, grid_size)
+            .await?;
+        let listed: Vec<&str> = listed.iter().map(|s| s.game_id.as_str()).collect();
+        assert_eq!(listed, vec![open.game_id.as_str()]);
+
+        for session in sessions {
+            discovery.remove_game_session(&session.game_id).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_sweep_removes_expired_sessions() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let discovery = DiscoveryService::new(redis.clone());
+        let single_bet_size = 0.01 + f64::from(rand::random::<u16>()) / 1e4;
+        let matchmaking_key = matchmaking_key(Currency::SOL, single_bet_size, 2, 4);
+
+        let expired = session(single_bet_size, 1);
+        let live = session(single_bet_size, 1);
+        discovery.register_game_session(expired.clone()).await?;
+        discovery.register_game_session(live.clone()).await?;
+        // What the session TTL does to an abandoned lobby
+        let mut conn = redis.get_multiplexed_async_connection().await?;
+        let _: () = conn
+            .del(format!("game_session:{}", expired.game_id))
+            .await?;
+
+        assert!(discovery.sweep_matchmaking().await? >= 1);
+        let members: Vec<String> = conn.smembers(&matchmaking_key).await?;
+        assert_eq!(members, vec![live.game_id.clone()]);
+        let found = discovery
+            .find_game_session(Currency::SOL, single_bet_size, 2, 4)
+            .await?;
+        assert_eq!(
+            found.map(|session| session.game_id),
+            Some(live.game_id.clone())
+        );
+
+        discovery.remove_game_session(&live.game_id).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_find_game_session_skips_full_and_stale_sessions() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let discovery = DiscoveryService::new(redis.clone());
+        // A bet nobody else uses gives the test its own matchmaking bucket
+        let single_bet_size = 0.01 + f64::from(rand::random::<u16>()) / 1e4;
+        let matchmaking_key = matchmaking_key(Currency::SOL, single_bet_size, 2, 4);
+
+        let full = session(single_bet_size, 2);
+        let open = session(single_bet_size, 1);
+        discovery.register_game_session(full.clone()).await?;
+        discovery.register_game_session(open.clone()).await?;
+        let mut conn = redis.get_multiplexed_async_connection().await?;
+        let _: () = conn.sadd(&matchmaking_key, "stale-game").await?;
+
+        for _ in 0..10 {
+            let found = discovery
+                .find_game_session(Currency::SOL, single_bet_size, 2, 4)
+                .await?;
+            assert_eq!(
+                found.map(|session| session.game_id),
+                Some(open.game_id.clone())
+            );
+        }
+        let members: Vec<String> = conn.smembers(&matchmaking_key).await?;
+        assert!(
+            !members.contains(&"stale-game".to_string()),
+            "{:?}",
+            members
+        );
+        assert!(members.contains(&full.game_id));
+
+        discovery.remove_game_session(&full.game_id).await?;
+        discovery.remove_game_session(&open.game_id).await?;
+        assert!(discovery
+            .find_game_session(Currency::SOL, single_bet_size, 2, 4)
+            .await?
+            .is_none());
+        Ok(())
+    }
 }
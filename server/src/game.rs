@@ -1,6 +1,8 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use common::{
-    db::{self, establish_connection},
+    db::{self, establish_connection, FinishedGame},
+    health::{self, HealthReport},
+    payout::{GameResult, PayoutPolicy},
     telegram::send_telegram_message,
     utils::Currency,
 };
@@ -13,141 +15,389 @@ use futures_util::{
 use http::HeaderValue;
 use redis::Client;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, env, sync::Arc};
+use sqlx::{Pool, Postgres};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
     io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
     sync::{
         broadcast::{self},
-        mpsc, RwLock,
+        mpsc, OwnedSemaphorePermit, RwLock, Semaphore,
     },
 };
-use tokio_websockets::{Message, ServerBuilder, WebSocketStream};
-use tracing::{error, info};
+use tokio_websockets::{Limits, Message, ServerBuilder, WebSocketStream};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
 
 use uuid::Uuid;
 
 use crate::{
-    board::Board,
-    discovery::{DiscoveryService, GameSession},
+    board::{Board, BombLayout},
+    config::AppConfig,
+    discovery::{default_currency, DiscoveryService, GameSession, SessionStats, BET_SIZE_DECIMALS},
+    house_bot::HouseBot,
+    metrics,
     player::Player,
+    protocol::{BlockchainUpdateType, ErrorCode, GameMessage, GameState, ProtocolError},
+    router::{router_for, ServerRouter},
+    seed_gen::{game_seed, seed_hash},
     xplode_moves::XplodeMovesClient,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum GameState {
-    WAITING {
-        game_id: String,
-        creator: Player,
-        board: Board,
-        single_bet_size: f64,
-        min_players: u32,
-        players: Vec<Player>,
-    },
-    RUNNING {
-        game_id: String,
-        players: Vec<Player>,
-        board: Board,
-        turn_idx: usize,
-        single_bet_size: f64,
-        locks: Option<Vec<(usize, usize)>>,
-    },
-    FINISHED {
-        game_id: String,
-        loser_idx: usize,
-        board: Board,
-        players: Vec<Player>,
-        single_bet_size: f64,
-    },
-    REMATCH {
-        game_id: String,
-        players: Vec<Player>,
-        board: Board,
-        single_bet_size: f64,
-        accepted: Vec<usize>,
-    },
-    // During the start, user doesn't make a move for some predefined time
-    ABORTED {
-        game_id: String,
-    },
-    RematchRejected {
-        game_id: String,
-    },
+fn game_not_running() -> ProtocolError {
+    ProtocolError::new(ErrorCode::InvalidGameState, "Game is not running")
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum BlockchainUpdateType {
-    GameInitialized,
-    MoveRecorded,
-    GameCommitted,
+impl GameState {
+    // Locks and turn changes may only come from the player whose turn it is
+    fn check_turn(&self, player_id: &str) -> Result<(), ProtocolError> {
+        match self {
+            GameState::RUNNING {
+                players, turn_idx, ..
+            } => {
+                if players.get(*turn_idx).is_some_and(|p| p.id == player_id) {
+                    Ok(())
+                } else {
+                    Err(ProtocolError::new(
+                        ErrorCode::NotYourTurn,
+                        "It is not your turn",
+                    ))
+                }
+            }
+            _ => Err(game_not_running()),
+        }
+    }
+
+    // Records a lock for the current turn, ignoring duplicates; rejected once the
+    // turn already holds MAX_LOCKS distinct cells
+    fn add_lock(&mut self, x: usize, y: usize) -> Result<(), ProtocolError> {
+        let GameState::RUNNING { locks, .. } = self else {
+            return Err(game_not_running());
+        };
+        let locks = locks.get_or_insert_with(Vec::new);
+        if locks.contains(&(x, y)) {
+            return Ok(());
+        }
+        if locks.len() >= MAX_LOCKS {
+            return Err(ProtocolError::new(
+                ErrorCode::InvalidMove,
+                format!("At most {} cells can be locked per turn", MAX_LOCKS),
+            ));
+        }
+        locks.push((x, y));
+        Ok(())
+    }
+
+    fn toggle_flag(&mut self, x: usize, y: usize) -> Result<bool, ProtocolError> {
+        let GameState::RUNNING { board, .. } = self else {
+            return Err(game_not_running());
+        };
+        board
+            .toggle_flag(x, y)
+            .map_err(|reason| ProtocolError::new(ErrorCode::InvalidMove, reason))
+    }
+
+    // Hands the turn to the next player, dropping the previous player's locks
+    fn advance_turn(&mut self) {
+        if let GameState::RUNNING {
+            turn_idx,
+            players,
+            locks,
+            ..
+        } = self
+        {
+            *turn_idx = (*turn_idx + 1) % players.len();
+            *locks = None;
+        }
+    }
+
+    // Games that still have players and money in play
+    fn is_active(&self) -> bool {
+        matches!(
+            self,
+            GameState::WAITING { .. } | GameState::RUNNING { .. } | GameState::REMATCH { .. }
+        )
+    }
+
+    // Records a seated player's nonce for the board's seed. Each player contributes
+    // once, so nobody can redraw the board after seeing the others' nonces.
+    fn add_seed_contribution(&mut self, player_id: &str, nonce: u64) -> Result<(), ProtocolError> {
+        let GameState::WAITING {
+            players,
+            seed_contributions,
+            ..
+        } = self
+        else {
+            return Err(ProtocolError::new(
+                ErrorCode::InvalidGameState,
+                "The board's seed can only be contributed to before the game starts",
+            ));
+        };
+        if !players.iter().any(|p| p.id == player_id) {
+            return Err(ProtocolError::new(
+                ErrorCode::InvalidRequest,
+                "Only players in the game can contribute to its seed",
+            ));
+        }
+        match seed_contributions.entry(player_id.to_string()) {
+            Entry::Occupied(_) => Err(ProtocolError::new(
+                ErrorCode::InvalidRequest,
+                "You already contributed to this game's seed",
+            )),
+            Entry::Vacant(entry) => {
+                entry.insert(nonce);
+                Ok(())
+            }
+        }
+    }
+
+    // A lobby with every seat taken
+    fn is_full(&self) -> bool {
+        matches!(
+            self,
+            GameState::WAITING { players, min_players, .. } if players.len() >= *min_players as usize
+        )
+    }
+
+    // Whether every seated player has contributed to the seed
+    fn seed_complete(&self) -> bool {
+        match self {
+            GameState::WAITING {
+                players,
+                seed_contributions,
+                ..
+            } => players
+                .iter()
+                .all(|p| seed_contributions.contains_key(&p.id)),
+            _ => false,
+        }
+    }
+
+    // Turns a lobby into a running game. The board is redrawn from the server's seed
+    // and the players' contributions, or kept as drawn when nobody contributed.
+    fn start(self) -> GameState {
+        match self {
+            GameState::WAITING {
+                game_id,
+                board,
+                single_bet_size,
+                players,
+                lives,
+                currency,
+                seed_contributions,
+                ..
+            } => {
+                let board = if seed_contributions.is_empty() {
+                    board
+                } else {
+                    let seed = game_seed(board.seed, &seed_contributions);
+                    Board::new(
+                        board.dimension(),
+                        board.bomb_count(),
+                        board.layout,
+                        Some(seed),
+                    )
+                };
+                GameState::RUNNING {
+                    game_id,
+                    lives_left: vec![lives; players.len()],
+                    players,
+                    board,
+                    turn_idx: 0,
+                    single_bet_size,
+                    locks: None,
+                    lives,
+                    currency,
+                }
+            }
+            state => state,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum GameMessage {
-    Play {
-        player_id: String,
-        name: String,
-        single_bet_size: f64,
-        min_players: u32,
-        bombs: u32,
-        grid: u32,
-        is_creating_room: bool,
-    },
-    Join {
-        game_id: String,
-        player_id: String,
-        name: String,
-    },
-    MakeMove {
-        game_id: String,
-        x: usize,
-        y: usize,
-    },
-    Lock {
-        x: usize,
-        y: usize,
-        game_id: String,
-    },
-    LockComplete {
-        game_id: String,
-    },
-    Stop {
-        game_id: String,
-        abort: bool,
-    },
-    Ping {
-        game_id: Option<String>,
-        player_id: Option<String>,
-    },
-    GameUpdate(GameState),
-    Error(String),
-    RedirectToServer {
-        game_id: String,
-        machine_id: String,
-    },
-    Rematch {
-        game_id: String,
-        player_id: String,
-    },
-    RematchRequest {
-        game_id: String,
-        requester_id: String,
-    },
-    RematchResponse {
-        game_id: String,
-        player_id: String,
-        want_rematch: bool,
-    },
-    BlockchainUpdate {
-        game_id: String,
-        update_type: BlockchainUpdateType,
-        transaction_hash: String,
-    },
-    Gif {
-        game_id: String,
-        player_id: String,
-        gif_id: usize,
-    },
+const SERVER_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often dead sessions are swept out of matchmaking
+const MATCHMAKING_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Connections served at once; anything past this is turned away with a 503
+pub(crate) const DEFAULT_MAX_CONNECTIONS: usize = 1000;
+
+const CONNECTION_LIMIT_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Largest board a Play may ask for, unless MAX_GRID and MAX_BOMBS say otherwise
+const DEFAULT_MAX_GRID: u32 = 20;
+const DEFAULT_MAX_BOMBS: u32 = 100;
+// Largest message sent to a client, unless MAX_MESSAGE_BYTES says otherwise. Every
+// update carries the full board, so this also caps the board size.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+// Games one player may create per window, unless MAX_GAMES_CREATED and
+// GAME_CREATION_WINDOW_SECS say otherwise
+const DEFAULT_MAX_GAMES_CREATED: u64 = 5;
+const DEFAULT_GAME_CREATION_WINDOW: Duration = Duration::from_secs(60);
+
+// Every created game registers a session and sends notifications, so one player
+// can't spam lobbies
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CreationLimit {
+    pub(crate) max_games: u64,
+    pub(crate) window: Duration,
+}
+
+impl Default for CreationLimit {
+    fn default() -> Self {
+        Self {
+            max_games: DEFAULT_MAX_GAMES_CREATED,
+            window: DEFAULT_GAME_CREATION_WINDOW,
+        }
+    }
+}
+
+// Caps board sizes so a single Play can't allocate a huge grid or bomb search, or
+// produce updates too large for clients and proxies to accept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoardLimits {
+    pub(crate) max_grid: u32,
+    pub(crate) max_bombs: u32,
+    pub(crate) max_message_bytes: usize,
+}
+
+impl Default for BoardLimits {
+    fn default() -> Self {
+        Self {
+            max_grid: DEFAULT_MAX_GRID,
+            max_bombs: DEFAULT_MAX_BOMBS,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        }
+    }
+}
+
+impl BoardLimits {
+    // A board needs a bomb to lose on and a safe cell to start with
+    fn validate(&self, grid: u32, bombs: u32) -> Result<(), String> {
+        if grid < 2 || grid > self.max_grid {
+            return Err(format!("Grid must be between 2 and {}", self.max_grid));
+        }
+        if bombs == 0 || bombs > self.max_bombs {
+            return Err(format!("Bombs must be between 1 and {}", self.max_bombs));
+        }
+        if bombs >= grid * grid {
+            return Err(format!(
+                "{} bombs leave no safe cell on a {}x{} grid",
+                bombs, grid, grid
+            ));
+        }
+        let update_bytes = full_board_update_bytes(grid, bombs);
+        if update_bytes > self.max_message_bytes {
+            return Err(format!(
+                "Updates of a {}x{} grid with {} bombs would be {} bytes, over the {} byte limit",
+                grid, grid, bombs, update_bytes, self.max_message_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Grid sizes a Play may ask for, when ALLOWED_GRIDS lists them, e.g. "3,4,5,6,8". Every size
+// is its own matchmaking pool, so a short list keeps each pool full. Without a list any size
+// BoardLimits accepts is allowed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AllowedGrids(Option<BTreeSet<u32>>);
+
+impl AllowedGrids {
+    // Blank entries are skipped, so a blank list allows every size
+    pub(crate) fn parse(grids: &str) -> Result<Self> {
+        let grids = grids
+            .split(',')
+            .map(str::trim)
+            .filter(|grid| !grid.is_empty())
+            .map(|grid| match grid.parse() {
+                Ok(size) if size >= 2 => Ok(size),
+                _ => Err(anyhow!("Invalid ALLOWED_GRIDS entry {:?}", grid)),
+            })
+            .collect::<Result<BTreeSet<u32>>>()?;
+        Ok(Self(Some(grids).filter(|grids| !grids.is_empty())))
+    }
+
+    fn validate(&self, grid: u32) -> Result<(), String> {
+        match &self.0 {
+            Some(grids) if !grids.contains(&grid) => Err(format!(
+                "Grid must be one of {}",
+                grids
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Serialized size of the largest update a game on this board can send: a full table of
+// players with the longest ids and names, every lock taken
+fn full_board_update_bytes(grid: u32, bombs: u32) -> usize {
+    // Control characters are escaped as \u0000, the longest a character serializes to
+    let player = Player::new(i32::MIN.to_string(), "\0".repeat(MAX_NAME_CHARS));
+    let last_cell = (grid as usize).saturating_sub(1);
+    let update = GameMessage::GameUpdate(GameState::RUNNING {
+        game_id: Uuid::nil().to_string(),
+        players: vec![player; MAX_PLAYERS as usize],
+        board: Board::widest(grid as usize, bombs as usize),
+        turn_idx: MAX_PLAYERS as usize,
+        single_bet_size: f64::MAX,
+        locks: Some(vec![(last_cell, last_cell); MAX_LOCKS]),
+        lives: MAX_LIVES,
+        lives_left: vec![MAX_LIVES; MAX_PLAYERS as usize],
+        currency: default_currency(),
+    });
+    serde_json::to_vec(&update).map_or(usize::MAX, |bytes| bytes.len())
+}
+
+// Gives the forwarding tasks a moment to flush the draining notice before exiting
+const DRAIN_FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+// Every log line of a connection carries its id. `game_id` and `player_id` are filled in
+// from the messages as they arrive, so they also cover messages like MakeMove that don't
+// name the player.
+fn connection_span() -> Span {
+    info_span!(
+        "connection",
+        connection_id = %Uuid::new_v4(),
+        game_id = field::Empty,
+        player_id = field::Empty,
+    )
+}
+
+// The ids last recorded on a connection span. The fmt subscriber appends every
+// `record` call, so a field is only recorded again when it changes.
+#[derive(Default)]
+struct SpanIds {
+    game_id: Option<String>,
+    player_id: Option<String>,
+}
+
+impl SpanIds {
+    fn record(&mut self, span: &Span, message: &GameMessage) {
+        if let Some(game_id) = message.game_id() {
+            if self.game_id.as_deref() != Some(game_id) {
+                span.record("game_id", field::display(game_id));
+                self.game_id = Some(game_id.to_string());
+            }
+        }
+        if let Some(player_id) = message.player_id() {
+            if self.player_id.as_deref() != Some(player_id) {
+                span.record("player_id", field::display(player_id));
+                self.player_id = Some(player_id.to_string());
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -159,6 +409,146 @@ struct PlayRequest {
     bombs: u32,
     grid: u32,
     is_creating_room: bool,
+    layout: BombLayout,
+    lives: u32,
+    currency: Currency,
+}
+
+const MAX_PLAYERS: u32 = 10;
+const MAX_LOCKS: usize = 5;
+const MAX_LIVES: u32 = 5;
+const MAX_NAME_CHARS: usize = 32;
+
+const ALREADY_IN_GAME: &str = "You are already in a game";
+const INVALID_RESUME_TOKEN: &str = "Invalid resume token";
+const NOT_IN_GAME: &str = "You don't hold a seat in this game";
+const MESSAGE_TOO_LARGE: &str = "Game update too large to send";
+const GAME_NOT_FOUND: &str = "Game not found on this server";
+const TOO_MANY_GAMES_CREATED: &str = "You are creating games too quickly, try again shortly";
+
+// Settlement splits the loser's bet between the others, so a game needs at least two players
+fn validate_min_players(min_players: u32) -> Result<(), String> {
+    if min_players < 2 {
+        return Err("A game needs at least 2 players".to_string());
+    }
+    if min_players > MAX_PLAYERS {
+        return Err(format!("A game can have at most {} players", MAX_PLAYERS));
+    }
+    Ok(())
+}
+
+fn validate_lives(lives: u32) -> Result<(), String> {
+    if lives == 0 || lives > MAX_LIVES {
+        return Err(format!("Lives must be between 1 and {}", MAX_LIVES));
+    }
+    Ok(())
+}
+
+// Every user has a wallet in these, so a game in one can always be settled
+const GAME_CURRENCIES: [Currency; 2] = [Currency::SOL, Currency::MON];
+
+fn validate_currency(currency: Currency) -> Result<(), String> {
+    if !GAME_CURRENCIES.contains(&currency) {
+        return Err(format!("Games can't be played in {}", currency));
+    }
+    Ok(())
+}
+
+// Takes a life from the player at `player_idx` after they hit a bomb, returning whether
+// that was their last one
+pub(crate) fn lose_life(
+    lives_left: &mut Vec<u32>,
+    lives: u32,
+    players: usize,
+    player_idx: usize,
+) -> bool {
+    // States saved before lives existed start everyone on a full set
+    if lives_left.len() != players {
+        *lives_left = vec![lives; players];
+    }
+    let left = &mut lives_left[player_idx];
+    *left = left.saturating_sub(1);
+    *left == 0
+}
+
+// The board's bombs as the (x, y) cells moves name, the way the moves API takes them
+fn bomb_positions(board: &Board) -> Vec<(usize, usize)> {
+    board
+        .bomb_coordinates
+        .iter()
+        .map(|&pos| {
+            let x = (pos / board.n as u64) as usize;
+            let y = (pos % board.n as u64) as usize;
+            (x, y)
+        })
+        .collect()
+}
+
+// Same size, bomb count and layout as the finished board, with freshly placed bombs
+fn rematch_board(finished: &Board) -> Board {
+    Board::new(
+        finished.dimension(),
+        finished.bomb_count(),
+        finished.layout,
+        None,
+    )
+}
+
+// The seat a connection took or resumed, the only one it may act in
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Seat {
+    game_id: String,
+    player_id: String,
+}
+
+// Messages acting in a game are only taken from the connection seated in it, and only
+// for the player it is seated as
+fn authorize_seat(seat: Option<&Seat>, message: &GameMessage) -> Result<(), ProtocolError> {
+    let authorized = seat.is_some_and(|seat| {
+        message.game_id() == Some(seat.game_id.as_str())
+            && message
+                .player_id()
+                .is_none_or(|player_id| player_id == seat.player_id)
+    });
+    if authorized {
+        Ok(())
+    } else {
+        Err(ProtocolError::new(ErrorCode::NotInGame, NOT_IN_GAME))
+    }
+}
+
+// Every update carries each player's name, so a long one would bloat them all
+pub(crate) fn validate_name(name: &str) -> Result<(), String> {
+    if name.chars().count() > MAX_NAME_CHARS {
+        return Err(format!(
+            "Names can be at most {} characters long",
+            MAX_NAME_CHARS
+        ));
+    }
+    Ok(())
+}
+
+// Player ids are the wallet's numeric user ids, which is what settlement credits
+fn parse_player_id(player_id: &str) -> Result<i32, String> {
+    player_id
+        .parse()
+        .map_err(|_| format!("Invalid player id {:?}, expected a user id", player_id))
+}
+
+fn settlement_user_ids(players: &[Player]) -> Result<Vec<i32>, String> {
+    players.iter().map(|p| parse_player_id(&p.id)).collect()
+}
+
+fn normalize_bet_size(single_bet_size: f64) -> Result<f64, String> {
+    if !single_bet_size.is_finite() || single_bet_size <= 0.0 {
+        return Err("Bet size must be a positive number".to_string());
+    }
+    let scale = 10f64.powi(BET_SIZE_DECIMALS);
+    let normalized = (single_bet_size * scale).round() / scale;
+    if normalized <= 0.0 {
+        return Err(format!("Bet size must be at least {}", 1.0 / scale));
+    }
+    Ok(normalized)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -175,16 +565,51 @@ pub struct GameRegistry {
     discovery: DiscoveryService,
     server_id: String,
     xplode_moves: XplodeMovesClient,
+    reconnect_grace: Duration,
+    lobby_timeout: Duration,
+    seed_contribution_timeout: Duration,
+    board_limits: BoardLimits,
+    allowed_grids: AllowedGrids,
+    creation_limit: CreationLimit,
+    // Shared by every connection for settlement
+    pool: Pool<Postgres>,
+    payout_policy: PayoutPolicy,
+    // When each running game started, to time it for the duration histogram
+    game_starts: Arc<RwLock<HashMap<String, GameStart>>>,
+    // Token each seated player resumes with, by game id and player id
+    resume_tokens: Arc<RwLock<HashMap<(String, String), String>>>,
+    // Sends connections meant for another server on to it
+    router: Arc<dyn ServerRouter>,
+    // Whether new games are announced on Telegram and to the notify service
+    notifications_enabled: bool,
+    // Fills public lobbies left one player short, if the house plays
+    house_bot: Option<HouseBot>,
+}
+
+struct GameStart {
+    started: Instant,
+    game_type: String,
+}
+
+// Label for the duration histogram, e.g. "grid5_bombs3"
+fn game_type(board: &Board) -> String {
+    format!("grid{}_bombs{}", board.dimension(), board.bomb_count())
 }
 
 type WebSocketSink = SplitSink<WebSocketStream<TcpStream>, Message>;
 
 impl GameRegistry {
-    pub fn new(redis: redis::Client, server_id: String) -> Self {
-        let api_base = env::var("XPLODE_MOVES_API")
-            .unwrap_or_else(|_| "https://xplode-moves.fly.dev/api/game".to_string());
-        // let api_base = env::var("XPLODE_MOVES_API")
-        //     .unwrap_or_else(|_| "http://localhost:3004/api/game".to_string());
+    // A registry with the default config, which never sends notifications
+    pub fn new(redis: redis::Client, server_id: String, pool: Pool<Postgres>) -> Self {
+        Self::with_config(redis, server_id, pool, &AppConfig::default())
+    }
+
+    pub fn with_config(
+        redis: redis::Client,
+        server_id: String,
+        pool: Pool<Postgres>,
+        config: &AppConfig,
+    ) -> Self {
         Self {
             games: Arc::new(RwLock::new(HashMap::new())),
             active_players: Arc::new(RwLock::new(HashMap::new())),
@@ -192,10 +617,56 @@ impl GameRegistry {
             broadcast_channels: Arc::new(RwLock::new(HashMap::new())),
             discovery: DiscoveryService::new(redis),
             server_id,
-            xplode_moves: XplodeMovesClient::new(api_base),
+            xplode_moves: XplodeMovesClient::new(config.moves_api.clone()),
+            reconnect_grace: config.reconnect_grace,
+            lobby_timeout: config.lobby_timeout,
+            seed_contribution_timeout: config.seed_contribution_timeout,
+            board_limits: config.board_limits,
+            allowed_grids: config.allowed_grids.clone(),
+            creation_limit: config.creation_limit,
+            pool,
+            payout_policy: config.payout_policy,
+            game_starts: Arc::new(RwLock::new(HashMap::new())),
+            resume_tokens: Arc::new(RwLock::new(HashMap::new())),
+            router: router_for(config.game_servers.clone()),
+            notifications_enabled: config.notifications_enabled,
+            house_bot: config.house_bot.clone().map(HouseBot::new),
         }
     }
 
+    // Whether this server can take games: Postgres for settlement, Redis for discovery
+    pub async fn health(&self) -> HealthReport {
+        HealthReport::new()
+            .check("postgres", health::check_postgres(&self.pool))
+            .await
+            .check("redis", self.discovery.ping())
+            .await
+    }
+
+    // Players and games across all servers, as tracked by discovery
+    pub async fn stats(&self) -> Result<SessionStats> {
+        self.discovery.stats().await
+    }
+
+    // Public games in `currency` on a `grid_size` board that players can still join
+    pub async fn open_games(&self, currency: Currency, grid_size: u32) -> Result<Vec<GameSession>> {
+        self.discovery.list_open_sessions(currency, grid_size).await
+    }
+
+    // Whether a Play on this server could have asked for such a game
+    pub fn validate_game(
+        &self,
+        grid: u32,
+        bombs: u32,
+        players: u32,
+        lives: u32,
+    ) -> Result<(), String> {
+        validate_min_players(players)
+            .and_then(|_| validate_lives(lives))
+            .and_then(|_| self.allowed_grids.validate(grid))
+            .and_then(|_| self.board_limits.validate(grid, bombs))
+    }
+
     pub async fn save_game_state(&self, game_id: String, state: GameState) {
         match &state {
             GameState::RUNNING { players, .. } => {
@@ -208,15 +679,131 @@ impl GameRegistry {
             GameState::FINISHED { .. } | GameState::ABORTED { .. } => {
                 // Remove from discovery when game ends
                 let _ = self.discovery.remove_game_session(&game_id).await;
+                self.game_ended(&game_id, Instant::now()).await;
             }
             _ => {}
         }
     }
 
+    // Starts timing a game that just started running, records its stakes as in flight
+    // until it settles and initializes it on chain with the board it is played on
+    async fn game_started(&self, state: &GameState, now: Instant) {
+        let GameState::RUNNING {
+            game_id,
+            board,
+            players,
+            single_bet_size,
+            currency,
+            ..
+        } = state
+        else {
+            return;
+        };
+        let mut game_starts = self.game_starts.write().await;
+        if game_starts.contains_key(game_id) {
+            return;
+        }
+        game_starts.insert(
+            game_id.clone(),
+            GameStart {
+                started: now,
+                game_type: game_type(board),
+            },
+        );
+        drop(game_starts);
+        self.initialize_on_chain(game_id, board);
+
+        let recorded = match settlement_user_ids(players) {
+            Ok(user_ids) => {
+                db::record_running_game(&self.pool, game_id, &user_ids, *currency, *single_bet_size)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            Err(reason) => Err(reason),
+        };
+        if let Err(e) = recorded {
+            warn!("Failed to record running game {}: {}", game_id, e);
+        }
+    }
+
+    // Only a running game's board is final, a lobby's is redrawn from the seed
+    // contributions when it starts
+    fn initialize_on_chain(&self, game_id: &str, board: &Board) {
+        let registry_clone = self.clone();
+        let game_id_clone = game_id.to_string();
+        let grid_size = board.dimension() as u32;
+        let bomb_positions = bomb_positions(board);
+
+        tokio::spawn(
+            async move {
+                if let Ok(tx_hash) = registry_clone
+                    .xplode_moves
+                    .initialize_game(&game_id_clone, grid_size, bomb_positions)
+                    .await
+                {
+                    let update = GameMessage::BlockchainUpdate {
+                        game_id: game_id_clone.clone(),
+                        update_type: BlockchainUpdateType::GameInitialized,
+                        transaction_hash: tx_hash,
+                    };
+                    let wrapper = GameMessageWrapper {
+                        server_id: registry_clone.server_id.clone(),
+                        game_message: update,
+                    };
+                    let _ = registry_clone
+                        .publish_message(game_id_clone.clone(), wrapper, false)
+                        .await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    // Records how long a game ran. Games that never started running aren't timed.
+    async fn game_ended(&self, game_id: &str, now: Instant) {
+        if let Some(start) = self.game_starts.write().await.remove(game_id) {
+            metrics::record_game_end(&start.game_type, now.duration_since(start.started));
+            // Settlement already removed it, unless the game ended without settling
+            if let Err(e) = db::remove_running_game(&self.pool, game_id).await {
+                warn!("Failed to remove running game {}: {}", game_id, e);
+            }
+        }
+    }
+
+    // Hands a player who just took a seat in `game_id` the token to resume it with.
+    // A player id alone is guessable, the token isn't.
+    async fn issue_resume_token(&self, game_id: &str, player_id: &str) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        self.resume_tokens
+            .write()
+            .await
+            .insert((game_id.to_string(), player_id.to_string()), token.clone());
+        token
+    }
+
+    // Only a token issued to this player for this game resumes their seat
+    async fn check_resume_token(
+        &self,
+        game_id: &str,
+        player_id: &str,
+        token: Option<&str>,
+    ) -> Result<(), ProtocolError> {
+        let resume_tokens = self.resume_tokens.read().await;
+        match (
+            resume_tokens.get(&(game_id.to_string(), player_id.to_string())),
+            token,
+        ) {
+            (Some(issued), Some(token)) if issued == token => Ok(()),
+            _ => Err(ProtocolError::new(
+                ErrorCode::InvalidResumeToken,
+                INVALID_RESUME_TOKEN,
+            )),
+        }
+    }
+
     pub async fn get_game_state(&self, game_id: &str) -> Option<GameState> {
         // Only check in-memory state since we don't store in Redis anymore
         let games_read = self.games.read().await;
-        info!("Game keys: {:?}", games_read.keys().len());
         games_read.get(game_id).cloned()
     }
 
@@ -227,7 +814,7 @@ impl GameRegistry {
         channel: String,
         ws_write: Arc<Mutex<WebSocketSink>>,
     ) -> Result<()> {
-        info!("Subscribing to channel: {:?}", channel);
+        debug!("Subscribing to channel: {:?}", channel);
         let mut broadcast_channels = self.broadcast_channels.write().await;
 
         // Create a new broadcast channel if it doesn't exist
@@ -242,19 +829,33 @@ impl GameRegistry {
         drop(broadcast_channels); // Release the write lock
 
         // Spawn a task to forward messages to this client's WebSocket
-        tokio::spawn(async move {
-            while let Ok(game_message) = broadcast_rx.recv().await {
-                let mut ws_sink = ws_write.lock().await;
-                if ws_sink
-                    .send(Message::binary(serde_json::to_vec(&game_message).unwrap()))
-                    .await
-                    .is_err()
-                {
-                    eprintln!("Player disconnected");
-                    break; // Exit the loop if client disconnects
+        let max_message_bytes = self.board_limits.max_message_bytes;
+        tokio::spawn(
+            async move {
+                while let Ok(game_message) = broadcast_rx.recv().await {
+                    let mut bytes = serde_json::to_vec(&game_message).unwrap();
+                    if bytes.len() > max_message_bytes {
+                        error!(
+                            "Not sending {} byte message on {}, over the {} byte limit",
+                            bytes.len(),
+                            channel,
+                            max_message_bytes
+                        );
+                        bytes = serde_json::to_vec(&GameMessage::error(
+                            ErrorCode::MessageTooLarge,
+                            MESSAGE_TOO_LARGE,
+                        ))
+                        .unwrap();
+                    }
+                    let mut ws_sink = ws_write.lock().await;
+                    if ws_sink.send(Message::binary(bytes)).await.is_err() {
+                        info!("Player disconnected");
+                        break; // Exit the loop if client disconnects
+                    }
                 }
             }
-        });
+            .in_current_span(),
+        );
 
         Ok(())
     }
@@ -266,18 +867,111 @@ impl GameRegistry {
         game_message_wrapper: GameMessageWrapper,
         _from_redis: bool, // Not needed anymore since we're local only
     ) -> Result<()> {
-        info!("--------------------------------");
-        info!("Publishing message to channel: {:?}", channel);
-        info!("--------------------------------");
         if let Some(broadcast_tx) = self.broadcast_channels.read().await.get(&channel) {
-            info!("--------------------------------");
-            info!("Sending message to channel: {:?}", channel);
-            info!("--------------------------------");
+            debug!("Publishing message to channel: {:?}", channel);
             let _ = broadcast_tx.send(game_message_wrapper.game_message);
         }
         Ok(())
     }
 
+    // Whether the player is in a game on this or any other server
+    async fn player_in_game(&self, player_id: &str) -> Result<bool> {
+        if self.active_players.read().await.contains_key(player_id) {
+            return Ok(true);
+        }
+        Ok(self.discovery.player_game(player_id).await?.is_some())
+    }
+
+    // Puts the player into the game and claims them across all servers, so they can't
+    // enter a game anywhere else until released. False if they are already in another game.
+    async fn claim_player(&self, player_id: &str, game_id: &str) -> Result<bool> {
+        if !self.discovery.claim_player(player_id, game_id).await? {
+            return Ok(false);
+        }
+        self.active_players
+            .write()
+            .await
+            .insert(player_id.to_string(), game_id.to_string());
+        Ok(true)
+    }
+
+    // Seats the player in the lobby `game_id` if it has room left; None if it doesn't.
+    // The lobby is re-read and changed under the games lock after the claim, so joins
+    // racing for the last seat, or an abort, can't be overwritten. A claim that didn't
+    // get a seat is released.
+    async fn join_lobby(
+        &self,
+        game_id: &str,
+        player_id: &str,
+        name: &str,
+    ) -> Result<Option<GameState>> {
+        let has_room =
+            |state: &GameState| matches!(state, GameState::WAITING { .. }) && !state.is_full();
+        // Claiming is only worth it for a lobby this server has
+        if !self.games.read().await.get(game_id).is_some_and(has_room) {
+            return Ok(None);
+        }
+        if self.active_players.read().await.contains_key(player_id)
+            || !self.claim_player(player_id, game_id).await?
+        {
+            return Err(ProtocolError::new(ErrorCode::AlreadyInGame, ALREADY_IN_GAME).into());
+        }
+
+        let mut games_write = self.games.write().await;
+        let joined = match games_write.get(game_id).filter(|state| has_room(state)) {
+            Some(lobby) => {
+                let mut lobby = lobby.clone();
+                if let GameState::WAITING { players, .. } = &mut lobby {
+                    players.push(Player::new(player_id.to_string(), name.to_string()));
+                }
+                self.seat_in_lobby(game_id, lobby).await.map(|joined| {
+                    games_write.insert(game_id.to_string(), joined.clone());
+                    Some(joined)
+                })
+            }
+            None => Ok(None),
+        };
+        drop(games_write);
+
+        match &joined {
+            Ok(Some(state)) => self.game_started(state, Instant::now()).await,
+            _ => self.release_players(&[player_id.to_string()]).await,
+        }
+        joined
+    }
+
+    // Records a lobby's new player count in discovery, starting the game once it is full
+    async fn seat_in_lobby(&self, game_id: &str, lobby: GameState) -> Result<GameState> {
+        let GameState::WAITING { players, .. } = &lobby else {
+            return Ok(lobby);
+        };
+        self.discovery
+            .update_player_count(game_id, players.len() as u32)
+            .await?;
+        if !lobby.is_full() {
+            return Ok(lobby);
+        }
+        // Remove from discovery since it's no longer accepting players
+        self.discovery.remove_game_session(game_id).await?;
+        Ok(self.start_when_seeded(lobby))
+    }
+
+    // Takes players out of their game so they can play again, here or elsewhere. Their
+    // resume tokens go with the seat.
+    async fn release_players(&self, player_ids: &[String]) {
+        self.active_players
+            .write()
+            .await
+            .retain(|x, _| !player_ids.contains(x));
+        self.resume_tokens
+            .write()
+            .await
+            .retain(|(_, player_id), _| !player_ids.contains(player_id));
+        if let Err(e) = self.discovery.release_players(player_ids).await {
+            warn!("Failed to release players {:?}: {}", player_ids, e);
+        }
+    }
+
     // Add new cleanup method
     pub async fn cleanup_player(&self, player_id: &str) {
         // Remove from active players
@@ -288,10 +982,16 @@ impl GameRegistry {
         let mut games_write = self.games.write().await;
         let mut games_to_abort = Vec::new();
 
+        let mut lobby_players = Vec::new();
+
         for (game_id, state) in games_write.iter() {
-            if let GameState::WAITING { creator, .. } = state {
+            if let GameState::WAITING {
+                creator, players, ..
+            } = state
+            {
                 if creator.id == player_id {
                     games_to_abort.push(game_id.clone());
+                    lobby_players.extend(players.iter().map(|p| p.id.clone()));
                 }
             }
         }
@@ -306,6 +1006,36 @@ impl GameRegistry {
             // Only remove from discovery service, no need to save state
             let _ = self.discovery.remove_game_session(&game_id).await;
         }
+        drop(games_write);
+        drop(active_players_write);
+        self.release_players(&lobby_players).await;
+    }
+
+    // Posts `message` to Telegram and pings the notify service in the background, if
+    // notifications are enabled. Returns whether they were sent.
+    fn notify_game_created(&self, message: String) -> bool {
+        if !self.notifications_enabled {
+            return false;
+        }
+        info!("Sending Telegram notification");
+        tokio::spawn(
+            async move {
+                if let Err(e) = send_telegram_message(&message).await {
+                    error!("Failed to send Telegram notification: {}", e);
+                }
+                let client = reqwest::Client::new();
+
+                if let Err(e) = client
+                    .get("https://xplode-notify-service-production.up.railway.app/matchmaking")
+                    .send()
+                    .await
+                {
+                    error!("Failed to send notification to notify service: {}", e);
+                }
+            }
+            .in_current_span(),
+        );
+        true
     }
 
     // Modify the matchmaking logic in handle_play_message
@@ -320,6 +1050,9 @@ impl GameRegistry {
             bombs,
             min_players,
             is_creating_room,
+            layout,
+            lives,
+            currency,
         } = play_request;
         // First check if player is already in a game
         let active_players_read = self.active_players.read().await;
@@ -332,67 +1065,38 @@ impl GameRegistry {
         // let current_region = env::var("FLY_REGION").unwrap_or_else(|_| "unknown".to_string());
         if let Some(session) = self
             .discovery
-            .find_game_session(single_bet_size, min_players, grid)
+            .find_game_session(currency, single_bet_size, min_players, grid)
             .await?
         {
-            // If the session is on this server, get it from local state
+            // If the session is on this server, join it here
             if session.server_id == self.server_id {
-                let games_read = self.games.read().await;
-
-                let state = games_read.get(&session.server_id).cloned();
-
-                if let Some(GameState::WAITING {
-                    game_id,
-                    creator,
-                    board,
-                    single_bet_size,
-                    min_players,
-                    mut players,
-                }) = state
-                {
-                    let player = Player::new(player_id.clone(), name.clone());
-                    players.push(player);
-
-                    // Update player count in Redis
-                    self.discovery
-                        .update_player_count(&game_id, players.len() as u32)
-                        .await?;
-
-                    let new_state = if players.len() < min_players as usize {
-                        GameState::WAITING {
-                            game_id: game_id.clone(),
-                            creator,
-                            board,
-                            single_bet_size,
-                            min_players,
-                            players,
-                        }
-                    } else {
-                        // Game is transitioning to RUNNING state
-                        // Remove from discovery since it's no longer accepting players
-                        self.discovery.remove_game_session(&game_id).await?;
-                        GameState::RUNNING {
-                            game_id: game_id.clone(),
-                            players,
-                            board,
-                            turn_idx: 0,
-                            single_bet_size,
-                            locks: None,
-                        }
-                    };
-
-                    let mut games_write = self.games.write().await;
-                    games_write.insert(game_id.clone(), new_state.clone());
-                    return Ok(Some(new_state));
-                }
-            }
-            // If session is on another server, return None - client should reconnect to that server
-            return Ok(None);
-        }
+                if let Some(joined) = self.join_lobby(&session.game_id, &player_id, &name).await? {
+                    return Ok(Some(joined));
+                }
+            }
+            // If session is on another server, return None - client should reconnect to that server
+            return Ok(None);
+        }
 
         // Create new game if no suitable session found
+        let created = self
+            .discovery
+            .record_game_creation(&player_id, self.creation_limit.window.as_secs())
+            .await?;
+        if created > self.creation_limit.max_games {
+            warn!(
+                "Player {} created {} games within {:?}",
+                player_id, created, self.creation_limit.window
+            );
+            return Err(
+                ProtocolError::new(ErrorCode::TooManyGamesCreated, TOO_MANY_GAMES_CREATED).into(),
+            );
+        }
         let game_id = Uuid::new_v4().to_string();
-        let board = Board::new(grid as usize, bombs as usize);
+        if !self.claim_player(&player_id, &game_id).await? {
+            return Err(anyhow!(ALREADY_IN_GAME));
+        }
+        let board = Board::new(grid as usize, bombs as usize, layout, None);
         let player = Player::new(player_id.clone(), name.clone());
 
         let game_state = GameState::WAITING {
@@ -402,64 +1106,15 @@ impl GameRegistry {
             single_bet_size,
             min_players,
             players: vec![player.clone()],
+            lives,
+            currency,
+            seed_contributions: BTreeMap::new(),
         };
-        // Initialize game on blockchain
-        let registry_clone = self.clone();
-        let game_id_clone = game_id.clone();
-        let grid_size = board.n as u32;
-        let bomb_positions: Vec<(usize, usize)> = board
-            .bomb_coordinates
-            .iter()
-            .map(|&pos| {
-                let x = (pos / board.n as u64) as usize;
-                let y = (pos % board.n as u64) as usize;
-                (x, y)
-            })
-            .collect();
-
-        tokio::spawn(async move {
-            if let Ok(tx_hash) = registry_clone
-                .xplode_moves
-                .initialize_game(&game_id_clone, grid_size, bomb_positions)
-                .await
-            {
-                let update = GameMessage::BlockchainUpdate {
-                    game_id: game_id_clone.clone(),
-                    update_type: BlockchainUpdateType::GameInitialized,
-                    transaction_hash: tx_hash,
-                };
-                let wrapper = GameMessageWrapper {
-                    server_id: registry_clone.server_id.clone(),
-                    game_message: update,
-                };
-                let _ = registry_clone
-                    .publish_message(game_id_clone.clone(), wrapper, false)
-                    .await;
-            }
-        });
-
-        info!("Sending Telegram notification");
-        // Send Telegram notification.
+        // Announce the new game
         let game_url = format!("https://playxplode.xyz/multiplayer/{}", game_id);
-        let notification_message = format!(
+        self.notify_game_created(format!(
             "🎮 New game created!\n\nGame URL: {}\nCreator: {}\nBet Size: {}\nMin Players: {}\nGrid Size: {}x{}\nBombs: {}\nIs Creating Room: {}",
-            game_url, name, single_bet_size, min_players, grid, grid, bombs, is_creating_room);
-
-        // Spawn a separate task for Telegram notification
-        tokio::spawn(async move {
-            if let Err(e) = send_telegram_message(&notification_message).await {
-                error!("Failed to send Telegram notification: {}", e);
-            }
-            let client = reqwest::Client::new();
-
-            if let Err(e) = client
-                .get("https://xplode-notify-service-production.up.railway.app/matchmaking")
-                .send()
-                .await
-            {
-                error!("Failed to send notification to notify service: {}", e);
-            }
-        });
+            game_url, name, single_bet_size, min_players, grid, grid, bombs, is_creating_room));
 
         // Register the new game session
         let session = GameSession {
@@ -469,89 +1124,487 @@ impl GameRegistry {
             min_players,
             current_players: 1,
             grid_size: grid,
+            private: is_creating_room,
+            currency,
         };
         self.discovery.register_game_session(session).await?;
 
-        info!("Storing game state in local state");
-        info!("--------------------------------");
-        info!("Game state: {:?}", game_state);
-        info!("--------------------------------");
+        debug!("Storing game state: {:?}", game_state);
         // Store in local state
         let mut games_write = self.games.write().await;
         games_write.insert(game_id.clone(), game_state.clone());
+        drop(games_write);
+
+        self.spawn_lobby_timeout(game_id);
 
         Ok(Some(game_state))
     }
 
+    fn spawn_lobby_timeout(&self, game_id: String) {
+        let registry = self.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(registry.lobby_timeout).await;
+                if registry.seat_house_bot(&game_id).await {
+                    info!("House bot is joining lobby {}", game_id);
+                } else if registry.abort_unfilled_lobby(&game_id).await {
+                    info!("Aborted lobby {} that never filled", game_id);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    // Sends the house bot into a public lobby that is one player short. If it doesn't
+    // take the seat, e.g. because it is still playing another game, the lobby is aborted
+    // as it would have been without it.
+    async fn seat_house_bot(&self, game_id: &str) -> bool {
+        let Some(bot) = self.house_bot.clone() else {
+            return false;
+        };
+        let one_short = matches!(
+            self.games.read().await.get(game_id),
+            Some(GameState::WAITING { players, min_players, .. })
+                if players.len() + 1 == *min_players as usize
+                    && players.iter().all(|p| p.id != bot.player_id())
+        );
+        if !one_short {
+            return false;
+        }
+        // Private rooms are for players who invited each other
+        match self.discovery.find_game_session_by_id(game_id).await {
+            Ok(Some(session)) if !session.private => {}
+            _ => return false,
+        }
+
+        let registry = self.clone();
+        let game_id = game_id.to_string();
+        tokio::spawn(
+            async move {
+                if let Err(e) = bot.play(&game_id).await {
+                    warn!("House bot left game {}: {}", game_id, e);
+                }
+                if registry.abort_unfilled_lobby(&game_id).await {
+                    info!("Aborted lobby {} that never filled", game_id);
+                }
+            }
+            .in_current_span(),
+        );
+        true
+    }
+
+    // Aborts the game if it is still waiting for players. Bets are only moved at
+    // settlement, so there is nothing to refund.
+    async fn abort_unfilled_lobby(&self, game_id: &str) -> bool {
+        let mut games_write = self.games.write().await;
+        // A full lobby is only waiting for seed contributions and starts on its own
+        let Some(GameState::WAITING { players, .. }) =
+            games_write.get(game_id).filter(|state| !state.is_full())
+        else {
+            return false;
+        };
+        let ids = players.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+        let aborted_state = GameState::ABORTED {
+            game_id: game_id.to_string(),
+        };
+        games_write.insert(game_id.to_string(), aborted_state.clone());
+        drop(games_write);
+
+        self.release_players(&ids).await;
+
+        // Update discovery service
+        self.save_game_state(game_id.to_string(), aborted_state.clone())
+            .await;
+
+        let wrapper = GameMessageWrapper {
+            server_id: self.server_id.clone(),
+            game_message: GameMessage::GameUpdate(aborted_state),
+        };
+        let _ = self
+            .publish_message(game_id.to_string(), wrapper, false)
+            .await;
+        self.cleanup_broadcast_channel(game_id).await;
+
+        true
+    }
+
+    // What a lobby that just filled moves to: running straight away unless it waits for
+    // seed contributions, in which case it starts once they are all in or the timeout passes
+    fn start_when_seeded(&self, lobby: GameState) -> GameState {
+        if self.seed_contribution_timeout.is_zero() || lobby.seed_complete() {
+            return lobby.start();
+        }
+        if let GameState::WAITING { game_id, .. } = &lobby {
+            let registry = self.clone();
+            let game_id = game_id.clone();
+            tokio::spawn(
+                async move {
+                    tokio::time::sleep(registry.seed_contribution_timeout).await;
+                    if registry.start_lobby(&game_id).await {
+                        info!("Started game {} without every seed contribution", game_id);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+        lobby
+    }
+
+    // Starts the game if it is a full lobby and tells its players; false if it wasn't
+    async fn start_lobby(&self, game_id: &str) -> bool {
+        let mut games_write = self.games.write().await;
+        let Some(lobby) = games_write.get(game_id).filter(|state| state.is_full()) else {
+            return false;
+        };
+        let running = lobby.clone().start();
+        games_write.insert(game_id.to_string(), running.clone());
+        drop(games_write);
+
+        self.game_started(&running, Instant::now()).await;
+        let wrapper = GameMessageWrapper {
+            server_id: self.server_id.clone(),
+            game_message: GameMessage::GameUpdate(running),
+        };
+        let _ = self
+            .publish_message(game_id.to_string(), wrapper, false)
+            .await;
+        true
+    }
+
     // Add new method to clean up broadcast channels
     pub async fn cleanup_broadcast_channel(&self, game_id: &str) {
         let mut broadcast_channels = self.broadcast_channels.write().await;
         broadcast_channels.remove(game_id);
         info!("Cleaned up broadcast channel for game: {}", game_id);
     }
+
+    // Called once a disconnected player's grace period is over. Ends the game as
+    // abandoned unless they reconnected; returns the finished state to settle.
+    // When nobody else is still connected either, it ends as a draw, so players
+    // who dropped out together don't pay each other.
+    async fn abandon_if_disconnected(&self, game_id: &str, player_id: &str) -> Option<GameState> {
+        // Reconnecting sends a Ping that puts the player back into active players
+        let connected = self
+            .active_players
+            .read()
+            .await
+            .iter()
+            .filter(|(_, active_game_id)| *active_game_id == game_id)
+            .map(|(id, _)| id.clone())
+            .collect::<HashSet<_>>();
+        if connected.contains(player_id) {
+            info!("Player {} reconnected to game {}", player_id, game_id);
+            return None;
+        }
+
+        let mut games_write = self.games.write().await;
+        let game_state = games_write.get_mut(game_id)?;
+        let GameState::RUNNING {
+            players,
+            board,
+            single_bet_size,
+            lives,
+            currency,
+            ..
+        } = game_state
+        else {
+            return None;
+        };
+        let loser_idx = players.iter().position(|p| p.id == player_id)?;
+        let result = if players.iter().any(|p| connected.contains(&p.id)) {
+            GameResult::Loser(loser_idx)
+        } else {
+            info!("Every player left game {}, ending it as a draw", game_id);
+            GameResult::Draw
+        };
+        let finished = GameState::FINISHED {
+            game_id: game_id.to_string(),
+            result,
+            board: board.revealed_clone(),
+            players: players.clone(),
+            single_bet_size: *single_bet_size,
+            lives: *lives,
+            currency: *currency,
+        };
+        *game_state = finished.clone();
+        drop(games_write);
+
+        if let GameState::FINISHED { players, .. } = &finished {
+            let ids = players.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+            self.release_players(&ids).await;
+        }
+        self.save_game_state(game_id.to_string(), finished.clone())
+            .await;
+        metrics::GAMES_ABANDONED.inc();
+
+        Some(finished)
+    }
+
+    // Only redirects to servers that are heartbeating, so a crafted machine id can't
+    // bounce the client around; unknown targets are served locally instead
+    async fn redirect_target(&self, data: &[u8], server_id: &str) -> Option<String> {
+        let target = self.router.requested_server(data, server_id)?;
+        match self.discovery.is_server_live(&target).await {
+            Ok(true) => Some(target),
+            Ok(false) => {
+                warn!("Ignoring redirect to unknown machine: {}", target);
+                None
+            }
+            Err(e) => {
+                warn!("Failed to check machine {}, not redirecting: {}", target, e);
+                None
+            }
+        }
+    }
+
+    // Reply to a message about a game this server doesn't have: a redirect when a live
+    // server has it registered, an error otherwise
+    async fn missing_game_reply(&self, game_id: &str) -> GameMessage {
+        let not_found = GameMessage::error(ErrorCode::GameNotFound, GAME_NOT_FOUND);
+        let server_id = match self.discovery.game_server(game_id).await {
+            Ok(Some(server_id)) if server_id != self.server_id => server_id,
+            Ok(_) => return not_found,
+            Err(e) => {
+                warn!("Failed to look up game {}: {}", game_id, e);
+                return not_found;
+            }
+        };
+        match self.discovery.is_server_live(&server_id).await {
+            Ok(true) => GameMessage::RedirectToServer {
+                game_id: game_id.to_string(),
+                machine_id: server_id,
+            },
+            Ok(false) => not_found,
+            Err(e) => {
+                warn!(
+                    "Failed to check machine {}, not redirecting: {}",
+                    server_id, e
+                );
+                not_found
+            }
+        }
+    }
+
+    // Called on shutdown: takes unfinished games out of matchmaking, snapshots them
+    // to Redis and tells their players. Returns the ids of the persisted games.
+    pub async fn drain(&self) -> Vec<String> {
+        let active_games: Vec<(String, GameState)> = self
+            .games
+            .read()
+            .await
+            .iter()
+            .filter(|(_, state)| state.is_active())
+            .map(|(game_id, state)| (game_id.clone(), state.clone()))
+            .collect();
+
+        let mut persisted = Vec::with_capacity(active_games.len());
+        for (game_id, state) in active_games {
+            // Seats are resumed on whichever server picks the game up, not here
+            self.resume_tokens
+                .write()
+                .await
+                .retain(|(token_game_id, _), _| *token_game_id != game_id);
+            if let Err(e) = self.discovery.remove_game_session(&game_id).await {
+                warn!("Failed to remove game session {}: {}", game_id, e);
+            }
+
+            let snapshot = match serde_json::to_string(&state) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    error!("Failed to serialize game {}: {}", game_id, e);
+                    continue;
+                }
+            };
+            match self.discovery.persist_game_state(&game_id, &snapshot).await {
+                Ok(()) => persisted.push(game_id.clone()),
+                Err(e) => error!("Failed to persist game {}: {}", game_id, e),
+            }
+
+            let wrapper = GameMessageWrapper {
+                server_id: self.server_id.clone(),
+                game_message: GameMessage::ServerDraining {
+                    game_id: game_id.clone(),
+                },
+            };
+            let _ = self.publish_message(game_id, wrapper, false).await;
+        }
+
+        persisted
+    }
 }
 
 pub struct GameServer {
     server_id: String,
     registry: GameRegistry,
+    connection_limit: Arc<Semaphore>,
 }
 
 impl GameServer {
-    pub async fn new() -> Self {
-        let redis_url = env::var("REDIS_URL").unwrap();
-        info!("Redis URL: {}", redis_url);
-        let redis_client = Client::open(redis_url).unwrap();
-        let server_id = env::var("FLY_MACHINE_ID").unwrap_or_else(|_| "LocalServer".to_string());
+    pub async fn new(config: &AppConfig) -> Result<Self> {
+        let redis_client = Client::open(config.redis_url.as_str())?;
+        let server_id = config.server_id.clone();
 
-        Self {
+        let max_connections = config.max_connections;
+        info!("Accepting at most {} connections", max_connections);
+
+        Ok(Self {
             server_id: server_id.clone(),
-            registry: GameRegistry::new(redis_client, server_id),
+            registry: GameRegistry::with_config(
+                redis_client,
+                server_id,
+                establish_connection().await,
+                config,
+            ),
+            connection_limit: Arc::new(Semaphore::new(max_connections)),
+        })
+    }
+
+    // Serves an existing registry, e.g. one pointed at test Redis and Postgres
+    #[cfg(test)]
+    pub(crate) fn with_registry(registry: GameRegistry) -> Self {
+        Self {
+            server_id: registry.server_id.clone(),
+            registry,
+            connection_limit: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
         }
     }
 
+    pub fn registry(&self) -> GameRegistry {
+        self.registry.clone()
+    }
+
     pub async fn start(&self, addr: &str) -> anyhow::Result<()> {
+        self.start_with_shutdown(addr, shutdown_signal()).await
+    }
+
+    // Accepts connections until `shutdown` resolves, then drains active games
+    pub async fn start_with_shutdown(
+        &self,
+        addr: &str,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
         let listener = TcpListener::bind(addr).await?;
         info!("Server listening on {}", addr);
+        self.serve(listener, shutdown).await
+    }
+
+    pub(crate) async fn serve(
+        &self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        tokio::pin!(shutdown);
+        if let Some(bot) = &self.registry.house_bot {
+            bot.serving_on(listener.local_addr()?);
+        }
+
+        let heartbeat = tokio::spawn({
+            let discovery = self.registry.discovery.clone();
+            let server_id = self.server_id.clone();
+            async move {
+                loop {
+                    if let Err(e) = discovery.heartbeat_server(&server_id).await {
+                        warn!("Failed to send server heartbeat: {}", e);
+                    }
+                    tokio::time::sleep(SERVER_HEARTBEAT_INTERVAL).await;
+                }
+            }
+        });
+
+        let sweeper = tokio::spawn({
+            let discovery = self.registry.discovery.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(MATCHMAKING_SWEEP_INTERVAL).await;
+                    match discovery.sweep_matchmaking().await {
+                        Ok(0) => {}
+                        Ok(removed) => info!("Swept {} dead sessions from matchmaking", removed),
+                        Err(e) => warn!("Failed to sweep matchmaking: {}", e),
+                    }
+                }
+            }
+        });
+
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(_) => break,
+                },
+                _ = &mut shutdown => {
+                    info!("Shutdown signal received, no longer accepting connections");
+                    break;
+                }
+            };
+
+            let Some(permit) = self.admit_connection() else {
+                tokio::spawn(reject_connection(stream));
+                continue;
+            };
 
-        while let std::result::Result::Ok((stream, _)) = listener.accept().await {
             let registry = self.registry.clone();
             let server_id = self.server_id.clone();
-            tokio::spawn(async move {
-                info!("Establishing connection");
-                if let Err(e) = GameServer::handle_connection(server_id, registry, stream).await {
-                    eprintln!("Error handling connection: {}", e);
+            tokio::spawn(
+                async move {
+                    info!("Establishing connection");
+                    if let Err(e) = GameServer::handle_connection(server_id, registry, stream).await
+                    {
+                        error!("Error handling connection: {}", e);
+                    }
+                    // Frees the slot for the next connection
+                    drop(permit);
                 }
-            });
+                .instrument(connection_span()),
+            );
+        }
+        drop(listener);
+        heartbeat.abort();
+        sweeper.abort();
+        if let Err(e) = self
+            .registry
+            .discovery
+            .unregister_server(&self.server_id)
+            .await
+        {
+            warn!("Failed to unregister server: {}", e);
         }
 
+        let persisted = self.registry.drain().await;
+        info!("Persisted {} active games before shutdown", persisted.len());
+        tokio::time::sleep(DRAIN_FLUSH_DELAY).await;
+
         Ok(())
     }
 
+    // Takes a connection slot, or counts the rejection when all are in use
+    fn admit_connection(&self) -> Option<OwnedSemaphorePermit> {
+        match self.connection_limit.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                warn!("Connection limit reached, rejecting connection");
+                metrics::CONNECTIONS_REJECTED.inc();
+                None
+            }
+        }
+    }
+
     async fn handle_connection(
         server_id: String,
         registry: GameRegistry,
         mut stream: TcpStream,
     ) -> anyhow::Result<()> {
         // Read the HTTP request to check for cookies before accepting WebSocket connection
-        let mut buf = [0; 8192];
-        let n = stream.peek(&mut buf).await?;
-        let data = &buf[..n];
+        let head = peek_request_head(&stream).await?;
+        let data = head.as_slice();
 
         // Extract machine ID and handle redirection
-        if let Some(target_machine_id) = extract_machine_id(data, &server_id) {
+        if let Some(target_machine_id) = registry.redirect_target(data, &server_id).await {
             info!(
                 "Redirecting WebSocket connection to machine: {}",
                 target_machine_id
             );
 
-            // Complete HTTP response with proper headers
-            let response = format!(
-                "HTTP/1.1 307 Temporary Redirect\r\n\
-             fly-replay: instance={}\r\n\
-             Content-Length: 0\r\n\
-             Connection: close\r\n\r\n",
-                target_machine_id
-            );
+            let response = registry.router.redirect_response(&target_machine_id);
 
             match stream.write_all(response.as_bytes()).await {
                 Ok(_) => {
@@ -568,108 +1621,183 @@ impl GameServer {
                 }
             }
         }
-        let ws_stream = ServerBuilder::new().accept(stream).await?;
-        let pool = establish_connection().await;
+        // Nothing a client sends may be larger than what the server sends on
+        let limits =
+            Limits::default().max_payload_len(Some(registry.board_limits.max_message_bytes));
+        let ws_stream = ServerBuilder::new().limits(limits).accept(stream).await?;
+        let pool = registry.pool.clone();
 
         let (ws_write, mut ws_read) = ws_stream.split();
 
         let ws_write = Arc::new(Mutex::new(ws_write));
 
         // Create a channel for this game connection
-        let (server_tx, mut server_rx) = tokio::sync::mpsc::channel(500);
+        let (server_tx, mut server_rx) = tokio::sync::mpsc::channel(INCOMING_CHANNEL_CAPACITY);
         let server_tx = Arc::new(server_tx);
 
-        // Keep track of the current player_id for cleanup
-        let current_player_id = Arc::new(RwLock::new(String::new()));
+        // The seat this connection holds, cleaned up when it closes. Set only once a
+        // seat is taken or resumed, so a refused request can't act in or clean up
+        // somebody else's seat.
+        let current_seat: Arc<RwLock<Option<Seat>>> = Arc::new(RwLock::new(None));
 
         // Spawn a task to handle incoming WebSocket messages
-        tokio::spawn({
-            let server_tx = server_tx.clone();
-            let current_player_id = current_player_id.clone();
-            let registry_clone = registry.clone();
-            async move {
-                while let Some(msg) = ws_read.next().await {
-                    info!("Incoming msg");
-                    let server_tx_inner = server_tx.clone();
+        tokio::spawn(
+            {
+                let server_tx = server_tx.clone();
+                let current_seat = current_seat.clone();
+                let ws_write = ws_write.clone();
+                let registry_clone = registry.clone();
+                let pool = pool.clone();
+                async move {
+                    if let Err(e) =
+                        forward_incoming(&mut ws_read, &server_tx, INCOMING_SEND_TIMEOUT).await
+                    {
+                        info!("Closing connection: {}", e);
+                        // Disconnects the client, flushing the close frame the stream
+                        // queued if the message was too large
+                        let _ = ws_write.lock().await.close().await;
+                    }
 
-                    match msg {
-                        Ok(message) => {
-                            let current_player_id = current_player_id.clone();
-                            tokio::spawn(async move {
-                                match serde_json::from_slice(message.as_payload()) {
-                                    Ok(game_msg) => {
-                                        info!("msg: {:?}", game_msg);
-                                        // Update current_player_id if this is a Play or Join message
-                                        if let GameMessage::Play { player_id, .. } = &game_msg {
-                                            *current_player_id.write().await = player_id.clone();
-                                        } else if let GameMessage::Join { player_id, .. } =
-                                            &game_msg
+                    // WebSocket connection closed - clean up the player
+                    let seat = current_seat.read().await.clone();
+                    if let Some(Seat { player_id, .. }) = seat {
+                        let game_id = registry_clone
+                            .active_players
+                            .read()
+                            .await
+                            .get(&player_id)
+                            .cloned();
+                        if let Some(game_id) = game_id {
+                            if let Some(GameState::RUNNING { .. }) =
+                                registry_clone.get_game_state(&game_id).await
+                            {
+                                // Give the player a chance to reconnect before calling it abandoned
+                                let registry = registry_clone.clone();
+                                let player_id = player_id.clone();
+                                let pool = pool.clone();
+                                tokio::spawn(
+                                    async move {
+                                        tokio::time::sleep(registry.reconnect_grace).await;
+                                        let Some(finished) = registry
+                                            .abandon_if_disconnected(&game_id, &player_id)
+                                            .await
+                                        else {
+                                            return;
+                                        };
+                                        info!("Player {} abandoned game {}", player_id, game_id);
+
+                                        if let GameState::FINISHED {
+                                            result,
+                                            board,
+                                            players,
+                                            single_bet_size,
+                                            currency,
+                                            ..
+                                        } = &finished
                                         {
-                                            *current_player_id.write().await = player_id.clone();
-                                        }
-                                        if let Err(e) = server_tx_inner.send(game_msg).await {
-                                            eprintln!("Error sending message: {}", e);
+                                            let settled = match settlement_user_ids(players) {
+                                                Ok(user_ids) => db::update_player_balances(
+                                                    &pool,
+                                                    &FinishedGame {
+                                                        game_id: &game_id,
+                                                        user_ids: &user_ids,
+                                                        result,
+                                                        single_bet_size: *single_bet_size,
+                                                        abandoned: true,
+                                                        seed_hash: &seed_hash(board.seed),
+                                                    },
+                                                    *currency,
+                                                    &registry.payout_policy,
+                                                )
+                                                .await
+                                                .map_err(|e| e.to_string()),
+                                                Err(reason) => Err(reason),
+                                            };
+                                            if let Err(e) = settled {
+                                                error!(
+                                                    "Failed to settle abandoned game {}: {}",
+                                                    game_id, e
+                                                );
+                                            }
                                         }
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Deserialization error: {}", e);
-                                    }
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            eprintln!("WebSocket error: {}", e);
-                            break;
-                        }
-                    }
-                }
-
-                // WebSocket connection closed - clean up the player
-                let player_id = current_player_id.read().await.clone();
-                if !player_id.is_empty() {
-                    let server_tx_inner = server_tx.clone();
-                    let active_players_read = registry_clone.active_players.read().await;
-                    let game_id = active_players_read.get(&player_id);
-                    if let Some(game_id) = game_id {
-                        let game_state = registry_clone.get_game_state(game_id).await;
-                        if let Some(GameState::RUNNING {
-                            game_id,
-                            players,
-                            board,
-                            single_bet_size,
-                            ..
-                        }) = game_state
-                        {
-                            let loser_idx = players.iter().position(|p| p.id == player_id).unwrap();
-                            let new_game_state = GameState::FINISHED {
-                                game_id: game_id.clone(),
-                                loser_idx,
-                                board: board.clone(),
-                                players: players.clone(),
-                                single_bet_size,
-                            };
 
-                            let game_message = GameMessage::GameUpdate(new_game_state);
+                                        let wrapper = GameMessageWrapper {
+                                            server_id: registry.server_id.clone(),
+                                            game_message: GameMessage::GameUpdate(finished),
+                                        };
+                                        let _ = registry
+                                            .publish_message(game_id.clone(), wrapper, false)
+                                            .await;
 
-                            server_tx_inner.send(game_message).await.unwrap();
-
-                            // Clean up broadcast channel since player has left
-                            registry_clone.cleanup_broadcast_channel(&game_id).await;
+                                        // Clean up broadcast channel since player has left
+                                        registry.cleanup_broadcast_channel(&game_id).await;
+                                    }
+                                    .in_current_span(),
+                                );
+                            }
                         }
+                        info!("Cleaning up player: {}", player_id);
+                        registry_clone.cleanup_player(&player_id).await;
                     }
-                    drop(active_players_read);
-                    info!("Cleaning up player: {}", player_id);
-                    registry_clone.cleanup_player(&player_id).await;
                 }
             }
-        });
+            .in_current_span(),
+        );
         // Process game messages
+        let mut span_ids = SpanIds::default();
         while let Some(message) = server_rx.recv().await {
+            metrics::record_websocket_message(message.message_type());
+            span_ids.record(&Span::current(), &message);
+            let seat = current_seat.read().await.clone();
+            if message.acts_in_game() {
+                if let Err(reason) = authorize_seat(seat.as_ref(), &message) {
+                    // Games held elsewhere get pointed there instead
+                    let game_id = message.game_id().unwrap_or_default();
+                    let reply = if registry.games.read().await.contains_key(game_id) {
+                        GameMessage::from(reason)
+                    } else {
+                        registry.missing_game_reply(game_id).await
+                    };
+                    ws_write
+                        .lock()
+                        .await
+                        .send(Message::binary(serde_json::to_vec(&reply)?))
+                        .await?;
+                    continue;
+                }
+            }
+            let seated = seat.as_ref().map_or("", |seat| seat.player_id.as_str());
             match message {
-                GameMessage::Ping { game_id, player_id } => {
-                    info!("Pong sent from {}", server_id);
-                    info!("Pong set from {}", server_id);
-                    if let Some(game_id) = &game_id {
+                GameMessage::Ping {
+                    game_id,
+                    player_id,
+                    resume_token,
+                } => {
+                    debug!("Pong sent from {}", server_id);
+                    // A player who isn't in a game yet, or is already seated in this one,
+                    // just gets the pong
+                    let resuming = game_id.filter(|game_id| {
+                        seat.as_ref().is_none_or(|seat| {
+                            seat.game_id != *game_id
+                                || player_id.as_ref().is_some_and(|id| *id != seat.player_id)
+                        })
+                    });
+                    if let Some(game_id) = resuming {
+                        let player_id = player_id.unwrap_or_default();
+                        if let Err(reason) = registry
+                            .check_resume_token(&game_id, &player_id, resume_token.as_deref())
+                            .await
+                        {
+                            warn!("Refused to resume player {} in game {}", player_id, game_id);
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&GameMessage::from(
+                                    reason,
+                                ))?))
+                                .await?;
+                            continue;
+                        }
                         registry
                             .subscribe_to_channel(
                                 server_id.clone(),
@@ -677,20 +1805,23 @@ impl GameServer {
                                 ws_write.clone(),
                             )
                             .await?;
-                    }
 
-                    if let Some(player_id) = player_id {
                         let mut active_players_write = registry.active_players.write().await;
-                        active_players_write.insert(player_id, game_id.unwrap());
+                        active_players_write.insert(player_id.clone(), game_id.clone());
+                        drop(active_players_write);
+                        *current_seat.write().await = Some(Seat { game_id, player_id });
                     }
-                    let response = "Pong".to_string();
+                    let response = GameMessage::Pong {
+                        server_id: server_id.clone(),
+                        resume_token: None,
+                    };
                     if let Err(e) = ws_write
                         .lock()
                         .await
                         .send(Message::binary(serde_json::to_vec(&response)?))
                         .await
                     {
-                        eprintln!("Error sending GameUpdate message: {}", e);
+                        error!("Error sending Pong message: {}", e);
                     }
                 }
                 GameMessage::Play {
@@ -701,14 +1832,35 @@ impl GameServer {
                     bombs,
                     grid,
                     is_creating_room,
+                    layout,
+                    lives,
+                    currency,
                 } => {
                     info!("Play request at machine: {}", server_id);
-                    let active_players_read = registry.active_players.read().await;
-
-                    if active_players_read.contains_key(&player_id) {
-                        info!("Player is already waiting for a game");
+                    let validated = parse_player_id(&player_id)
+                        .and_then(|_| validate_name(&name))
+                        .and_then(|_| validate_min_players(min_players))
+                        .and_then(|_| validate_lives(lives))
+                        .and_then(|_| validate_currency(currency))
+                        .and_then(|_| registry.allowed_grids.validate(grid))
+                        .and_then(|_| registry.board_limits.validate(grid, bombs))
+                        .and_then(|_| normalize_bet_size(single_bet_size));
+                    let single_bet_size = match validated {
+                        Ok(single_bet_size) => single_bet_size,
+                        Err(reason) => {
+                            let response = GameMessage::error(ErrorCode::InvalidRequest, reason);
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&response)?))
+                                .await?;
+                            continue;
+                        }
+                    };
+                    if registry.player_in_game(&player_id).await? {
+                        info!("Player is already in a game");
                         let response =
-                            GameMessage::Error("You are already waiting for a game".to_string());
+                            GameMessage::error(ErrorCode::AlreadyInGame, ALREADY_IN_GAME);
                         ws_write
                             .lock()
                             .await
@@ -716,7 +1868,6 @@ impl GameServer {
                             .await?;
                         continue;
                     }
-                    drop(active_players_read);
 
                     let play_request = PlayRequest {
                         player_id: player_id.clone(),
@@ -726,11 +1877,14 @@ impl GameServer {
                         bombs,
                         grid,
                         is_creating_room,
+                        layout,
+                        lives,
+                        currency,
                     };
                     // Try to find or create a game using discovery service
                     match registry.handle_play_message(play_request).await {
                         Ok(Some(game_state)) => {
-                            info!("created or joined on this server");
+                            debug!("created or joined on this server");
                             // Game was created or joined on this server
                             let game_id = match &game_state {
                                 GameState::WAITING { game_id, .. } => game_id.clone(),
@@ -750,9 +1904,24 @@ impl GameServer {
                             let mut game_channels_write = registry.game_channels.write().await;
                             game_channels_write.insert(game_id.clone(), server_tx.clone());
                             drop(game_channels_write);
+                            *current_seat.write().await = Some(Seat {
+                                game_id: game_id.clone(),
+                                player_id: player_id.clone(),
+                            });
+
+                            let resume = GameMessage::Pong {
+                                server_id: server_id.clone(),
+                                resume_token: Some(
+                                    registry.issue_resume_token(&game_id, &player_id).await,
+                                ),
+                            };
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&resume)?))
+                                .await?;
 
                             let game_message = GameMessage::GameUpdate(game_state.clone());
-                            info!("Game Message: {:?}", game_message);
                             let wrapper = GameMessageWrapper {
                                 server_id: server_id.clone(),
                                 game_message,
@@ -761,32 +1930,29 @@ impl GameServer {
                             registry
                                 .publish_message(game_id.clone(), wrapper, false)
                                 .await?;
-
-                            let mut active_players_write = registry.active_players.write().await;
-                            active_players_write.insert(player_id, game_id);
                         }
                         Ok(None) => {
                             // Game exists on another server, send redirect message
                             if let Some(session) = registry
                                 .discovery
-                                .find_game_session(single_bet_size, min_players, grid)
+                                .find_game_session(currency, single_bet_size, min_players, grid)
                                 .await?
                             {
                                 let redirect = GameMessage::RedirectToServer {
                                     game_id: session.game_id,
                                     machine_id: session.server_id,
                                 };
-                                info!("--------------------------------");
                                 info!("Redirecting to server: {:?}", redirect);
-                                info!("--------------------------------");
                                 ws_write
                                     .lock()
                                     .await
                                     .send(Message::binary(serde_json::to_vec(&redirect)?))
                                     .await?;
                             } else {
-                                let response =
-                                    GameMessage::Error("No suitable game found".to_string());
+                                let response = GameMessage::error(
+                                    ErrorCode::GameNotFound,
+                                    "No suitable game found",
+                                );
                                 ws_write
                                     .lock()
                                     .await
@@ -795,8 +1961,13 @@ impl GameServer {
                             }
                         }
                         Err(e) => {
-                            let response =
-                                GameMessage::Error(format!("Error handling play request: {}", e));
+                            let response = match e.downcast::<ProtocolError>() {
+                                Ok(error) => GameMessage::from(error),
+                                Err(e) => GameMessage::error(
+                                    ErrorCode::Internal,
+                                    format!("Error handling play request: {}", e),
+                                ),
+                            };
                             ws_write
                                 .lock()
                                 .await
@@ -811,64 +1982,43 @@ impl GameServer {
                     name,
                 } => {
                     info!("Join request at machine: {}", server_id);
-                    info!("Request to join:: {:?} game", game_id);
-
-                    // let games_read = registry.games.read().await;
-                    // info!("Game keys: {:?}", games_read.keys().len());
-                    let game_state = registry.get_game_state(&game_id).await;
-                    // let game_state = registry.get_game_state(&game_id).await;
-                    info!("Game state: {:?}", game_state);
-                    info!("About to join game");
-                    if let Some(GameState::WAITING {
-                        game_id,
-                        creator,
-                        board,
-                        single_bet_size,
-                        min_players,
-                        players,
-                    }) = game_state
+                    if let Err(reason) =
+                        parse_player_id(&player_id).and_then(|_| validate_name(&name))
                     {
-                        info!("Inside waiting state");
-                        let new_player = Player::new(player_id.clone(), name.clone());
-                        let mut players = players.clone();
-                        players.push(new_player);
-
-                        // Update player count in Redis
-                        info!("Updating player count in Redis");
-                        registry
-                            .discovery
-                            .update_player_count(&game_id, players.len() as u32)
+                        ws_write
+                            .lock()
+                            .await
+                            .send(Message::binary(serde_json::to_vec(&GameMessage::error(
+                                ErrorCode::InvalidRequest,
+                                reason,
+                            ))?))
                             .await?;
+                        continue;
+                    }
 
-                        let new_game_state = if players.len() < min_players as usize {
-                            GameState::WAITING {
-                                game_id: game_id.clone(),
-                                creator: creator.clone(),
-                                board: board.clone(),
-                                single_bet_size,
-                                min_players,
-                                players,
-                            }
-                        } else {
-                            // Game is transitioning to RUNNING state
-                            // Remove from discovery since it's no longer accepting players
-                            registry.discovery.remove_game_session(&game_id).await?;
-
-                            GameState::RUNNING {
-                                game_id: game_id.clone(),
-                                players,
-                                board: board.clone(),
-                                turn_idx: 0,
-                                single_bet_size,
-                                locks: None,
-                            }
-                        };
-
-                        let mut games_write = registry.games.write().await;
-
-                        games_write.insert(game_id.clone(), new_game_state.clone());
-
-                        drop(games_write);
+                    let joined = match registry.join_lobby(&game_id, &player_id, &name).await {
+                        Ok(joined) => joined,
+                        Err(e) => {
+                            let response = match e.downcast::<ProtocolError>() {
+                                Ok(error) => GameMessage::from(error),
+                                Err(e) => GameMessage::error(
+                                    ErrorCode::Internal,
+                                    format!("Error handling join request: {}", e),
+                                ),
+                            };
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&response)?))
+                                .await?;
+                            continue;
+                        }
+                    };
+                    if let Some(new_game_state) = joined {
+                        *current_seat.write().await = Some(Seat {
+                            game_id: game_id.clone(),
+                            player_id: player_id.clone(),
+                        });
 
                         registry
                             .subscribe_to_channel(
@@ -878,19 +2028,29 @@ impl GameServer {
                             )
                             .await?;
 
+                        let resume = GameMessage::Pong {
+                            server_id: server_id.clone(),
+                            resume_token: Some(
+                                registry.issue_resume_token(&game_id, &player_id).await,
+                            ),
+                        };
+                        ws_write
+                            .lock()
+                            .await
+                            .send(Message::binary(serde_json::to_vec(&resume)?))
+                            .await?;
+
                         let game_message = GameMessage::GameUpdate(new_game_state.clone());
 
                         let wrapper = GameMessageWrapper {
                             server_id: server_id.clone(),
                             game_message,
                         };
-                        info!("Publishing message to game");
+                        debug!("Publishing message to game");
                         registry
                             .publish_message(game_id.clone(), wrapper, false)
                             .await?;
-                        let mut active_players_write = registry.active_players.write().await;
-                        active_players_write.insert(player_id, game_id);
-                        info!("Player added to active players");
+                        debug!("Player added to active players");
                     } else {
                         let game_session =
                             registry.discovery.find_game_session_by_id(&game_id).await?;
@@ -906,12 +2066,13 @@ impl GameServer {
                                 .send(Message::binary(serde_json::to_vec(&redirect)?))
                                 .await
                             {
-                                eprintln!("Failed to send error message to the client:: {:?}", err);
+                                error!("Failed to send error message to the client: {:?}", err);
                             }
                         } else {
                             info!("Game is not accepting players");
-                            let response = GameMessage::Error(
-                                "this game is not accepting players".to_string(),
+                            let response = GameMessage::error(
+                                ErrorCode::GameFull,
+                                "this game is not accepting players",
                             );
                             if let Err(err) = ws_write
                                 .lock()
@@ -919,13 +2080,24 @@ impl GameServer {
                                 .send(Message::binary(serde_json::to_vec(&response)?))
                                 .await
                             {
-                                eprintln!("Failed to send error message to the client:: {:?}", err);
+                                error!("Failed to send error message to the client: {:?}", err);
                             }
                         }
                     }
                 }
                 GameMessage::Stop { game_id, abort } => {
                     let mut games_write = registry.games.write().await;
+
+                    if !games_write.contains_key(&game_id) {
+                        drop(games_write);
+                        let reply = registry.missing_game_reply(&game_id).await;
+                        ws_write
+                            .lock()
+                            .await
+                            .send(Message::binary(serde_json::to_vec(&reply)?))
+                            .await?;
+                        continue;
+                    }
                     if !abort {
                         // Meaning other players won
                         if let Some(game_state) = games_write.get_mut(&game_id) {
@@ -934,25 +2106,24 @@ impl GameServer {
                                 board,
                                 turn_idx,
                                 single_bet_size,
+                                lives,
+                                currency,
                                 ..
                             } = game_state
                             {
-                                info!("Hello about to stop the game**************************************");
                                 let loser = turn_idx;
                                 let new_game_state = GameState::FINISHED {
                                     game_id: game_id.clone(),
-                                    loser_idx: *loser,
-                                    board: board.clone(),
+                                    result: GameResult::Loser(*loser),
+                                    board: board.revealed_clone(),
                                     players: players.clone(),
                                     single_bet_size: *single_bet_size,
+                                    lives: *lives,
+                                    currency: *currency,
                                 };
                                 // remove players from active state
-                                let mut active_players_write =
-                                    registry.active_players.write().await;
-
                                 let ids = players.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
-
-                                active_players_write.retain(|x, _| !ids.contains(x));
+                                registry.release_players(&ids).await;
 
                                 // Update discovery service
                                 registry
@@ -960,23 +2131,32 @@ impl GameServer {
                                     .await;
 
                                 // UPDATING THE DB AS WELL HERE
-                                let winning_amount =
-                                    *single_bet_size / ((players.clone().len() - 1) as f64);
-
-                                let user_ids: Vec<i32> = players
-                                    .iter()
-                                    .map(|p| p.id.parse::<i32>().unwrap())
-                                    .collect();
-                                db::update_player_balances(
-                                    &pool,
-                                    &user_ids,
-                                    *loser,
-                                    *single_bet_size,
-                                    winning_amount,
-                                    Currency::SOL,
-                                )
-                                .await?;
+                                match settlement_user_ids(players) {
+                                    Ok(user_ids) => {
+                                        db::update_player_balances(
+                                            &pool,
+                                            &FinishedGame {
+                                                game_id: &game_id,
+                                                user_ids: &user_ids,
+                                                result: &GameResult::Loser(*loser),
+                                                single_bet_size: *single_bet_size,
+                                                abandoned: false,
+                                                seed_hash: &seed_hash(board.seed),
+                                            },
+                                            *currency,
+                                            &registry.payout_policy,
+                                        )
+                                        .await?
+                                    }
+                                    Err(reason) => {
+                                        error!(
+                                            "Skipping settlement of game {}: {}",
+                                            game_id, reason
+                                        )
+                                    }
+                                }
                                 *game_state = new_game_state;
+                                metrics::GAMES_COMPLETED.inc();
                                 let game_message = GameMessage::GameUpdate(game_state.clone());
 
                                 let wrapper = GameMessageWrapper {
@@ -994,18 +2174,14 @@ impl GameServer {
                         if let Some(game_state) = games_write.get_mut(&game_id) {
                             match game_state {
                                 GameState::RUNNING { players, .. } => {
-                                    let mut active_players_write =
-                                        registry.active_players.write().await;
                                     let ids =
                                         players.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
-                                    active_players_write.retain(|x, _| !ids.contains(x));
+                                    registry.release_players(&ids).await;
                                 }
                                 GameState::WAITING { players, .. } => {
-                                    let mut active_players_write =
-                                        registry.active_players.write().await;
                                     let ids =
                                         players.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
-                                    active_players_write.retain(|x, _| !ids.contains(x));
+                                    registry.release_players(&ids).await;
                                 }
                                 _ => {
                                     // Do nothing
@@ -1037,10 +2213,79 @@ impl GameServer {
                         }
                     }
                 }
+                GameMessage::SeedContribution {
+                    game_id,
+                    player_id,
+                    nonce,
+                } => {
+                    let mut games_write = registry.games.write().await;
+                    let Some(game_state) = games_write.get_mut(&game_id) else {
+                        drop(games_write);
+                        let reply = registry.missing_game_reply(&game_id).await;
+                        ws_write
+                            .lock()
+                            .await
+                            .send(Message::binary(serde_json::to_vec(&reply)?))
+                            .await?;
+                        continue;
+                    };
+                    if let Err(reason) = game_state.add_seed_contribution(&player_id, nonce) {
+                        drop(games_write);
+                        ws_write
+                            .lock()
+                            .await
+                            .send(Message::binary(serde_json::to_vec(&GameMessage::from(
+                                reason,
+                            ))?))
+                            .await?;
+                        continue;
+                    }
+                    let seeded = game_state.is_full() && game_state.seed_complete();
+                    let game_message = GameMessage::GameUpdate(game_state.clone());
+                    drop(games_write);
+
+                    // The last contribution a full lobby waited for starts the game
+                    if !(seeded && registry.start_lobby(&game_id).await) {
+                        let wrapper = GameMessageWrapper {
+                            server_id: server_id.clone(),
+                            game_message,
+                        };
+                        registry
+                            .publish_message(game_id.clone(), wrapper, false)
+                            .await?;
+                    }
+                }
                 GameMessage::MakeMove { game_id, x, y } => {
                     let mut games_write = registry.games.write().await;
 
+                    if !games_write.contains_key(&game_id) {
+                        drop(games_write);
+                        let reply = registry.missing_game_reply(&game_id).await;
+                        ws_write
+                            .lock()
+                            .await
+                            .send(Message::binary(serde_json::to_vec(&reply)?))
+                            .await?;
+                        continue;
+                    }
+
                     if let Some(game_state) = games_write.get_mut(&game_id) {
+                        // Games that aren't running get the reply further down
+                        let turn = match game_state {
+                            GameState::RUNNING { .. } => game_state.check_turn(seated),
+                            _ => Ok(()),
+                        };
+                        if let Err(reason) = turn {
+                            drop(games_write);
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&GameMessage::from(
+                                    reason,
+                                ))?))
+                                .await?;
+                            continue;
+                        }
                         match game_state {
                             GameState::RUNNING {
                                 players,
@@ -1048,24 +2293,54 @@ impl GameServer {
                                 turn_idx,
                                 single_bet_size,
                                 locks,
+                                lives,
+                                lives_left,
+                                currency,
                                 ..
                             } => {
-                                let game_ended = board.mine(x, y);
+                                // Mining a revealed bomb again would cost another life
+                                if let Err(reason) = board.check_hidden(x, y) {
+                                    drop(games_write);
+                                    ws_write
+                                        .lock()
+                                        .await
+                                        .send(Message::binary(serde_json::to_vec(
+                                            &GameMessage::error(ErrorCode::InvalidMove, reason),
+                                        )?))
+                                        .await?;
+                                    continue;
+                                }
+                                let hit_bomb = board.mine(x, y);
+                                let result = if hit_bomb
+                                    && lose_life(lives_left, *lives, players.len(), *turn_idx)
+                                {
+                                    Some(GameResult::Loser(*turn_idx))
+                                } else if board.is_cleared() {
+                                    // Only bombs are left to reveal, so nobody lost: a draw
+                                    Some(GameResult::Draw)
+                                } else {
+                                    None
+                                };
 
                                 // Clone everything we need before any modifications
                                 let players_clone = players.clone();
                                 let turn_idx_clone = *turn_idx;
                                 let single_bet_size_clone = *single_bet_size;
+                                let currency = *currency;
 
-                                if game_ended {
+                                if let Some(result) = result {
                                     let new_game_state = GameState::FINISHED {
                                         game_id: game_id.clone(),
-                                        loser_idx: turn_idx_clone,
-                                        board: board.clone(),
+                                        result: result.clone(),
+                                        board: board.revealed_clone(),
                                         players: players_clone.clone(),
                                         single_bet_size: single_bet_size_clone,
+                                        lives: *lives,
+                                        currency,
                                     };
+                                    let board_seed_hash = seed_hash(board.seed);
                                     *game_state = new_game_state.clone();
+                                    metrics::GAMES_COMPLETED.inc();
 
                                     // Record move and commit game on blockchain
                                     let registry_clone = registry.clone();
@@ -1073,55 +2348,49 @@ impl GameServer {
                                     let player_name = players_clone[turn_idx_clone].name.clone();
                                     let x_clone = x;
                                     let y_clone = y;
-                                    tokio::spawn(async move {
-                                        // First record the move
-                                        if let Ok(tx_hash) = registry_clone
-                                            .xplode_moves
-                                            .record_move(
-                                                &game_id_clone,
-                                                &player_name,
-                                                x_clone,
-                                                y_clone,
-                                            )
-                                            .await
-                                        {
-                                            let update = GameMessage::BlockchainUpdate {
-                                                game_id: game_id_clone.clone(),
-                                                update_type: BlockchainUpdateType::MoveRecorded,
-                                                transaction_hash: tx_hash,
-                                            };
-                                            let wrapper = GameMessageWrapper {
-                                                server_id: registry_clone.server_id.clone(),
-                                                game_message: update,
-                                            };
-                                            let _ = registry_clone
-                                                .publish_message(
-                                                    game_id_clone.clone(),
-                                                    wrapper,
-                                                    false,
+                                    tokio::spawn(
+                                        async move {
+                                            // First record the move
+                                            if let Ok(tx_hash) = registry_clone
+                                                .xplode_moves
+                                                .record_move(
+                                                    &game_id_clone,
+                                                    &player_name,
+                                                    x_clone,
+                                                    y_clone,
                                                 )
-                                                .await;
+                                                .await
+                                            {
+                                                let update = GameMessage::BlockchainUpdate {
+                                                    game_id: game_id_clone.clone(),
+                                                    update_type: BlockchainUpdateType::MoveRecorded,
+                                                    transaction_hash: tx_hash,
+                                                };
+                                                let wrapper = GameMessageWrapper {
+                                                    server_id: registry_clone.server_id.clone(),
+                                                    game_message: update,
+                                                };
+                                                let _ = registry_clone
+                                                    .publish_message(
+                                                        game_id_clone.clone(),
+                                                        wrapper,
+                                                        false,
+                                                    )
+                                                    .await;
+                                            }
                                         }
-                                    });
+                                        .in_current_span(),
+                                    );
 
                                     // Async DB operations
-                                    let winning_amount =
-                                        single_bet_size_clone / ((players_clone.len() - 1) as f64);
-                                    let user_ids: Vec<i32> = players_clone
-                                        .iter()
-                                        .map(|p| p.id.parse::<i32>().unwrap())
-                                        .collect();
+                                    let user_ids = settlement_user_ids(&players_clone);
 
                                     // remove players from active state
-                                    let mut active_players_write =
-                                        registry.active_players.write().await;
-
                                     let ids = players_clone
                                         .iter()
                                         .map(|p| p.id.clone())
                                         .collect::<Vec<_>>();
-
-                                    active_players_write.retain(|x, _| !ids.contains(x));
+                                    registry.release_players(&ids).await;
 
                                     // Update discovery service
                                     registry
@@ -1129,21 +2398,41 @@ impl GameServer {
                                         .await;
 
                                     let pool_clone = pool.clone();
-                                    tokio::spawn(async move {
-                                        let _ = db::update_player_balances(
-                                            &pool_clone,
-                                            &user_ids,
-                                            turn_idx_clone,
-                                            single_bet_size_clone,
-                                            winning_amount,
-                                            Currency::SOL,
-                                        )
-                                        .await;
-                                    });
+                                    let game_id_clone = game_id.clone();
+                                    let payout_policy = registry.payout_policy;
+                                    tokio::spawn(
+                                        async move {
+                                            let user_ids = match user_ids {
+                                                Ok(user_ids) => user_ids,
+                                                Err(reason) => {
+                                                    error!(
+                                                        "Skipping settlement of game {}: {}",
+                                                        game_id_clone, reason
+                                                    );
+                                                    return;
+                                                }
+                                            };
+                                            let _ = db::update_player_balances(
+                                                &pool_clone,
+                                                &FinishedGame {
+                                                    game_id: &game_id_clone,
+                                                    user_ids: &user_ids,
+                                                    result: &result,
+                                                    single_bet_size: single_bet_size_clone,
+                                                    abandoned: false,
+                                                    seed_hash: &board_seed_hash,
+                                                },
+                                                currency,
+                                                &payout_policy,
+                                            )
+                                            .await;
+                                        }
+                                        .in_current_span(),
+                                    );
                                 } else {
                                     // Not needed here as they will be updated in lock complete
                                     // *turn_idx = (*turn_idx + 1) % players.len();
-                                    info!("Setting locks to None, befor locks value: {:?}", *locks);
+                                    debug!("Clearing locks: {:?}", *locks);
                                     *locks = None;
 
                                     // Record move on blockchain
@@ -1152,31 +2441,34 @@ impl GameServer {
                                     let player_name = players[turn_idx_clone].name.clone();
                                     let x_clone = x;
                                     let y_clone = y;
-                                    tokio::spawn(async move {
-                                        if let Ok(tx_hash) = registry_clone
-                                            .xplode_moves
-                                            .record_move(
-                                                &game_id_clone,
-                                                &player_name,
-                                                x_clone,
-                                                y_clone,
-                                            )
-                                            .await
-                                        {
-                                            let update = GameMessage::BlockchainUpdate {
-                                                game_id: game_id_clone.clone(),
-                                                update_type: BlockchainUpdateType::MoveRecorded,
-                                                transaction_hash: tx_hash,
-                                            };
-                                            let wrapper = GameMessageWrapper {
-                                                server_id: registry_clone.server_id.clone(),
-                                                game_message: update,
-                                            };
-                                            let _ = registry_clone
-                                                .publish_message(game_id_clone, wrapper, false)
-                                                .await;
+                                    tokio::spawn(
+                                        async move {
+                                            if let Ok(tx_hash) = registry_clone
+                                                .xplode_moves
+                                                .record_move(
+                                                    &game_id_clone,
+                                                    &player_name,
+                                                    x_clone,
+                                                    y_clone,
+                                                )
+                                                .await
+                                            {
+                                                let update = GameMessage::BlockchainUpdate {
+                                                    game_id: game_id_clone.clone(),
+                                                    update_type: BlockchainUpdateType::MoveRecorded,
+                                                    transaction_hash: tx_hash,
+                                                };
+                                                let wrapper = GameMessageWrapper {
+                                                    server_id: registry_clone.server_id.clone(),
+                                                    game_message: update,
+                                                };
+                                                let _ = registry_clone
+                                                    .publish_message(game_id_clone, wrapper, false)
+                                                    .await;
+                                            }
                                         }
-                                    });
+                                        .in_current_span(),
+                                    );
                                 }
 
                                 // Broadcast the update for both cases
@@ -1196,8 +2488,9 @@ impl GameServer {
                                     .lock()
                                     .await
                                     .send(Message::binary(serde_json::to_vec(
-                                        &GameMessage::Error(
-                                            "Cannot make move in current game state".to_string(),
+                                        &GameMessage::error(
+                                            ErrorCode::InvalidGameState,
+                                            "Cannot make move in current game state",
                                         ),
                                     )?))
                                     .await?;
@@ -1205,14 +2498,43 @@ impl GameServer {
                         }
                     }
                 }
-                GameMessage::Lock { x, y, game_id } => {
+                GameMessage::Lock { x, y, game_id, .. } => {
                     let mut games_write = registry.games.write().await;
 
+                    if !games_write.contains_key(&game_id) {
+                        drop(games_write);
+                        let reply = registry.missing_game_reply(&game_id).await;
+                        ws_write
+                            .lock()
+                            .await
+                            .send(Message::binary(serde_json::to_vec(&reply)?))
+                            .await?;
+                        continue;
+                    }
+
                     if let Some(game_state) = games_write.get_mut(&game_id) {
-                        if let GameState::RUNNING { locks, .. } = game_state {
-                            let locks = locks.get_or_insert_with(Vec::new);
-                            locks.push((x, y));
-                            // Don't save to Redis for lock updates - they're temporary
+                        if let Err(reason) = game_state.check_turn(seated) {
+                            drop(games_write);
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&GameMessage::from(
+                                    reason,
+                                ))?))
+                                .await?;
+                            continue;
+                        }
+                        // Don't save to Redis for lock updates - they're temporary
+                        if let Err(reason) = game_state.add_lock(x, y) {
+                            drop(games_write);
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&GameMessage::from(
+                                    reason,
+                                ))?))
+                                .await?;
+                            continue;
                         }
 
                         // Just broadcast the update
@@ -1227,15 +2549,62 @@ impl GameServer {
                             .await?;
                     }
                 }
-                GameMessage::LockComplete { game_id } => {
+                GameMessage::LockComplete { game_id, .. } => {
                     let mut games_write = registry.games.write().await;
 
+                    if !games_write.contains_key(&game_id) {
+                        drop(games_write);
+                        let reply = registry.missing_game_reply(&game_id).await;
+                        ws_write
+                            .lock()
+                            .await
+                            .send(Message::binary(serde_json::to_vec(&reply)?))
+                            .await?;
+                        continue;
+                    }
+
                     if let Some(game_state) = games_write.get_mut(&game_id) {
-                        if let GameState::RUNNING {
-                            turn_idx, players, ..
-                        } = game_state
-                        {
-                            *turn_idx = (*turn_idx + 1) % players.len();
+                        if let Err(reason) = game_state.check_turn(seated) {
+                            drop(games_write);
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&GameMessage::from(
+                                    reason,
+                                ))?))
+                                .await?;
+                            continue;
+                        }
+                        game_state.advance_turn();
+
+                        let game_message = GameMessage::GameUpdate(game_state.clone());
+                        let wrapper = GameMessageWrapper {
+                            server_id: server_id.clone(),
+                            game_message,
+                        };
+
+                        registry
+                            .publish_message(game_id.clone(), wrapper.clone(), false)
+                            .await?;
+                    }
+                }
+                GameMessage::Flag { game_id, x, y, .. } => {
+                    let mut games_write = registry.games.write().await;
+
+                    if let Some(game_state) = games_write.get_mut(&game_id) {
+                        let flagged = game_state
+                            .check_turn(seated)
+                            .and_then(|_| game_state.toggle_flag(x, y));
+                        if let Err(reason) = flagged {
+                            drop(games_write);
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&GameMessage::from(
+                                    reason,
+                                ))?))
+                                .await?;
+                            continue;
                         }
 
                         let game_message = GameMessage::GameUpdate(game_state.clone());
@@ -1254,9 +2623,7 @@ impl GameServer {
                     game_id,
                     requester_id,
                 } => {
-                    info!("--------------------------------");
-                    info!("Rematch request received");
-                    info!("--------------------------------");
+                    info!("Rematch requested by {}", requester_id);
                     let mut games_write = registry.games.write().await;
                     if let Some(game_state) = games_write.get_mut(&game_id) {
                         if let GameState::FINISHED {
@@ -1264,12 +2631,12 @@ impl GameServer {
                             board,
                             players,
                             single_bet_size,
+                            lives,
+                            currency,
                             ..
                         } = game_state
                         {
-                            let grid = board.n;
-                            let bombs = board.bomb_coordinates.len();
-                            let new_board = Board::new(grid, bombs);
+                            let new_board = rematch_board(board);
 
                             let (index, _) = players
                                 .iter()
@@ -1285,10 +2652,32 @@ impl GameServer {
                                 board: new_board,
                                 single_bet_size: *single_bet_size,
                                 accepted: rematch_acceptants,
+                                lives: *lives,
+                                currency: *currency,
                             };
 
-                            let mut active_players = registry.active_players.write().await;
-                            active_players.insert(requester_id.clone(), game_id.clone());
+                            if !registry.claim_player(&requester_id, game_id).await? {
+                                let response =
+                                    GameMessage::error(ErrorCode::AlreadyInGame, ALREADY_IN_GAME);
+                                ws_write
+                                    .lock()
+                                    .await
+                                    .send(Message::binary(serde_json::to_vec(&response)?))
+                                    .await?;
+                                continue;
+                            }
+                            // Finishing the game revoked the old token
+                            let resume = GameMessage::Pong {
+                                server_id: server_id.clone(),
+                                resume_token: Some(
+                                    registry.issue_resume_token(game_id, &requester_id).await,
+                                ),
+                            };
+                            ws_write
+                                .lock()
+                                .await
+                                .send(Message::binary(serde_json::to_vec(&resume)?))
+                                .await?;
 
                             let game_message = GameMessage::RematchRequest {
                                 game_id: game_id.clone(),
@@ -1322,6 +2711,8 @@ impl GameServer {
                             board,
                             single_bet_size,
                             accepted,
+                            lives,
+                            currency,
                             ..
                         } = game_state
                         {
@@ -1332,11 +2723,31 @@ impl GameServer {
                                     .find(|(_, p)| *p.id == player_id)
                                     .expect("Failed to find player id in player array");
 
+                                if !registry.claim_player(&player_id, game_id).await? {
+                                    let response = GameMessage::error(
+                                        ErrorCode::AlreadyInGame,
+                                        ALREADY_IN_GAME,
+                                    );
+                                    ws_write
+                                        .lock()
+                                        .await
+                                        .send(Message::binary(serde_json::to_vec(&response)?))
+                                        .await?;
+                                    continue;
+                                }
+                                let resume = GameMessage::Pong {
+                                    server_id: server_id.clone(),
+                                    resume_token: Some(
+                                        registry.issue_resume_token(game_id, &player_id).await,
+                                    ),
+                                };
+                                ws_write
+                                    .lock()
+                                    .await
+                                    .send(Message::binary(serde_json::to_vec(&resume)?))
+                                    .await?;
                                 accepted[index] = 1;
 
-                                let mut active_players = registry.active_players.write().await;
-                                active_players.insert(player_id.clone(), game_id.clone());
-
                                 if accepted.iter().all(|&x| x == 1) {
                                     let new_game_state = GameState::RUNNING {
                                         game_id: game_id.clone(),
@@ -1345,6 +2756,9 @@ impl GameServer {
                                         turn_idx: 0,
                                         single_bet_size: *single_bet_size,
                                         locks: None,
+                                        lives: *lives,
+                                        lives_left: vec![*lives; players.len()],
+                                        currency: *currency,
                                     };
 
                                     let game_message =
@@ -1357,12 +2771,12 @@ impl GameServer {
                                     registry
                                         .publish_message(game_id.clone(), wrapper.clone(), false)
                                         .await?;
+                                    registry.game_started(&new_game_state, Instant::now()).await;
                                     *game_state = new_game_state.clone();
                                 }
                             } else {
-                                let mut active_players = registry.active_players.write().await;
-                                active_players
-                                    .retain(|p, _| !players.iter().any(|player| player.id == *p));
+                                let ids = players.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+                                registry.release_players(&ids).await;
                                 let new_game_state = GameState::RematchRejected {
                                     game_id: game_id.clone(),
                                 };
@@ -1412,7 +2826,6 @@ impl GameServer {
                         server_id: server_id.clone(),
                         game_message,
                     };
-                    info!("Inside game update");
                     match msg {
                         GameState::RUNNING { game_id, .. } => {
                             registry
@@ -1421,9 +2834,11 @@ impl GameServer {
                         }
                         GameState::FINISHED {
                             game_id,
-                            loser_idx,
+                            result,
+                            board,
                             players,
                             single_bet_size,
+                            currency,
                             ..
                         } => {
                             registry
@@ -1431,27 +2846,30 @@ impl GameServer {
                                 .await?;
 
                             // remove players from active state
-                            let mut active_players_write = registry.active_players.write().await;
-
                             let ids = players.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
-
-                            active_players_write.retain(|x, _| !ids.contains(x));
+                            registry.release_players(&ids).await;
                             // Update the db
-                            let winning_amount = single_bet_size / ((players.len() - 1) as f64);
-
-                            let user_ids: Vec<i32> = players
-                                .iter()
-                                .map(|p| p.id.parse::<i32>().unwrap())
-                                .collect();
-                            db::update_player_balances(
-                                &pool,
-                                &user_ids,
-                                loser_idx,
-                                single_bet_size,
-                                winning_amount,
-                                Currency::SOL,
-                            )
-                            .await?;
+                            match settlement_user_ids(&players) {
+                                Ok(user_ids) => {
+                                    db::update_player_balances(
+                                        &pool,
+                                        &FinishedGame {
+                                            game_id: &game_id,
+                                            user_ids: &user_ids,
+                                            result: &result,
+                                            single_bet_size,
+                                            abandoned: false,
+                                            seed_hash: &seed_hash(board.seed),
+                                        },
+                                        currency,
+                                        &registry.payout_policy,
+                                    )
+                                    .await?
+                                }
+                                Err(reason) => {
+                                    error!("Skipping settlement of game {}: {}", game_id, reason)
+                                }
+                            }
                         }
                         GameState::RematchRejected { game_id } => {
                             registry
@@ -1488,15 +2906,15 @@ impl GameServer {
                     transaction_hash,
                 } => {
                     // Implementation for handling blockchain update
-                    info!("Handling blockchain update");
-                    info!("Game ID: {}", game_id);
-                    info!("Update Type: {:?}", update_type);
-                    info!("Transaction Hash: {}", transaction_hash);
+                    info!(
+                        "Blockchain update {:?} for game {}: {}",
+                        update_type, game_id, transaction_hash
+                    );
 
                     // Placeholder for actual blockchain update logic
                     // This is a placeholder and should be replaced with actual implementation
                     let response = "Blockchain update received";
-                    let game_message = GameMessage::Error(response.to_string());
+                    let game_message = GameMessage::error(ErrorCode::InvalidRequest, response);
                     let wrapper = GameMessageWrapper {
                         server_id: server_id.clone(),
                         game_message,
@@ -1505,6 +2923,14 @@ impl GameServer {
                         .publish_message(game_id.clone(), wrapper, false)
                         .await?;
                 }
+                // Only raised by the reader for frames it couldn't decode
+                error @ GameMessage::Error { .. } => {
+                    ws_write
+                        .lock()
+                        .await
+                        .send(Message::binary(serde_json::to_vec(&error)?))
+                        .await?;
+                }
                 _ => {}
             }
         }
@@ -1514,27 +2940,177 @@ impl GameServer {
     }
 }
 
-// Helper function to parse HTTP headers from a byte slice
-fn parse_http_headers(data: &[u8]) -> Result<HashMap<String, HeaderValue>, anyhow::Error> {
-    let mut headers = HashMap::new();
+// Frames a connection may have queued before its reader stops pulling more off the socket
+const INCOMING_CHANNEL_CAPACITY: usize = 500;
+// How long a full queue may stay full before the client is treated as flooding
+const INCOMING_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Decodes frames and hands them to the message loop in order. Sending waits on the
+// bounded channel, so a client can't get ahead of its handler by more than the channel
+// capacity; one that stays ahead for longer than `send_timeout` is disconnected.
+async fn forward_incoming<S, E>(
+    ws_read: &mut S,
+    server_tx: &mpsc::Sender<GameMessage>,
+    send_timeout: Duration,
+) -> anyhow::Result<()>
+where
+    S: futures_util::Stream<Item = Result<Message, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    while let Some(msg) = ws_read.next().await {
+        let message = msg.map_err(|e| anyhow::anyhow!("WebSocket error: {}", e))?;
+        // Pings and closes are answered by the socket itself
+        if !message.is_text() && !message.is_binary() {
+            continue;
+        }
+        let game_msg = match serde_json::from_slice(message.as_payload()) {
+            // Errors only flow from the server to the client
+            Ok(GameMessage::Error { .. }) => continue,
+            Ok(game_msg) => game_msg,
+            Err(e) => {
+                warn!("Deserialization error: {}", e);
+                // The loop relays the error, so the client learns its message was dropped
+                GameMessage::error(ErrorCode::InvalidRequest, malformed_message_reason(&e))
+            }
+        };
+        // Carries player names and resume tokens, so only traced
+        trace!("Incoming message: {:?}", game_msg);
+        match tokio::time::timeout(send_timeout, server_tx.send(game_msg)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err(anyhow::anyhow!("message loop has stopped")),
+            Err(_) => {
+                return Err(anyhow::anyhow!(
+                    "client is sending faster than it is served"
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+// Longest serde detail relayed back to a client
+const MALFORMED_REASON_MAX_LEN: usize = 200;
+
+// Describes why a frame was rejected. Syntax errors only get a position, since the
+// input around them is whatever the client sent; data errors name the offending field
+// or variant, which is what a client needs to fix its message.
+fn malformed_message_reason(err: &serde_json::Error) -> String {
+    match err.classify() {
+        serde_json::error::Category::Data => {
+            let mut detail = err.to_string();
+            if detail.len() > MALFORMED_REASON_MAX_LEN {
+                let mut end = MALFORMED_REASON_MAX_LEN;
+                while !detail.is_char_boundary(end) {
+                    end -= 1;
+                }
+                detail.truncate(end);
+                detail.push_str("...");
+            }
+            format!("Invalid message: {}", detail)
+        }
+        _ => format!(
+            "Malformed message: not valid JSON (line {}, column {})",
+            err.line(),
+            err.column()
+        ),
+    }
+}
+
+async fn reject_connection(mut stream: TcpStream) {
+    if let Err(e) = stream.write_all(CONNECTION_LIMIT_RESPONSE).await {
+        warn!("Failed to reject connection: {}", e);
+    }
+    let _ = stream.shutdown().await;
+}
 
-    if let Ok(request_str) = std::str::from_utf8(data) {
-        // Split the request into lines
-        let lines: Vec<&str> = request_str.split("\r\n").collect();
+// Resolves on ctrl-c or, on unix, SIGTERM (what fly sends on deploys)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
 
-        // Skip the request line and parse headers
-        for line in lines.iter().skip(1) {
-            if line.is_empty() {
-                break; // End of headers
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
             }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-            if let Some(idx) = line.find(':') {
-                let key = line[..idx].trim().to_lowercase();
-                let value = line[idx + 1..].trim();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
 
-                if let Ok(header_value) = HeaderValue::from_str(value) {
-                    headers.insert(key, header_value);
-                }
+// Upgrade requests are small; anything past this is treated as the whole head
+const MAX_REQUEST_HEAD_BYTES: usize = 16 * 1024;
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_HEAD_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Peeks (without consuming, so the WebSocket handshake can still read it) until the
+// end of the HTTP head arrives, the size cap is hit, the peer closes or time runs out
+async fn peek_request_head(stream: &TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_REQUEST_HEAD_BYTES];
+    let deadline = tokio::time::Instant::now() + REQUEST_HEAD_TIMEOUT;
+    let mut n;
+
+    loop {
+        n = stream.peek(&mut buf).await?;
+        // Nothing buffered at all means the peer closed the connection
+        if n == 0
+            || n == buf.len()
+            || find_head_end(&buf[..n]).is_some()
+            || tokio::time::Instant::now() >= deadline
+        {
+            break;
+        }
+        // peek resolves as soon as anything is buffered, so wait for more to arrive
+        tokio::time::sleep(REQUEST_HEAD_POLL_INTERVAL).await;
+    }
+
+    buf.truncate(n);
+    Ok(buf)
+}
+
+fn find_head_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+// The request line and headers, decoded leniently so stray bytes can't hide the rest
+fn request_head(data: &[u8]) -> std::borrow::Cow<'_, str> {
+    let end = find_head_end(data).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end])
+}
+
+// Helper function to parse HTTP headers from a byte slice
+pub(crate) fn parse_http_headers(
+    data: &[u8],
+) -> Result<HashMap<String, HeaderValue>, anyhow::Error> {
+    let mut headers = HashMap::new();
+
+    // Skip the request line and parse headers; lines() also accepts bare LF framing
+    for line in request_head(data).lines().skip(1) {
+        if line.is_empty() {
+            break; // End of headers
+        }
+
+        if let Some(idx) = line.find(':') {
+            let key = line[..idx].trim().to_lowercase();
+            let value = line[idx + 1..].trim();
+
+            if let Ok(header_value) = HeaderValue::from_str(value) {
+                headers.insert(key, header_value);
             }
         }
     }
@@ -1543,7 +3119,7 @@ fn parse_http_headers(data: &[u8]) -> Result<HashMap<String, HeaderValue>, anyho
 }
 
 // Helper function to parse cookies from a header value
-fn parse_cookies(cookie_header: Option<&HeaderValue>) -> HashMap<String, String> {
+pub(crate) fn parse_cookies(cookie_header: Option<&HeaderValue>) -> HashMap<String, String> {
     let mut cookies = HashMap::new();
 
     if let Some(header) = cookie_header {
@@ -1561,21 +3137,20 @@ fn parse_cookies(cookie_header: Option<&HeaderValue>) -> HashMap<String, String>
 }
 
 // Function to parse the HTTP request URI from raw bytes
-fn parse_request_uri(data: &[u8]) -> Option<String> {
-    if let Ok(request_str) = std::str::from_utf8(data) {
-        // HTTP request first line format: "GET /path?query HTTP/1.1"
-        let first_line = request_str.lines().next()?;
-        let parts: Vec<&str> = first_line.split_whitespace().collect();
-
-        if parts.len() >= 2 {
-            return Some(parts[1].to_string());
-        }
+pub(crate) fn parse_request_uri(data: &[u8]) -> Option<String> {
+    // HTTP request first line format: "GET /path?query HTTP/1.1"
+    let head = request_head(data);
+    let first_line = head.lines().next()?;
+    let parts: Vec<&str> = first_line.split_whitespace().collect();
+
+    if parts.len() >= 2 {
+        return Some(parts[1].to_string());
     }
     None
 }
 
 // Parse query parameters from a URI string
-fn parse_query_string(query: &str) -> HashMap<String, String> {
+pub(crate) fn parse_query_string(query: &str) -> HashMap<String, String> {
     let mut params = HashMap::new();
 
     for param_pair in query.split('&') {
@@ -1596,35 +3171,2895 @@ fn parse_query_string(query: &str) -> HashMap<String, String> {
     params
 }
 
-// Extract the machine ID from a WebSocket request
-fn extract_machine_id(data: &[u8], server_id: &str) -> Option<String> {
-    info!("Extracting machine ID");
-    // Try to get machine ID from URL parameter
-    if let Some(uri) = parse_request_uri(data) {
-        info!("URI: {}", uri);
-        if let Some(query_pos) = uri.find('?') {
-            let query = &uri[query_pos + 1..];
-            let params = parse_query_string(query);
-
-            if let Some(machine_id) = params.get("machine_id") {
-                // If request targets a different machine, return it
-                if machine_id != server_id {
-                    println!("Machine ID: {}", machine_id);
-                    return Some(machine_id.clone());
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use redis::AsyncCommands;
+    use sqlx::Row;
+
+    use super::*;
+    use crate::{
+        board::CellState, config::Environment, house_bot::HouseBotConfig, router::FlyRouter,
+        test_harness::TestServer,
+    };
+
+    fn running_game(game_id: &str) -> GameState {
+        GameState::RUNNING {
+            game_id: game_id.to_string(),
+            players: vec![
+                Player::new("1".to_string(), "alice".to_string()),
+                Player::new("2".to_string(), "bob".to_string()),
+            ],
+            board: Board::new(4, 2, BombLayout::Scattered, Some(0)),
+            turn_idx: 0,
+            single_bet_size: 0.1,
+            locks: None,
+            lives: 1,
+            lives_left: vec![1, 1],
+            currency: Currency::SOL,
+        }
+    }
+
+    fn test_registry() -> GameRegistry {
+        // Discovery updates fail without Redis, which the registry already tolerates
+        let redis = Client::open("redis://127.0.0.1:1").unwrap();
+        GameRegistry::new(redis, "test-server".to_string(), test_pool())
+    }
+
+    // Never connects, so anything that reaches the database fails, and quickly
+    fn test_pool() -> Pool<Postgres> {
+        sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://127.0.0.1:1/test")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_quick_reconnect_avoids_loss() {
+        let registry = test_registry();
+        let game_id = Uuid::new_v4().to_string();
+        registry
+            .games
+            .write()
+            .await
+            .insert(game_id.clone(), running_game(&game_id));
+        // The player pinged back in during the grace period
+        registry
+            .active_players
+            .write()
+            .await
+            .insert("2".to_string(), game_id.clone());
+
+        assert!(registry
+            .abandon_if_disconnected(&game_id, "2")
+            .await
+            .is_none());
+        assert!(matches!(
+            registry.get_game_state(&game_id).await,
+            Some(GameState::RUNNING { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resume_token_check() {
+        let registry = test_registry();
+        let token = registry.issue_resume_token("g", "1").await;
+
+        assert!(registry
+            .check_resume_token("g", "1", Some(&token))
+            .await
+            .is_ok());
+        assert!(registry.check_resume_token("g", "1", None).await.is_err());
+        assert!(registry
+            .check_resume_token("g", "1", Some("guess"))
+            .await
+            .is_err());
+        // A token only resumes the seat it was issued for
+        assert!(registry
+            .check_resume_token("g", "2", Some(&token))
+            .await
+            .is_err());
+        assert!(registry
+            .check_resume_token("other", "1", Some(&token))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resume_tokens_go_with_the_seat() {
+        let registry = test_registry();
+        let finished = registry.issue_resume_token("finished", "1").await;
+        let other = registry.issue_resume_token("finished", "2").await;
+        let drained = Uuid::new_v4().to_string();
+        registry
+            .games
+            .write()
+            .await
+            .insert(drained.clone(), running_game(&drained));
+        let draining = registry.issue_resume_token(&drained, "3").await;
+
+        registry.release_players(&["1".to_string()]).await;
+        assert!(registry
+            .check_resume_token("finished", "1", Some(&finished))
+            .await
+            .is_err());
+        assert!(registry
+            .check_resume_token("finished", "2", Some(&other))
+            .await
+            .is_ok());
+
+        registry.drain().await;
+        assert!(registry
+            .check_resume_token(&drained, "3", Some(&draining))
+            .await
+            .is_err());
+        assert!(registry
+            .check_resume_token("finished", "2", Some(&other))
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_authorize_seat() {
+        let seat = Seat {
+            game_id: "g".to_string(),
+            player_id: "1".to_string(),
+        };
+        let lock = |game_id: &str, player_id: &str| GameMessage::Lock {
+            game_id: game_id.to_string(),
+            player_id: player_id.to_string(),
+            x: 0,
+            y: 0,
+        };
+        let stop = |game_id: &str| GameMessage::Stop {
+            game_id: game_id.to_string(),
+            abort: true,
+        };
+
+        assert!(authorize_seat(Some(&seat), &lock("g", "1")).is_ok());
+        assert!(authorize_seat(Some(&seat), &stop("g")).is_ok());
+        for (seat, message) in [
+            (None, lock("g", "1")),
+            (None, stop("g")),
+            // Another player's seat, or another game
+            (Some(&seat), lock("g", "2")),
+            (Some(&seat), lock("other", "1")),
+            (Some(&seat), stop("other")),
+        ] {
+            assert_eq!(
+                authorize_seat(seat, &message),
+                Err(ProtocolError::new(ErrorCode::NotInGame, NOT_IN_GAME)),
+                "{:?}",
+                message
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_abandonment_is_counted() {
+        let registry = test_registry();
+        let game_id = Uuid::new_v4().to_string();
+        registry
+            .games
+            .write()
+            .await
+            .insert(game_id.clone(), running_game(&game_id));
+        registry
+            .active_players
+            .write()
+            .await
+            .insert("1".to_string(), game_id.clone());
+
+        let abandoned = metrics::GAMES_ABANDONED.get();
+        let completed = metrics::GAMES_COMPLETED.get();
+        let finished = registry.abandon_if_disconnected(&game_id, "2").await;
+
+        assert!(matches!(
+            finished,
+            Some(GameState::FINISHED {
+                result: GameResult::Loser(1),
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry.get_game_state(&game_id).await,
+            Some(GameState::FINISHED {
+                result: GameResult::Loser(1),
+                ..
+            })
+        ));
+        assert!(registry.active_players.read().await.is_empty());
+        assert_eq!(metrics::GAMES_ABANDONED.get(), abandoned + 1);
+        assert_eq!(metrics::GAMES_COMPLETED.get(), completed);
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_disconnects_are_a_draw() {
+        let registry = test_registry();
+        let game_id = Uuid::new_v4().to_string();
+        registry
+            .games
+            .write()
+            .await
+            .insert(game_id.clone(), running_game(&game_id));
+        // Both players dropped before either grace period ran out
+        registry
+            .active_players
+            .write()
+            .await
+            .insert("1".to_string(), Uuid::new_v4().to_string());
+
+        let (first, second) = tokio::join!(
+            registry.abandon_if_disconnected(&game_id, "1"),
+            registry.abandon_if_disconnected(&game_id, "2"),
+        );
+
+        let finished = first.xor(second).expect("the game ended exactly once");
+        assert!(matches!(
+            finished,
+            GameState::FINISHED {
+                result: GameResult::Draw,
+                ..
+            }
+        ));
+        assert!(matches!(
+            registry.get_game_state(&game_id).await,
+            Some(GameState::FINISHED {
+                result: GameResult::Draw,
+                ..
+            })
+        ));
+        assert_eq!(
+            PayoutPolicy::default()
+                .settle(&GameResult::Draw, 0.1, &[1.0, 1.0], true)
+                .deltas,
+            vec![0.0, 0.0]
+        );
+    }
+
+    // Bomb cells in the grid a client draws from a state's serialized board
+    fn shown_bombs(state: &GameState) -> usize {
+        let state = serde_json::to_value(state).unwrap();
+        let (_, fields) = state.as_object().unwrap().iter().next().unwrap();
+        fields["board"]["grid"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|row| row.as_array().unwrap())
+            .filter(|cell| *cell == "Bomb")
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_finished_board_reveals_all_bombs() {
+        let registry = test_registry();
+        let game_id = Uuid::new_v4().to_string();
+        let running = running_game(&game_id);
+        registry
+            .games
+            .write()
+            .await
+            .insert(game_id.clone(), running.clone());
+        assert_eq!(shown_bombs(&running), 0);
+
+        let finished = registry
+            .abandon_if_disconnected(&game_id, "2")
+            .await
+            .unwrap();
+        assert!(matches!(finished, GameState::FINISHED { .. }));
+        assert_eq!(shown_bombs(&finished), 2);
+    }
+
+    #[tokio::test]
+    async fn test_game_duration_is_observed_by_game_type() {
+        use prometheus::core::Metric;
+
+        let registry = test_registry();
+        let game_id = Uuid::new_v4().to_string();
+        // A board no other test plays on, so the label's counts are this test's alone
+        let mut game = running_game(&game_id);
+        if let GameState::RUNNING { board, .. } = &mut game {
+            *board = Board::new(9, 7, BombLayout::Scattered, Some(0));
+        }
+        let histogram = metrics::GAME_DURATION.with_label_values(&["grid9_bombs7"]);
+        let buckets = || {
+            histogram
+                .metric()
+                .get_histogram()
+                .get_bucket()
+                .iter()
+                .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                .collect::<Vec<_>>()
+        };
+        let before = buckets();
+
+        let started = Instant::now();
+        registry.game_started(&game, started).await;
+        // Later updates of the running game keep the original start
+        registry
+            .game_started(&game, started + Duration::from_secs(20))
+            .await;
+        registry
+            .game_ended(&game_id, started + Duration::from_secs(45))
+            .await;
+        // A game is only timed once
+        registry
+            .game_ended(&game_id, started + Duration::from_secs(90))
+            .await;
+
+        for ((upper_bound, before), (_, after)) in before.into_iter().zip(buckets()) {
+            let expected = if upper_bound >= 45.0 { 1 } else { 0 };
+            assert_eq!(after - before, expected, "bucket le={}", upper_bound);
+        }
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("alice").is_ok());
+        assert!(validate_name(&"é".repeat(MAX_NAME_CHARS)).is_ok());
+        assert!(validate_name(&"a".repeat(MAX_NAME_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn test_validate_min_players() {
+        assert!(validate_min_players(0).is_err());
+        assert!(validate_min_players(1).is_err());
+        assert!(validate_min_players(2).is_ok());
+        assert!(validate_min_players(MAX_PLAYERS).is_ok());
+        assert!(validate_min_players(MAX_PLAYERS + 1).is_err());
+    }
+
+    #[test]
+    fn test_normalize_bet_size() {
+        assert!(normalize_bet_size(f64::NAN).is_err());
+        assert!(normalize_bet_size(f64::INFINITY).is_err());
+        assert!(normalize_bet_size(-0.1).is_err());
+        assert!(normalize_bet_size(0.0).is_err());
+        // Rounds to zero
+        assert!(normalize_bet_size(0.00001).is_err());
+        assert_eq!(normalize_bet_size(0.1), Ok(0.1));
+        assert_eq!(normalize_bet_size(0.30000000000000004), Ok(0.3));
+        assert_eq!(normalize_bet_size(0.12345), Ok(0.1235));
+    }
+
+    #[test]
+    fn test_websocket_message_metrics() {
+        let counter = |message_type| {
+            metrics::WEBSOCKET_MESSAGES
+                .with_label_values(&[message_type])
+                .get()
+        };
+        let plays = counter("play");
+        let moves = counter("make_move");
+
+        let messages = [
+            GameMessage::Play {
+                player_id: "1".to_string(),
+                name: "alice".to_string(),
+                single_bet_size: 0.1,
+                min_players: 2,
+                bombs: 2,
+                grid: 4,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            },
+            GameMessage::MakeMove {
+                game_id: "g".to_string(),
+                x: 0,
+                y: 0,
+            },
+        ];
+        for message in &messages {
+            metrics::record_websocket_message(message.message_type());
+        }
+
+        assert_eq!(counter("play"), plays + 1);
+        assert_eq!(counter("make_move"), moves + 1);
+    }
+
+    #[test]
+    fn test_lose_life() {
+        let mut lives_left = vec![2, 2];
+        assert!(!lose_life(&mut lives_left, 2, 2, 0));
+        assert_eq!(lives_left, [1, 2]);
+        assert!(lose_life(&mut lives_left, 2, 2, 0));
+        assert_eq!(lives_left, [0, 2]);
+
+        // Games saved before lives existed have none recorded
+        let mut lives_left = Vec::new();
+        assert!(lose_life(&mut lives_left, 1, 3, 2));
+        assert_eq!(lives_left, [1, 1, 0]);
+    }
+
+    #[test]
+    fn test_board_limits() {
+        let limits = BoardLimits {
+            max_grid: 8,
+            max_bombs: 10,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        };
+
+        // At the configured maximums
+        assert!(limits.validate(8, 10).is_ok());
+        assert!(limits.validate(2, 3).is_ok());
+        // One over
+        assert!(limits.validate(9, 10).is_err());
+        assert!(limits.validate(8, 11).is_err());
+        // Impossible boards
+        assert!(limits.validate(0, 1).is_err());
+        assert!(limits.validate(4, 0).is_err());
+        assert!(limits.validate(2, 4).is_err());
+    }
+
+    #[test]
+    fn test_board_limits_cap_update_size() {
+        let limits = BoardLimits {
+            max_grid: 200,
+            max_bombs: 1000,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        };
+        let large = full_board_update_bytes(200, 1000);
+        assert!(large > DEFAULT_MAX_MESSAGE_BYTES);
+        let error = limits.validate(200, 1000).unwrap_err();
+        assert!(error.contains(&large.to_string()), "{}", error);
+
+        // A board at the default limits fits comfortably
+        assert!(full_board_update_bytes(DEFAULT_MAX_GRID, DEFAULT_MAX_BOMBS) < 8 * 1024);
+        assert!(limits.validate(DEFAULT_MAX_GRID, DEFAULT_MAX_BOMBS).is_ok());
+
+        // Right at the cap still fits, one byte less doesn't
+        let exact = BoardLimits {
+            max_message_bytes: full_board_update_bytes(30, 50),
+            ..limits
+        };
+        assert!(exact.validate(30, 50).is_ok());
+        let under = BoardLimits {
+            max_message_bytes: exact.max_message_bytes - 1,
+            ..limits
+        };
+        assert!(under.validate(30, 50).is_err());
+    }
+
+    #[test]
+    fn test_allowed_grids() {
+        let allowed = AllowedGrids::parse("3, 4,5,6,8").unwrap();
+        for grid in [3, 4, 5, 6, 8] {
+            assert!(allowed.validate(grid).is_ok(), "{}", grid);
+        }
+        assert_eq!(
+            allowed.validate(7).unwrap_err(),
+            "Grid must be one of 3, 4, 5, 6, 8"
+        );
+
+        // Without a list every size is left to the board limits
+        for grids in ["", " , "] {
+            assert_eq!(AllowedGrids::parse(grids).unwrap(), AllowedGrids::default());
+        }
+        assert!(AllowedGrids::default().validate(7).is_ok());
+        assert_eq!(
+            AllowedGrids::parse("4,6,").unwrap(),
+            AllowedGrids::parse("4,6").unwrap()
+        );
+        for grids in ["big", "4,x,6", "1,4"] {
+            assert!(AllowedGrids::parse(grids).is_err(), "{}", grids);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_play_outside_allowed_grids_is_rejected() -> Result<()> {
+        let mut registry = test_registry();
+        registry.allowed_grids = AllowedGrids::parse("4,6").unwrap();
+        let server = TestServer::start_with_registry(registry, test_pool()).await?;
+        let mut client = server.client().await?;
+        client
+            .send(&GameMessage::Play {
+                player_id: "1".to_string(),
+                name: "alice".to_string(),
+                single_bet_size: 0.1,
+                min_players: 2,
+                bombs: 3,
+                grid: 5,
+                is_creating_room: false,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let err = client
+            .next_update(Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Grid must be one of 4, 6"),
+            "{}",
+            err
+        );
+
+        client.close().await?;
+        server.stop().await
+    }
+
+    #[test]
+    fn test_validate_lives() {
+        assert!(validate_lives(0).is_err());
+        assert!(validate_lives(1).is_ok());
+        assert!(validate_lives(MAX_LIVES).is_ok());
+        assert!(validate_lives(MAX_LIVES + 1).is_err());
+    }
+
+    #[test]
+    fn test_play_lives_default_to_one() {
+        let play = r#"{"Play":{"player_id":"1","name":"alice","single_bet_size":0.1,
+            "min_players":2,"bombs":2,"grid":4,"is_creating_room":true}}"#;
+        match serde_json::from_str(play).unwrap() {
+            GameMessage::Play { lives, .. } => assert_eq!(lives, 1),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_play_currency_defaults_to_sol() {
+        let play = r#"{"Play":{"player_id":"1","name":"alice","single_bet_size":0.1,
+            "min_players":2,"bombs":2,"grid":4,"is_creating_room":true}}"#;
+        match serde_json::from_str(play).unwrap() {
+            GameMessage::Play { currency, .. } => assert_eq!(currency, Currency::SOL),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validate_currency() {
+        assert!(validate_currency(Currency::SOL).is_ok());
+        assert!(validate_currency(Currency::MON).is_ok());
+        // Not every user has a wallet to settle these in
+        assert!(validate_currency(Currency::USDC).is_err());
+        assert!(validate_currency(Currency::INR).is_err());
+    }
+
+    #[test]
+    fn test_play_layout_defaults_to_scattered() {
+        let play = r#"{"Play":{"player_id":"1","name":"alice","single_bet_size":0.1,
+            "min_players":2,"bombs":2,"grid":4,"is_creating_room":true}}"#;
+        match serde_json::from_str(play).unwrap() {
+            GameMessage::Play { layout, .. } => assert_eq!(layout, BombLayout::Scattered),
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        let play = play.replace("true}", r#"true,"layout":"Continuous"}"#);
+        match serde_json::from_str(&play).unwrap() {
+            GameMessage::Play { layout, .. } => assert_eq!(layout, BombLayout::Continuous),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_game_message_round_trip() {
+        let messages = [
+            GameMessage::Join {
+                game_id: "g".to_string(),
+                player_id: "1".to_string(),
+                name: "alice".to_string(),
+            },
+            GameMessage::Ping {
+                game_id: None,
+                player_id: Some("1".to_string()),
+                resume_token: None,
+            },
+            GameMessage::GameUpdate(running_game("g")),
+            GameMessage::error(ErrorCode::Internal, "oops"),
+            GameMessage::ServerDraining {
+                game_id: "g".to_string(),
+            },
+        ];
+
+        for message in messages {
+            let encoded = serde_json::to_vec(&message).unwrap();
+            let decoded: GameMessage = serde_json::from_slice(&encoded).unwrap();
+            assert_eq!(decoded.message_type(), message.message_type());
+            assert_eq!(serde_json::to_vec(&decoded).unwrap(), encoded);
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_connection_span_fields_in_logs() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = connection_span();
+            let mut span_ids = SpanIds::default();
+            span.in_scope(|| info!("Establishing connection"));
+            span_ids.record(
+                &span,
+                &GameMessage::Join {
+                    game_id: "game-1".to_string(),
+                    player_id: "7".to_string(),
+                    name: "alice".to_string(),
+                },
+            );
+            // MakeMove doesn't name the player, the span still remembers it
+            span_ids.record(
+                &span,
+                &GameMessage::MakeMove {
+                    game_id: "game-1".to_string(),
+                    x: 0,
+                    y: 0,
+                },
+            );
+            span.in_scope(|| info!("Move handled"));
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = logs.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", logs);
+        assert!(lines[0].contains("connection{connection_id="), "{}", logs);
+        assert!(!lines[0].contains("game_id"), "{}", logs);
+        assert!(lines[1].contains("game_id=game-1 player_id=7}"), "{}", logs);
+    }
+
+    const REDIRECT_REQUEST: &[u8] =
+        b"GET /?machine_id=machine-b HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+    #[tokio::test]
+    async fn test_no_redirect_to_self() {
+        let registry = test_registry();
+        assert!(registry
+            .redirect_target(REDIRECT_REQUEST, "machine-b")
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_no_redirect_when_liveness_unknown() {
+        // Redis is unreachable, so the target can't be validated
+        let registry = test_registry();
+        assert!(registry
+            .redirect_target(REDIRECT_REQUEST, "machine-a")
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_game_without_discovery_is_an_error() {
+        let registry = test_registry();
+        assert!(matches!(
+            registry.missing_game_reply("no-such-game").await,
+            GameMessage::Error { code: ErrorCode::GameNotFound, message } if message == GAME_NOT_FOUND
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_notifications_follow_the_config() {
+        let production = |notifications_enabled| AppConfig {
+            environment: Environment::Production,
+            notifications_enabled,
+            ..AppConfig::default()
+        };
+        let registry = |config: &AppConfig| {
+            let redis = Client::open("redis://127.0.0.1:1").unwrap();
+            GameRegistry::with_config(redis, "test-server".to_string(), test_pool(), config)
+        };
+        assert!(!registry(&production(false)).notify_game_created("New game".to_string()));
+        assert!(!test_registry().notify_game_created("New game".to_string()));
+        // Disabled in production all the same once the flag is off
+        assert!(!registry(
+            &AppConfig::from_lookup(|key| match key {
+                "ENVIRONMENT" => Some("production".to_string()),
+                "NOTIFICATIONS_ENABLED" => Some("off".to_string()),
+                "REDIS_URL" => Some("redis://127.0.0.1:1".to_string()),
+                _ => None,
+            })
+            .unwrap()
+        )
+        .notify_game_created("New game".to_string()));
+        assert!(registry(&production(true)).notifications_enabled);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_moves_on_unknown_games_get_a_reply() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let discovery = DiscoveryService::new(redis.clone());
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let mut client = server.client().await?;
+
+        client
+            .send(&GameMessage::MakeMove {
+                game_id: Uuid::new_v4().to_string(),
+                x: 0,
+                y: 0,
+            })
+            .await?;
+        let err = client.next_update(timeout).await.unwrap_err();
+        assert!(err.to_string().contains(GAME_NOT_FOUND), "{}", err);
+
+        // A game registered by another live server is redirected to it
+        let game_id = Uuid::new_v4().to_string();
+        let other_server = Uuid::new_v4().to_string();
+        discovery
+            .register_game_session(GameSession {
+                game_id: game_id.clone(),
+                server_id: other_server.clone(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                current_players: 2,
+                grid_size: 4,
+                private: true,
+                currency: Currency::SOL,
+            })
+            .await?;
+        discovery.heartbeat_server(&other_server).await?;
+        for message in [
+            GameMessage::Lock {
+                game_id: game_id.clone(),
+                player_id: "1".to_string(),
+                x: 0,
+                y: 0,
+            },
+            GameMessage::LockComplete {
+                game_id: game_id.clone(),
+                player_id: "1".to_string(),
+            },
+            GameMessage::Stop {
+                game_id: game_id.clone(),
+                abort: false,
+            },
+        ] {
+            client.send(&message).await?;
+            match client.recv(timeout).await? {
+                GameMessage::RedirectToServer {
+                    game_id: redirected_id,
+                    machine_id,
+                } => {
+                    assert_eq!(redirected_id, game_id);
+                    assert_eq!(machine_id, other_server);
                 }
+                reply => panic!("expected a redirect, got {:?}", reply),
+            }
+        }
+
+        discovery.remove_game_session(&game_id).await?;
+        discovery.unregister_server(&other_server).await?;
+        client.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_redirect_only_to_live_servers() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let registry = GameRegistry::new(redis, "machine-a".to_string(), test_pool());
+        let target = Uuid::new_v4().to_string();
+        let request = format!("GET /?machine_id={} HTTP/1.1\r\n\r\n", target);
+
+        assert!(registry
+            .redirect_target(request.as_bytes(), "machine-a")
+            .await
+            .is_none());
+
+        registry.discovery.heartbeat_server(&target).await?;
+        assert_eq!(
+            registry
+                .redirect_target(request.as_bytes(), "machine-a")
+                .await,
+            Some(target.clone())
+        );
+
+        registry.discovery.unregister_server(&target).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_truncated_request() {
+        // Cut off mid-header, before the terminating blank line
+        let data = b"GET /?machine_id=machine-b HTTP/1.1\r\nHost: example.com\r\nCookie: fly-mach";
+        assert_eq!(
+            parse_request_uri(data).as_deref(),
+            Some("/?machine_id=machine-b")
+        );
+        let headers = parse_http_headers(data).unwrap();
+        assert_eq!(headers["host"], "example.com");
+        assert!(parse_cookies(headers.get("cookie")).is_empty());
+
+        assert_eq!(parse_request_uri(b"GET"), None);
+        assert_eq!(parse_request_uri(b""), None);
+    }
+
+    #[test]
+    fn test_parse_oversized_request() {
+        let mut data =
+            b"GET / HTTP/1.1\r\nCookie: fly-machine-id=machine-b\r\nX-Padding: ".to_vec();
+        data.resize(MAX_REQUEST_HEAD_BYTES * 2, b'a');
+        assert_eq!(
+            FlyRouter.requested_server(&data, "machine-a").as_deref(),
+            Some("machine-b")
+        );
+    }
+
+    #[test]
+    fn test_parse_non_utf8_request() {
+        let mut data = b"GET /?machine_id=machine-b HTTP/1.1\r\nX-Junk: ".to_vec();
+        data.extend_from_slice(&[0xff, 0xfe, 0xfd]);
+        data.extend_from_slice(b"\r\nCookie: fly-machine-id=machine-c\r\n\r\n\xff");
+
+        assert_eq!(
+            parse_request_uri(&data).as_deref(),
+            Some("/?machine_id=machine-b")
+        );
+        let headers = parse_http_headers(&data).unwrap();
+        assert_eq!(
+            parse_cookies(headers.get("cookie"))["fly-machine-id"],
+            "machine-c"
+        );
+    }
+
+    #[test]
+    fn test_parse_bare_lf_request() {
+        let data = b"GET / HTTP/1.1\nCookie: fly-machine-id=machine-b\n\n";
+        assert_eq!(
+            FlyRouter.requested_server(data, "machine-a").as_deref(),
+            Some("machine-b")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peek_request_head_across_split_reads() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let client = tokio::spawn(async move {
+            let mut client = TcpStream::connect(addr).await?;
+            client
+                .write_all(b"GET /?machine_id=machine-b HTTP/1.1\r\n")
+                .await?;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(b"Host: example.com\r\n\r\n").await?;
+            // Keep the connection open until the server has peeked
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            anyhow::Ok(())
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let head = peek_request_head(&stream).await?;
+        assert!(head.ends_with(b"Host: example.com\r\n\r\n"));
+
+        client.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pong_response() {
+        let response = GameMessage::Pong {
+            server_id: "machine-a".to_string(),
+            resume_token: None,
+        };
+        let encoded = serde_json::to_vec(&response).unwrap();
+        assert_eq!(encoded, br#"{"Pong":{"server_id":"machine-a"}}"#);
+
+        let decoded: GameMessage = serde_json::from_slice(&encoded).unwrap();
+        assert!(matches!(decoded, GameMessage::Pong { server_id, .. } if server_id == "machine-a"));
+    }
+
+    fn waiting_game(game_id: &str) -> GameState {
+        let creator = Player::new("1".to_string(), "alice".to_string());
+        GameState::WAITING {
+            game_id: game_id.to_string(),
+            creator: creator.clone(),
+            board: Board::new(4, 2, BombLayout::Scattered, Some(0)),
+            single_bet_size: 0.1,
+            min_players: 2,
+            players: vec![creator],
+            lives: 1,
+            currency: Currency::SOL,
+            seed_contributions: BTreeMap::new(),
+        }
+    }
+
+    fn full_lobby(game_id: &str) -> GameState {
+        let mut lobby = waiting_game(game_id);
+        if let GameState::WAITING { players, .. } = &mut lobby {
+            players.push(Player::new("2".to_string(), "bob".to_string()));
+        }
+        lobby
+    }
+
+    fn bombs(state: &GameState) -> Vec<u64> {
+        match state {
+            GameState::RUNNING { board, .. } => board.bomb_coordinates.clone(),
+            state => panic!("expected a running game, got {:?}", state),
+        }
+    }
+
+    #[test]
+    fn test_seed_contributions() {
+        let mut lobby = full_lobby("g");
+        assert!(lobby.is_full());
+        assert!(lobby.add_seed_contribution("3", 5).is_err());
+        lobby.add_seed_contribution("1", 5).unwrap();
+        assert!(!lobby.seed_complete());
+        // A player can't redraw the board once they've seen the other nonces
+        assert!(lobby.add_seed_contribution("1", 6).is_err());
+        lobby.add_seed_contribution("2", 9).unwrap();
+        assert!(lobby.seed_complete());
+
+        // The same contributions in the other order draw the same board
+        let mut reordered = full_lobby("g");
+        reordered.add_seed_contribution("2", 9).unwrap();
+        reordered.add_seed_contribution("1", 5).unwrap();
+        let mut started = lobby.start();
+        assert_eq!(bombs(&started), bombs(&reordered.start()));
+        assert!(started.add_seed_contribution("1", 7).is_err());
+
+        // Without contributions the board is the one drawn for the lobby
+        let GameState::WAITING { board, .. } = full_lobby("g") else {
+            unreachable!()
+        };
+        assert_eq!(bombs(&full_lobby("g").start()), board.bomb_coordinates);
+        let mut other = full_lobby("g");
+        other.add_seed_contribution("1", 6).unwrap();
+        other.add_seed_contribution("2", 9).unwrap();
+        assert_ne!(bombs(&other.start()), bombs(&started));
+    }
+
+    #[tokio::test]
+    async fn test_full_lobby_waits_for_seed_contributions() {
+        let mut registry = test_registry();
+        registry.seed_contribution_timeout = Duration::from_millis(100);
+        registry.lobby_timeout = Duration::from_millis(10);
+        let game_id = Uuid::new_v4().to_string();
+
+        let lobby = registry.start_when_seeded(full_lobby(&game_id));
+        assert!(matches!(lobby, GameState::WAITING { .. }));
+        registry.games.write().await.insert(game_id.clone(), lobby);
+        // The lobby timeout leaves a full lobby alone
+        assert!(!registry.abort_unfilled_lobby(&game_id).await);
+
+        // Nobody contributed in time, so it starts on the lobby's own board
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(matches!(
+            registry.get_game_state(&game_id).await,
+            Some(GameState::RUNNING { .. })
+        ));
+
+        // Once everyone has contributed there is nothing to wait for
+        let mut seeded = full_lobby(&game_id);
+        seeded.add_seed_contribution("1", 1).unwrap();
+        seeded.add_seed_contribution("2", 2).unwrap();
+        assert!(matches!(
+            registry.start_when_seeded(seeded),
+            GameState::RUNNING { .. }
+        ));
+    }
+
+    // Answers the next request to the moves API at `listener` and returns its JSON body
+    async fn moves_api_request(listener: &TcpListener) -> Result<serde_json::Value> {
+        use tokio::io::AsyncReadExt;
+
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        let body = loop {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                return Err(anyhow!("request ended early"));
+            }
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request);
+            let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                continue;
+            };
+            let length = head
+                .lines()
+                .find_map(|line| {
+                    line.to_ascii_lowercase()
+                        .strip_prefix("content-length:")
+                        .map(|len| len.trim().parse::<usize>())
+                })
+                .transpose()?
+                .unwrap_or_default();
+            if body.len() >= length {
+                break body.to_string();
             }
+        };
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 20\r\n\r\n{\"transaction\":\"tx\"}")
+            .await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    #[tokio::test]
+    async fn test_game_is_initialized_with_the_seeded_board() -> Result<()> {
+        let moves_api = TcpListener::bind("127.0.0.1:0").await?;
+        let config = AppConfig {
+            moves_api: format!("http://{}", moves_api.local_addr()?),
+            ..AppConfig::default()
+        };
+        let redis = Client::open("redis://127.0.0.1:1")?;
+        let registry =
+            GameRegistry::with_config(redis, "test-server".to_string(), test_pool(), &config);
+        let game_id = Uuid::new_v4().to_string();
+        let mut lobby = full_lobby(&game_id);
+        lobby.add_seed_contribution("1", 5).unwrap();
+        lobby.add_seed_contribution("2", 9).unwrap();
+        let GameState::WAITING { board: drawn, .. } = &lobby else {
+            unreachable!()
+        };
+        let drawn = bomb_positions(drawn);
+        registry.games.write().await.insert(game_id.clone(), lobby);
+
+        assert!(registry.start_lobby(&game_id).await);
+        let request =
+            tokio::time::timeout(Duration::from_secs(5), moves_api_request(&moves_api)).await??;
+        let Some(GameState::RUNNING { board, .. }) = registry.get_game_state(&game_id).await else {
+            panic!("the game should be running");
+        };
+        let initialized = request["bombPositions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|cell| {
+                (
+                    cell["x"].as_u64().unwrap() as usize,
+                    cell["y"].as_u64().unwrap() as usize,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(request["gameId"], game_id);
+        assert_eq!(initialized, bomb_positions(&board));
+        // Not the board the lobby was created with, which the contributions redrew
+        assert_ne!(initialized, drawn);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_joins_race_for_the_last_seat() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let registry = GameRegistry::new(redis, Uuid::new_v4().to_string(), test_pool());
+        let [alice, bob, carol] =
+            [(); 3].map(|_| (5_000_000 + u32::from(rand::random::<u16>())).to_string());
+        let Some(GameState::WAITING { game_id, .. }) = registry
+            .handle_play_message(PlayRequest {
+                player_id: alice.clone(),
+                name: "alice".to_string(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?
+        else {
+            panic!("alice should be waiting for players");
+        };
+
+        let (bob_joined, carol_joined) = tokio::join!(
+            registry.join_lobby(&game_id, &bob, "bob"),
+            registry.join_lobby(&game_id, &carol, "carol"),
+        );
+        let (seated, left_out) = match (bob_joined?, carol_joined?) {
+            (Some(_), None) => (&bob, &carol),
+            (None, Some(_)) => (&carol, &bob),
+            joined => panic!("exactly one join should get the seat, got {:?}", joined),
+        };
+        let Some(GameState::RUNNING { players, .. }) = registry.get_game_state(&game_id).await
+        else {
+            panic!("the full lobby should have started");
+        };
+        let ids = players.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids, [alice.clone(), seated.clone()]);
+        // The left out player is free to play elsewhere
+        assert_eq!(registry.discovery.player_game(left_out).await?, None);
+        assert!(!registry.active_players.read().await.contains_key(left_out));
+        assert_eq!(
+            registry.discovery.player_game(seated).await?.as_deref(),
+            Some(game_id.as_str())
+        );
+
+        registry.release_players(&ids).await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_rapid_game_creation_is_throttled() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let mut registry = GameRegistry::new(redis, "machine-a".to_string(), test_pool());
+        registry.creation_limit = CreationLimit {
+            max_games: 2,
+            window: Duration::from_secs(60),
+        };
+        let play = |player_id: &str| PlayRequest {
+            player_id: player_id.to_string(),
+            name: "alice".to_string(),
+            single_bet_size: unique_bet_size(),
+            min_players: 2,
+            bombs: 3,
+            grid: 4,
+            is_creating_room: true,
+            layout: BombLayout::Scattered,
+            lives: 1,
+            currency: Currency::SOL,
+        };
+        let alice = Uuid::new_v4().to_string();
+
+        // Leaving each lobby straight away, like a client spamming Play would
+        let mut game_ids = Vec::new();
+        for _ in 0..2 {
+            let Some(GameState::WAITING { game_id, .. }) =
+                registry.handle_play_message(play(&alice)).await?
+            else {
+                panic!("alice should be waiting for players");
+            };
+            registry.release_players(std::slice::from_ref(&alice)).await;
+            game_ids.push(game_id);
         }
+        let err = registry
+            .handle_play_message(play(&alice))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ProtocolError>().map(|e| e.code),
+            Some(ErrorCode::TooManyGamesCreated)
+        );
+        assert_eq!(err.to_string(), TOO_MANY_GAMES_CREATED);
+
+        // The limit is per player
+        let bob = Uuid::new_v4().to_string();
+        let Some(GameState::WAITING { game_id, .. }) =
+            registry.handle_play_message(play(&bob)).await?
+        else {
+            panic!("bob should be waiting for players");
+        };
+        registry.release_players(&[bob]).await;
+        game_ids.push(game_id);
+
+        for game_id in game_ids {
+            registry.discovery.remove_game_session(&game_id).await?;
+        }
+        Ok(())
     }
 
-    // Try to get machine ID from cookies as fallback
-    if let Ok(headers) = parse_http_headers(data) {
-        let cookies = parse_cookies(headers.get("cookie"));
-        if let Some(machine_id) = cookies.get("fly-machine-id") {
-            if machine_id != server_id {
-                return Some(machine_id.clone());
+    #[tokio::test]
+    async fn test_lobby_filled_before_timeout() {
+        let mut registry = test_registry();
+        registry.lobby_timeout = Duration::from_millis(50);
+        let game_id = Uuid::new_v4().to_string();
+        registry
+            .games
+            .write()
+            .await
+            .insert(game_id.clone(), waiting_game(&game_id));
+
+        registry.spawn_lobby_timeout(game_id.clone());
+        // Second player joins in time
+        registry
+            .games
+            .write()
+            .await
+            .insert(game_id.clone(), running_game(&game_id));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        assert!(matches!(
+            registry.get_game_state(&game_id).await,
+            Some(GameState::RUNNING { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unfilled_lobby_is_aborted() {
+        let mut registry = test_registry();
+        registry.lobby_timeout = Duration::from_millis(50);
+        let game_id = Uuid::new_v4().to_string();
+        registry
+            .games
+            .write()
+            .await
+            .insert(game_id.clone(), waiting_game(&game_id));
+        registry
+            .active_players
+            .write()
+            .await
+            .insert("1".to_string(), game_id.clone());
+
+        registry.spawn_lobby_timeout(game_id.clone());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        assert!(matches!(
+            registry.get_game_state(&game_id).await,
+            Some(GameState::ABORTED { .. })
+        ));
+        assert!(registry.active_players.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_actions_carry_error_codes() {
+        fn code<T>(result: Result<T, ProtocolError>) -> ErrorCode {
+            result.err().expect("expected an error").code
+        }
+        let mut game = running_game("g");
+
+        assert_eq!(code(game.check_turn("2")), ErrorCode::NotYourTurn);
+        assert_eq!(
+            code(waiting_game("g").check_turn("1")),
+            ErrorCode::InvalidGameState
+        );
+        for i in 0..MAX_LOCKS {
+            game.add_lock(i, 0).unwrap();
+        }
+        assert_eq!(code(game.add_lock(0, 1)), ErrorCode::InvalidMove);
+        assert_eq!(code(game.toggle_flag(9, 9)), ErrorCode::InvalidMove);
+        assert_eq!(
+            code(waiting_game("g").toggle_flag(0, 0)),
+            ErrorCode::InvalidGameState
+        );
+
+        let mut lobby = waiting_game("g");
+        assert_eq!(
+            code(lobby.add_seed_contribution("3", 1)),
+            ErrorCode::InvalidRequest
+        );
+        lobby.add_seed_contribution("1", 1).unwrap();
+        assert_eq!(
+            code(lobby.add_seed_contribution("1", 2)),
+            ErrorCode::InvalidRequest
+        );
+        assert_eq!(
+            code(game.add_seed_contribution("1", 1)),
+            ErrorCode::InvalidGameState
+        );
+
+        let registry = test_registry();
+        assert_eq!(
+            code(registry.check_resume_token("g", "1", Some("guess")).await),
+            ErrorCode::InvalidResumeToken
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_replies_carry_codes() -> Result<()> {
+        let redis = Client::open("redis://127.0.0.1:1")?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let mut client = server.client().await?;
+        let mut expect = async |message: Option<GameMessage>, expected: ErrorCode| {
+            match message {
+                Some(message) => client.send(&message).await?,
+                None => client.send_frame(Message::text("not json")).await?,
+            }
+            match client.recv(timeout).await? {
+                GameMessage::Error { code, .. } => assert_eq!(code, expected),
+                message => panic!("expected a {:?} error, got {:?}", expected, message),
             }
+            anyhow::Ok(())
+        };
+
+        expect(None, ErrorCode::InvalidRequest).await?;
+        expect(
+            Some(GameMessage::Play {
+                player_id: "alice".to_string(),
+                name: "alice".to_string(),
+                single_bet_size: 0.1,
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: false,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            }),
+            ErrorCode::InvalidRequest,
+        )
+        .await?;
+        let long_name = "a".repeat(MAX_NAME_CHARS + 1);
+        expect(
+            Some(GameMessage::Play {
+                player_id: "1".to_string(),
+                name: long_name.clone(),
+                single_bet_size: 0.1,
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: false,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            }),
+            ErrorCode::InvalidRequest,
+        )
+        .await?;
+        expect(
+            Some(GameMessage::Join {
+                game_id: "no-such-game".to_string(),
+                player_id: "1".to_string(),
+                name: long_name,
+            }),
+            ErrorCode::InvalidRequest,
+        )
+        .await?;
+        expect(
+            Some(GameMessage::MakeMove {
+                game_id: "no-such-game".to_string(),
+                x: 0,
+                y: 0,
+            }),
+            ErrorCode::GameNotFound,
+        )
+        .await?;
+        expect(
+            Some(GameMessage::Ping {
+                game_id: Some("no-such-game".to_string()),
+                player_id: Some("1".to_string()),
+                resume_token: None,
+            }),
+            ErrorCode::InvalidResumeToken,
+        )
+        .await?;
+
+        client.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frames_close_the_connection() -> Result<()> {
+        let redis = Client::open("redis://127.0.0.1:1")?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let mut client = server.client().await?;
+
+        // Anything larger than the updates the server sends could only be relayed as an error
+        let oversized = " ".repeat(DEFAULT_MAX_MESSAGE_BYTES + 1);
+        client.send_frame(Message::text(oversized)).await?;
+        let err = client.recv(timeout).await.unwrap_err();
+        assert!(!err.to_string().starts_with("no message"), "{}", err);
+
+        server.stop().await
+    }
+
+    #[test]
+    fn test_check_turn() {
+        // Player "1" is at turn_idx 0
+        let game = running_game("g");
+        assert!(game.check_turn("1").is_ok());
+        assert_eq!(
+            game.check_turn("2"),
+            Err(ProtocolError::new(
+                ErrorCode::NotYourTurn,
+                "It is not your turn"
+            ))
+        );
+        assert!(game.check_turn("3").is_err());
+
+        assert!(waiting_game("g").check_turn("1").is_err());
+        assert!(GameState::ABORTED {
+            game_id: "g".to_string()
         }
+        .check_turn("1")
+        .is_err());
     }
 
-    None
+    fn locks_of(game: &GameState) -> Option<Vec<(usize, usize)>> {
+        match game {
+            GameState::RUNNING { locks, .. } => locks.clone(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_lock_limit() {
+        let mut game = running_game("g");
+        for i in 0..MAX_LOCKS {
+            assert!(game.add_lock(i, 0).is_ok());
+        }
+        // Duplicates are coalesced rather than rejected
+        assert!(game.add_lock(0, 0).is_ok());
+        assert!(game.add_lock(MAX_LOCKS, 0).is_err());
+        assert_eq!(locks_of(&game).unwrap().len(), MAX_LOCKS);
+
+        assert!(waiting_game("g").add_lock(0, 0).is_err());
+    }
+
+    #[test]
+    fn test_lock_complete_clears_locks() {
+        let mut game = running_game("g");
+        game.add_lock(1, 1).unwrap();
+        game.advance_turn();
+
+        assert!(matches!(game, GameState::RUNNING { turn_idx: 1, .. }));
+        assert_eq!(locks_of(&game), None);
+    }
+
+    #[test]
+    fn test_settlement_rejects_non_numeric_player_ids() {
+        let players = vec![
+            Player::new("1".to_string(), "alice".to_string()),
+            Player::new("2".to_string(), "bob".to_string()),
+        ];
+        assert_eq!(settlement_user_ids(&players), Ok(vec![1, 2]));
+
+        let players = vec![
+            Player::new("1".to_string(), "alice".to_string()),
+            Player::new(
+                "0b5f8c1e-6d1a-4c2e-9a57-3f1f0e2d4c6b".to_string(),
+                "bob".to_string(),
+            ),
+        ];
+        let err = settlement_user_ids(&players).unwrap_err();
+        assert!(err.contains("0b5f8c1e"), "{}", err);
+    }
+
+    #[test]
+    fn test_rematch_board_keeps_size_and_bomb_count() {
+        for layout in [BombLayout::Scattered, BombLayout::Continuous] {
+            let mut finished = Board::new(6, 5, layout, Some(3));
+            let bomb = finished.bomb_coordinates[0] as usize;
+            finished.mine(bomb / 6, bomb % 6);
+
+            let rematch = rematch_board(&finished);
+            assert_eq!(rematch.dimension(), 6);
+            assert_eq!(rematch.bomb_count(), 5);
+            assert_eq!(rematch.layout, layout);
+            // Nothing from the finished game is revealed on the new board
+            assert!(!rematch.render_ascii().contains('*'));
+        }
+    }
+
+    #[test]
+    fn test_flag_only_on_running_games() {
+        let mut game = running_game("g");
+        assert_eq!(game.toggle_flag(0, 0), Ok(true));
+        assert_eq!(game.toggle_flag(0, 0), Ok(false));
+
+        assert!(waiting_game("g").toggle_flag(0, 0).is_err());
+    }
+
+    #[test]
+    fn test_is_active() {
+        assert!(running_game("g").is_active());
+        assert!(!GameState::ABORTED {
+            game_id: "g".to_string()
+        }
+        .is_active());
+        assert!(!GameState::RematchRejected {
+            game_id: "g".to_string()
+        }
+        .is_active());
+    }
+
+    async fn read_response(addr: std::net::SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(request).await?;
+        let mut response = Vec::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            tokio::io::AsyncReadExt::read_to_end(&mut client, &mut response),
+        )
+        .await??;
+        Ok(response)
+    }
+
+    fn ping_frames(
+        count: usize,
+        pulled: Arc<std::sync::atomic::AtomicUsize>,
+    ) -> impl futures_util::Stream<Item = Result<Message, std::convert::Infallible>> + Unpin {
+        let ping = serde_json::to_vec(&GameMessage::Ping {
+            game_id: None,
+            player_id: None,
+            resume_token: None,
+        })
+        .unwrap();
+        futures_util::stream::iter((0..count).map(move |_| {
+            pulled.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(Message::binary(ping.clone()))
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_incoming_backpressure() {
+        let pulled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut frames = ping_frames(100, pulled.clone());
+        let (tx, mut rx) = mpsc::channel(4);
+        let forwarding = tokio::spawn(async move {
+            forward_incoming(&mut frames, &tx, Duration::from_secs(5)).await
+        });
+
+        // Nothing is draining the channel, so the reader stalls once it is full
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pulled.load(std::sync::atomic::Ordering::SeqCst), 5);
+
+        let mut received = 0;
+        while rx.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 100);
+        assert!(forwarding.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frames_become_error_replies() {
+        let frames = [
+            Message::binary(b"{\"Play\": {\"player_id\": \"secret-token\"".to_vec()),
+            Message::text(r#"{"Teleport": {"game_id": "g"}}"#),
+            // Clients can't inject errors of their own
+            Message::text(r#"{"Error": {"code": "Internal", "message": "spoofed"}}"#),
+            Message::ping("keepalive"),
+            Message::binary(
+                serde_json::to_vec(&GameMessage::Ping {
+                    game_id: None,
+                    player_id: None,
+                    resume_token: None,
+                })
+                .unwrap(),
+            ),
+        ];
+        let mut frames = futures_util::stream::iter(frames.map(Ok::<_, std::convert::Infallible>));
+        let (tx, mut rx) = mpsc::channel(8);
+        forward_incoming(&mut frames, &tx, Duration::from_secs(1))
+            .await
+            .unwrap();
+        drop(tx);
+
+        let mut forwarded = Vec::new();
+        while let Some(message) = rx.recv().await {
+            forwarded.push(message);
+        }
+        assert_eq!(forwarded.len(), 3, "{:?}", forwarded);
+        let GameMessage::Error {
+            code: ErrorCode::InvalidRequest,
+            message: syntax,
+        } = &forwarded[0]
+        else {
+            panic!("expected an error, got {:?}", forwarded[0]);
+        };
+        assert!(syntax.starts_with("Malformed message"), "{}", syntax);
+        assert!(!syntax.contains("secret-token"), "{}", syntax);
+        let GameMessage::Error {
+            code: ErrorCode::InvalidRequest,
+            message: data,
+        } = &forwarded[1]
+        else {
+            panic!("expected an error, got {:?}", forwarded[1]);
+        };
+        assert!(data.contains("unknown variant"), "{}", data);
+        assert!(matches!(forwarded[2], GameMessage::Ping { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_payload_gets_error_reply() -> Result<()> {
+        let redis = Client::open("redis://127.0.0.1:1")?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let mut client = server.client().await?;
+
+        client.send_frame(Message::text("not json")).await?;
+        assert!(matches!(
+            client.recv(timeout).await?,
+            GameMessage::Error { code: ErrorCode::InvalidRequest, message }
+                if message.starts_with("Malformed message")
+        ));
+
+        // The connection stays usable
+        client
+            .send(&GameMessage::Ping {
+                game_id: None,
+                player_id: None,
+                resume_token: None,
+            })
+            .await?;
+        assert!(matches!(
+            client.recv(timeout).await?,
+            GameMessage::Pong { .. }
+        ));
+
+        client.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_ping_without_game_id_is_answered() -> Result<()> {
+        // Never reached, pinging without a game subscribes to nothing
+        let redis = Client::open("redis://127.0.0.1:1")?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let mut client = server.client().await?;
+
+        // Twice, so the first ping must have left the connection handler running
+        for _ in 0..2 {
+            client
+                .send(&GameMessage::Ping {
+                    game_id: None,
+                    player_id: Some("1".to_string()),
+                    resume_token: None,
+                })
+                .await?;
+            assert!(matches!(
+                client.recv(timeout).await?,
+                GameMessage::Pong { .. }
+            ));
+        }
+
+        client.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_flooding_client_is_disconnected() {
+        let pulled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut frames = ping_frames(100, pulled.clone());
+        let (tx, _rx) = mpsc::channel(4);
+
+        let result = forward_incoming(&mut frames, &tx, Duration::from_millis(20)).await;
+        assert!(result.is_err());
+        assert_eq!(pulled.load(std::sync::atomic::Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_connections_share_one_pool() -> Result<()> {
+        let server = Arc::new(GameServer {
+            server_id: "test-server".to_string(),
+            registry: test_registry(),
+            connection_limit: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let uri: http::Uri = format!("ws://{}/", listener.local_addr()?).parse()?;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve(listener, async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        let pools_before = db::pools_established();
+        let ping = serde_json::to_vec(&GameMessage::Ping {
+            game_id: None,
+            player_id: None,
+            resume_token: None,
+        })?;
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (mut client, _) = tokio_websockets::ClientBuilder::from_uri(uri.clone())
+                .connect()
+                .await?;
+            client.send(Message::binary(ping.clone())).await?;
+            // A Pong means the connection got past setup into the message loop
+            let reply = tokio::time::timeout(Duration::from_secs(5), client.next())
+                .await?
+                .expect("connection closed")?;
+            let reply: GameMessage = serde_json::from_slice(reply.as_payload())?;
+            assert!(matches!(reply, GameMessage::Pong { .. }));
+            clients.push(client);
+        }
+        assert_eq!(db::pools_established(), pools_before);
+
+        drop(clients);
+        let _ = stop.send(());
+        serving.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() -> Result<()> {
+        let server = Arc::new(GameServer {
+            server_id: "test-server".to_string(),
+            registry: test_registry(),
+            connection_limit: Arc::new(Semaphore::new(1)),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve(listener, async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        // Holds the only slot while the server waits for its request head
+        let held = TcpStream::connect(addr).await?;
+        while server.connection_limit.available_permits() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let rejected_before = metrics::CONNECTIONS_REJECTED.get();
+        let response = read_response(addr, b"GET / HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(response, CONNECTION_LIMIT_RESPONSE);
+        assert!(metrics::CONNECTIONS_REJECTED.get() > rejected_before);
+
+        // Once the first client leaves, its slot is handed to the next one
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.connection_limit.available_permits() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let response = read_response(addr, b"GET / HTTP/1.1\r\n\r\n").await?;
+        assert_ne!(response, CONNECTION_LIMIT_RESPONSE);
+
+        let _ = stop.send(());
+        serving.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_game_client_round_trip() -> Result<()> {
+        let redis = Client::open("redis://127.0.0.1:1")?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let mut client = server.client().await?;
+
+        client
+            .send(&GameMessage::Ping {
+                game_id: None,
+                player_id: None,
+                resume_token: None,
+            })
+            .await?;
+        assert!(matches!(
+            client.recv(timeout).await?,
+            GameMessage::Pong { .. }
+        ));
+
+        // Rejected before matchmaking, so this needs no Redis
+        client
+            .send(&GameMessage::Play {
+                player_id: "alice".to_string(),
+                name: "alice".to_string(),
+                single_bet_size: 0.1,
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let err = client.next_update(timeout).await.unwrap_err();
+        assert!(err.to_string().contains("Invalid player id"), "{}", err);
+
+        client.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_oversized_board_is_rejected() -> Result<()> {
+        // Rejected before matchmaking, so this needs no Redis
+        let redis = Client::open("redis://127.0.0.1:1")?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let mut client = server.client().await?;
+
+        client
+            .send(&GameMessage::Play {
+                player_id: "1".to_string(),
+                name: "alice".to_string(),
+                single_bet_size: 0.1,
+                min_players: 2,
+                bombs: 3,
+                grid: 1000,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let err = client.next_update(timeout).await.unwrap_err();
+        assert!(err.to_string().contains("Grid must be between"), "{}", err);
+
+        client.close().await?;
+        server.stop().await
+    }
+
+    // Alice creates a room, Bob joins it and Alice, who moves first, steps on a bomb.
+    // Returns the game id once both players have seen the game finish.
+    async fn play_to_first_bomb(
+        server: &TestServer,
+        alice_id: &str,
+        bob_id: &str,
+        single_bet_size: f64,
+    ) -> Result<(String, Board)> {
+        let timeout = Duration::from_secs(5);
+        let mut alice = server.client().await?;
+        let mut bob = server.client().await?;
+
+        alice
+            .send(&GameMessage::Play {
+                player_id: alice_id.to_string(),
+                name: "alice".to_string(),
+                single_bet_size,
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let GameState::WAITING {
+            game_id, players, ..
+        } = alice.next_update(timeout).await?
+        else {
+            panic!("alice should be waiting for players");
+        };
+        assert_eq!(players.len(), 1);
+
+        bob.send(&GameMessage::Join {
+            game_id: game_id.clone(),
+            player_id: bob_id.to_string(),
+            name: "bob".to_string(),
+        })
+        .await?;
+        let mut board = None;
+        for client in [&mut alice, &mut bob] {
+            match client.next_update(timeout).await? {
+                GameState::RUNNING {
+                    game_id: running_id,
+                    players,
+                    turn_idx,
+                    board: running_board,
+                    ..
+                } => {
+                    assert_eq!(running_id, game_id);
+                    assert_eq!(players.len(), 2);
+                    assert_eq!(turn_idx, 0);
+                    board = Some(running_board);
+                }
+                state => panic!("expected a running game, got {:?}", state),
+            }
+        }
+
+        let board = board.unwrap();
+        let bomb = board.bomb_coordinates[0] as usize;
+        alice
+            .send(&GameMessage::MakeMove {
+                game_id: game_id.clone(),
+                x: bomb / board.n,
+                y: bomb % board.n,
+            })
+            .await?;
+        for client in [&mut alice, &mut bob] {
+            match client.next_update(timeout).await? {
+                GameState::FINISHED {
+                    game_id: finished_id,
+                    result: GameResult::Loser(loser_idx),
+                    players,
+                    ..
+                } => {
+                    assert_eq!(finished_id, game_id);
+                    assert_eq!(players[loser_idx].id, alice_id);
+                }
+                state => panic!("expected a finished game, got {:?}", state),
+            }
+        }
+
+        alice.close().await?;
+        bob.close().await?;
+        Ok((game_id, board))
+    }
+
+    // A bet nobody else uses keeps matchmaking from pairing with a leftover lobby
+    fn unique_bet_size() -> f64 {
+        0.01 + f64::from(rand::random::<u16>()) / 1e4
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_house_bot_fills_a_lobby_left_one_short() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let alice_id = (5_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let bot_id = 6_000_000 + i32::from(rand::random::<u16>());
+        let config = AppConfig {
+            lobby_timeout: Duration::from_millis(200),
+            house_bot: Some(HouseBotConfig {
+                user_id: bot_id,
+                name: "House".to_string(),
+            }),
+            ..AppConfig::default()
+        };
+        let server = TestServer::start_with_config(redis, test_pool(), &config).await?;
+        let timeout = Duration::from_secs(5);
+        let mut alice = server.client().await?;
+
+        alice
+            .send(&GameMessage::Play {
+                player_id: alice_id.clone(),
+                name: "alice".to_string(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: false,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let GameState::WAITING { game_id, .. } = alice.next_update(timeout).await? else {
+            panic!("alice should be waiting for players");
+        };
+
+        // Nobody else joins, so the bot takes the seat once the lobby times out
+        let mut update = alice.next_update(timeout).await?;
+        let GameState::RUNNING { players, .. } = &update else {
+            panic!("the bot should have started the game, got {:?}", update);
+        };
+        assert_eq!(players[1].id, bot_id.to_string());
+        assert_eq!(players[1].name, "House");
+
+        // Alice only mines safe cells, so the game ends with the bot's moves. It only
+        // gets there if every move the bot makes is accepted.
+        let mut alice_moved = false;
+        let mut alice_moves = 0;
+        let finished = loop {
+            match update {
+                GameState::RUNNING {
+                    turn_idx: 0, board, ..
+                } if !alice_moved => {
+                    let n = board.dimension();
+                    let (x, y) = (0..n * n)
+                        .map(|c| (c / n, c % n))
+                        .find(|&(x, y)| {
+                            matches!(board.cell(x, y), Some(CellState::Hidden))
+                                && !board.bomb_coordinates.contains(&((x * n + y) as u64))
+                        })
+                        .unwrap();
+                    alice
+                        .send(&GameMessage::MakeMove {
+                            game_id: game_id.clone(),
+                            x,
+                            y,
+                        })
+                        .await?;
+                    alice_moved = true;
+                    alice_moves += 1;
+                }
+                GameState::RUNNING { turn_idx: 0, .. } => {
+                    alice
+                        .send(&GameMessage::LockComplete {
+                            game_id: game_id.clone(),
+                            player_id: alice_id.clone(),
+                        })
+                        .await?;
+                    alice_moved = false;
+                }
+                GameState::RUNNING { .. } => {}
+                state => break state,
+            }
+            update = alice.next_update(timeout).await?;
+        };
+        let GameState::FINISHED { result, board, .. } = finished else {
+            panic!("expected a finished game, got {:?}", finished);
+        };
+        assert!(
+            matches!(result, GameResult::Loser(1) | GameResult::Draw),
+            "{:?}",
+            result
+        );
+        let mined =
+            board.revealed_safe_count() + usize::from(matches!(result, GameResult::Loser(_)));
+        assert!(mined > alice_moves, "the bot never moved");
+
+        alice.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_resume_needs_the_issued_token() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let alice_id = (3_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let bob_id = (4_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let mut alice = server.client().await?;
+        let mut bob = server.client().await?;
+
+        alice
+            .send(&GameMessage::Play {
+                player_id: alice_id.clone(),
+                name: "alice".to_string(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let token = match alice.recv(timeout).await? {
+            GameMessage::Pong {
+                resume_token: Some(token),
+                ..
+            } => token,
+            message => panic!("expected a resume token, got {:?}", message),
+        };
+        let GameState::WAITING { game_id, .. } = alice.next_update(timeout).await? else {
+            panic!("alice should be waiting for players");
+        };
+        bob.send(&GameMessage::Join {
+            game_id: game_id.clone(),
+            player_id: bob_id.clone(),
+            name: "bob".to_string(),
+        })
+        .await?;
+        for client in [&mut alice, &mut bob] {
+            let state = client.next_update(timeout).await?;
+            assert!(matches!(state, GameState::RUNNING { .. }), "{:?}", state);
+        }
+        alice.close().await?;
+
+        let resume = |resume_token: &str| GameMessage::Ping {
+            game_id: Some(game_id.clone()),
+            player_id: Some(alice_id.clone()),
+            resume_token: Some(resume_token.to_string()),
+        };
+        let mut hijacker = server.client().await?;
+        hijacker.send(&resume("guess")).await?;
+        assert!(matches!(
+            hijacker.recv(timeout).await?,
+            GameMessage::Error { code: ErrorCode::InvalidResumeToken, message }
+                if message == INVALID_RESUME_TOKEN
+        ));
+        hijacker.close().await?;
+
+        let mut resumed = server.client().await?;
+        resumed.send(&resume(&token)).await?;
+        assert!(matches!(
+            resumed.recv(timeout).await?,
+            GameMessage::Pong { .. }
+        ));
+        // The resumed connection holds alice's seat and takes her turn
+        resumed
+            .send(&GameMessage::Lock {
+                game_id: game_id.clone(),
+                player_id: alice_id.clone(),
+                x: 0,
+                y: 0,
+            })
+            .await?;
+        assert!(matches!(
+            resumed.next_update(timeout).await?,
+            GameState::RUNNING { locks: Some(_), .. }
+        ));
+        resumed.close().await?;
+        bob.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_resumed_seat_is_abandoned_on_second_disconnect() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let config = AppConfig {
+            reconnect_grace: Duration::from_millis(500),
+            ..AppConfig::default()
+        };
+        let registry =
+            GameRegistry::with_config(redis, Uuid::new_v4().to_string(), test_pool(), &config);
+        let server = TestServer::start_with_registry(registry.clone(), test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let alice_id = (3_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let bob_id = (4_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let mut alice = server.client().await?;
+        let mut bob = server.client().await?;
+
+        alice
+            .send(&GameMessage::Play {
+                player_id: alice_id.clone(),
+                name: "alice".to_string(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let token = match alice.recv(timeout).await? {
+            GameMessage::Pong {
+                resume_token: Some(token),
+                ..
+            } => token,
+            message => panic!("expected a resume token, got {:?}", message),
+        };
+        let GameState::WAITING { game_id, .. } = alice.next_update(timeout).await? else {
+            panic!("alice should be waiting for players");
+        };
+        bob.send(&GameMessage::Join {
+            game_id: game_id.clone(),
+            player_id: bob_id.clone(),
+            name: "bob".to_string(),
+        })
+        .await?;
+        for client in [&mut alice, &mut bob] {
+            let state = client.next_update(timeout).await?;
+            assert!(matches!(state, GameState::RUNNING { .. }), "{:?}", state);
+        }
+
+        // Alice drops and is back within the grace period
+        alice.close().await?;
+        let mut alice = server.client().await?;
+        alice
+            .send(&GameMessage::Ping {
+                game_id: Some(game_id.clone()),
+                player_id: Some(alice_id.clone()),
+                resume_token: Some(token),
+            })
+            .await?;
+        assert!(matches!(
+            alice.recv(timeout).await?,
+            GameMessage::Pong { .. }
+        ));
+        tokio::time::sleep(config.reconnect_grace * 2).await;
+        assert!(matches!(
+            registry.get_game_state(&game_id).await,
+            Some(GameState::RUNNING { .. })
+        ));
+
+        // Her resumed connection is tracked, so dropping it again abandons the game
+        alice.close().await?;
+        assert!(matches!(
+            bob.next_update(timeout).await?,
+            GameState::FINISHED {
+                result: GameResult::Loser(0),
+                ..
+            }
+        ));
+
+        bob.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_turns_are_taken_by_the_seated_connection() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let registry = GameRegistry::new(redis, Uuid::new_v4().to_string(), test_pool());
+        let server = TestServer::start_with_registry(registry.clone(), test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let alice_id = (3_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let bob_id = (4_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let mut alice = server.client().await?;
+        let mut bob = server.client().await?;
+
+        alice
+            .send(&GameMessage::Play {
+                player_id: alice_id.clone(),
+                name: "alice".to_string(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let GameState::WAITING { game_id, .. } = alice.next_update(timeout).await? else {
+            panic!("alice should be waiting for players");
+        };
+        bob.send(&GameMessage::Join {
+            game_id: game_id.clone(),
+            player_id: bob_id.clone(),
+            name: "bob".to_string(),
+        })
+        .await?;
+        for client in [&mut alice, &mut bob] {
+            let state = client.next_update(timeout).await?;
+            assert!(
+                matches!(&state, GameState::RUNNING { players, turn_idx: 0, .. } if players[0].id == alice_id),
+                "{:?}",
+                state
+            );
+        }
+
+        // It's alice's turn, but only her own connection may take it
+        let lock = |player_id: &str| GameMessage::Lock {
+            game_id: game_id.clone(),
+            player_id: player_id.to_string(),
+            x: 0,
+            y: 0,
+        };
+        let lock_complete = |player_id: &str| GameMessage::LockComplete {
+            game_id: game_id.clone(),
+            player_id: player_id.to_string(),
+        };
+        let make_move = GameMessage::MakeMove {
+            game_id: game_id.clone(),
+            x: 0,
+            y: 0,
+        };
+        let mut mallory = server.client().await?;
+        let refused = [
+            (
+                &mut mallory,
+                vec![
+                    (lock(&alice_id), ErrorCode::NotInGame),
+                    (lock_complete(&alice_id), ErrorCode::NotInGame),
+                    (make_move.clone(), ErrorCode::NotInGame),
+                ],
+            ),
+            (
+                &mut bob,
+                vec![
+                    (lock(&alice_id), ErrorCode::NotInGame),
+                    (lock_complete(&alice_id), ErrorCode::NotInGame),
+                    (lock(&bob_id), ErrorCode::NotYourTurn),
+                    (lock_complete(&bob_id), ErrorCode::NotYourTurn),
+                    (make_move, ErrorCode::NotYourTurn),
+                ],
+            ),
+        ];
+        for (client, messages) in refused {
+            for (message, code) in messages {
+                client.send(&message).await?;
+                let err = client.next_update(timeout).await.unwrap_err();
+                assert_eq!(
+                    err.downcast_ref::<ProtocolError>().map(|e| e.code),
+                    Some(code),
+                    "{:?}: {}",
+                    message,
+                    err
+                );
+            }
+        }
+        assert!(matches!(
+            registry.get_game_state(&game_id).await,
+            Some(GameState::RUNNING {
+                turn_idx: 0,
+                locks: None,
+                ..
+            })
+        ));
+
+        mallory.close().await?;
+        alice.close().await?;
+        bob.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_two_lives_survive_first_bomb() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        // Fresh ids so claims left in Redis by other games don't interfere
+        let alice_id = (3_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let bob_id = (4_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let mut alice = server.client().await?;
+        let mut bob = server.client().await?;
+
+        alice
+            .send(&GameMessage::Play {
+                player_id: alice_id.clone(),
+                name: "alice".to_string(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 2,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let GameState::WAITING { game_id, lives, .. } = alice.next_update(timeout).await? else {
+            panic!("alice should be waiting for players");
+        };
+        assert_eq!(lives, 2);
+
+        bob.send(&GameMessage::Join {
+            game_id: game_id.clone(),
+            player_id: bob_id.clone(),
+            name: "bob".to_string(),
+        })
+        .await?;
+        let mut board = None;
+        for client in [&mut alice, &mut bob] {
+            match client.next_update(timeout).await? {
+                GameState::RUNNING {
+                    board: running_board,
+                    lives_left,
+                    ..
+                } => {
+                    assert_eq!(lives_left, [2, 2]);
+                    board = Some(running_board);
+                }
+                state => panic!("expected a running game, got {:?}", state),
+            }
+        }
+        let board = board.unwrap();
+        let bomb = |i: usize| {
+            let pos = board.bomb_coordinates[i] as usize;
+            (pos / board.n, pos % board.n)
+        };
+
+        // Alice survives her first bomb and the game carries on
+        let (x, y) = bomb(0);
+        alice
+            .send(&GameMessage::MakeMove {
+                game_id: game_id.clone(),
+                x,
+                y,
+            })
+            .await?;
+        for client in [&mut alice, &mut bob] {
+            match client.next_update(timeout).await? {
+                GameState::RUNNING {
+                    lives_left,
+                    turn_idx,
+                    ..
+                } => {
+                    assert_eq!(lives_left, [1, 2]);
+                    assert_eq!(turn_idx, 0);
+                }
+                state => panic!("expected a running game, got {:?}", state),
+            }
+        }
+
+        // The revealed bomb can't be mined again for another life
+        alice
+            .send(&GameMessage::MakeMove {
+                game_id: game_id.clone(),
+                x,
+                y,
+            })
+            .await?;
+        let err = alice.next_update(timeout).await.unwrap_err();
+        assert!(err.to_string().contains("already revealed"), "{}", err);
+
+        alice
+            .send(&GameMessage::LockComplete {
+                game_id: game_id.clone(),
+                player_id: alice_id.clone(),
+            })
+            .await?;
+        for client in [&mut alice, &mut bob] {
+            assert!(matches!(
+                client.next_update(timeout).await?,
+                GameState::RUNNING { turn_idx: 1, .. }
+            ));
+        }
+        let (x, y) = bomb(1);
+        bob.send(&GameMessage::MakeMove {
+            game_id: game_id.clone(),
+            x,
+            y,
+        })
+        .await?;
+        bob.send(&GameMessage::LockComplete {
+            game_id: game_id.clone(),
+            player_id: bob_id.clone(),
+        })
+        .await?;
+        for client in [&mut alice, &mut bob] {
+            for _ in 0..2 {
+                client.next_update(timeout).await?;
+            }
+        }
+
+        // Her second bomb takes her last life
+        let (x, y) = bomb(2);
+        alice
+            .send(&GameMessage::MakeMove {
+                game_id: game_id.clone(),
+                x,
+                y,
+            })
+            .await?;
+        for client in [&mut alice, &mut bob] {
+            match client.next_update(timeout).await? {
+                GameState::FINISHED { result, lives, .. } => {
+                    assert_eq!(result, GameResult::Loser(0));
+                    assert_eq!(lives, 2);
+                }
+                state => panic!("expected a finished game, got {:?}", state),
+            }
+        }
+
+        alice.close().await?;
+        bob.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_clearing_the_board_is_a_draw() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let alice_id = (5_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let bob_id = (6_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let mut alice = server.client().await?;
+        let mut bob = server.client().await?;
+
+        // Three bombs on a 2x2 board leave a single safe cell
+        alice
+            .send(&GameMessage::Play {
+                player_id: alice_id.clone(),
+                name: "alice".to_string(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                bombs: 3,
+                grid: 2,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let GameState::WAITING { game_id, .. } = alice.next_update(timeout).await? else {
+            panic!("alice should be waiting for players");
+        };
+        bob.send(&GameMessage::Join {
+            game_id: game_id.clone(),
+            player_id: bob_id.clone(),
+            name: "bob".to_string(),
+        })
+        .await?;
+        let mut board = None;
+        for client in [&mut alice, &mut bob] {
+            match client.next_update(timeout).await? {
+                GameState::RUNNING {
+                    board: running_board,
+                    ..
+                } => board = Some(running_board),
+                state => panic!("expected a running game, got {:?}", state),
+            }
+        }
+        let board = board.unwrap();
+        let safe = (0..4u64)
+            .find(|c| !board.bomb_coordinates.contains(c))
+            .unwrap() as usize;
+
+        alice
+            .send(&GameMessage::MakeMove {
+                game_id: game_id.clone(),
+                x: safe / 2,
+                y: safe % 2,
+            })
+            .await?;
+        for client in [&mut alice, &mut bob] {
+            match client.next_update(timeout).await? {
+                GameState::FINISHED {
+                    game_id: finished_id,
+                    result,
+                    board,
+                    ..
+                } => {
+                    assert_eq!(finished_id, game_id);
+                    assert_eq!(result, GameResult::Draw);
+                    assert!(board.is_cleared());
+                }
+                state => panic!("expected a finished game, got {:?}", state),
+            }
+        }
+
+        alice.close().await?;
+        bob.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_seed_contributions_draw_the_board() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let server = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        let alice_id = (7_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let bob_id = (8_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let mut alice = server.client().await?;
+        let mut bob = server.client().await?;
+
+        alice
+            .send(&GameMessage::Play {
+                player_id: alice_id.clone(),
+                name: "alice".to_string(),
+                single_bet_size: unique_bet_size(),
+                min_players: 2,
+                bombs: 3,
+                grid: 6,
+                is_creating_room: true,
+                layout: BombLayout::Scattered,
+                lives: 1,
+                currency: Currency::SOL,
+            })
+            .await?;
+        let GameState::WAITING { game_id, board, .. } = alice.next_update(timeout).await? else {
+            panic!("alice should be waiting for players");
+        };
+
+        alice
+            .send(&GameMessage::SeedContribution {
+                game_id: game_id.clone(),
+                player_id: alice_id.clone(),
+                nonce: 99,
+            })
+            .await?;
+        match alice.next_update(timeout).await? {
+            GameState::WAITING {
+                seed_contributions, ..
+            } => assert_eq!(seed_contributions.get(&alice_id), Some(&99)),
+            state => panic!("expected the lobby, got {:?}", state),
+        }
+
+        bob.send(&GameMessage::Join {
+            game_id: game_id.clone(),
+            player_id: bob_id.clone(),
+            name: "bob".to_string(),
+        })
+        .await?;
+        let contributions = BTreeMap::from([(alice_id.clone(), 99)]);
+        let seed = game_seed(board.seed, &contributions);
+        for client in [&mut alice, &mut bob] {
+            match client.next_update(timeout).await? {
+                GameState::RUNNING {
+                    board: running_board,
+                    ..
+                } => {
+                    assert_eq!(running_board.seed, seed);
+                    assert_eq!(
+                        running_board.bomb_coordinates,
+                        Board::new(6, 3, BombLayout::Scattered, Some(seed)).bomb_coordinates
+                    );
+                }
+                state => panic!("expected a running game, got {:?}", state),
+            }
+        }
+
+        // The board is drawn, so it can't be contributed to any more
+        bob.send(&GameMessage::SeedContribution {
+            game_id: game_id.clone(),
+            player_id: bob_id.clone(),
+            nonce: 1,
+        })
+        .await?;
+        let err = bob.next_update(timeout).await.unwrap_err();
+        assert!(
+            err.to_string().contains("before the game starts"),
+            "{}",
+            err
+        );
+
+        alice.close().await?;
+        bob.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_full_two_player_game() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        // Settlement fails without a database, which doesn't affect the broadcasts
+        let server = TestServer::start_with(redis, test_pool()).await?;
+
+        play_to_first_bomb(&server, "1", "2", unique_bet_size()).await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_player_in_a_game_cannot_join_on_another_server() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let server_a = TestServer::start_with(redis.clone(), test_pool()).await?;
+        let server_b = TestServer::start_with(redis, test_pool()).await?;
+        let timeout = Duration::from_secs(5);
+        // Fresh ids so claims left in Redis by earlier runs don't interfere
+        let alice_id = (1_000_000 + u32::from(rand::random::<u16>())).to_string();
+        let bob_id = (2_000_000 + u32::from(rand::random::<u16>())).to_string();
+
+        let create_room = |player_id: &str, name: &str| GameMessage::Play {
+            player_id: player_id.to_string(),
+            name: name.to_string(),
+            single_bet_size: unique_bet_size(),
+            min_players: 2,
+            bombs: 3,
+            grid: 4,
+            is_creating_room: true,
+            layout: BombLayout::Scattered,
+            lives: 1,
+            currency: Currency::SOL,
+        };
+        let mut alice = server_a.client().await?;
+        alice.send(&create_room(&alice_id, "alice")).await?;
+        assert!(matches!(
+            alice.next_update(timeout).await?,
+            GameState::WAITING { .. }
+        ));
+        let mut bob = server_b.client().await?;
+        bob.send(&create_room(&bob_id, "bob")).await?;
+        let GameState::WAITING { game_id, .. } = bob.next_update(timeout).await? else {
+            panic!("bob should be waiting for players");
+        };
+
+        // Server B only knows alice through the claim server A made
+        let mut alice_on_b = server_b.client().await?;
+        alice_on_b
+            .send(&GameMessage::Join {
+                game_id: game_id.clone(),
+                player_id: alice_id.clone(),
+                name: "alice".to_string(),
+            })
+            .await?;
+        match alice_on_b.recv(timeout).await? {
+            GameMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::AlreadyInGame);
+                assert_eq!(message, ALREADY_IN_GAME);
+            }
+            message => panic!("expected an error, got {:?}", message),
+        }
+
+        for client in [alice, bob, alice_on_b] {
+            client.close().await?;
+        }
+        server_a.stop().await?;
+        server_b.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL and DATABASE_URL pointing at a migrated database"]
+    async fn test_bomb_finishes_and_settles_game() -> Result<()> {
+        let server = TestServer::start().await?;
+        let alice = server.create_player(1.0).await?;
+        let bob = server.create_player(1.0).await?;
+        let single_bet_size = unique_bet_size();
+
+        play_to_first_bomb(
+            &server,
+            &alice.to_string(),
+            &bob.to_string(),
+            single_bet_size,
+        )
+        .await?;
+
+        // Settlement runs in the background after the FINISHED broadcast
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.balance(alice).await? == 1.0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        assert!((server.balance(alice).await? - (1.0 - single_bet_size)).abs() < 1e-9);
+        assert!((server.balance(bob).await? - (1.0 + single_bet_size)).abs() < 1e-9);
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL and DATABASE_URL pointing at a migrated database"]
+    async fn test_finished_game_is_recorded() -> Result<()> {
+        let server = TestServer::start().await?;
+        let alice = server.create_player(1.0).await?;
+        let bob = server.create_player(1.0).await?;
+        let single_bet_size = unique_bet_size();
+
+        let (game_id, board) = play_to_first_bomb(
+            &server,
+            &alice.to_string(),
+            &bob.to_string(),
+            single_bet_size,
+        )
+        .await?;
+
+        // The record is written with the settlement, after the FINISHED broadcast
+        let row = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let row = sqlx::query("SELECT * FROM games WHERE game_id = $1")
+                    .bind(&game_id)
+                    .fetch_optional(&server.pool)
+                    .await?;
+                match row {
+                    Some(row) => return anyhow::Ok(row),
+                    None => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        })
+        .await??;
+        assert_eq!(row.get::<String, _>("seed_hash"), seed_hash(board.seed));
+        assert_eq!(row.get::<Vec<i32>, _>("user_ids"), vec![alice, bob]);
+        assert_eq!(row.get::<Vec<i32>, _>("loser_ids"), vec![alice]);
+        assert_eq!(row.get::<String, _>("currency"), Currency::SOL.to_string());
+        assert!((row.get::<f64, _>("single_bet_size") - single_bet_size).abs() < 1e-9);
+        assert!((row.get::<f64, _>("pot") - single_bet_size).abs() < 1e-9);
+        assert!(!row.get::<bool, _>("abandoned"));
+        // Settling took its stakes out of flight
+        let running: Option<String> =
+            sqlx::query_scalar("SELECT game_id FROM running_games WHERE game_id = $1")
+                .bind(&game_id)
+                .fetch_optional(&server.pool)
+                .await?;
+        assert_eq!(running, None);
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL and DATABASE_URL pointing at a migrated database"]
+    async fn test_game_is_matched_and_settled_in_its_currency() -> Result<()> {
+        let server = TestServer::start().await?;
+        let timeout = Duration::from_secs(5);
+        let single_bet_size = unique_bet_size();
+        let play = |player_id: i32, currency| GameMessage::Play {
+            player_id: player_id.to_string(),
+            name: player_id.to_string(),
+            single_bet_size,
+            min_players: 2,
+            bombs: 3,
+            grid: 4,
+            is_creating_room: false,
+            layout: BombLayout::Scattered,
+            lives: 1,
+            currency,
+        };
+        let (alice, bob, carol) = (
+            server.create_player(1.0).await?,
+            server.create_player(1.0).await?,
+            server.create_player(1.0).await?,
+        );
+        let mut alice_client = server.client().await?;
+        let mut bob_client = server.client().await?;
+        let mut carol_client = server.client().await?;
+
+        alice_client.send(&play(alice, Currency::SOL)).await?;
+        let GameState::WAITING { game_id, .. } = alice_client.next_update(timeout).await? else {
+            panic!("alice should be waiting for players");
+        };
+        // The same bet in MON doesn't join alice's SOL game
+        carol_client.send(&play(carol, Currency::MON)).await?;
+        let GameState::WAITING {
+            game_id: carol_game_id,
+            ..
+        } = carol_client.next_update(timeout).await?
+        else {
+            panic!("carol should be waiting for players");
+        };
+        assert_ne!(carol_game_id, game_id);
+
+        bob_client.send(&play(bob, Currency::SOL)).await?;
+        let board = match bob_client.next_update(timeout).await? {
+            GameState::RUNNING {
+                game_id: running_id,
+                board,
+                currency,
+                ..
+            } => {
+                assert_eq!(running_id, game_id);
+                assert_eq!(currency, Currency::SOL);
+                board
+            }
+            state => panic!("expected bob to be matched with alice, got {:?}", state),
+        };
+
+        let bomb = board.bomb_coordinates[0] as usize;
+        alice_client
+            .send(&GameMessage::MakeMove {
+                game_id: game_id.clone(),
+                x: bomb / board.n,
+                y: bomb % board.n,
+            })
+            .await?;
+        loop {
+            if let GameState::FINISHED { currency, .. } = bob_client.next_update(timeout).await? {
+                assert_eq!(currency, Currency::SOL);
+                break;
+            }
+        }
+
+        // Settlement runs in the background after the FINISHED broadcast
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.balance(alice).await? == 1.0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        assert!((server.balance(alice).await? - (1.0 - single_bet_size)).abs() < 1e-9);
+        assert!((server.balance(bob).await? - (1.0 + single_bet_size)).abs() < 1e-9);
+        for player in [alice, bob, carol] {
+            assert_eq!(server.balance_in(player, Currency::MON).await?, 1.0);
+        }
+
+        alice_client.close().await?;
+        bob_client.close().await?;
+        carol_client.close().await?;
+        server.stop().await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_shutdown_persists_active_games() -> Result<()> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let server = GameServer {
+            server_id: "test-server".to_string(),
+            registry: GameRegistry::new(redis.clone(), "test-server".to_string(), test_pool()),
+            connection_limit: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+        };
+
+        let running_id = Uuid::new_v4().to_string();
+        let aborted_id = Uuid::new_v4().to_string();
+        {
+            let mut games = server.registry.games.write().await;
+            games.insert(running_id.clone(), running_game(&running_id));
+            games.insert(
+                aborted_id.clone(),
+                GameState::ABORTED {
+                    game_id: aborted_id.clone(),
+                },
+            );
+        }
+
+        // Shut down straight away; everything must be persisted by the time this returns
+        server
+            .start_with_shutdown("127.0.0.1:0", std::future::ready(()))
+            .await?;
+
+        let mut conn = redis.get_multiplexed_async_connection().await?;
+        let snapshot: Option<String> = conn.get(format!("game_state:{}", running_id)).await?;
+        let state: GameState = serde_json::from_str(&snapshot.expect("game was not persisted"))?;
+        assert!(matches!(state, GameState::RUNNING { game_id, .. } if game_id == running_id));
+
+        let snapshot: Option<String> = conn.get(format!("game_state:{}", aborted_id)).await?;
+        assert!(snapshot.is_none());
+
+        let _: () = conn.del(format!("game_state:{}", running_id)).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,181 @@
+//! An optional house player that takes the last seat of a public lobby nobody else
+//! filled in time, so a player waiting alone still gets a game.
+//!
+//! The bot plays through a [`GameClient`] connected to its own server, so it sees the
+//! same updates as the other players and nothing more, and its bets are settled like
+//! theirs against the house's wallet user. It never looks at where the bombs are.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    board::{Board, BombLayout, CellState},
+    client::GameClient,
+    protocol::{GameMessage, GameState},
+};
+
+// How long the bot waits on a game that has stopped moving before it leaves
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Who the house plays as. Its bets settle against `user_id`'s wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HouseBotConfig {
+    pub user_id: i32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct HouseBot {
+    config: HouseBotConfig,
+    // Where the server accepts connections, known once it is listening
+    server_uri: Arc<OnceLock<String>>,
+}
+
+impl HouseBot {
+    pub fn new(config: HouseBotConfig) -> Self {
+        Self {
+            config,
+            server_uri: Arc::new(OnceLock::new()),
+        }
+    }
+
+    pub fn player_id(&self) -> String {
+        self.config.user_id.to_string()
+    }
+
+    // Called once the server is listening on `addr`; the bot connects over loopback
+    // when it listens on every interface
+    pub(crate) fn serving_on(&self, mut addr: SocketAddr) {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = self.server_uri.set(format!("ws://{}/", addr));
+    }
+
+    /// Joins `game_id` and plays it to the end. Errors if the seat was taken, the bot
+    /// is still busy with another game or the game stalls.
+    pub(crate) async fn play(&self, game_id: &str) -> Result<()> {
+        let uri = self
+            .server_uri
+            .get()
+            .ok_or_else(|| anyhow!("the server isn't listening yet"))?;
+        let player_id = self.player_id();
+        let mut client = GameClient::connect(uri).await?;
+        client
+            .send(&GameMessage::Join {
+                game_id: game_id.to_string(),
+                player_id: player_id.clone(),
+                name: self.config.name.clone(),
+            })
+            .await?;
+
+        // A turn is a move and then a LockComplete handing the turn on
+        let mut moved = false;
+        loop {
+            let GameState::RUNNING {
+                players,
+                turn_idx,
+                board,
+                ..
+            } = (match client.next_update(IDLE_TIMEOUT).await? {
+                GameState::WAITING { .. } => continue,
+                state => state,
+            })
+            else {
+                break;
+            };
+            if players.get(turn_idx).map(|p| p.id.as_str()) != Some(player_id.as_str()) {
+                moved = false;
+                continue;
+            }
+            let message = match moved {
+                true => GameMessage::LockComplete {
+                    game_id: game_id.to_string(),
+                    player_id: player_id.clone(),
+                },
+                false => {
+                    let (x, y) =
+                        pick_cell(&board).ok_or_else(|| anyhow!("no cell left to mine"))?;
+                    GameMessage::MakeMove {
+                        game_id: game_id.to_string(),
+                        x,
+                        y,
+                    }
+                }
+            };
+            client.send(&message).await?;
+            moved = !moved;
+        }
+        client.close().await
+    }
+}
+
+/// The cell the bot mines next, from what every player can see: the first hidden cell in
+/// row order, passing over flagged cells and, where bombs come in runs, cells next to a
+/// revealed bomb while there are others.
+pub fn pick_cell(board: &Board) -> Option<(usize, usize)> {
+    let n = board.dimension();
+    let next_to_bomb = |x: usize, y: usize| {
+        board.layout == BombLayout::Continuous
+            && [(0, 1), (2, 1), (1, 0), (1, 2)].iter().any(|&(dx, dy)| {
+                matches!(
+                    (x + dx)
+                        .checked_sub(1)
+                        .zip((y + dy).checked_sub(1))
+                        .and_then(|(x, y)| board.cell(x, y)),
+                    Some(CellState::Bomb)
+                )
+            })
+    };
+    (0..n)
+        .flat_map(|x| (0..n).map(move |y| (x, y)))
+        .filter_map(|(x, y)| match board.cell(x, y)? {
+            CellState::Hidden => Some(((next_to_bomb(x, y), false), (x, y))),
+            CellState::Flagged => Some(((next_to_bomb(x, y), true), (x, y))),
+            CellState::Mined | CellState::Bomb => None,
+        })
+        .min_by_key(|(risk, _)| *risk)
+        .map(|(_, cell)| cell)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_cell_only_mines_hidden_cells() {
+        let mut board = Board::new(3, 2, BombLayout::Scattered, Some(7));
+        let mut picked = Vec::new();
+        while let Some((x, y)) = pick_cell(&board) {
+            assert!(board.check_hidden(x, y).is_ok(), "({}, {})", x, y);
+            board.mine(x, y);
+            picked.push((x, y));
+        }
+        // Every cell once, in row order, whatever the bombs
+        let all: Vec<_> = (0..3).flat_map(|x| (0..3).map(move |y| (x, y))).collect();
+        assert_eq!(picked, all);
+    }
+
+    #[test]
+    fn test_pick_cell_passes_over_flags_and_bomb_runs() {
+        let mut board = Board::new(3, 1, BombLayout::Continuous, Some(7));
+        board.toggle_flag(0, 0).unwrap();
+        assert_eq!(pick_cell(&board), Some((0, 1)));
+
+        // A revealed bomb makes its neighbours riskier under a continuous layout
+        let bomb = board.bomb_coordinates[0] as usize;
+        let (bx, by) = (bomb / 3, bomb % 3);
+        board.mine(bx, by);
+        let (x, y) = pick_cell(&board).unwrap();
+        assert!(x.abs_diff(bx) + y.abs_diff(by) > 1, "({}, {})", x, y);
+        assert!(board.check_hidden(x, y).is_ok());
+    }
+}
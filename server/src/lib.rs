@@ -0,0 +1,6 @@
+use common::agg_mod;
+
+agg_mod!(board client config game house_bot player protocol seed_gen discovery xplode_moves metrics router verify);
+
+#[cfg(test)]
+mod test_harness;
@@ -1,10 +1,7 @@
-use common::agg_mod;
 use dotenv::dotenv;
-use game::GameServer;
+use server::{config::AppConfig, game::GameServer, metrics};
 use tracing::info;
 
-agg_mod!(board game player seed_gen discovery xplode_moves);
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Load environment variables from .env file if it exists
@@ -15,8 +12,20 @@ async fn main() -> anyhow::Result<()> {
         .init();
     info!("Starting the game server");
 
+    let config = AppConfig::from_env()?;
+    info!(
+        "Running in {:?}, notifications {}",
+        config.environment,
+        if config.notifications_enabled {
+            "on"
+        } else {
+            "off"
+        }
+    );
+
     // Start the game server
-    let game_server = GameServer::new().await;
-    game_server.start("0.0.0.0:3000").await?;
+    let game_server = GameServer::new(&config).await?;
+    tokio::spawn(metrics::serve(config.metrics_port, game_server.registry()));
+    game_server.start(&config.bind_addr.to_string()).await?;
     Ok(())
 }
@@ -0,0 +1,339 @@
+use common::utils::Currency;
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::{collections::HashMap, time::Duration};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use tracing::{error, info};
+use warp::{http::StatusCode, Filter};
+
+use crate::{
+    game::GameRegistry,
+    verify::{verify_game, GameRecord},
+};
+
+// Board size /lobbies lists when the query doesn't name one
+const DEFAULT_LOBBY_GRID: u32 = 4;
+// Enough for a move on every cell of the largest board
+const MAX_VERIFY_BODY_BYTES: u64 = 256 * 1024;
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    pub static ref GAMES_COMPLETED: IntCounter = register(IntCounter::new(
+        "games_completed_total",
+        "Games that ended with a player hitting a bomb or stopping"
+    ));
+    pub static ref GAMES_ABANDONED: IntCounter = register(IntCounter::new(
+        "games_abandoned_total",
+        "Games that ended because a player disconnected and didn't come back"
+    ));
+    pub static ref CONNECTIONS_REJECTED: IntCounter = register(IntCounter::new(
+        "connections_rejected_total",
+        "Connections turned away because the connection limit was reached"
+    ));
+    pub static ref WEBSOCKET_MESSAGES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "websocket_messages_total",
+            "WebSocket messages processed, by message type"
+        ),
+        &["message_type"]
+    ));
+    pub static ref GAME_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "game_duration_seconds",
+            "Time from a game starting to it finishing or being aborted, by game type"
+        )
+        .buckets(vec![10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0]),
+        &["game_type"]
+    ));
+}
+
+pub fn record_websocket_message(message_type: &str) {
+    WEBSOCKET_MESSAGES.with_label_values(&[message_type]).inc();
+}
+
+pub fn record_game_end(game_type: &str, duration: Duration) {
+    GAME_DURATION
+        .with_label_values(&[game_type])
+        .observe(duration.as_secs_f64());
+}
+
+fn register<T>(metric: prometheus::Result<T>) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+    metric
+}
+
+// GET /metrics in the Prometheus text format, GET /live as a cheap liveness probe,
+// GET /health, which checks the game server's dependencies and answers 503 if any is down,
+// GET /stats with the players online and games active across all servers,
+// GET /lobbies?currency=SOL&grid=4 with the public games that can still be joined, and
+// POST /verify, which replays a finished game's record and says whether it holds up
+fn routes(
+    game_registry: GameRegistry,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let metrics = warp::path("metrics").and(warp::get()).map(|| {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {}", e);
+        }
+        warp::reply::with_header(buffer, "content-type", prometheus::TEXT_FORMAT)
+    });
+    let live = warp::path("live").and(warp::get()).map(|| "OK");
+    let health = warp::path("health").and(warp::get()).then({
+        let game_registry = game_registry.clone();
+        move || {
+            let game_registry = game_registry.clone();
+            async move {
+                let report = game_registry.health().await;
+                let status = match report.is_healthy() {
+                    true => StatusCode::OK,
+                    false => StatusCode::SERVICE_UNAVAILABLE,
+                };
+                warp::reply::with_status(warp::reply::json(&report.to_json()), status)
+            }
+        }
+    });
+    let stats = warp::path("stats").and(warp::get()).then({
+        let game_registry = game_registry.clone();
+        move || {
+            let game_registry = game_registry.clone();
+            async move {
+                match game_registry.stats().await {
+                    Ok(stats) => {
+                        warp::reply::with_status(warp::reply::json(&stats), StatusCode::OK)
+                    }
+                    Err(e) => {
+                        error!("Failed to gather stats: {}", e);
+                        unavailable("stats unavailable")
+                    }
+                }
+            }
+        }
+    });
+    let verify = warp::path("verify")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_VERIFY_BODY_BYTES))
+        .and(warp::body::json())
+        .map({
+            let game_registry = game_registry.clone();
+            move |record: GameRecord| {
+                // The record sizes the board that is redrawn, so it gets a Play's limits
+                if let Err(reason) = game_registry.validate_game(
+                    record.grid,
+                    record.bombs,
+                    record.players,
+                    record.lives,
+                ) {
+                    return warp::reply::with_status(
+                        warp::reply::json(&json!({ "error": reason })),
+                        StatusCode::BAD_REQUEST,
+                    );
+                }
+                warp::reply::with_status(warp::reply::json(&verify_game(&record)), StatusCode::OK)
+            }
+        });
+    let lobbies = warp::path("lobbies")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .then(move |query: HashMap<String, String>| {
+            let game_registry = game_registry.clone();
+            async move {
+                let currency = query
+                    .get("currency")
+                    .map_or(Ok(Currency::SOL), |c| c.parse());
+                let grid = query
+                    .get("grid")
+                    .map_or(Ok(DEFAULT_LOBBY_GRID), |g| g.parse());
+                let (Ok(currency), Ok(grid)) = (currency, grid) else {
+                    return warp::reply::with_status(
+                        warp::reply::json(&json!({ "error": "invalid currency or grid" })),
+                        StatusCode::BAD_REQUEST,
+                    );
+                };
+                match game_registry.open_games(currency, grid).await {
+                    Ok(games) => {
+                        warp::reply::with_status(warp::reply::json(&games), StatusCode::OK)
+                    }
+                    Err(e) => {
+                        error!("Failed to list lobbies: {}", e);
+                        unavailable("lobbies unavailable")
+                    }
+                }
+            }
+        });
+
+    metrics.or(live).or(health).or(stats).or(lobbies).or(verify)
+}
+
+fn unavailable(reason: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&json!({ "error": reason })),
+        StatusCode::SERVICE_UNAVAILABLE,
+    )
+}
+
+pub async fn serve(port: u16, game_registry: GameRegistry) {
+    info!("Metrics server listening on port {}", port);
+    warp::serve(routes(game_registry))
+        .run(([0, 0, 0, 0], port))
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use redis::Client;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::board::{Board, BombLayout};
+
+    fn registry(redis_url: &str, database_url: &str) -> GameRegistry {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy(database_url)
+            .unwrap();
+        GameRegistry::new(
+            Client::open(redis_url).unwrap(),
+            "test-server".to_string(),
+            pool,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_live() {
+        let routes = routes(registry(
+            "redis://127.0.0.1:1",
+            "postgres://127.0.0.1:1/test",
+        ));
+        let response = warp::test::request().path("/live").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "OK");
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_down_dependencies() {
+        let routes = routes(registry(
+            "redis://127.0.0.1:1",
+            "postgres://127.0.0.1:1/test",
+        ));
+        let response = warp::test::request().path("/health").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["status"], "unavailable");
+        assert_ne!(body["checks"]["postgres"], "ok");
+        assert_ne!(body["checks"]["redis"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_stats_unavailable_without_redis() {
+        let routes = routes(registry(
+            "redis://127.0.0.1:1",
+            "postgres://127.0.0.1:1/test",
+        ));
+        let response = warp::test::request().path("/stats").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_lobbies_rejects_bad_query() {
+        let routes = routes(registry(
+            "redis://127.0.0.1:1",
+            "postgres://127.0.0.1:1/test",
+        ));
+        for path in ["/lobbies?currency=DOGE", "/lobbies?grid=big"] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        let response = warp::test::request()
+            .path("/lobbies?currency=SOL&grid=4")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_verify_route() {
+        let routes = routes(registry(
+            "redis://127.0.0.1:1",
+            "postgres://127.0.0.1:1/test",
+        ));
+        // Three bombs on a 2x2 board leave one safe cell; mining it ends in a draw
+        let board = Board::new(2, 3, BombLayout::Scattered, Some(7));
+        let safe = (0..4)
+            .find(|c| !board.bomb_coordinates.contains(c))
+            .unwrap();
+        let mut record = json!({
+            "grid": 2,
+            "bombs": 3,
+            "genesis_seed": 7,
+            "players": 2,
+            "moves": [{ "player_idx": 1, "x": safe / 2, "y": safe % 2 }],
+            "result": "Draw",
+        });
+        let verify = |record: &Value| {
+            warp::test::request()
+                .method("POST")
+                .path("/verify")
+                .json(record)
+                .reply(&routes)
+        };
+
+        let response = verify(&record).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["consistent"], true, "{}", body);
+        assert_eq!(body["bomb_coordinates"], json!(board.bomb_coordinates));
+
+        record["result"] = json!({ "Loser": 1 });
+        let body: Value = serde_json::from_slice(verify(&record).await.body()).unwrap();
+        assert_eq!(body["consistent"], false, "{}", body);
+
+        // The board is only redrawn within a Play's limits
+        record["grid"] = json!(1000);
+        assert_eq!(verify(&record).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL"]
+    async fn test_stats_route() {
+        dotenv::dotenv().ok();
+        let routes = routes(registry(
+            &env::var("REDIS_URL").unwrap(),
+            "postgres://127.0.0.1:1/test",
+        ));
+        let response = warp::test::request().path("/stats").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body["players_online"].is_u64(), "{}", body);
+        assert!(body["active_games"].is_u64(), "{}", body);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL and REDIS_URL"]
+    async fn test_health_all_dependencies_up() {
+        dotenv::dotenv().ok();
+        let routes = routes(registry(
+            &env::var("REDIS_URL").unwrap(),
+            &env::var("DATABASE_URL").unwrap(),
+        ));
+        let response = warp::test::request().path("/health").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "status": "ok", "checks": { "postgres": "ok", "redis": "ok" } })
+        );
+    }
+}
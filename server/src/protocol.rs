@@ -0,0 +1,619 @@
+//! The messages the game server and its clients exchange over the WebSocket, each
+//! sent as one JSON frame. The server and [`crate::client::GameClient`] both speak
+//! these types, so changing their shape is a protocol change.
+
+use std::{collections::BTreeMap, fmt};
+
+use common::{payout::GameResult, utils::Currency};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{Board, BombLayout},
+    discovery::default_currency,
+    player::Player,
+};
+
+// Without a lives option the first bomb a player hits ends the game
+pub fn default_lives() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GameState {
+    WAITING {
+        game_id: String,
+        creator: Player,
+        board: Board,
+        single_bet_size: f64,
+        min_players: u32,
+        players: Vec<Player>,
+        // Lives each player starts with
+        #[serde(default = "default_lives")]
+        lives: u32,
+        // Bets are placed and settled in this currency
+        #[serde(default = "default_currency")]
+        currency: Currency,
+        // Nonce each player added to the board's seed, by player id
+        #[serde(default)]
+        seed_contributions: BTreeMap<String, u64>,
+    },
+    RUNNING {
+        game_id: String,
+        players: Vec<Player>,
+        board: Board,
+        turn_idx: usize,
+        single_bet_size: f64,
+        locks: Option<Vec<(usize, usize)>>,
+        #[serde(default = "default_lives")]
+        lives: u32,
+        // Lives each player has left, by player index. A bomb that doesn't take a
+        // player's last life stays revealed and the turn passes as after a safe cell.
+        #[serde(default)]
+        lives_left: Vec<u32>,
+        #[serde(default = "default_currency")]
+        currency: Currency,
+    },
+    FINISHED {
+        game_id: String,
+        result: GameResult,
+        board: Board,
+        players: Vec<Player>,
+        single_bet_size: f64,
+        #[serde(default = "default_lives")]
+        lives: u32,
+        #[serde(default = "default_currency")]
+        currency: Currency,
+    },
+    REMATCH {
+        game_id: String,
+        players: Vec<Player>,
+        board: Board,
+        single_bet_size: f64,
+        accepted: Vec<usize>,
+        #[serde(default = "default_lives")]
+        lives: u32,
+        #[serde(default = "default_currency")]
+        currency: Currency,
+    },
+    // During the start, user doesn't make a move for some predefined time
+    ABORTED {
+        game_id: String,
+    },
+    RematchRejected {
+        game_id: String,
+    },
+}
+
+impl GameState {
+    pub(crate) fn game_id(&self) -> &str {
+        match self {
+            GameState::WAITING { game_id, .. }
+            | GameState::RUNNING { game_id, .. }
+            | GameState::FINISHED { game_id, .. }
+            | GameState::REMATCH { game_id, .. }
+            | GameState::ABORTED { game_id }
+            | GameState::RematchRejected { game_id } => game_id,
+        }
+    }
+}
+
+/// What went wrong with a client's message, for clients to act on. The message that comes
+/// with it is for people and may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The message couldn't be read or asked for something the server doesn't allow
+    InvalidRequest,
+    /// Only the player whose turn it is may do this
+    NotYourTurn,
+    /// The connection hasn't joined or resumed a seat in the game, or acts for another player
+    NotInGame,
+    /// The game isn't in a state that allows this, e.g. a move before it started
+    InvalidGameState,
+    /// The cell can't be played, e.g. it is off the board or already revealed
+    InvalidMove,
+    /// The lobby has no seat left
+    GameFull,
+    /// The player is already seated in another game
+    AlreadyInGame,
+    /// Neither this server nor any other has the game
+    GameNotFound,
+    /// Resuming a seat needs the token the server issued when the player joined
+    InvalidResumeToken,
+    /// The player created too many games recently and should retry later
+    TooManyGamesCreated,
+    /// An update was too large to send, so the client missed it
+    MessageTooLarge,
+    /// Something failed on the server; the request may succeed if retried
+    Internal,
+}
+
+/// An error for the client that sent a message, sent as [`GameMessage::Error`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for GameMessage {
+    fn from(error: ProtocolError) -> Self {
+        GameMessage::Error {
+            code: error.code,
+            message: error.message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BlockchainUpdateType {
+    GameInitialized,
+    MoveRecorded,
+    GameCommitted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GameMessage {
+    Play {
+        player_id: String,
+        name: String,
+        single_bet_size: f64,
+        min_players: u32,
+        bombs: u32,
+        grid: u32,
+        is_creating_room: bool,
+        #[serde(default)]
+        layout: BombLayout,
+        // Bombs a player can survive is one less than this
+        #[serde(default = "default_lives")]
+        lives: u32,
+        #[serde(default = "default_currency")]
+        currency: Currency,
+    },
+    Join {
+        game_id: String,
+        player_id: String,
+        name: String,
+    },
+    MakeMove {
+        game_id: String,
+        x: usize,
+        y: usize,
+    },
+    Lock {
+        x: usize,
+        y: usize,
+        game_id: String,
+        player_id: String,
+    },
+    LockComplete {
+        game_id: String,
+        player_id: String,
+    },
+    // Toggles a flag on a hidden cell, never revealing it
+    Flag {
+        game_id: String,
+        player_id: String,
+        x: usize,
+        y: usize,
+    },
+    Stop {
+        game_id: String,
+        abort: bool,
+    },
+    // A player's nonce for the board's seed, accepted while the lobby is waiting
+    SeedContribution {
+        game_id: String,
+        player_id: String,
+        nonce: u64,
+    },
+    Ping {
+        game_id: Option<String>,
+        player_id: Option<String>,
+        // Required to resume a seat, see `Pong`
+        resume_token: Option<String>,
+    },
+    Pong {
+        server_id: String,
+        // Sent once, right after the player joins a game. Pings naming that game and
+        // player must carry it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
+    GameUpdate(GameState),
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    RedirectToServer {
+        game_id: String,
+        machine_id: String,
+    },
+    Rematch {
+        game_id: String,
+        player_id: String,
+    },
+    RematchRequest {
+        game_id: String,
+        requester_id: String,
+    },
+    RematchResponse {
+        game_id: String,
+        player_id: String,
+        want_rematch: bool,
+    },
+    BlockchainUpdate {
+        game_id: String,
+        update_type: BlockchainUpdateType,
+        transaction_hash: String,
+    },
+    Gif {
+        game_id: String,
+        player_id: String,
+        gif_id: usize,
+    },
+    ServerDraining {
+        game_id: String,
+    },
+}
+
+impl GameMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtocolError::new(code, message).into()
+    }
+
+    // Label used for the websocket message metrics
+    pub(crate) fn message_type(&self) -> &'static str {
+        match self {
+            GameMessage::Play { .. } => "play",
+            GameMessage::Join { .. } => "join",
+            GameMessage::MakeMove { .. } => "make_move",
+            GameMessage::Lock { .. } => "lock",
+            GameMessage::LockComplete { .. } => "lock_complete",
+            GameMessage::Flag { .. } => "flag",
+            GameMessage::Stop { .. } => "stop",
+            GameMessage::SeedContribution { .. } => "seed_contribution",
+            GameMessage::Ping { .. } => "ping",
+            GameMessage::Pong { .. } => "pong",
+            GameMessage::GameUpdate(_) => "game_update",
+            GameMessage::Error { .. } => "error",
+            GameMessage::RedirectToServer { .. } => "redirect_to_server",
+            GameMessage::Rematch { .. } => "rematch",
+            GameMessage::RematchRequest { .. } => "rematch_request",
+            GameMessage::RematchResponse { .. } => "rematch_response",
+            GameMessage::BlockchainUpdate { .. } => "blockchain_update",
+            GameMessage::Gif { .. } => "gif",
+            GameMessage::ServerDraining { .. } => "server_draining",
+        }
+    }
+
+    pub(crate) fn game_id(&self) -> Option<&str> {
+        match self {
+            GameMessage::Join { game_id, .. }
+            | GameMessage::MakeMove { game_id, .. }
+            | GameMessage::Lock { game_id, .. }
+            | GameMessage::LockComplete { game_id, .. }
+            | GameMessage::Flag { game_id, .. }
+            | GameMessage::Stop { game_id, .. }
+            | GameMessage::SeedContribution { game_id, .. }
+            | GameMessage::RedirectToServer { game_id, .. }
+            | GameMessage::Rematch { game_id, .. }
+            | GameMessage::RematchRequest { game_id, .. }
+            | GameMessage::RematchResponse { game_id, .. }
+            | GameMessage::BlockchainUpdate { game_id, .. }
+            | GameMessage::Gif { game_id, .. }
+            | GameMessage::ServerDraining { game_id } => Some(game_id),
+            GameMessage::Ping { game_id, .. } => game_id.as_deref(),
+            GameMessage::GameUpdate(state) => Some(state.game_id()),
+            _ => None,
+        }
+    }
+
+    // Messages that act in a game a player is seated in, rather than take or resume
+    // a seat. The server only takes them from the connection holding that seat.
+    pub(crate) fn acts_in_game(&self) -> bool {
+        matches!(
+            self,
+            GameMessage::MakeMove { .. }
+                | GameMessage::Lock { .. }
+                | GameMessage::LockComplete { .. }
+                | GameMessage::Flag { .. }
+                | GameMessage::Stop { .. }
+                | GameMessage::SeedContribution { .. }
+                | GameMessage::GameUpdate(_)
+                | GameMessage::Rematch { .. }
+                | GameMessage::RematchRequest { .. }
+                | GameMessage::RematchResponse { .. }
+                | GameMessage::BlockchainUpdate { .. }
+                | GameMessage::Gif { .. }
+        )
+    }
+
+    pub(crate) fn player_id(&self) -> Option<&str> {
+        match self {
+            GameMessage::Play { player_id, .. }
+            | GameMessage::Join { player_id, .. }
+            | GameMessage::Lock { player_id, .. }
+            | GameMessage::LockComplete { player_id, .. }
+            | GameMessage::Flag { player_id, .. }
+            | GameMessage::SeedContribution { player_id, .. }
+            | GameMessage::Rematch { player_id, .. }
+            | GameMessage::RematchResponse { player_id, .. }
+            | GameMessage::Gif { player_id, .. } => Some(player_id),
+            GameMessage::RematchRequest { requester_id, .. } => Some(requester_id),
+            GameMessage::Ping { player_id, .. } => player_id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn players() -> Vec<Player> {
+        vec![
+            Player::new("1".to_string(), "alice".to_string()),
+            Player::new("2".to_string(), "bob".to_string()),
+        ]
+    }
+
+    fn states() -> Vec<GameState> {
+        let board = Board::new(3, 2, BombLayout::Scattered, Some(7));
+        let game_id = "game-1".to_string();
+        vec![
+            GameState::WAITING {
+                game_id: game_id.clone(),
+                creator: players()[0].clone(),
+                board: board.clone(),
+                single_bet_size: 0.5,
+                min_players: 2,
+                players: players(),
+                lives: 2,
+                currency: Currency::SOL,
+                seed_contributions: BTreeMap::from([("1".to_string(), 42)]),
+            },
+            GameState::RUNNING {
+                game_id: game_id.clone(),
+                players: players(),
+                board: board.clone(),
+                turn_idx: 1,
+                single_bet_size: 0.5,
+                locks: Some(vec![(0, 1)]),
+                lives: 2,
+                lives_left: vec![2, 1],
+                currency: Currency::SOL,
+            },
+            GameState::FINISHED {
+                game_id: game_id.clone(),
+                result: GameResult::Losers(vec![0, 1]),
+                board: board.clone(),
+                players: players(),
+                single_bet_size: 0.5,
+                lives: 1,
+                currency: Currency::SOL,
+            },
+            GameState::REMATCH {
+                game_id: game_id.clone(),
+                players: players(),
+                board,
+                single_bet_size: 0.5,
+                accepted: vec![0],
+                lives: 1,
+                currency: Currency::SOL,
+            },
+            GameState::ABORTED {
+                game_id: game_id.clone(),
+            },
+            GameState::RematchRejected { game_id },
+        ]
+    }
+
+    fn messages() -> Vec<GameMessage> {
+        let game_id = || "game-1".to_string();
+        let player_id = || "1".to_string();
+        let mut messages = vec![
+            GameMessage::Play {
+                player_id: player_id(),
+                name: "alice".to_string(),
+                single_bet_size: 0.5,
+                min_players: 2,
+                bombs: 3,
+                grid: 4,
+                is_creating_room: false,
+                layout: BombLayout::Continuous,
+                lives: 2,
+                currency: Currency::SOL,
+            },
+            GameMessage::Join {
+                game_id: game_id(),
+                player_id: player_id(),
+                name: "alice".to_string(),
+            },
+            GameMessage::MakeMove {
+                game_id: game_id(),
+                x: 1,
+                y: 2,
+            },
+            GameMessage::Lock {
+                x: 1,
+                y: 2,
+                game_id: game_id(),
+                player_id: player_id(),
+            },
+            GameMessage::LockComplete {
+                game_id: game_id(),
+                player_id: player_id(),
+            },
+            GameMessage::Flag {
+                game_id: game_id(),
+                player_id: player_id(),
+                x: 0,
+                y: 0,
+            },
+            GameMessage::Stop {
+                game_id: game_id(),
+                abort: true,
+            },
+            GameMessage::SeedContribution {
+                game_id: game_id(),
+                player_id: player_id(),
+                nonce: u64::MAX,
+            },
+            GameMessage::Ping {
+                game_id: Some(game_id()),
+                player_id: Some(player_id()),
+                resume_token: Some("token".to_string()),
+            },
+            GameMessage::Pong {
+                server_id: "server-1".to_string(),
+                resume_token: Some("token".to_string()),
+            },
+            GameMessage::error(ErrorCode::GameNotFound, "Game not found"),
+            GameMessage::RedirectToServer {
+                game_id: game_id(),
+                machine_id: "machine-b".to_string(),
+            },
+            GameMessage::Rematch {
+                game_id: game_id(),
+                player_id: player_id(),
+            },
+            GameMessage::RematchRequest {
+                game_id: game_id(),
+                requester_id: player_id(),
+            },
+            GameMessage::RematchResponse {
+                game_id: game_id(),
+                player_id: player_id(),
+                want_rematch: true,
+            },
+            GameMessage::BlockchainUpdate {
+                game_id: game_id(),
+                update_type: BlockchainUpdateType::MoveRecorded,
+                transaction_hash: "0xabc".to_string(),
+            },
+            GameMessage::Gif {
+                game_id: game_id(),
+                player_id: player_id(),
+                gif_id: 3,
+            },
+            GameMessage::ServerDraining { game_id: game_id() },
+        ];
+        messages.extend(states().into_iter().map(GameMessage::GameUpdate));
+        messages
+    }
+
+    #[test]
+    fn test_every_message_round_trips() {
+        let messages = messages();
+        // Adding a message means adding it here, or this count is off
+        let message_types: HashSet<_> = messages.iter().map(GameMessage::message_type).collect();
+        assert_eq!(message_types.len(), 19);
+
+        for message in messages {
+            let encoded = serde_json::to_value(&message).unwrap();
+            let decoded: GameMessage = serde_json::from_value(encoded.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), encoded);
+        }
+    }
+
+    #[test]
+    fn test_message_shapes() {
+        let encoded = |message: &GameMessage| serde_json::to_value(message).unwrap();
+        assert_eq!(
+            encoded(&GameMessage::MakeMove {
+                game_id: "game-1".to_string(),
+                x: 1,
+                y: 2,
+            }),
+            json!({ "MakeMove": { "game_id": "game-1", "x": 1, "y": 2 } })
+        );
+        assert_eq!(
+            encoded(&GameMessage::error(
+                ErrorCode::GameNotFound,
+                "Game not found"
+            )),
+            json!({ "Error": { "code": "GameNotFound", "message": "Game not found" } })
+        );
+        // The resume token is left out of Pongs that don't carry one
+        assert_eq!(
+            encoded(&GameMessage::Pong {
+                server_id: "server-1".to_string(),
+                resume_token: None,
+            }),
+            json!({ "Pong": { "server_id": "server-1" } })
+        );
+        assert_eq!(
+            encoded(&GameMessage::GameUpdate(GameState::ABORTED {
+                game_id: "game-1".to_string(),
+            })),
+            json!({ "GameUpdate": { "ABORTED": { "game_id": "game-1" } } })
+        );
+        let Value::Object(running) =
+            &encoded(&GameMessage::GameUpdate(states().remove(1)))["GameUpdate"]["RUNNING"]
+        else {
+            panic!("RUNNING isn't an object");
+        };
+        let mut fields: Vec<_> = running.keys().map(String::as_str).collect();
+        fields.sort_unstable();
+        assert_eq!(
+            fields,
+            [
+                "board",
+                "currency",
+                "game_id",
+                "lives",
+                "lives_left",
+                "locks",
+                "players",
+                "single_bet_size",
+                "turn_idx"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_older_clients_fill_in_defaults() {
+        let message: GameMessage = serde_json::from_value(json!({ "Play": {
+            "player_id": "1",
+            "name": "alice",
+            "single_bet_size": 0.5,
+            "min_players": 2,
+            "bombs": 3,
+            "grid": 4,
+            "is_creating_room": false,
+        } }))
+        .unwrap();
+        let GameMessage::Play {
+            layout,
+            lives,
+            currency,
+            ..
+        } = message
+        else {
+            panic!("expected a Play, got {:?}", message);
+        };
+        assert_eq!(layout, BombLayout::default());
+        assert_eq!(lives, default_lives());
+        assert_eq!(currency, default_currency());
+    }
+}
@@ -0,0 +1,182 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+
+use crate::game::{parse_cookies, parse_http_headers, parse_query_string, parse_request_uri};
+
+/// How a connection that asked for another game server gets there. Which server it
+/// asked for and what it is told to do about it depend on the platform.
+pub trait ServerRouter: Send + Sync {
+    /// The server the request in `data` asked for, unless that is `server_id`.
+    fn requested_server(&self, data: &[u8], server_id: &str) -> Option<String>;
+
+    /// The full HTTP response sending the client on to `target`.
+    fn redirect_response(&self, target: &str) -> String;
+}
+
+/// Picks the router for this deployment: the [`ServerListRouter`] when `GAME_SERVERS`
+/// lists the servers, Fly.io's replay otherwise.
+pub fn router_for(servers: Option<ServerListRouter>) -> Arc<dyn ServerRouter> {
+    match servers {
+        Some(servers) => Arc::new(servers),
+        None => Arc::new(FlyRouter),
+    }
+}
+
+// The machine a request asked for with `?machine_id=`
+fn query_machine_id(data: &[u8]) -> Option<String> {
+    let uri = parse_request_uri(data)?;
+    let (_, query) = uri.split_once('?')?;
+    parse_query_string(query).remove("machine_id")
+}
+
+/// Has Fly.io's proxy replay the request on the machine, which is named by the
+/// `machine_id` query parameter or the `fly-machine-id` cookie.
+pub struct FlyRouter;
+
+impl ServerRouter for FlyRouter {
+    fn requested_server(&self, data: &[u8], server_id: &str) -> Option<String> {
+        if let Some(machine_id) = query_machine_id(data) {
+            // If request targets a different machine, return it
+            if machine_id != server_id {
+                return Some(machine_id);
+            }
+        }
+
+        // Try to get machine ID from cookies as fallback
+        let headers = parse_http_headers(data).ok()?;
+        parse_cookies(headers.get("cookie"))
+            .remove("fly-machine-id")
+            .filter(|machine_id| machine_id != server_id)
+    }
+
+    fn redirect_response(&self, target: &str) -> String {
+        format!(
+            "HTTP/1.1 307 Temporary Redirect\r\n\
+             fly-replay: instance={}\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+            target
+        )
+    }
+}
+
+/// Sends clients to the URL a fixed list gives each server, for deployments without a
+/// proxy that can replay requests. The client reconnects to the `Location`, which the
+/// body repeats as `reconnect_url` for clients that can't read headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerListRouter {
+    urls: HashMap<String, String>,
+}
+
+impl ServerListRouter {
+    /// Parses `id=url` pairs separated by commas, e.g.
+    /// `game-1=wss://game-1.example.com,game-2=wss://game-2.example.com`.
+    pub fn parse(servers: &str) -> Result<Self> {
+        let mut urls = HashMap::new();
+        for entry in servers.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (id, url) = entry
+                .split_once('=')
+                .map(|(id, url)| (id.trim(), url.trim()))
+                .filter(|(id, url)| !id.is_empty() && !url.is_empty())
+                .ok_or_else(|| anyhow!("GAME_SERVERS entries must be id=url, got {:?}", entry))?;
+            urls.insert(id.to_string(), url.to_string());
+        }
+        if urls.is_empty() {
+            return Err(anyhow!("GAME_SERVERS lists no servers"));
+        }
+        Ok(Self { urls })
+    }
+}
+
+impl ServerRouter for ServerListRouter {
+    // Servers missing from the list can't be reached, so the request is served here
+    fn requested_server(&self, data: &[u8], server_id: &str) -> Option<String> {
+        query_machine_id(data)
+            .filter(|machine_id| machine_id != server_id && self.urls.contains_key(machine_id))
+    }
+
+    fn redirect_response(&self, target: &str) -> String {
+        let url = &self.urls[target];
+        let body = serde_json::json!({ "reconnect_url": url }).to_string();
+        format!(
+            "HTTP/1.1 307 Temporary Redirect\r\n\
+             Location: {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            url,
+            body.len(),
+            body
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fly_router() {
+        let router = FlyRouter;
+        let request = b"GET /?machine_id=machine-b HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            router.requested_server(request, "machine-a").as_deref(),
+            Some("machine-b")
+        );
+        assert_eq!(router.requested_server(request, "machine-b"), None);
+
+        let response = router.redirect_response("machine-b");
+        assert!(response.starts_with("HTTP/1.1 307"), "{}", response);
+        assert!(response.contains("fly-replay: instance=machine-b\r\n"));
+    }
+
+    #[test]
+    fn test_server_list_router_sends_a_reconnect_url() {
+        let router = ServerListRouter::parse(
+            "machine-a=wss://a.example.com, machine-b=wss://b.example.com/game",
+        )
+        .unwrap();
+        let request = b"GET /?machine_id=machine-b HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            router.requested_server(request, "machine-a").as_deref(),
+            Some("machine-b")
+        );
+        // Unlisted servers and the cookie Fly sets are ignored
+        assert_eq!(
+            router.requested_server(b"GET /?machine_id=machine-c HTTP/1.1\r\n\r\n", "machine-a"),
+            None
+        );
+        assert_eq!(
+            router.requested_server(
+                b"GET / HTTP/1.1\r\nCookie: fly-machine-id=machine-b\r\n\r\n",
+                "machine-a"
+            ),
+            None
+        );
+
+        let response = router.redirect_response("machine-b");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 307"), "{}", response);
+        assert!(head.contains("\r\nLocation: wss://b.example.com/game"));
+        assert!(!head.contains("fly-replay"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(body).unwrap(),
+            serde_json::json!({ "reconnect_url": "wss://b.example.com/game" })
+        );
+    }
+
+    #[test]
+    fn test_parse_server_list() {
+        assert!(ServerListRouter::parse("").is_err());
+        assert!(ServerListRouter::parse("machine-a").is_err());
+        assert!(ServerListRouter::parse("=wss://a.example.com").is_err());
+        assert_eq!(
+            ServerListRouter::parse("a=wss://a.example.com,")
+                .unwrap()
+                .urls["a"],
+            "wss://a.example.com"
+        );
+    }
+}
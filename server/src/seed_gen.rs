@@ -1,15 +1,17 @@
 #![allow(dead_code)]
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 use rand::{rngs::StdRng, RngCore, SeedableRng};
 use sha3::{Digest, Sha3_256};
 
-struct DistributedSeedGen {
+/// Seed that every player helps draw: each contribution is hashed into the server's
+/// genesis seed, so no single party picks the board on its own.
+pub struct DistributedSeedGen {
     pub seed_hash: [u8; 32],
 }
 
 impl DistributedSeedGen {
-    fn new(genesis_contrib: u64) -> Self {
+    pub fn new(genesis_contrib: u64) -> Self {
         let mut hasher = sha3::Sha3_256::new();
 
         hasher.update(genesis_contrib.to_be_bytes());
@@ -19,7 +21,17 @@ impl DistributedSeedGen {
         DistributedSeedGen { seed_hash }
     }
 
-    fn update_seed_hash(&mut self, new_contrib: u64) {
+    /// Folds in every player's contribution in player id order, so the seed depends
+    /// only on who contributed what and not on the order they arrived in.
+    pub fn with_contributions(genesis_contrib: u64, contributions: &BTreeMap<String, u64>) -> Self {
+        let mut seed_gen = Self::new(genesis_contrib);
+        for &nonce in contributions.values() {
+            seed_gen.update_seed_hash(nonce);
+        }
+        seed_gen
+    }
+
+    pub fn update_seed_hash(&mut self, new_contrib: u64) {
         let mut hasher = Sha3_256::new();
         hasher.update(self.seed_hash);
         hasher.update(new_contrib.to_be_bytes());
@@ -27,21 +39,168 @@ impl DistributedSeedGen {
         self.seed_hash = hasher.finalize().into();
     }
 
-    fn seed(&self) -> u64 {
+    pub fn seed(&self) -> u64 {
         // take first 8 bytes from hash and parse it to u64
 
         u64::from_be_bytes(self.seed_hash[..8].try_into().unwrap())
     }
 }
 
+/// The seed a game's board is drawn from: the server's genesis seed with every
+/// contribution folded in, or the genesis seed itself when nobody contributed.
+pub fn game_seed(genesis: u64, contributions: &BTreeMap<String, u64>) -> u64 {
+    if contributions.is_empty() {
+        genesis
+    } else {
+        DistributedSeedGen::with_contributions(genesis, contributions).seed()
+    }
+}
+
+/// Hex SHA3-256 of a board's seed. Recorded with a finished game, so a seed shown
+/// afterwards can be checked against the game that was played.
+pub fn seed_hash(seed: u64) -> String {
+    Sha3_256::digest(seed.to_be_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 pub fn get_bomb_coords(bombs_needed: usize, dimension: u64) -> Vec<u64> {
-    let seed = rand::random();
+    get_bomb_coords_seeded(bombs_needed, dimension, rand::random())
+}
+
+/// Same as `get_bomb_coords`, but always yields the same layout for a given seed.
+pub fn get_bomb_coords_seeded(bombs_needed: usize, dimension: u64, seed: u64) -> Vec<u64> {
     let mut rng = StdRng::seed_from_u64(seed);
 
-    let mut coords = HashSet::new();
+    // Keep draw order so the output doesn't depend on HashSet iteration order
+    let mut seen = HashSet::new();
+    let mut coords = Vec::with_capacity(bombs_needed);
     while coords.len() < bombs_needed {
-        coords.insert(rng.next_u64() % (dimension * dimension));
+        let coord = rng.next_u64() % (dimension * dimension);
+        if seen.insert(coord) {
+            coords.push(coord);
+        }
+    }
+
+    coords
+}
+
+/// Places the bombs as one adjacent cluster: a near-square block filled row by
+/// row, at a position picked from the seed.
+pub fn get_bomb_coords_continuous_seeded(
+    bombs_needed: usize,
+    dimension: u64,
+    seed: u64,
+) -> Vec<u64> {
+    if bombs_needed == 0 {
+        return Vec::new();
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let bombs = bombs_needed as u64;
+    let width = ((bombs as f64).sqrt().ceil() as u64).clamp(1, dimension);
+    let height = bombs.div_ceil(width);
+    let top = rng.next_u64() % (dimension - height + 1);
+    let left = rng.next_u64() % (dimension - width + 1);
+
+    (0..bombs)
+        .map(|i| (top + i / width) * dimension + left + i % width)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_same_coords() {
+        assert_eq!(
+            get_bomb_coords_seeded(10, 8, 42),
+            get_bomb_coords_seeded(10, 8, 42)
+        );
+        assert_ne!(
+            get_bomb_coords_seeded(10, 8, 42),
+            get_bomb_coords_seeded(10, 8, 43)
+        );
+    }
+
+    #[test]
+    fn test_seed_hash() {
+        let hash = seed_hash(42);
+        assert_eq!(hash.len(), 64);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hash, seed_hash(42));
+        assert_ne!(hash, seed_hash(43));
+    }
+
+    #[test]
+    fn test_contribution_order_doesnt_change_the_seed() {
+        let contributions = [("alice", 7u64), ("bob", 11), ("carol", 13)];
+        let in_order: BTreeMap<String, u64> = contributions
+            .iter()
+            .map(|(player, nonce)| (player.to_string(), *nonce))
+            .collect();
+        let reversed: BTreeMap<String, u64> = contributions
+            .iter()
+            .rev()
+            .map(|(player, nonce)| (player.to_string(), *nonce))
+            .collect();
+
+        let seed = DistributedSeedGen::with_contributions(42, &in_order).seed();
+        assert_eq!(
+            seed,
+            DistributedSeedGen::with_contributions(42, &reversed).seed()
+        );
+        // Each contribution and the genesis seed change the outcome
+        let mut changed = in_order.clone();
+        changed.insert("bob".to_string(), 12);
+        assert_ne!(
+            seed,
+            DistributedSeedGen::with_contributions(42, &changed).seed()
+        );
+        assert_ne!(
+            seed,
+            DistributedSeedGen::with_contributions(43, &in_order).seed()
+        );
     }
 
-    coords.into_iter().collect()
+    #[test]
+    fn test_coords_are_unique_and_in_bounds() {
+        for seed in 0..100 {
+            for (bombs, dimension) in [(1, 2), (5, 4), (24, 5), (16, 4)] {
+                let coords = get_bomb_coords_seeded(bombs, dimension, seed);
+                assert_eq!(coords.len(), bombs);
+                assert!(coords.iter().all(|&c| c < dimension * dimension));
+                assert_eq!(coords.iter().collect::<HashSet<_>>().len(), bombs);
+            }
+        }
+        assert_eq!(get_bomb_coords(3, 4).len(), 3);
+    }
+
+    #[test]
+    fn test_continuous_coords_are_adjacent() {
+        let dimension = 6;
+        let adjacent = |a: u64, b: u64| {
+            let (ax, ay) = (a / dimension, a % dimension);
+            let (bx, by) = (b / dimension, b % dimension);
+            ax.abs_diff(bx) + ay.abs_diff(by) == 1
+        };
+        for seed in 0..50 {
+            for bombs in [2, 3, 5, 9, 10, 20, 36] {
+                let coords = get_bomb_coords_continuous_seeded(bombs, dimension, seed);
+                assert_eq!(coords.len(), bombs);
+                assert_eq!(coords.iter().collect::<HashSet<_>>().len(), bombs);
+                assert!(coords.iter().all(|&c| c < dimension * dimension));
+                // Every bomb touches another, so the cluster is one connected block
+                for &coord in &coords {
+                    assert!(coords.iter().any(|&other| adjacent(coord, other)));
+                }
+                assert_eq!(
+                    coords,
+                    get_bomb_coords_continuous_seeded(bombs, dimension, seed)
+                );
+            }
+        }
+    }
 }
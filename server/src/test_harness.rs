@@ -0,0 +1,118 @@
+use std::env;
+
+use anyhow::Result;
+use common::{
+    db::{self, PoolConfig},
+    utils::{Currency, WalletType},
+};
+use redis::Client;
+use sqlx::{Pool, Postgres};
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::{
+    client::GameClient,
+    config::AppConfig,
+    game::{GameRegistry, GameServer},
+};
+
+/// A game server on an ephemeral port for end-to-end tests.
+///
+/// Every server registers under a fresh id, so tests can share one Redis.
+/// Players created through the harness are real users with SOL wallets,
+/// so settlement can be checked against the database.
+pub struct TestServer {
+    pub uri: String,
+    pub pool: Pool<Postgres>,
+    stop: oneshot::Sender<()>,
+    serving: JoinHandle<Result<()>>,
+}
+
+impl TestServer {
+    /// Backed by the Redis at `REDIS_URL` and the migrated Postgres at `DATABASE_URL`.
+    pub async fn start() -> Result<Self> {
+        dotenv::dotenv().ok();
+        let redis = Client::open(env::var("REDIS_URL")?)?;
+        let pool = PoolConfig::default()
+            .pool_options()
+            .connect(&env::var("DATABASE_URL")?)
+            .await?;
+        Self::start_with(redis, pool).await
+    }
+
+    pub async fn start_with(redis: Client, pool: Pool<Postgres>) -> Result<Self> {
+        Self::start_with_config(redis, pool, &AppConfig::default()).await
+    }
+
+    pub async fn start_with_config(
+        redis: Client,
+        pool: Pool<Postgres>,
+        config: &AppConfig,
+    ) -> Result<Self> {
+        let registry =
+            GameRegistry::with_config(redis, Uuid::new_v4().to_string(), pool.clone(), config);
+        Self::start_with_registry(registry, pool).await
+    }
+
+    /// Serves `registry`, e.g. one with limits a test changed. `pool` is the one it settles with.
+    pub async fn start_with_registry(registry: GameRegistry, pool: Pool<Postgres>) -> Result<Self> {
+        let server = GameServer::with_registry(registry);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let uri = format!("ws://{}/", listener.local_addr()?);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            server
+                .serve(listener, async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        Ok(Self {
+            uri,
+            pool,
+            stop,
+            serving,
+        })
+    }
+
+    pub async fn client(&self) -> Result<GameClient> {
+        GameClient::connect(&self.uri).await
+    }
+
+    /// Creates a user whose SOL and MON wallets each hold `balance` and returns
+    /// its id, which is also the player id to play with.
+    pub async fn create_player(&self, balance: f64) -> Result<i32> {
+        let mut tx = self.pool.begin().await?;
+        let user_id = db::create_test_user(&mut tx).await?;
+        db::provision_user_wallets_tx(
+            &mut tx,
+            user_id,
+            &[Currency::SOL, Currency::MON],
+            WalletType::PDA,
+        )
+        .await?;
+        sqlx::query("UPDATE wallet SET balance = $1 WHERE user_id = $2")
+            .bind(balance)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user_id)
+    }
+
+    pub async fn balance(&self, user_id: i32) -> Result<f64> {
+        self.balance_in(user_id, Currency::SOL).await
+    }
+
+    pub async fn balance_in(&self, user_id: i32, currency: Currency) -> Result<f64> {
+        Ok(db::get_user_wallet(&self.pool, user_id, currency)
+            .await?
+            .balance)
+    }
+
+    /// Stops accepting connections and waits for the server to drain.
+    pub async fn stop(self) -> Result<()> {
+        let _ = self.stop.send(());
+        self.serving.await?
+    }
+}
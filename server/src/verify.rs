@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+
+use common::payout::GameResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{Board, BombLayout},
+    game::lose_life,
+    protocol::default_lives,
+    seed_gen::game_seed,
+};
+
+/// Everything needed to check a finished game without trusting the server: the board's
+/// inputs, the moves as recorded and the result the game was settled with.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GameRecord {
+    pub grid: u32,
+    pub bombs: u32,
+    #[serde(default)]
+    pub layout: BombLayout,
+    /// The seed the server drew for the lobby, before any contributions
+    pub genesis_seed: u64,
+    #[serde(default)]
+    pub seed_contributions: BTreeMap<String, u64>,
+    pub players: u32,
+    #[serde(default = "default_lives")]
+    pub lives: u32,
+    pub moves: Vec<RecordedMove>,
+    pub result: GameResult,
+}
+
+/// A cell mined by the player at `player_idx`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordedMove {
+    pub player_idx: usize,
+    pub x: usize,
+    pub y: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    /// Whether replaying the moves on the recomputed board ends in the recorded result
+    pub consistent: bool,
+    pub seed: u64,
+    pub bomb_coordinates: Vec<u64>,
+    /// Why the record is inconsistent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Redraws the board from the record's seeds and replays its moves under the game's
+/// rules. The record's sizes must already be validated, as they size the board.
+pub fn verify_game(record: &GameRecord) -> Verification {
+    let seed = game_seed(record.genesis_seed, &record.seed_contributions);
+    let mut board = Board::new(
+        record.grid as usize,
+        record.bombs as usize,
+        record.layout,
+        Some(seed),
+    );
+    let reason = match replay(&mut board, record) {
+        Ok(result) if result == record.result => None,
+        Ok(result) => Some(format!(
+            "The moves end in {:?}, not the recorded {:?}",
+            result, record.result
+        )),
+        Err(reason) => Some(reason),
+    };
+    Verification {
+        consistent: reason.is_none(),
+        seed,
+        bomb_coordinates: board.bomb_coordinates,
+        reason,
+    }
+}
+
+// The result the moves lead to, the same way MakeMove decides it
+fn replay(board: &mut Board, record: &GameRecord) -> Result<GameResult, String> {
+    let players = record.players as usize;
+    let mut lives_left = vec![record.lives; players];
+    for (i, RecordedMove { player_idx, x, y }) in record.moves.iter().enumerate() {
+        let number = i + 1;
+        if *player_idx >= players {
+            return Err(format!(
+                "Move {} is by player {} of a {} player game",
+                number, player_idx, players
+            ));
+        }
+        board
+            .check_hidden(*x, *y)
+            .map_err(|reason| format!("Move {}: {}", number, reason))?;
+        let result = if board.mine(*x, *y)
+            && lose_life(&mut lives_left, record.lives, players, *player_idx)
+        {
+            Some(GameResult::Loser(*player_idx))
+        } else if board.is_cleared() {
+            Some(GameResult::Draw)
+        } else {
+            None
+        };
+        if let Some(result) = result {
+            if number < record.moves.len() {
+                return Err(format!(
+                    "The game ended on move {}, but {} moves were recorded",
+                    number,
+                    record.moves.len()
+                ));
+            }
+            return Ok(result);
+        }
+    }
+    Err("The moves don't finish the game".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Alice mines a safe cell, Bob one too, then Alice hits a bomb
+    fn known_game() -> GameRecord {
+        let seed_contributions = BTreeMap::from([("1".to_string(), 5), ("2".to_string(), 9)]);
+        let board = Board::new(
+            4,
+            3,
+            BombLayout::Scattered,
+            Some(game_seed(42, &seed_contributions)),
+        );
+        let mut safe = (0..16).filter(|c| !board.bomb_coordinates.contains(c));
+        let bomb = board.bomb_coordinates[0];
+        let moves = [
+            (0, safe.next().unwrap()),
+            (1, safe.next().unwrap()),
+            (0, bomb),
+        ]
+        .into_iter()
+        .map(|(player_idx, position)| RecordedMove {
+            player_idx,
+            x: (position / 4) as usize,
+            y: (position % 4) as usize,
+        })
+        .collect();
+        GameRecord {
+            grid: 4,
+            bombs: 3,
+            layout: BombLayout::Scattered,
+            genesis_seed: 42,
+            seed_contributions,
+            players: 2,
+            lives: 1,
+            moves,
+            result: GameResult::Loser(0),
+        }
+    }
+
+    #[test]
+    fn test_known_game_verifies() {
+        let record = known_game();
+        let verification = verify_game(&record);
+        assert!(verification.consistent, "{:?}", verification.reason);
+        assert_eq!(verification.seed, game_seed(42, &record.seed_contributions));
+        assert_eq!(verification.bomb_coordinates.len(), 3);
+    }
+
+    #[test]
+    fn test_tampered_game_fails() {
+        // Blaming Bob for Alice's bomb
+        let mut blamed = known_game();
+        blamed.moves[2].player_idx = 1;
+        assert!(!verify_game(&blamed).consistent);
+
+        // A result the moves don't lead to
+        let mut result = known_game();
+        result.result = GameResult::Loser(1);
+        assert!(!verify_game(&result).consistent);
+
+        // Moves after the game ended
+        let mut extended = known_game();
+        extended.moves.push(extended.moves[0].clone());
+        let verification = verify_game(&extended);
+        assert!(!verification.consistent);
+        assert!(verification.reason.unwrap().contains("ended on move 3"));
+
+        // A different contribution draws a different board
+        let mut reseeded = known_game();
+        reseeded.seed_contributions.insert("2".to_string(), 10);
+        assert_ne!(
+            verify_game(&reseeded).bomb_coordinates,
+            verify_game(&known_game()).bomb_coordinates
+        );
+
+        // Stopping short of the bomb
+        let mut truncated = known_game();
+        truncated.moves.pop();
+        assert_eq!(
+            verify_game(&truncated).reason.as_deref(),
+            Some("The moves don't finish the game")
+        );
+    }
+}
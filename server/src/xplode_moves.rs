@@ -1,6 +1,7 @@
 use anyhow::Result;
 use reqwest::Client as HttpClient;
 use serde_json::json;
+use tracing::info;
 
 #[derive(Clone)]
 pub struct XplodeMovesClient {
@@ -71,7 +72,7 @@ impl XplodeMovesClient {
     }
 
     pub async fn commit_game(&self, game_id: &str) -> Result<String> {
-        println!("Committing game on blockchain");
+        info!("Committing game {} on blockchain", game_id);
         let response = self
             .client
             .post(format!("{}/commit", self.api_base))
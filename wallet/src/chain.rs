@@ -0,0 +1,342 @@
+use anyhow::anyhow;
+use common::utils::{Currency, DepositRequest};
+use deposits::sol::{self, DepositService};
+use evm_deposits::MonadClient;
+use futures_util::future::BoxFuture;
+use tracing::warn;
+
+use crate::error::WalletError;
+
+pub(crate) const LAMPORTS_PER_SOL: f64 = 1_000_000_000.0;
+
+/// Claimed and on-chain amounts may differ by the rounding of lamports and wei to f64
+const AMOUNT_TOLERANCE: f64 = 1e-9;
+
+/// Looks up deposits on the chain each currency lives on.
+pub trait ChainClient: Send + Sync {
+    /// Where deposits in `currency` have to be sent, or `None` if they can't be verified on chain.
+    fn treasury_address(&self, currency: Currency) -> Option<String>;
+
+    /// How much `recipient` received in `tx_hash`, in whole units of `currency`.
+    /// `None` if the transaction is unknown, unconfirmed or failed.
+    fn received_by<'a>(
+        &'a self,
+        currency: Currency,
+        tx_hash: &'a str,
+        recipient: &'a str,
+    ) -> BoxFuture<'a, Result<Option<f64>, WalletError>>;
+
+    /// Whether `tx_hash` was sent from the wallet `sender`: signed by it on Solana, from
+    /// it on Monad. `false` if the transaction is unknown, unconfirmed or failed.
+    fn sent_by<'a>(
+        &'a self,
+        currency: Currency,
+        tx_hash: &'a str,
+        sender: &'a str,
+    ) -> BoxFuture<'a, Result<bool, WalletError>>;
+}
+
+/// SOL and USDC through the deposit service's RPC client, Monad through `MonadClient`.
+pub struct OnChain {
+    solana: DepositService,
+    monad: Option<MonadClient>,
+}
+
+impl OnChain {
+    /// Without a Monad client, or one without the treasury key, MON deposits can't be verified.
+    pub fn new(solana: DepositService, monad: Option<MonadClient>) -> Self {
+        if monad
+            .as_ref()
+            .is_some_and(|monad| monad.treasury_address().is_none())
+        {
+            warn!("MON deposits can't be verified: MONAD_ACCOUNT_PRIVATE_KEY is not set");
+        }
+        Self { solana, monad }
+    }
+}
+
+impl ChainClient for OnChain {
+    fn treasury_address(&self, currency: Currency) -> Option<String> {
+        match currency {
+            // USDC lands in the treasury's associated token account
+            Currency::SOL | Currency::USDC => Some(self.solana.treasury_address().to_string()),
+            Currency::MON => self.monad.as_ref()?.treasury_address(),
+            Currency::INR => None,
+        }
+    }
+
+    fn received_by<'a>(
+        &'a self,
+        currency: Currency,
+        tx_hash: &'a str,
+        recipient: &'a str,
+    ) -> BoxFuture<'a, Result<Option<f64>, WalletError>> {
+        Box::pin(async move {
+            match currency {
+                Currency::SOL => {
+                    let recipient = sol::parse_address(recipient)?;
+                    let lamports = self.solana.received_by(tx_hash, &recipient).await?;
+                    Ok(lamports.map(|lamports| lamports as f64 / LAMPORTS_PER_SOL))
+                }
+                Currency::USDC => {
+                    let recipient = sol::parse_address(recipient)?;
+                    let mint = self.solana.usdc_mint();
+                    let units = self
+                        .solana
+                        .tokens_received_by(tx_hash, &recipient, &mint)
+                        .await?;
+                    Ok(units.map(|units| units as f64 / 10f64.powi(sol::USDC_DECIMALS.into())))
+                }
+                Currency::MON => {
+                    let monad = self
+                        .monad
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("Monad RPC is not configured"))?;
+                    Ok(monad.received_by(tx_hash, recipient).await?)
+                }
+                Currency::INR => Ok(None),
+            }
+        })
+    }
+
+    fn sent_by<'a>(
+        &'a self,
+        currency: Currency,
+        tx_hash: &'a str,
+        sender: &'a str,
+    ) -> BoxFuture<'a, Result<bool, WalletError>> {
+        Box::pin(async move {
+            match currency {
+                Currency::SOL | Currency::USDC => {
+                    let sender = sol::parse_address(sender)?;
+                    Ok(self.solana.signed_by(tx_hash, &sender).await?)
+                }
+                Currency::MON => {
+                    let monad = self
+                        .monad
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("Monad RPC is not configured"))?;
+                    Ok(monad.sent_by(tx_hash, sender).await?)
+                }
+                Currency::INR => Ok(false),
+            }
+        })
+    }
+}
+
+/// Checks that `request.tx_hash` moved exactly `request.amount` of `request.currency`
+/// into our treasury from `sender`, the user's own wallet, so nobody can claim a
+/// transfer someone else made. Client-supplied amounts are never credited on their own.
+pub async fn verify_deposit(
+    chain: &dyn ChainClient,
+    request: &DepositRequest,
+    sender: &str,
+) -> Result<(), WalletError> {
+    if !request.amount.is_finite() || request.amount <= 0.0 {
+        return Err(WalletError::InvalidRequest(
+            "Deposit amount must be positive".to_string(),
+        ));
+    }
+    let treasury = chain.treasury_address(request.currency).ok_or_else(|| {
+        WalletError::InvalidRequest(format!(
+            "{} deposits can't be verified on chain",
+            request.currency
+        ))
+    })?;
+
+    let received = chain
+        .received_by(request.currency, &request.tx_hash, &treasury)
+        .await?
+        .ok_or_else(|| {
+            WalletError::DepositNotVerified("transaction not found or not successful".to_string())
+        })?;
+    if (received - request.amount).abs() > AMOUNT_TOLERANCE {
+        warn!(
+            user_id = request.user_id,
+            currency = %request.currency,
+            amount = request.amount,
+            received,
+            tx_hash = %request.tx_hash,
+            "Deposit does not match the chain"
+        );
+        return Err(WalletError::DepositNotVerified(format!(
+            "transaction sent {} {} to the treasury, not {}",
+            received, request.currency, request.amount
+        )));
+    }
+    if !chain
+        .sent_by(request.currency, &request.tx_hash, sender)
+        .await?
+    {
+        warn!(
+            user_id = request.user_id,
+            currency = %request.currency,
+            tx_hash = %request.tx_hash,
+            "Deposit was sent from another wallet"
+        );
+        return Err(WalletError::DepositNotVerified(format!(
+            "transaction was not sent from the user's {} wallet",
+            request.currency
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::{http::StatusCode, ResponseError};
+
+    use super::*;
+
+    pub const TREASURY: &str = "treasury";
+    /// The user's wallet, which `MockChain::with_transfer` sends from
+    pub const SENDER: &str = "sender";
+
+    /// Chain with fixed transfers: tx hash -> (sender, recipient, amount)
+    #[derive(Default)]
+    pub struct MockChain {
+        pub transfers: HashMap<String, (String, String, f64)>,
+    }
+
+    impl MockChain {
+        pub fn with_transfer(tx_hash: &str, recipient: &str, amount: f64) -> Self {
+            Self::with_transfer_from(tx_hash, SENDER, recipient, amount)
+        }
+
+        pub fn with_transfer_from(
+            tx_hash: &str,
+            sender: &str,
+            recipient: &str,
+            amount: f64,
+        ) -> Self {
+            let mut chain = Self::default();
+            chain.transfers.insert(
+                tx_hash.to_string(),
+                (sender.to_string(), recipient.to_string(), amount),
+            );
+            chain
+        }
+    }
+
+    impl ChainClient for MockChain {
+        fn treasury_address(&self, currency: Currency) -> Option<String> {
+            matches!(currency, Currency::SOL | Currency::USDC | Currency::MON)
+                .then(|| TREASURY.to_string())
+        }
+
+        fn received_by<'a>(
+            &'a self,
+            _currency: Currency,
+            tx_hash: &'a str,
+            recipient: &'a str,
+        ) -> BoxFuture<'a, Result<Option<f64>, WalletError>> {
+            let received =
+                self.transfers
+                    .get(tx_hash)
+                    .map(|(_, to, amount)| match to == recipient {
+                        true => *amount,
+                        false => 0.0,
+                    });
+            Box::pin(async move { Ok(received) })
+        }
+
+        fn sent_by<'a>(
+            &'a self,
+            _currency: Currency,
+            tx_hash: &'a str,
+            sender: &'a str,
+        ) -> BoxFuture<'a, Result<bool, WalletError>> {
+            let sent = self
+                .transfers
+                .get(tx_hash)
+                .is_some_and(|(from, _, _)| from == sender);
+            Box::pin(async move { Ok(sent) })
+        }
+    }
+
+    fn deposit(currency: Currency, amount: f64, tx_hash: &str) -> DepositRequest {
+        DepositRequest {
+            user_id: 1,
+            amount,
+            currency,
+            tx_hash: tx_hash.to_string(),
+        }
+    }
+
+    async fn verify_deposit_from_user(
+        chain: &MockChain,
+        request: &DepositRequest,
+    ) -> Result<(), WalletError> {
+        verify_deposit(chain, request, SENDER).await
+    }
+
+    #[tokio::test]
+    async fn test_matching_deposit_is_verified() {
+        let chain = MockChain::with_transfer("tx", TREASURY, 1.5);
+        assert!(
+            verify_deposit_from_user(&chain, &deposit(Currency::SOL, 1.5, "tx"))
+                .await
+                .is_ok()
+        );
+        // Lamport rounding is tolerated
+        assert!(
+            verify_deposit_from_user(&chain, &deposit(Currency::SOL, 1.5 + 1e-12, "tx"))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_deposits_are_rejected() {
+        let chain = MockChain::with_transfer("tx", TREASURY, 0.1);
+        let not_verified = |err: WalletError| {
+            assert!(
+                matches!(err, WalletError::DepositNotVerified(_)),
+                "{:?}",
+                err
+            );
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        };
+
+        // More than was sent
+        not_verified(
+            verify_deposit_from_user(&chain, &deposit(Currency::SOL, 100.0, "tx"))
+                .await
+                .unwrap_err(),
+        );
+        // A transaction that never happened
+        not_verified(
+            verify_deposit_from_user(&chain, &deposit(Currency::SOL, 0.1, "made-up"))
+                .await
+                .unwrap_err(),
+        );
+        // A transfer to someone else
+        let elsewhere = MockChain::with_transfer("tx", "someone-else", 0.1);
+        not_verified(
+            verify_deposit_from_user(&elsewhere, &deposit(Currency::SOL, 0.1, "tx"))
+                .await
+                .unwrap_err(),
+        );
+
+        // A genuine transfer to the treasury, made from another user's wallet
+        let stranger = MockChain::with_transfer_from("tx", "someone-else", TREASURY, 0.1);
+        not_verified(
+            verify_deposit_from_user(&stranger, &deposit(Currency::SOL, 0.1, "tx"))
+                .await
+                .unwrap_err(),
+        );
+
+        // Currencies without an on-chain treasury and non-positive amounts are bad requests
+        for request in [
+            deposit(Currency::INR, 0.1, "tx"),
+            deposit(Currency::SOL, -0.1, "tx"),
+        ] {
+            let err = verify_deposit_from_user(&chain, &request)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, WalletError::InvalidRequest(_)), "{:?}", err);
+        }
+    }
+}
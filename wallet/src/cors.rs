@@ -0,0 +1,126 @@
+use std::env;
+
+use actix_cors::Cors;
+use actix_web::http::{header, Method};
+use anyhow::{anyhow, Result};
+
+/// Used when `ALLOWED_ORIGINS` isn't set: only the production frontend
+pub const DEFAULT_ALLOWED_ORIGINS: &[&str] = &["https://playxplode.xyz"];
+
+/// Reads the comma-separated `ALLOWED_ORIGINS`, e.g.
+/// `https://playxplode.xyz,http://localhost:3000`.
+pub fn allowed_origins_from_env() -> Result<Vec<String>> {
+    parse_allowed_origins(env::var("ALLOWED_ORIGINS").ok().as_deref())
+}
+
+fn parse_allowed_origins(value: Option<&str>) -> Result<Vec<String>> {
+    let Some(value) = value else {
+        return Ok(DEFAULT_ALLOWED_ORIGINS
+            .iter()
+            .map(|origin| origin.to_string())
+            .collect());
+    };
+
+    let origins: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| origin.trim_end_matches('/').to_string())
+        .collect();
+    if origins.is_empty() {
+        return Err(anyhow!("ALLOWED_ORIGINS must list at least one origin"));
+    }
+    for origin in &origins {
+        validate_origin(origin)?;
+    }
+    Ok(origins)
+}
+
+// An origin is a scheme and host with an optional port, nothing else. Wildcards are
+// rejected on purpose, this service moves real balances.
+fn validate_origin(origin: &str) -> Result<()> {
+    let host = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+        .ok_or_else(|| anyhow!("Origin {:?} must start with http:// or https://", origin))?;
+    let valid = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':'));
+    if !valid {
+        return Err(anyhow!("Invalid origin {:?}", origin));
+    }
+    Ok(())
+}
+
+pub fn configure_cors(allowed_origins: &[String]) -> Cors {
+    allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods([Method::GET, Method::POST])
+        .allowed_headers([header::CONTENT_TYPE, header::AUTHORIZATION, header::ACCEPT])
+        .max_age(3600)
+        // Requests without an Origin header (servers, curl) are unaffected
+        .block_on_origin_mismatch(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{self as actix_test, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_parse_allowed_origins() {
+        assert_eq!(
+            parse_allowed_origins(None).unwrap(),
+            vec!["https://playxplode.xyz"]
+        );
+        assert_eq!(
+            parse_allowed_origins(Some("https://a.example.com/, http://localhost:3000")).unwrap(),
+            vec!["https://a.example.com", "http://localhost:3000"]
+        );
+        assert!(parse_allowed_origins(Some("")).is_err());
+        assert!(parse_allowed_origins(Some("*")).is_err());
+        assert!(parse_allowed_origins(Some("https://*.example.com")).is_err());
+        assert!(parse_allowed_origins(Some("example.com")).is_err());
+        assert!(parse_allowed_origins(Some("https://example.com/path")).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_only_allowed_origins_pass() {
+        let origins = vec!["https://playxplode.xyz".to_string()];
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(configure_cors(&origins))
+                .route("/health", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri("/health")
+            .insert_header((header::ORIGIN, "https://playxplode.xyz"))
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some(&header::HeaderValue::from_static("https://playxplode.xyz"))
+        );
+
+        let request = TestRequest::get()
+            .uri("/health")
+            .insert_header((header::ORIGIN, "https://evil.example.com"))
+            .to_request();
+        let response = actix_test::try_call_service(&app, request).await;
+        let status = match response {
+            Ok(response) => response.status(),
+            Err(err) => err.as_response_error().status_code(),
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
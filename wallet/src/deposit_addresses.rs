@@ -0,0 +1,78 @@
+use deposits::{error::DepositError, sol::DepositService};
+use futures_util::future::BoxFuture;
+
+/// Hands out the Solana deposit addresses users pay into, which the deposit service
+/// keeps in Redis and sweeps into the treasury.
+pub trait DepositAddresses: Send + Sync {
+    /// Derives a fresh deposit address for `user_id` and records it.
+    fn generate_deposit_address(&self, user_id: i32) -> Result<String, DepositError>;
+
+    fn get_user_deposit_addresses(&self, user_id: i32) -> Result<Vec<String>, DepositError>;
+
+    /// Whether Redis, where the addresses are kept, answers.
+    fn ping_redis(&self) -> BoxFuture<'_, Result<(), DepositError>>;
+
+    /// Whether the Solana RPC node the deposits are swept through answers.
+    fn check_rpc_health(&self) -> BoxFuture<'_, Result<(), DepositError>>;
+}
+
+impl DepositAddresses for DepositService {
+    fn generate_deposit_address(&self, user_id: i32) -> Result<String, DepositError> {
+        DepositService::generate_deposit_address(self, user_id).map(|address| address.to_string())
+    }
+
+    fn get_user_deposit_addresses(&self, user_id: i32) -> Result<Vec<String>, DepositError> {
+        let addresses = DepositService::get_user_deposit_addresses(self, user_id)?;
+        Ok(addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect())
+    }
+
+    fn ping_redis(&self) -> BoxFuture<'_, Result<(), DepositError>> {
+        Box::pin(DepositService::ping_redis(self))
+    }
+
+    fn check_rpc_health(&self) -> BoxFuture<'_, Result<(), DepositError>> {
+        Box::pin(DepositService::check_rpc_health(self))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Addresses kept in memory as (user id, address), in the order they were handed out
+    #[derive(Default)]
+    pub struct MockDepositAddresses {
+        pub addresses: Mutex<Vec<(i32, String)>>,
+    }
+
+    impl DepositAddresses for MockDepositAddresses {
+        fn generate_deposit_address(&self, user_id: i32) -> Result<String, DepositError> {
+            let mut addresses = self.addresses.lock().unwrap();
+            let address = format!("deposit-{}-{}", user_id, addresses.len());
+            addresses.push((user_id, address.clone()));
+            Ok(address)
+        }
+
+        fn get_user_deposit_addresses(&self, user_id: i32) -> Result<Vec<String>, DepositError> {
+            let addresses = self.addresses.lock().unwrap();
+            Ok(addresses
+                .iter()
+                .filter(|(owner, _)| *owner == user_id)
+                .map(|(_, address)| address.clone())
+                .collect())
+        }
+
+        fn ping_redis(&self) -> BoxFuture<'_, Result<(), DepositError>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn check_rpc_health(&self) -> BoxFuture<'_, Result<(), DepositError>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+}
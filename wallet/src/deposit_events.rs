@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use common::{db, utils::Currency};
+use deposits::sol::{DepositConfirmed, DepositService};
+use sqlx::{Pool, Postgres};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::{
+    chain::LAMPORTS_PER_SOL,
+    error::WalletError,
+    referral::{self, ReferralBonus},
+};
+
+// Wait before resubscribing after the Redis connection drops
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Credits deposits as the deposit worker announces their sweeps, for as long as the
+/// wallet runs. Announcements missed while unsubscribed are left to `POST /deposit`.
+pub fn spawn_listener(
+    pool: Pool<Postgres>,
+    deposit_service: DepositService,
+    referral_bonus: ReferralBonus,
+) {
+    let (events, mut received) = mpsc::unbounded_channel();
+
+    tokio::task::spawn_blocking(move || loop {
+        let result = deposit_service.listen_for_confirmed_deposits(|event| {
+            let _ = events.send(event);
+        });
+        if let Err(err) = result {
+            warn!("Lost the confirmed deposit subscription: {}", err);
+        }
+        std::thread::sleep(RESUBSCRIBE_DELAY);
+    });
+
+    tokio::spawn(async move {
+        while let Some(event) = received.recv().await {
+            match credit_swept_deposit(&pool, &event).await {
+                Ok(true) => {
+                    info!(
+                        user_id = event.user_id,
+                        lamports = event.lamports,
+                        signature = %event.signature,
+                        "Credited swept deposit"
+                    );
+                    referral::reward_referrer(
+                        &pool,
+                        &referral_bonus,
+                        event.user_id,
+                        Currency::SOL,
+                        event.lamports as f64 / LAMPORTS_PER_SOL,
+                    )
+                    .await;
+                }
+                Ok(false) => info!(signature = %event.signature, "Swept deposit already credited"),
+                Err(err) => error!(
+                    signature = %event.signature,
+                    "Failed to credit swept deposit: {:?}", err
+                ),
+            }
+        }
+    });
+}
+
+/// Credits a swept deposit once; the sweep signature is its reference, so a repeated
+/// announcement returns false and leaves the balance alone.
+pub async fn credit_swept_deposit(
+    pool: &Pool<Postgres>,
+    event: &DepositConfirmed,
+) -> Result<bool, WalletError> {
+    let mut tx = pool.begin().await?;
+    let credited = db::credit_deposit_once_tx(
+        &mut tx,
+        event.user_id,
+        Currency::SOL,
+        event.lamports as f64 / LAMPORTS_PER_SOL,
+        &event.signature,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(credited)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{db::establish_connection, utils::WalletType};
+
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_swept_deposit_is_credited_once() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let unique = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        let mut tx = pool.begin().await?;
+        let user_id = db::create_test_user(&mut tx).await?;
+        db::provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        tx.commit().await?;
+
+        let event = DepositConfirmed {
+            user_id,
+            deposit_address: "deposit-pda".to_string(),
+            lamports: 250_000_000,
+            signature: format!("sweep-{}", unique),
+        };
+        assert!(credit_swept_deposit(&pool, &event).await?);
+        assert!(!credit_swept_deposit(&pool, &event).await?);
+        assert_eq!(
+            db::get_user_wallet(&pool, user_id, Currency::SOL)
+                .await?
+                .balance,
+            0.25
+        );
+        Ok(())
+    }
+}
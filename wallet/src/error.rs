@@ -0,0 +1,197 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use deposits::error::DepositError;
+use serde_json::json;
+use thiserror::Error;
+use tracing::error;
+
+#[derive(Debug, Error)]
+pub enum WalletError {
+    #[error("Missing or invalid authentication token")]
+    Unauthorized,
+    #[error("Not allowed to access this resource")]
+    Forbidden,
+    #[error("Too many requests, try again later")]
+    RateLimited,
+    #[error("Insufficient balance: requested {requested}, available {balance}")]
+    InsufficientBalance { balance: f64, requested: f64 },
+    #[error("Wallet not found")]
+    WalletNotFound,
+    #[error("Withdrawal not found")]
+    WithdrawalNotFound,
+    #[error("{0}")]
+    WalletAddressConflict(String),
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("Deposit could not be verified: {0}")]
+    DepositNotVerified(String),
+    #[error(transparent)]
+    Deposit(#[from] DepositError),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl WalletError {
+    /// Stable identifier clients can match on instead of the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            WalletError::Unauthorized => "UNAUTHORIZED",
+            WalletError::Forbidden => "FORBIDDEN",
+            WalletError::RateLimited => "RATE_LIMITED",
+            WalletError::InsufficientBalance { .. } => "INSUFFICIENT_BALANCE",
+            WalletError::WalletNotFound => "WALLET_NOT_FOUND",
+            WalletError::WithdrawalNotFound => "WITHDRAWAL_NOT_FOUND",
+            WalletError::WalletAddressConflict(_) => "WALLET_ADDRESS_CONFLICT",
+            WalletError::InvalidRequest(_) => "INVALID_REQUEST",
+            WalletError::DepositNotVerified(_) => "DEPOSIT_NOT_VERIFIED",
+            WalletError::Deposit(DepositError::InvalidAddress(_)) => "INVALID_ADDRESS",
+            WalletError::Deposit(DepositError::InvalidSignature(_)) => "INVALID_TX_HASH",
+            WalletError::Deposit(DepositError::AddressAlreadyAssigned(_)) => {
+                "ADDRESS_ALREADY_ASSIGNED"
+            }
+            WalletError::Deposit(DepositError::Rpc(_)) => "RPC_ERROR",
+            WalletError::Deposit(_) | WalletError::Database(_) | WalletError::Internal(_) => {
+                "INTERNAL_ERROR"
+            }
+        }
+    }
+}
+
+impl ResponseError for WalletError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WalletError::InsufficientBalance { .. }
+            | WalletError::InvalidRequest(_)
+            | WalletError::DepositNotVerified(_) => StatusCode::BAD_REQUEST,
+            WalletError::Unauthorized => StatusCode::UNAUTHORIZED,
+            WalletError::Forbidden => StatusCode::FORBIDDEN,
+            WalletError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            WalletError::WalletNotFound | WalletError::WithdrawalNotFound => StatusCode::NOT_FOUND,
+            WalletError::Deposit(
+                DepositError::InvalidAddress(_) | DepositError::InvalidSignature(_),
+            ) => StatusCode::BAD_REQUEST,
+            WalletError::WalletAddressConflict(_)
+            | WalletError::Deposit(DepositError::AddressAlreadyAssigned(_)) => StatusCode::CONFLICT,
+            WalletError::Deposit(DepositError::Rpc(_)) => StatusCode::BAD_GATEWAY,
+            WalletError::Deposit(_) | WalletError::Database(_) | WalletError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        // Server-side failures are logged in full but not leaked to the client
+        let message = if status.is_server_error() {
+            error!("{:?}", self);
+            match status {
+                StatusCode::BAD_GATEWAY => "Upstream RPC request failed".to_string(),
+                _ => "Internal server error".to_string(),
+            }
+        } else {
+            self.to_string()
+        };
+        let mut body = json!({ "code": self.code(), "error": message });
+        if let WalletError::InsufficientBalance { balance, requested } = self {
+            body["balance"] = json!(balance);
+            body["requested"] = json!(requested);
+        }
+        HttpResponse::build(status).json(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    #[test]
+    fn test_client_errors_map_to_4xx() {
+        let cases = [
+            (
+                WalletError::InsufficientBalance {
+                    balance: 1.0,
+                    requested: 2.0,
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                WalletError::InvalidRequest("Invalid currency".into()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (WalletError::Unauthorized, StatusCode::UNAUTHORIZED),
+            (WalletError::Forbidden, StatusCode::FORBIDDEN),
+            (WalletError::RateLimited, StatusCode::TOO_MANY_REQUESTS),
+            (WalletError::WalletNotFound, StatusCode::NOT_FOUND),
+            (WalletError::WithdrawalNotFound, StatusCode::NOT_FOUND),
+            (
+                DepositError::InvalidAddress("nope".into()).into(),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                DepositError::InvalidSignature("nope".into()).into(),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                WalletError::DepositNotVerified("no such transaction".into()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                DepositError::AddressAlreadyAssigned("pda".into()).into(),
+                StatusCode::CONFLICT,
+            ),
+            (
+                WalletError::WalletAddressConflict("taken".into()),
+                StatusCode::CONFLICT,
+            ),
+        ];
+        for (err, status) in cases {
+            assert_eq!(err.status_code(), status, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_server_errors_map_to_5xx() {
+        assert_eq!(
+            WalletError::from(sqlx::Error::PoolTimedOut).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            WalletError::from(anyhow::anyhow!("boom")).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    async fn body_json(err: WalletError) -> serde_json::Value {
+        let body = to_bytes(err.error_response().into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_error_response_hides_internal_details() {
+        let body = body_json(anyhow::anyhow!("secret connection string").into()).await;
+        assert_eq!(
+            body,
+            json!({ "code": "INTERNAL_ERROR", "error": "Internal server error" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_insufficient_balance_body() {
+        let err = WalletError::InsufficientBalance {
+            balance: 0.5,
+            requested: 1.25,
+        };
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body_json(err).await,
+            json!({
+                "code": "INSUFFICIENT_BALANCE",
+                "error": "Insufficient balance: requested 1.25, available 0.5",
+                "balance": 0.5,
+                "requested": 1.25
+            })
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::env;
+
+use anyhow::{anyhow, Result};
+
+/// Fee charged on withdrawals, expressed in units of the withdrawn currency.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WithdrawalFee {
+    pub flat: f64,
+    /// Percentage of the requested amount, e.g. `0.5` for 0.5%
+    pub percent: f64,
+}
+
+impl WithdrawalFee {
+    /// Reads `WITHDRAWAL_FEE_FLAT` and `WITHDRAWAL_FEE_PERCENT`, both defaulting to zero.
+    pub fn from_env() -> Result<Self> {
+        Self::new(
+            read_env_f64("WITHDRAWAL_FEE_FLAT")?,
+            read_env_f64("WITHDRAWAL_FEE_PERCENT")?,
+        )
+    }
+
+    /// Fails unless `flat` is a finite amount of at least zero and `percent` is below 100.
+    pub fn new(flat: f64, percent: f64) -> Result<Self> {
+        let fee = Self { flat, percent };
+        // NaN falls outside both ranges
+        if !(0.0..f64::INFINITY).contains(&flat) || !(0.0..100.0).contains(&percent) {
+            return Err(anyhow!("Invalid withdrawal fee configuration: {:?}", fee));
+        }
+        Ok(fee)
+    }
+
+    pub fn fee_for(&self, amount: f64) -> f64 {
+        self.flat + amount * self.percent / 100.0
+    }
+
+    /// Splits a withdrawal into the fee and the amount actually transferred.
+    /// Fails if the fee would consume the whole amount.
+    pub fn split(&self, amount: f64) -> Result<(f64, f64)> {
+        let fee = self.fee_for(amount);
+        if fee >= amount {
+            return Err(anyhow!(
+                "Withdrawal amount {} does not cover the fee {}",
+                amount,
+                fee
+            ));
+        }
+        Ok((amount - fee, fee))
+    }
+}
+
+fn read_env_f64(key: &str) -> Result<f64> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| anyhow!("{} must be a number, got {:?}", key, value)),
+        Err(_) => Ok(0.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flat_fee() {
+        let fee = WithdrawalFee {
+            flat: 0.01,
+            percent: 0.0,
+        };
+        let (net, charged) = fee.split(1.0).unwrap();
+        assert!((net - 0.99).abs() < 1e-9);
+        assert!((charged - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_percentage_fee() {
+        let fee = WithdrawalFee {
+            flat: 0.0,
+            percent: 2.0,
+        };
+        let (net, charged) = fee.split(5.0).unwrap();
+        assert!((net - 4.9).abs() < 1e-9);
+        assert!((charged - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_fee_exceeding_amount_is_rejected() {
+        let fee = WithdrawalFee {
+            flat: 0.5,
+            percent: 1.0,
+        };
+        assert!(fee.split(0.5).is_err());
+        assert!(fee.split(0.1).is_err());
+    }
+
+    #[test]
+    fn test_fee_configuration_is_validated() {
+        assert_eq!(
+            WithdrawalFee::new(0.01, 0.5).unwrap(),
+            WithdrawalFee {
+                flat: 0.01,
+                percent: 0.5
+            }
+        );
+        for (flat, percent) in [
+            (f64::NAN, 0.0),
+            (0.0, f64::NAN),
+            (f64::INFINITY, 0.0),
+            (-0.01, 0.0),
+            (0.0, 100.0),
+        ] {
+            assert!(
+                WithdrawalFee::new(flat, percent).is_err(),
+                "{} {}",
+                flat,
+                percent
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_fee() {
+        let (net, charged) = WithdrawalFee::default().split(1.0).unwrap();
+        assert_eq!(net, 1.0);
+        assert_eq!(charged, 0.0);
+    }
+}
@@ -1,120 +1,315 @@
-use std::env;
+use std::{env, str::FromStr, time::Duration};
 
-use actix_cors::Cors;
-use actix_web::{middleware::Logger, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{
+    middleware::{Condition, Logger},
+    web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError,
+};
+use auth::Claims;
+use chain::{ChainClient, OnChain};
 use common::{
     db,
-    models::{LeaderboardEntry, User, UserNetworkPnl, Wallet},
+    health::{self, HealthReport},
+    models::{LeaderboardEntry, Page, Timeframe, User, UserNetworkPnl, Wallet},
     utils::{
-        self, Currency, DepositRequest, Network, UserDetailsRequest, WalletType, WithdrawRequest,
+        self, Currency, DepositRequest, Network, RefundRequest, RegisterWalletRequest,
+        UserDetailsRequest, WalletType, WithdrawRequest,
     },
 };
 use db::establish_connection;
-use deposits::sol::DepositService;
+use deposit_addresses::DepositAddresses;
+use deposits::{
+    error::DepositError,
+    sol::{self, DepositService},
+};
 use dotenv::dotenv;
+use error::WalletError;
+use evm_deposits::MonadClient;
+use fees::WithdrawalFee;
+use metrics::RequestMetrics;
+use razorpay::RazorpayClient;
+use referral::ReferralBonus;
 
 use serde_json::json;
 use sqlx::{Pool, Postgres};
-use tracing::info;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 use utils::TxType;
 
-const SOL_TO_LAMPORTS: u64 = 1_000_000_000;
+mod auth;
+mod chain;
+mod cors;
+mod deposit_addresses;
+mod deposit_events;
+mod error;
+mod fees;
+mod metrics;
+mod rate_limit;
+mod razorpay;
+mod referral;
+mod shutdown;
+
+/// Currencies every user gets a wallet for
+const WALLET_CURRENCIES: [Currency; 2] = [Currency::SOL, Currency::MON];
+
+/// Currencies the withdrawal worker knows how to send
+const WITHDRAWAL_CURRENCIES: [Currency; 3] = [Currency::SOL, Currency::USDC, Currency::MON];
+
+/// How long a withdrawal's idempotency key is honoured, unless
+/// WITHDRAWAL_IDEMPOTENCY_WINDOW_SECS says otherwise
+const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Matches returned by /matches when the request doesn't set a limit, and the most it may ask for
+const DEFAULT_MATCH_PAGE_SIZE: i64 = 20;
+const MAX_MATCH_PAGE_SIZE: i64 = 100;
+
+fn idempotency_window_from_env() -> Duration {
+    env::var("WITHDRAWAL_IDEMPOTENCY_WINDOW_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW)
+}
+
+/// Rejects `address` unless it's a valid address on the chain `currency` lives on
+fn check_address(currency: Currency, address: &str) -> Result<(), WalletError> {
+    match currency {
+        Currency::SOL | Currency::USDC => {
+            sol::parse_address(address)?;
+        }
+        Currency::MON => {
+            evm_deposits::parse_address(address)
+                .map_err(|err| DepositError::InvalidAddress(err.0))?;
+        }
+        Currency::INR => {}
+    }
+    Ok(())
+}
+
+/// The form an address is registered in, so each address has one spelling: hex
+/// addresses are stored lowercase
+fn registered_address(currency: Currency, address: &str) -> Result<String, WalletError> {
+    check_address(currency, address)?;
+    Ok(match currency {
+        Currency::MON => address.trim().to_lowercase(),
+        _ => address.trim().to_string(),
+    })
+}
+
+/// What a wallet signs to prove it belongs to the user registering it
+fn wallet_registration_message(user_id: i32, currency: Currency, address: &str) -> String {
+    format!(
+        "Register {} wallet {} to xplode user {}",
+        currency,
+        address.trim(),
+        user_id
+    )
+}
+
+/// Rejects the registration unless the wallet signed its message: with `signMessage`
+/// on Solana, base58 encoded, and `personal_sign` on Monad, hex encoded.
+fn check_registration_signature(request: &RegisterWalletRequest) -> Result<(), WalletError> {
+    let message = wallet_registration_message(request.user_id, request.currency, &request.address);
+    let signed = match request.currency {
+        Currency::MON => evm_deposits::is_signed_by(&message, &request.signature, &request.address),
+        _ => sol::is_signed_by(&message, &request.signature, &request.address),
+    };
+    if !signed {
+        return Err(WalletError::InvalidRequest(format!(
+            "Signature does not match the message {:?}",
+            message
+        )));
+    }
+    Ok(())
+}
+
+fn check_withdrawal_currency(currency: Currency) -> Result<(), WalletError> {
+    if !WITHDRAWAL_CURRENCIES.contains(&currency) {
+        return Err(WalletError::InvalidRequest(format!(
+            "{} withdrawals are not supported",
+            currency
+        )));
+    }
+    Ok(())
+}
+
+/// Largest JSON body accepted. Every request type is a handful of short fields, so
+/// anything near this size is bogus.
+const JSON_PAYLOAD_LIMIT: usize = 4 * 1024;
+
+/// Rejects oversized, malformed and non-JSON bodies with our usual 400 instead of
+/// actix's plain-text errors.
+fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(JSON_PAYLOAD_LIMIT)
+        .error_handler(|err, _req| {
+            WalletError::InvalidRequest(format!("Invalid request body: {}", err)).into()
+        })
+}
 
 #[actix_web::post("/user-details")]
 async fn fetch_or_create_user(
     req: web::Json<UserDetailsRequest>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
+) -> Result<HttpResponse, WalletError> {
     let AppState {
         pool,
-        deposit_service,
+        deposit_addresses,
+        ..
     } = &**app_state;
-    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    let mut tx = pool.begin().await?;
+    let currency = req.currency.unwrap_or(Currency::SOL);
 
     // Check if the user already exists
     let existing_user: Option<User> = sqlx::query_as("SELECT * FROM users WHERE email = $1")
         .bind(&req.email)
         .fetch_optional(&mut *tx)
-        .await
-        .expect("Error fetching user");
+        .await?;
 
     match existing_user {
         Some(user) => {
-            let wallet: Wallet =
-                sqlx::query_as("SELECT * FROM wallet WHERE user_id = $1 AND currency = $2")
-                    .bind(user.id)
-                    .bind(Currency::SOL.to_string())
-                    .fetch_one(&mut *tx)
-                    .await
-                    .expect("Error fetching wallet");
+            // Older users only hold a SOL wallet, so fill in any missing currencies
+            let wallets = db::provision_user_wallets_tx(
+                &mut tx,
+                user.id,
+                &WALLET_CURRENCIES,
+                WalletType::PDA,
+            )
+            .await?;
 
-            tx.commit().await.expect("Failed to commit transaction");
+            tx.commit().await?;
 
-            HttpResponse::Ok().json(json!({
+            let wallet = wallets
+                .iter()
+                .find(|wallet| wallet.currency == currency.to_string());
+            Ok(HttpResponse::Ok().json(json!({
                 "id": user.id,
-                "currency": "SOL",
-                "balance": wallet.balance,
-                "wallet_type": wallet.wallet_type,
-                "wallet_address": wallet.wallet_address,
-                "user_pda": user.user_pda
-            }))
+                "currency": currency,
+                "balance": wallet.map(|wallet| wallet.balance).unwrap_or_default(),
+                "wallet_type": wallet.map(|wallet| &wallet.wallet_type),
+                "wallet_address": wallet.and_then(|wallet| wallet.wallet_address.as_ref()),
+                "wallets": wallets,
+                "user_pda": user.user_pda,
+                "referral_code": user.referral_code
+            })))
         }
         None => {
-            let user_pda = deposit_service
-                .generate_deposit_address()
-                .unwrap()
-                .to_string();
-
             // Create new user
             let created_user: User = sqlx::query_as(
-                "INSERT INTO users (clerk_id, email, name, user_pda) VALUES ($1, $2, $3, $4) RETURNING *",
+                "INSERT INTO users (privy_id, email, name) VALUES ($1, $2, $3) RETURNING *",
             )
-            .bind(&req.clerk_id)
+            .bind(&req.privy_id)
             .bind(&req.email)
             .bind(&req.name)
-            .bind(user_pda)
             .fetch_one(&mut *tx)
-            .await
-            .expect("Error creating new user");
+            .await?;
+
+            let user_pda = deposit_addresses.generate_deposit_address(created_user.id)?;
+
+            let created_user: User =
+                sqlx::query_as("UPDATE users SET user_pda = $1 WHERE id = $2 RETURNING *")
+                    .bind(user_pda)
+                    .bind(created_user.id)
+                    .fetch_one(&mut *tx)
+                    .await?;
 
-            // Create wallet with direct type
-            let _: Wallet = sqlx::query_as(
-                "INSERT INTO wallet (user_id, currency, balance, wallet_type) VALUES ($1, $2, $3, $4) RETURNING *",
+            // Create one wallet per supported currency
+            let wallets = db::provision_user_wallets_tx(
+                &mut tx,
+                created_user.id,
+                &WALLET_CURRENCIES,
+                WalletType::PDA,
             )
-            .bind(created_user.id)
-            .bind(Currency::SOL.to_string())
-            .bind(0.0)
-            .bind(WalletType::PDA.to_string())
-            .fetch_one(&mut *tx)
-            .await
-            .expect("Failed to create wallet");
+            .await?;
 
-            tx.commit().await.expect("Failed to commit transaction");
+            if let Some(referral_code) = &req.referral_code {
+                db::record_referral_tx(&mut tx, created_user.id, referral_code)
+                    .await?
+                    .ok_or_else(|| {
+                        WalletError::InvalidRequest("Unknown referral code".to_string())
+                    })?;
+            }
 
-            HttpResponse::Created().json(json!({
+            tx.commit().await?;
+
+            Ok(HttpResponse::Created().json(json!({
                 "user_id": created_user.id,
-                "currency": "SOL",
+                "currency": currency,
                 "balance": 0.0,
                 "wallet_type": WalletType::PDA.to_string(),
-                "wallet_address": "None"
-            }))
+                "wallet_address": "None",
+                "wallets": wallets,
+                "user_pda": created_user.user_pda,
+                "referral_code": created_user.referral_code
+            })))
         }
     }
 }
 
+/// Registers the wallet the user deposits a currency from. Deposits are only credited
+/// from it, so the wallet must sign `wallet_registration_message`, and the first one
+/// registered is kept.
+#[actix_web::post("/wallet-address")]
+async fn register_wallet_address(
+    req: web::Json<RegisterWalletRequest>,
+    claims: Option<web::ReqData<Claims>>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, WalletError> {
+    auth::authorize_user(claims.as_deref(), req.user_id)?;
+    if !WALLET_CURRENCIES.contains(&req.currency) {
+        return Err(WalletError::InvalidRequest(format!(
+            "{} wallets can't be registered",
+            req.currency
+        )));
+    }
+    let address = registered_address(req.currency, &req.address)?;
+    check_registration_signature(&req)?;
+    let AppState { pool, .. } = &**app_state;
+
+    let mut tx = pool.begin().await?;
+    let wallet = db::register_wallet_address_tx(&mut tx, req.user_id, req.currency, &address)
+        .await
+        .map_err(|err| match db::is_unique_violation(&err) {
+            true => WalletError::WalletAddressConflict(
+                "Address is registered to another user".to_string(),
+            ),
+            false => err.into(),
+        })?;
+    let Some(wallet) = wallet else {
+        // Either the user holds no such wallet or registered another address for it
+        db::get_user_wallet(pool, req.user_id, req.currency)
+            .await
+            .map_err(|err| match db::is_not_found(&err) {
+                true => WalletError::WalletNotFound,
+                false => err.into(),
+            })?;
+        return Err(WalletError::WalletAddressConflict(format!(
+            "Another {} wallet address is already registered",
+            req.currency
+        )));
+    };
+    tx.commit().await?;
+
+    info!(user_id = req.user_id, currency = %req.currency, address = %address, "Registered wallet address");
+    Ok(HttpResponse::Ok().json(wallet))
+}
+
 #[actix_web::get("/user-stats/{user_id}")]
 async fn get_user_stats(
     user_id: web::Path<String>,
+    claims: Option<web::ReqData<Claims>>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
-    let user_id: i32 = user_id.into_inner().parse().unwrap();
-    let AppState {
-        pool,
-        deposit_service: _,
-    } = &**app_state;
+) -> Result<HttpResponse, WalletError> {
+    let user_id: i32 = user_id
+        .into_inner()
+        .parse()
+        .map_err(|_| WalletError::InvalidRequest("Invalid user id".to_string()))?;
+    auth::authorize_user(claims.as_deref(), user_id)?;
+    let AppState { pool, .. } = &**app_state;
 
-    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    let mut tx = pool.begin().await?;
 
     // Use LEFT JOIN to handle case where user has no PNL records yet
     let user_pnl: Option<UserNetworkPnl> =
@@ -122,12 +317,11 @@ async fn get_user_stats(
             .bind(user_id)
             .bind(Network::SOLANA.to_string())
             .fetch_optional(&mut *tx)
-            .await
-            .expect("Error fetching user PNL");
+            .await?;
 
-    tx.commit().await.expect("Failed to commit transaction");
+    tx.commit().await?;
 
-    match user_pnl {
+    Ok(match user_pnl {
         Some(pnl) => HttpResponse::Ok().json(pnl),
         None => HttpResponse::Ok().json(json!({
             "user_id": user_id,
@@ -135,59 +329,254 @@ async fn get_user_stats(
             "total_matches": 0,
             "total_profit": 0.0
         })),
-    }
+    })
 }
 
 #[actix_web::get("/leaderboard/{network}/{timeframe}")]
 async fn get_leaderboard(
     path: web::Path<(String, String)>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
+) -> Result<HttpResponse, WalletError> {
     let (network, timeframe) = path.into_inner();
-    let AppState {
-        pool,
-        deposit_service: _,
-    } = &**app_state;
+    let AppState { pool, .. } = &**app_state;
 
     let leaders: Vec<LeaderboardEntry> = match timeframe.as_str() {
-        "24h" => db::get_leaderboard_24h(pool, &network, 100)
-            .await
-            .expect("Failed to fetch leaderboard"),
-        "all" => db::get_leaderboard_all_time(pool, &network, 100)
-            .await
-            .expect("Failed to fetch leaderboard"),
-        _ => return HttpResponse::BadRequest().body("Invalid timeframe"),
+        "24h" => db::get_leaderboard_24h(pool, &network, 100).await?,
+        "all" => db::get_leaderboard_all_time(pool, &network, 100).await?,
+        _ => return Err(WalletError::InvalidRequest("Invalid timeframe".to_string())),
     };
 
-    HttpResponse::Ok().json(leaders)
+    Ok(HttpResponse::Ok().json(leaders))
 }
 
-#[actix_web::get("/health")]
-async fn health_check() -> impl Responder {
-    info!("Health check request arrived");
+/// The limit and offset to list matches with
+fn match_page(page: Page) -> Result<(i64, i64), WalletError> {
+    let limit = page.limit.unwrap_or(DEFAULT_MATCH_PAGE_SIZE);
+    if !(1..=MAX_MATCH_PAGE_SIZE).contains(&limit) {
+        return Err(WalletError::InvalidRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_MATCH_PAGE_SIZE
+        )));
+    }
+    let offset = page.offset.unwrap_or(0);
+    if offset < 0 {
+        return Err(WalletError::InvalidRequest(
+            "offset must not be negative".to_string(),
+        ));
+    }
+    Ok((limit, offset))
+}
+
+/// The user's settled games, most recent first, paged by the `limit` and `offset`
+/// query parameters
+#[actix_web::get("/matches/{user_id}")]
+async fn get_matches(
+    user_id: web::Path<i32>,
+    page: web::Query<Page>,
+    claims: Option<web::ReqData<Claims>>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, WalletError> {
+    let user_id = user_id.into_inner();
+    auth::authorize_user(claims.as_deref(), user_id)?;
+    let (limit, offset) = match_page(page.into_inner())?;
+    let AppState { pool, .. } = &**app_state;
+
+    let matches = db::get_match_history(pool, user_id, limit, offset).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "user_id": user_id,
+        "limit": limit,
+        "offset": offset,
+        "matches": matches
+    })))
+}
+
+#[actix_web::get("/balance/{user_id}/{currency}")]
+async fn get_balance(
+    path: web::Path<(i32, String)>,
+    claims: Option<web::ReqData<Claims>>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, WalletError> {
+    let (user_id, currency) = path.into_inner();
+    auth::authorize_user(claims.as_deref(), user_id)?;
+    let AppState { pool, .. } = &**app_state;
+
+    let currency = Currency::from_str(&currency)
+        .map_err(|_| WalletError::InvalidRequest("Invalid currency".to_string()))?;
+
+    let wallet = db::get_user_wallet(pool, user_id, currency)
+        .await
+        .map_err(|err| match db::is_not_found(&err) {
+            true => WalletError::WalletNotFound,
+            false => err.into(),
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "user_id": user_id,
+        "currency": currency,
+        "balance": wallet.balance
+    })))
+}
+
+#[actix_web::get("/deposit-addresses/{user_id}")]
+async fn get_deposit_addresses(
+    user_id: web::Path<i32>,
+    claims: Option<web::ReqData<Claims>>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, WalletError> {
+    let user_id = user_id.into_inner();
+    auth::authorize_user(claims.as_deref(), user_id)?;
+    let AppState {
+        deposit_addresses, ..
+    } = &**app_state;
+
+    let addresses = deposit_addresses.get_user_deposit_addresses(user_id)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "user_id": user_id,
+        "deposit_addresses": addresses
+    })))
+}
+
+#[actix_web::get("/live")]
+async fn live() -> impl Responder {
     HttpResponse::Ok().content_type("text/plain").body("OK")
 }
 
+// Readiness: Postgres, Redis and, when HEALTH_CHECK_RPC is set, the Solana RPC node
+#[actix_web::get("/health")]
+async fn health_check(app_state: web::Data<AppState>) -> impl Responder {
+    debug!("Health check request arrived");
+    let AppState {
+        pool,
+        deposit_addresses,
+        check_rpc_health,
+        ..
+    } = &**app_state;
+
+    let mut report = HealthReport::new()
+        .check("postgres", health::check_postgres(pool))
+        .await
+        .check("redis", deposit_addresses.ping_redis())
+        .await;
+    if *check_rpc_health {
+        report = report
+            .check("rpc", deposit_addresses.check_rpc_health())
+            .await;
+    }
+    health_response(&report)
+}
+
+fn health_response(report: &HealthReport) -> HttpResponse {
+    if report.is_healthy() {
+        HttpResponse::Ok().json(report.to_json())
+    } else {
+        warn!("Health check failed: {}", report.to_json());
+        HttpResponse::ServiceUnavailable().json(report.to_json())
+    }
+}
+
 #[actix_web::post("/deposit")]
 async fn deposit(
     deposit_request: web::Json<DepositRequest>,
+    claims: Option<web::ReqData<Claims>>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
+) -> Result<HttpResponse, WalletError> {
+    auth::authorize_user(claims.as_deref(), deposit_request.user_id)?;
     let AppState {
         pool,
-        deposit_service: _,
+        chain,
+        referral_bonus,
+        ..
     } = &**app_state;
-    info!("Deposit request arrived");
+    let sender = deposit_wallet_address(pool, &deposit_request).await?;
+    let new_balance =
+        credit_verified_deposit(pool, chain.as_ref(), &deposit_request, &sender).await?;
+    referral::reward_referrer(
+        pool,
+        referral_bonus,
+        deposit_request.user_id,
+        deposit_request.currency,
+        deposit_request.amount,
+    )
+    .await;
 
-    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    Ok(HttpResponse::Ok().json(json!({
+        "user_id": deposit_request.user_id,
+        "currency": deposit_request.currency,
+        "balance": new_balance,
+        "tx_hash": deposit_request.tx_hash
+    })))
+}
+
+/// The wallet the user registered to deposit the request's currency from; transfers
+/// from anywhere else aren't credited to them.
+async fn deposit_wallet_address(
+    pool: &Pool<Postgres>,
+    deposit_request: &DepositRequest,
+) -> Result<String, WalletError> {
+    let wallet = db::get_user_wallet(pool, deposit_request.user_id, deposit_request.currency)
+        .await
+        .map_err(|err| match db::is_not_found(&err) {
+            true => WalletError::WalletNotFound,
+            false => err.into(),
+        })?;
+    wallet.wallet_address.ok_or_else(|| {
+        WalletError::InvalidRequest(format!(
+            "No {} wallet address is registered to deposit from",
+            deposit_request.currency
+        ))
+    })
+}
+
+/// Credits a deposit once the chain confirms it came from `sender` and returns the new
+/// balance.
+async fn credit_verified_deposit(
+    pool: &Pool<Postgres>,
+    chain: &dyn ChainClient,
+    deposit_request: &DepositRequest,
+    sender: &str,
+) -> Result<f64, WalletError> {
+    chain::verify_deposit(chain, deposit_request, sender).await?;
+    credit_deposit(pool, deposit_request).await
+}
 
+/// Credits a deposit to the user's wallet and returns the new balance.
+async fn credit_deposit(
+    pool: &Pool<Postgres>,
+    deposit_request: &DepositRequest,
+) -> Result<f64, WalletError> {
+    info!(
+        user_id = deposit_request.user_id,
+        currency = %deposit_request.currency,
+        amount = deposit_request.amount,
+        tx_hash = %deposit_request.tx_hash,
+        "Crediting deposit"
+    );
+    let mut tx = pool.begin().await?;
+
+    // Locking the wallet serialises deposits, so a replayed transaction sees the first credit
     let wallet: Wallet =
-        sqlx::query_as("SELECT * FROM wallet WHERE user_id = $1 AND currency = $2")
+        sqlx::query_as("SELECT * FROM wallet WHERE user_id = $1 AND currency = $2 FOR UPDATE")
             .bind(deposit_request.user_id)
             .bind(deposit_request.currency.to_string())
-            .fetch_one(&mut *tx)
-            .await
-            .expect("Error fetching wallet");
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(WalletError::WalletNotFound)?;
+
+    let already_credited: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM transactions WHERE currency = $1 AND tx_type = $2 AND tx_hash = $3)",
+    )
+    .bind(deposit_request.currency.to_string())
+    .bind(TxType::DEPOSIT.to_string())
+    .bind(&deposit_request.tx_hash)
+    .fetch_one(&mut *tx)
+    .await?;
+    if already_credited {
+        return Err(WalletError::DepositNotVerified(
+            "transaction was already credited".to_string(),
+        ));
+    }
 
     let new_balance = deposit_request.amount + wallet.balance;
 
@@ -198,8 +587,7 @@ async fn deposit(
     .bind(deposit_request.user_id)
     .bind(deposit_request.currency.to_string())
     .execute(&mut *tx)
-    .await
-    .expect("Error updating wallet balance");
+    .await?;
 
     // Record the transaction
     sqlx::query(
@@ -211,92 +599,364 @@ async fn deposit(
     .bind(TxType::DEPOSIT.to_string())
     .bind(&deposit_request.tx_hash)
     .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(new_balance)
+}
+
+#[actix_web::post("/razorpay/webhook")]
+async fn razorpay_webhook(
+    req: HttpRequest,
+    body: web::Bytes,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let AppState {
+        pool,
+        razorpay_webhook_secret,
+        referral_bonus,
+        ..
+    } = &**app_state;
+
+    let Some(secret) = razorpay_webhook_secret else {
+        warn!("Razorpay webhook received but RAZORPAY_WEBHOOK_SECRET is not set");
+        return HttpResponse::ServiceUnavailable().finish();
+    };
+
+    let signature = req
+        .headers()
+        .get(razorpay::SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !razorpay::verify_webhook_signature(secret, &body, signature) {
+        warn!("Rejected Razorpay webhook with an invalid signature");
+        return HttpResponse::Unauthorized().body("Invalid signature");
+    }
+
+    let event: razorpay::WebhookEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+
+    // Acknowledge everything else so Razorpay doesn't keep retrying it
+    if event.event != razorpay::PAYMENT_CAPTURED {
+        return HttpResponse::Ok().finish();
+    }
+    let Some(payment) = event.payload.payment.map(|payment| payment.entity) else {
+        return HttpResponse::BadRequest().body("Missing payment entity");
+    };
+    if payment.status != "captured" {
+        return HttpResponse::Ok().finish();
+    }
+    let Some(user_id) = payment.user_id() else {
+        error!("Razorpay payment {} has no user_id note", payment.id);
+        return HttpResponse::BadRequest().body("Missing user_id note");
+    };
+    if payment.currency != Currency::INR.to_string() {
+        error!(
+            "Razorpay payment {} is in {}, expected INR",
+            payment.id, payment.currency
+        );
+        return HttpResponse::BadRequest().body("Unsupported currency");
+    }
+
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return WalletError::from(err).error_response(),
+    };
+    let credited = match db::credit_deposit_once_tx(
+        &mut tx,
+        user_id,
+        Currency::INR,
+        payment.amount_in_rupees(),
+        &payment.id,
+    )
     .await
-    .expect("Error recording transaction");
+    {
+        Ok(credited) => credited,
+        Err(err) => {
+            error!(
+                "Failed to credit Razorpay payment {}: {:?}",
+                payment.id, err
+            );
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Err(err) = tx.commit().await {
+        return WalletError::from(err).error_response();
+    }
+    if credited {
+        referral::reward_referrer(
+            pool,
+            referral_bonus,
+            user_id,
+            Currency::INR,
+            payment.amount_in_rupees(),
+        )
+        .await;
+    }
 
-    tx.commit().await.expect("Failed to commit transaction");
+    info!(
+        "Razorpay payment {} for user {} credited: {}",
+        payment.id, user_id, credited
+    );
+    HttpResponse::Ok().json(json!({
+        "payment_id": payment.id,
+        "user_id": user_id,
+        "credited": credited
+    }))
+}
 
+#[actix_web::post("/razorpay/refund")]
+async fn razorpay_refund(
+    refund_req: web::Json<RefundRequest>,
+    claims: Option<web::ReqData<Claims>>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    if let Err(err) = auth::authorize_admin(claims.as_deref()) {
+        return err.error_response();
+    }
+    let AppState {
+        pool,
+        razorpay_client,
+        ..
+    } = &**app_state;
+
+    let Some(client) = razorpay_client else {
+        warn!("Razorpay refund requested but RAZORPAY_KEY_ID/RAZORPAY_KEY_SECRET are not set");
+        return HttpResponse::ServiceUnavailable().finish();
+    };
+
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return WalletError::from(err).error_response(),
+    };
+
+    let (user_id, amount) =
+        match db::refund_deposit_tx(&mut tx, &refund_req.payment_id, refund_req.amount).await {
+            Ok(refund) => refund,
+            Err(err) if db::is_not_found(&err) => {
+                return HttpResponse::NotFound().body("Deposit not found")
+            }
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        };
+
+    // The wallet debit is only committed once Razorpay has accepted the refund
+    let refund = match client
+        .refund_payment(&refund_req.payment_id, razorpay::rupees_to_paise(amount))
+        .await
+    {
+        Ok(refund) => refund,
+        Err(err) => {
+            error!(
+                "Failed to refund Razorpay payment {}: {:?}",
+                refund_req.payment_id, err
+            );
+            return HttpResponse::BadGateway().body("Refund failed");
+        }
+    };
+
+    if let Err(err) = tx.commit().await {
+        // Razorpay already sent the money back, so this needs manual reconciliation
+        error!(
+            "Razorpay refund {} succeeded but the wallet debit failed to commit: {:?}",
+            refund.id, err
+        );
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    info!(
+        "Refunded {} INR of Razorpay payment {} for user {}",
+        amount, refund_req.payment_id, user_id
+    );
     HttpResponse::Ok().json(json!({
-        "user_id": deposit_request.user_id,
-        "currency": deposit_request.currency,
-        "balance": new_balance,
-        "tx_hash": deposit_request.tx_hash
+        "refund_id": refund.id,
+        "payment_id": refund.payment_id,
+        "user_id": user_id,
+        "amount": amount,
+        "status": refund.status
     }))
 }
 
 #[actix_web::post("/withdraw")]
 async fn withdraw(
     withdraw_req: web::Json<WithdrawRequest>,
+    claims: Option<web::ReqData<Claims>>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
+) -> Result<HttpResponse, WalletError> {
+    auth::authorize_user(claims.as_deref(), withdraw_req.user_id)?;
     let AppState {
         pool,
-        deposit_service,
+        withdrawal_fee,
+        idempotency_window,
+        ..
     } = &**app_state;
-    info!("Attempting to withdraw");
 
-    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    let response =
+        request_withdrawal(pool, withdrawal_fee, *idempotency_window, &withdraw_req).await?;
+
+    // The transfer itself is sent by the withdrawal worker
+    Ok(HttpResponse::Accepted().json(response))
+}
+
+/// Queues a withdrawal and returns the response to send. A request repeating the
+/// idempotency key of one made within `idempotency_window` queues nothing and gets
+/// that request's response again.
+async fn request_withdrawal(
+    pool: &Pool<Postgres>,
+    withdrawal_fee: &WithdrawalFee,
+    idempotency_window: Duration,
+    withdraw_req: &WithdrawRequest,
+) -> Result<serde_json::Value, WalletError> {
+    // The destination address is kept out of the logs
+    info!(
+        user_id = withdraw_req.user_id,
+        currency = %withdraw_req.currency,
+        amount = withdraw_req.amount,
+        "Attempting to withdraw"
+    );
+
+    // Reject what the worker can't send up front rather than failing there
+    check_withdrawal_currency(withdraw_req.currency)?;
+    check_address(withdraw_req.currency, &withdraw_req.withdraw_address)?;
+    let idempotency_key = withdraw_req.idempotency_key.as_deref();
+    if idempotency_key.is_some_and(|key| key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN) {
+        return Err(WalletError::InvalidRequest(format!(
+            "idempotency_key must be 1 to {} characters",
+            MAX_IDEMPOTENCY_KEY_LEN
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    if let Some(key) = idempotency_key {
+        let replayed = db::idempotent_withdrawal_response_tx(
+            &mut tx,
+            withdraw_req.user_id,
+            key,
+            idempotency_window,
+        )
+        .await?;
+        if let Some(response) = replayed {
+            info!(
+                user_id = withdraw_req.user_id,
+                "Replaying withdrawal for a repeated idempotency key"
+            );
+            return serde_json::from_str(&response)
+                .map_err(|err| WalletError::Internal(err.into()));
+        }
+    }
 
     let wallet: Wallet =
         sqlx::query_as("SELECT * FROM wallet WHERE user_id = $1 AND currency = $2")
             .bind(withdraw_req.user_id)
             .bind(withdraw_req.currency.to_string())
-            .fetch_one(&mut *tx)
-            .await
-            .expect("Error fetching wallet");
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(WalletError::WalletNotFound)?;
 
     if withdraw_req.amount > wallet.balance {
-        return HttpResponse::BadRequest().body("Insufficient balance");
+        return Err(WalletError::InsufficientBalance {
+            balance: wallet.balance,
+            requested: withdraw_req.amount,
+        });
     }
 
-    let withdraw_txhash = deposit_service
-        .withdraw_to_user_from_treasury(
-            withdraw_req.withdraw_address.clone(),
-            (withdraw_req.amount * SOL_TO_LAMPORTS as f64) as u64,
-        )
-        .await
-        .unwrap();
-
-    let new_balance = wallet.balance - withdraw_req.amount;
-
-    // Update the user's wallet balance
-    sqlx::query(
-        "UPDATE wallet SET balance = $1, updated_at = NOW() WHERE user_id = $2 AND currency = $3",
-    )
-    .bind(new_balance)
-    .bind(withdraw_req.user_id)
-    .bind(withdraw_req.currency.to_string())
-    .execute(&mut *tx)
-    .await
-    .expect("Error updating wallet balance");
+    // The wallet is debited the full amount, the user receives it minus the fee
+    let (net_amount, fee) = withdrawal_fee
+        .split(withdraw_req.amount)
+        .map_err(|err| WalletError::InvalidRequest(err.to_string()))?;
 
-    // Record the transaction
-    sqlx::query(
-        "INSERT INTO transactions (user_id, amount, currency, tx_type, tx_hash) VALUES ($1, $2, $3, $4, $5)",
+    let withdrawal = db::enqueue_withdrawal_tx(
+        &mut tx,
+        withdraw_req.user_id,
+        withdraw_req.currency,
+        withdraw_req.amount,
+        fee,
+        &withdraw_req.withdraw_address,
     )
-    .bind(withdraw_req.user_id)
-    .bind(withdraw_req.amount)
-    .bind(withdraw_req.currency.to_string())
-    .bind(TxType::WITHDRAWAL.to_string())
-    .bind(&withdraw_txhash)
-    .execute(&mut *tx)
-    .await
-    .expect("Error recording transaction");
-
-    tx.commit().await.expect("Failed to commit transaction");
+    .await?;
 
-    HttpResponse::Ok().json(json!({
+    let response = json!({
+        "withdrawal_id": withdrawal.id,
+        "status": withdrawal.status,
         "user_id": withdraw_req.user_id,
         "currency": withdraw_req.currency,
-        "balance": new_balance,
-        "tx_hash": withdraw_txhash,
+        "balance": wallet.balance - withdraw_req.amount,
+        "amount": withdraw_req.amount,
+        "fee": fee,
+        "net_amount": net_amount,
         "withdraw_address": withdraw_req.withdraw_address
-    }))
+    });
+    if let Some(key) = idempotency_key {
+        db::record_withdrawal_response_tx(&mut tx, withdrawal.id, key, &response.to_string())
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(response)
+}
+
+#[actix_web::get("/withdraw/{withdrawal_id}")]
+async fn get_withdrawal(
+    withdrawal_id: web::Path<i32>,
+    claims: Option<web::ReqData<Claims>>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, WalletError> {
+    let AppState { pool, .. } = &**app_state;
+
+    let withdrawal = db::get_pending_withdrawal(pool, withdrawal_id.into_inner())
+        .await
+        .map_err(|err| match db::is_not_found(&err) {
+            true => WalletError::WithdrawalNotFound,
+            false => err.into(),
+        })?;
+    auth::authorize_user(claims.as_deref(), withdrawal.user_id)?;
+
+    Ok(HttpResponse::Ok().json(withdrawal))
+}
+
+/// Totals for finance to check against what the treasury actually holds
+#[actix_web::get("/admin/reconciliation")]
+async fn get_reconciliation(
+    claims: Option<web::ReqData<Claims>>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, WalletError> {
+    auth::authorize_admin(claims.as_deref())?;
+    let AppState { pool, .. } = &**app_state;
+
+    let currencies = db::reconciliation_report(pool).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "currencies": currencies })))
+}
+
+/// Rake the house kept per currency, optionally between the `from` and `to` query
+/// parameters (RFC 3339 timestamps)
+#[actix_web::get("/admin/rake")]
+async fn get_rake(
+    timeframe: web::Query<Timeframe>,
+    claims: Option<web::ReqData<Claims>>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, WalletError> {
+    auth::authorize_admin(claims.as_deref())?;
+    let AppState { pool, .. } = &**app_state;
+
+    let currencies = db::rake_totals(pool, timeframe.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "currencies": currencies })))
 }
 
 struct AppState {
     pool: Pool<Postgres>,
-    deposit_service: DepositService,
+    deposit_addresses: Box<dyn DepositAddresses>,
+    chain: Box<dyn ChainClient>,
+    withdrawal_fee: WithdrawalFee,
+    idempotency_window: Duration,
+    razorpay_webhook_secret: Option<String>,
+    razorpay_client: Option<RazorpayClient>,
+    check_rpc_health: bool,
+    referral_bonus: ReferralBonus,
 }
 
 #[actix_web::main]
@@ -313,33 +973,105 @@ async fn main() -> std::io::Result<()> {
     info!("Current working directory: {:?}", env::current_dir());
     let pool = establish_connection().await;
 
-    let program_id = env::var("PROGRAM_ID").unwrap();
+    let program_id = sol::program_id_from_env().expect("Invalid PROGRAM_ID");
+    info!("Deposit program: {}", program_id);
+
+    let treasury = sol::treasury_keypair_from_env().expect("Invalid treasury keypair");
+    let deposit_service = DepositService::new(treasury, program_id);
+
+    let token_key = auth::TokenKey::from_env().expect("Invalid JWT config");
+    match &token_key {
+        Some(key) => info!("Verifying user tokens with {:?}", key.algorithm()),
+        None => {
+            auth::check_unauthenticated_allowed(env::var("ENVIRONMENT").ok().as_deref())
+                .expect("Invalid JWT config");
+            warn!("JWT_SECRET is not set, wallet endpoints are unauthenticated");
+        }
+    }
+    let token_rules = auth::TokenRules::from_env().expect("Invalid JWT config");
+    info!("Token rules: {:?}", token_rules);
+
+    let rate_limit = rate_limit::rate_limit_from_env().expect("Invalid RATE_LIMIT_PER_MINUTE");
+    info!("Rate limit: {} requests per minute per client", rate_limit);
+    let rate_limiter = rate_limit::RateLimiter::new(rate_limit);
+
+    let allowed_origins = cors::allowed_origins_from_env().expect("Invalid ALLOWED_ORIGINS");
+    info!("Allowed CORS origins: {:?}", allowed_origins);
 
-    let cwd = std::env::current_dir().unwrap();
-    let deposit_service =
-        DepositService::new(cwd.join("treasury-keypair.json"), program_id.to_string());
+    let withdrawal_fee = WithdrawalFee::from_env().expect("Invalid withdrawal fee config");
+    info!("Withdrawal fee: {:?}", withdrawal_fee);
+
+    let referral_bonus = ReferralBonus::from_env().expect("Invalid referral bonus config");
+    info!("Referral bonus: {:?}", referral_bonus);
+
+    deposit_events::spawn_listener(pool.clone(), deposit_service.clone(), referral_bonus);
+
+    // Built once so every request shares its connections to the Monad nodes
+    let monad = MonadClient::from_env()
+        .map_err(|err| warn!("MON deposits can't be verified: {}", err))
+        .ok();
 
     let app_state = web::Data::new(AppState {
         pool,
-        deposit_service,
+        chain: Box::new(OnChain::new(deposit_service.clone(), monad)),
+        deposit_addresses: Box::new(deposit_service),
+        withdrawal_fee,
+        idempotency_window: idempotency_window_from_env(),
+        razorpay_webhook_secret: env::var("RAZORPAY_WEBHOOK_SECRET").ok(),
+        razorpay_client: RazorpayClient::from_env(),
+        check_rpc_health: env::var("HEALTH_CHECK_RPC").is_ok_and(|value| value == "true"),
+        referral_bonus,
     });
 
+    let shutdown_timeout =
+        shutdown::shutdown_timeout_from_env().expect("Invalid SHUTDOWN_TIMEOUT_SECS");
+    let pool = app_state.pool.clone();
+
     info!("Starting HTTP server on 0.0.0.0:8080");
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .app_data(json_config())
+            // Runs inside CORS so preflights and rejected origins never reach it
+            .wrap(Condition::new(
+                token_key.is_some(),
+                auth::Authentication::new(
+                    &token_key
+                        .clone()
+                        .unwrap_or_else(|| auth::TokenKey::hs256("")),
+                    &token_rules,
+                ),
+            ))
+            // Outside authentication so requests with bad tokens count against the limit too
+            .wrap(rate_limiter.clone())
             .wrap(Logger::default())
-            .wrap(Cors::permissive())
+            .wrap(cors::configure_cors(&allowed_origins))
+            .wrap(RequestMetrics)
+            .service(live)
             .service(health_check)
+            .service(metrics::metrics)
+            .service(get_balance)
+            .service(get_deposit_addresses)
             .service(deposit)
             .service(withdraw)
+            .service(razorpay_webhook)
+            .service(razorpay_refund)
+            .service(get_withdrawal)
+            .service(get_reconciliation)
+            .service(get_rake)
             .service(fetch_or_create_user)
+            .service(register_wallet_address)
             .service(get_user_stats)
             .service(get_leaderboard)
+            .service(get_matches)
     })
+    // Signals are handled by run_until, which also closes the pool afterwards
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout)
     .bind("0.0.0.0:8080")?
-    .run()
-    .await
+    .run();
+
+    shutdown::run_until(server, shutdown::shutdown_signal(), &pool).await
 }
 
 // async fn start_account_watchers(pool: sqlx::Pool<sqlx::Sqlite>, tx: mpsc::Sender<Pubkey>) {
@@ -358,3 +1090,697 @@ async fn main() -> std::io::Result<()> {
 //             .expect("Failed to send account to channel");
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{self as actix_test, TestRequest},
+    };
+
+    use solana_sdk::signature::{Keypair, Signer};
+
+    use super::*;
+    use crate::{
+        chain::tests::{MockChain, SENDER, TREASURY},
+        deposit_addresses::tests::MockDepositAddresses,
+    };
+
+    #[test]
+    fn test_match_page() {
+        assert_eq!(match_page(Page::default()).unwrap(), (20, 0));
+        let page = Page {
+            limit: Some(50),
+            offset: Some(100),
+        };
+        assert_eq!(match_page(page).unwrap(), (50, 100));
+        for (limit, offset) in [(0, 0), (101, 0), (10, -1)] {
+            let page = Page {
+                limit: Some(limit),
+                offset: Some(offset),
+            };
+            assert!(match_page(page).is_err(), "{} {}", limit, offset);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_health_response() {
+        let healthy = HealthReport::new()
+            .check("postgres", async { Ok::<_, String>(()) })
+            .await
+            .check("redis", async { Ok::<_, String>(()) })
+            .await;
+        assert_eq!(health_response(&healthy).status(), StatusCode::OK);
+
+        let redis_down = HealthReport::new()
+            .check("postgres", async { Ok::<_, String>(()) })
+            .await
+            .check("redis", async { Err("connection refused") })
+            .await;
+        let response = health_response(&redis_down);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["checks"]["redis"], "connection refused");
+        assert_eq!(body["checks"]["postgres"], "ok");
+    }
+
+    async fn post_deposit_body(body: String) -> (StatusCode, serde_json::Value) {
+        let app = actix_test::init_service(App::new().app_data(json_config()).route(
+            "/deposit",
+            web::post().to(|_: web::Json<DepositRequest>| async { HttpResponse::Ok().finish() }),
+        ))
+        .await;
+        let request = TestRequest::post()
+            .uri("/deposit")
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(body)
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        let status = response.status();
+        let body = actix_test::read_body(response).await;
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[actix_web::test]
+    async fn test_oversized_body_is_rejected() {
+        let body = json!({
+            "user_id": 1,
+            "amount": 1.0,
+            "currency": "SOL",
+            "tx_hash": "a".repeat(JSON_PAYLOAD_LIMIT),
+        });
+        let (status, body) = post_deposit_body(body.to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "INVALID_REQUEST");
+    }
+
+    #[actix_web::test]
+    async fn test_malformed_body_is_rejected() {
+        let (status, body) = post_deposit_body(r#"{"user_id": 1, "amount":"#.to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "INVALID_REQUEST");
+
+        // Well-formed JSON of the wrong shape fails the same way
+        let (status, _) = post_deposit_body(json!({ "user_id": "one" }).to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let valid = json!({ "user_id": 1, "amount": 1.0, "currency": "SOL", "tx_hash": "abc" });
+        let (status, _) = post_deposit_body(valid.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_deposit_is_logged_with_user_id() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // The deposit is logged before the database is touched
+        let pool = unreachable_pool();
+        let request = DepositRequest {
+            user_id: 42,
+            amount: 1.5,
+            currency: Currency::SOL,
+            tx_hash: "abc".to_string(),
+        };
+        assert!(credit_deposit(&pool, &request).await.is_err());
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Crediting deposit"))
+            .unwrap_or_else(|| panic!("deposit not logged: {}", logs));
+        assert!(line.contains(" INFO "), "{}", line);
+        assert!(line.contains("user_id=42"), "{}", line);
+        assert!(line.contains("currency=SOL"), "{}", line);
+        assert!(line.contains("amount=1.5"), "{}", line);
+    }
+
+    // Never connects, so anything that reaches the database fails
+    fn unreachable_pool() -> Pool<Postgres> {
+        sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(100))
+            .connect_lazy("postgres://127.0.0.1:1/test")
+            .unwrap()
+    }
+
+    // The state main builds, around `pool` and `chain`, with deposit addresses kept in memory
+    fn test_state(pool: Pool<Postgres>, chain: MockChain) -> web::Data<AppState> {
+        web::Data::new(AppState {
+            pool,
+            deposit_addresses: Box::new(MockDepositAddresses::default()),
+            chain: Box::new(chain),
+            withdrawal_fee: WithdrawalFee::default(),
+            idempotency_window: DEFAULT_IDEMPOTENCY_WINDOW,
+            razorpay_webhook_secret: None,
+            razorpay_client: None,
+            check_rpc_health: false,
+            referral_bonus: ReferralBonus::default(),
+        })
+    }
+
+    // Sends `request` to the wallet's routes with `token`
+    async fn call_with(
+        state: web::Data<AppState>,
+        token: &str,
+        request: TestRequest,
+    ) -> (StatusCode, serde_json::Value) {
+        call(
+            state,
+            request.insert_header((header::AUTHORIZATION, format!("Bearer {}", token))),
+        )
+        .await
+    }
+
+    // Sends `request` to the wallet's routes as it is
+    async fn call(
+        state: web::Data<AppState>,
+        request: TestRequest,
+    ) -> (StatusCode, serde_json::Value) {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(state)
+                .app_data(json_config())
+                .wrap(auth::Authentication::new(
+                    &auth::TokenKey::hs256(auth::tests::SECRET),
+                    &auth::TokenRules::default(),
+                ))
+                .service(get_balance)
+                .service(get_deposit_addresses)
+                .service(deposit)
+                .service(withdraw)
+                .service(register_wallet_address)
+                .service(get_user_stats)
+                .service(get_matches)
+                .service(razorpay_refund)
+                .service(get_reconciliation)
+                .service(get_rake),
+        )
+        .await;
+        // The authentication middleware fails the call instead of responding
+        let response = match actix_test::try_call_service(&app, request.to_request()).await {
+            Ok(response) => response.into_parts().1.map_into_boxed_body(),
+            Err(err) => err.error_response(),
+        };
+        let status = response.status();
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[actix_web::test]
+    async fn test_users_cannot_act_for_each_other() {
+        let state = test_state(unreachable_pool(), MockChain::default());
+        let withdraw_request = json!({
+            "user_id": 7,
+            "amount": 0.25,
+            "currency": "SOL",
+            "withdraw_address": "11111111111111111111111111111111",
+        });
+        let deposit_request =
+            json!({ "user_id": 7, "amount": 1.0, "currency": "SOL", "tx_hash": "tx" });
+        // Each is refused before the database is reached
+        for request in [
+            TestRequest::post()
+                .uri("/withdraw")
+                .set_json(&withdraw_request),
+            TestRequest::post()
+                .uri("/deposit")
+                .set_json(&deposit_request),
+            TestRequest::get().uri("/balance/7/SOL"),
+            TestRequest::get().uri("/deposit-addresses/7"),
+            TestRequest::get().uri("/user-stats/7"),
+            TestRequest::get().uri("/matches/7"),
+            TestRequest::post()
+                .uri("/wallet-address")
+                .set_json(wallet_registration(&Keypair::new(), 7)),
+        ] {
+            let (status, body) =
+                call_with(state.clone(), &auth::tests::user_token("42"), request).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+            assert_eq!(body["code"], "FORBIDDEN");
+        }
+        // Their own balance gets past the check, to the unreachable database
+        let (status, _) = call_with(
+            state,
+            &auth::tests::user_token("7"),
+            TestRequest::get().uri("/balance/7/SOL"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // A Solana wallet's signed request to register it to `user_id`
+    fn wallet_registration(wallet: &Keypair, user_id: i32) -> serde_json::Value {
+        let address = wallet.pubkey().to_string();
+        let message = wallet_registration_message(user_id, Currency::SOL, &address);
+        json!({
+            "user_id": user_id,
+            "currency": "SOL",
+            "address": address,
+            "signature": wallet.sign_message(message.as_bytes()).to_string(),
+        })
+    }
+
+    #[test]
+    fn test_wallet_registration_is_signed_by_the_wallet() {
+        let wallet = Keypair::new();
+        let request = |registration: serde_json::Value| -> RegisterWalletRequest {
+            serde_json::from_value(registration).unwrap()
+        };
+        assert!(check_registration_signature(&request(wallet_registration(&wallet, 7))).is_ok());
+
+        // Signed for another user
+        let mut registration = wallet_registration(&wallet, 8);
+        registration["user_id"] = json!(7);
+        assert!(check_registration_signature(&request(registration)).is_err());
+        // Signed by another wallet
+        let mut registration = wallet_registration(&Keypair::new(), 7);
+        registration["address"] = json!(wallet.pubkey().to_string());
+        assert!(check_registration_signature(&request(registration)).is_err());
+        // Not signed at all
+        let mut registration = wallet_registration(&wallet, 7);
+        registration["signature"] = json!("");
+        assert!(check_registration_signature(&request(registration)).is_err());
+    }
+
+    #[test]
+    fn test_hex_addresses_are_registered_lowercase() {
+        let address = "0x0BF493537Fa5b08836d7AE8750CFEA682a0f190C";
+        assert_eq!(
+            registered_address(Currency::MON, address).unwrap(),
+            address.to_lowercase()
+        );
+        assert_eq!(
+            registered_address(Currency::SOL, " 11111111111111111111111111111111 ").unwrap(),
+            "11111111111111111111111111111111"
+        );
+        assert!(registered_address(Currency::SOL, address).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_wallet_address_needs_a_token() {
+        let state = test_state(unreachable_pool(), MockChain::default());
+        let (status, body) = call(
+            state,
+            TestRequest::post()
+                .uri("/wallet-address")
+                .set_json(wallet_registration(&Keypair::new(), 7)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+        assert_eq!(body["code"], "UNAUTHORIZED");
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_wallet_address_is_registered_to_one_user() -> anyhow::Result<()> {
+        dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let owner = db::create_test_user(&mut tx).await?;
+        let other = db::create_test_user(&mut tx).await?;
+        for user_id in [owner, other] {
+            db::provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA)
+                .await?;
+        }
+        tx.commit().await?;
+        let state = test_state(pool, MockChain::default());
+        let register = |user_id: i32, wallet: &Keypair| {
+            let state = state.clone();
+            let request = TestRequest::post()
+                .uri("/wallet-address")
+                .set_json(wallet_registration(wallet, user_id));
+            async move {
+                let token = auth::tests::user_token(&user_id.to_string());
+                call_with(state, &token, request).await
+            }
+        };
+        let wallet = Keypair::new();
+
+        let (status, body) = register(owner, &wallet).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["wallet_address"], wallet.pubkey().to_string());
+        // Registering it again is harmless
+        let (status, body) = register(owner, &wallet).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        // Even with its signature, another user can't have the owner's deposits
+        let (status, body) = register(other, &wallet).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+        assert_eq!(body["code"], "WALLET_ADDRESS_CONFLICT");
+        // Nor can the owner move deposits to another wallet
+        let (status, body) = register(owner, &Keypair::new()).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+        assert_eq!(body["code"], "WALLET_ADDRESS_CONFLICT");
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_refunds_are_for_admins_only() {
+        let state = test_state(unreachable_pool(), MockChain::default());
+        let refund = || {
+            TestRequest::post()
+                .uri("/razorpay/refund")
+                .set_json(json!({ "payment_id": "pay_1", "amount": 100.0 }))
+        };
+
+        let (status, body) =
+            call_with(state.clone(), &auth::tests::user_token("7"), refund()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "FORBIDDEN");
+        // Admins get past the check, to the unconfigured Razorpay client
+        let (status, _) = call_with(state, &auth::tests::admin_token(), refund()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn test_admin_reports_are_for_admins_only() {
+        let state = test_state(unreachable_pool(), MockChain::default());
+        for uri in ["/admin/reconciliation", "/admin/rake"] {
+            let (status, body) = call_with(
+                state.clone(),
+                &auth::tests::user_token("7"),
+                TestRequest::get().uri(uri),
+            )
+            .await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}", uri);
+            assert_eq!(body["code"], "FORBIDDEN");
+            // Admins get past the check, to the unreachable database
+            let (status, _) = call_with(
+                state.clone(),
+                &auth::tests::admin_token(),
+                TestRequest::get().uri(uri),
+            )
+            .await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_spoofed_deposit_is_not_credited() {
+        let chain = MockChain::with_transfer("tx", TREASURY, 0.1);
+        let request = DepositRequest {
+            user_id: 1,
+            amount: 10.0,
+            currency: Currency::SOL,
+            tx_hash: "tx".to_string(),
+        };
+
+        let err = credit_verified_deposit(&unreachable_pool(), &chain, &request, SENDER)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, WalletError::DepositNotVerified(_)),
+            "{:?}",
+            err
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_deposit_from_another_wallet_is_not_credited() {
+        let chain = MockChain::with_transfer_from("tx", "someone-else", TREASURY, 0.1);
+        let request = DepositRequest {
+            user_id: 1,
+            amount: 0.1,
+            currency: Currency::SOL,
+            tx_hash: "tx".to_string(),
+        };
+
+        let err = credit_verified_deposit(&unreachable_pool(), &chain, &request, SENDER)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, WalletError::DepositNotVerified(_)),
+            "{:?}",
+            err
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_withdrawal_address_is_rejected() {
+        for (currency, address) in [
+            (Currency::SOL, "0x0000000000000000000000000000000000000001"),
+            (Currency::MON, "11111111111111111111111111111111"),
+            (Currency::MON, "0x12"),
+        ] {
+            let request = WithdrawRequest {
+                user_id: 1,
+                amount: 0.25,
+                currency,
+                withdraw_address: address.to_string(),
+                idempotency_key: None,
+            };
+            // Rejected before the database is reached
+            let err = request_withdrawal(
+                &unreachable_pool(),
+                &WithdrawalFee::default(),
+                DEFAULT_IDEMPOTENCY_WINDOW,
+                &request,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.error_response().status(), StatusCode::BAD_REQUEST);
+            assert_eq!(err.code(), "INVALID_ADDRESS");
+        }
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_balance_is_served_per_currency() -> anyhow::Result<()> {
+        dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let user_id = db::create_test_user(&mut tx).await?;
+        db::provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        sqlx::query("UPDATE wallet SET balance = 1.5 WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        let state = test_state(pool, MockChain::default());
+        let token = auth::tests::user_token(&user_id.to_string());
+
+        let (status, body) = call_with(
+            state.clone(),
+            &token,
+            TestRequest::get().uri(&format!("/balance/{}/SOL", user_id)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(
+            body,
+            json!({ "user_id": user_id, "currency": "SOL", "balance": 1.5 })
+        );
+        // The user holds no MON wallet
+        let (status, body) = call_with(
+            state,
+            &token,
+            TestRequest::get().uri(&format!("/balance/{}/MON", user_id)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+        assert_eq!(body["code"], "WALLET_NOT_FOUND");
+        Ok(())
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_verified_deposit_is_credited_once() -> anyhow::Result<()> {
+        dotenv().ok();
+        let pool = establish_connection().await;
+        let unique = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        let mut tx = pool.begin().await?;
+        let user_id = db::create_test_user(&mut tx).await?;
+        db::provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        tx.commit().await?;
+
+        let tx_hash = format!("tx-{}", unique);
+        let chain = MockChain::with_transfer(&tx_hash, TREASURY, 0.25);
+        let request = DepositRequest {
+            user_id,
+            amount: 0.25,
+            currency: Currency::SOL,
+            tx_hash,
+        };
+
+        assert_eq!(
+            credit_verified_deposit(&pool, &chain, &request, SENDER).await?,
+            0.25
+        );
+        // Replaying the same transaction credits nothing
+        let err = credit_verified_deposit(&pool, &chain, &request, SENDER)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, WalletError::DepositNotVerified(_)),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            db::get_user_wallet(&pool, user_id, Currency::SOL)
+                .await?
+                .balance,
+            0.25
+        );
+        Ok(())
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_repeated_idempotency_key_withdraws_once() -> anyhow::Result<()> {
+        dotenv().ok();
+        let pool = establish_connection().await;
+        let unique = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        let mut tx = pool.begin().await?;
+        let user_id = db::create_test_user(&mut tx).await?;
+        db::provision_user_wallets_tx(&mut tx, user_id, &[Currency::MON], WalletType::PDA).await?;
+        db::credit_deposit_once_tx(
+            &mut tx,
+            user_id,
+            Currency::MON,
+            1.0,
+            &format!("tx-{}", unique),
+        )
+        .await?;
+        tx.commit().await?;
+
+        let request = |key: &str| WithdrawRequest {
+            user_id,
+            amount: 0.25,
+            currency: Currency::MON,
+            withdraw_address: "0x0000000000000000000000000000000000000001".to_string(),
+            idempotency_key: Some(key.to_string()),
+        };
+        let fee = WithdrawalFee::default();
+        let window = DEFAULT_IDEMPOTENCY_WINDOW;
+        let withdrawals = || async {
+            sqlx::query_scalar::<_, i64>(
+                "SELECT COUNT(*) FROM pending_withdrawals WHERE user_id = $1",
+            )
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await
+        };
+
+        // A retry racing the original request
+        let retried = request("retry-me");
+        let (first, retry) = tokio::join!(
+            request_withdrawal(&pool, &fee, window, &retried),
+            request_withdrawal(&pool, &fee, window, &retried),
+        );
+        let (first, retry) = (first?, retry?);
+        assert_eq!(first, retry);
+        assert_eq!(first["balance"], 0.75);
+        assert_eq!(withdrawals().await?, 1);
+        assert_eq!(
+            db::get_user_wallet(&pool, user_id, Currency::MON)
+                .await?
+                .balance,
+            0.75
+        );
+
+        // Another key is another withdrawal
+        let other = request_withdrawal(&pool, &fee, window, &request("another")).await?;
+        assert_ne!(other["withdrawal_id"], first["withdrawal_id"]);
+        assert_eq!(withdrawals().await?, 2);
+
+        // Once the window has passed the key is free again
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let expired = request_withdrawal(&pool, &fee, Duration::from_millis(10), &retried).await?;
+        assert_ne!(expired["withdrawal_id"], first["withdrawal_id"]);
+        assert_eq!(withdrawals().await?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_withdrawal_currency_must_be_supported() {
+        for currency in WITHDRAWAL_CURRENCIES {
+            assert!(check_withdrawal_currency(currency).is_ok());
+        }
+        let err = check_withdrawal_currency(Currency::INR).unwrap_err();
+        assert_eq!(err.error_response().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.to_string(), "INR withdrawals are not supported");
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_deposit_in_unprovisioned_currency_is_not_found() -> anyhow::Result<()> {
+        dotenv().ok();
+        let pool = establish_connection().await;
+        let unique = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        let mut tx = pool.begin().await?;
+        let user_id = db::create_test_user(&mut tx).await?;
+        db::provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        tx.commit().await?;
+
+        // A genuine USDC transfer, but the user only holds a SOL wallet
+        let tx_hash = format!("tx-{}", unique);
+        let chain = MockChain::with_transfer(&tx_hash, TREASURY, 5.0);
+        let request = DepositRequest {
+            user_id,
+            amount: 5.0,
+            currency: Currency::USDC,
+            tx_hash: tx_hash.clone(),
+        };
+
+        let err = credit_verified_deposit(&pool, &chain, &request, SENDER)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, WalletError::WalletNotFound), "{:?}", err);
+        assert_eq!(err.error_response().status(), StatusCode::NOT_FOUND);
+        let recorded: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM transactions WHERE tx_hash = $1)")
+                .bind(&tx_hash)
+                .fetch_one(&pool)
+                .await?;
+        assert!(!recorded);
+        Ok(())
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_deposit_for_missing_wallet_is_not_found() {
+        dotenv().ok();
+        let pool = establish_connection().await;
+        let request = DepositRequest {
+            user_id: i32::MAX,
+            amount: 1.0,
+            currency: Currency::SOL,
+            tx_hash: "missing-wallet".to_string(),
+        };
+
+        let err = credit_deposit(&pool, &request).await.unwrap_err();
+        assert!(matches!(err, WalletError::WalletNotFound));
+        assert_eq!(err.error_response().status(), StatusCode::NOT_FOUND);
+    }
+}
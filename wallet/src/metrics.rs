@@ -0,0 +1,161 @@
+use std::{
+    future::{ready, Ready},
+    time::Instant,
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse, Responder,
+};
+use futures_util::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    pub static ref HTTP_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("http_requests_total", "HTTP requests handled, by status"),
+        &["endpoint", "method", "status"]
+    ));
+    pub static ref API_LATENCY: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "http_request_duration_seconds",
+            "Time spent handling HTTP requests"
+        ),
+        &["endpoint", "method"]
+    ));
+}
+
+fn register<T>(metric: prometheus::Result<T>) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+    metric
+}
+
+pub fn record_http_request(endpoint: &str, method: &str, status: u16, duration_secs: f64) {
+    HTTP_REQUESTS
+        .with_label_values(&[endpoint, method, &status.to_string()])
+        .inc();
+    API_LATENCY
+        .with_label_values(&[endpoint, method])
+        .observe(duration_secs);
+}
+
+#[actix_web::get("/metrics")]
+pub async fn metrics() -> impl Responder {
+    let mut buffer = Vec::new();
+    if let Err(err) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(buffer)
+}
+
+/// Records the latency and status of every request, labelled by route pattern
+/// rather than path so ids in the URL don't blow up the label cardinality.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let endpoint = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let method = req.method().to_string();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => res.status().as_u16(),
+                Err(err) => err.as_response_error().status_code().as_u16(),
+            };
+            record_http_request(&endpoint, &method, status, start.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_request_metrics() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestMetrics)
+                .route(
+                    "/items/{id}",
+                    web::get().to(|| async { HttpResponse::NotFound().finish() }),
+                )
+                .service(metrics),
+        )
+        .await;
+
+        let requests = || {
+            HTTP_REQUESTS
+                .with_label_values(&["/items/{id}", "GET", "404"])
+                .get()
+        };
+        let observations = || {
+            API_LATENCY
+                .with_label_values(&["/items/{id}", "GET"])
+                .get_sample_count()
+        };
+        let (before_requests, before_observations) = (requests(), observations());
+
+        let req = test::TestRequest::get().uri("/items/42").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status().as_u16(), 404);
+
+        assert_eq!(requests(), before_requests + 1);
+        assert_eq!(observations(), before_observations + 1);
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&body).contains("http_requests_total"));
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    env,
+    future::{ready, Ready},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use anyhow::{anyhow, Result};
+use futures_util::future::LocalBoxFuture;
+
+use crate::error::WalletError;
+
+pub const DEFAULT_RATE_LIMIT_PER_MINUTE: u32 = 120;
+
+/// Probes are polled by the platform and must never be throttled.
+const EXEMPT_ROUTES: &[&str] = &["/health", "/live"];
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Reads `RATE_LIMIT_PER_MINUTE`, defaulting to [`DEFAULT_RATE_LIMIT_PER_MINUTE`].
+pub fn rate_limit_from_env() -> Result<u32> {
+    match env::var("RATE_LIMIT_PER_MINUTE") {
+        Ok(value) => match value.parse() {
+            Ok(limit) if limit > 0 => Ok(limit),
+            _ => Err(anyhow!(
+                "RATE_LIMIT_PER_MINUTE must be a positive integer, got {:?}",
+                value
+            )),
+        },
+        Err(_) => Ok(DEFAULT_RATE_LIMIT_PER_MINUTE),
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    requests: u32,
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+    by_client: HashMap<String, Window>,
+    last_pruned: Option<Instant>,
+}
+
+/// Fixed one-minute window per client IP. Clone it into every worker's `App` so
+/// the workers share the counters instead of each allowing the full limit.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: u32,
+    windows: Arc<Mutex<Windows>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit_per_minute: u32) -> Self {
+        Self {
+            limit: limit_per_minute,
+            windows: Arc::new(Mutex::new(Windows::default())),
+        }
+    }
+
+    /// Counts a request from `client` and reports whether it is within the limit.
+    fn allow(&self, client: &str, now: Instant) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+
+        // Drop clients that have been quiet for a whole window so the map stays bounded
+        if windows
+            .last_pruned
+            .is_none_or(|pruned| now.duration_since(pruned) >= WINDOW)
+        {
+            windows
+                .by_client
+                .retain(|_, window| now.duration_since(window.started) < WINDOW);
+            windows.last_pruned = Some(now);
+        }
+
+        let window = windows
+            .by_client
+            .entry(client.to_string())
+            .or_insert(Window {
+                started: now,
+                requests: 0,
+            });
+        if now.duration_since(window.started) >= WINDOW {
+            window.started = now;
+            window.requests = 0;
+        }
+        window.requests += 1;
+        window.requests <= self.limit
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service,
+            limiter: Rc::new(self.clone()),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: S,
+    limiter: Rc<RateLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if EXEMPT_ROUTES.contains(&req.path()) {
+            return Box::pin(self.service.call(req));
+        }
+
+        let client = req
+            .connection_info()
+            .realip_remote_addr()
+            .unwrap_or("unknown")
+            .to_string();
+        if !self.limiter.allow(&client, Instant::now()) {
+            return Box::pin(ready(Err(WalletError::RateLimited.into())));
+        }
+        Box::pin(self.service.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{self as actix_test, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_window_resets_after_a_minute() {
+        let limiter = RateLimiter::new(2);
+        let start = Instant::now();
+
+        assert!(limiter.allow("1.1.1.1", start));
+        assert!(limiter.allow("1.1.1.1", start));
+        assert!(!limiter.allow("1.1.1.1", start));
+        // Other clients have their own budget
+        assert!(limiter.allow("2.2.2.2", start));
+
+        assert!(limiter.allow("1.1.1.1", start + WINDOW));
+    }
+
+    #[test]
+    fn test_quiet_clients_are_pruned() {
+        let limiter = RateLimiter::new(2);
+        let start = Instant::now();
+
+        limiter.allow("1.1.1.1", start);
+        limiter.allow("2.2.2.2", start + WINDOW);
+        assert_eq!(limiter.windows.lock().unwrap().by_client.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_requests_past_the_limit_get_429() {
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(RateLimiter::new(3))
+                .route("/health", web::get().to(HttpResponse::Ok))
+                .route("/balance/{user_id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |uri: &'static str| {
+            let app = &app;
+            async move {
+                let request = TestRequest::get()
+                    .uri(uri)
+                    .peer_addr("10.0.0.1:4000".parse().unwrap())
+                    .to_request();
+                match actix_test::try_call_service(app, request).await {
+                    Ok(response) => response.status(),
+                    Err(err) => err.as_response_error().status_code(),
+                }
+            }
+        };
+
+        for _ in 0..3 {
+            assert_eq!(status("/balance/1").await, StatusCode::OK);
+        }
+        assert_eq!(status("/balance/1").await, StatusCode::TOO_MANY_REQUESTS);
+        // Health checks are never limited
+        for _ in 0..5 {
+            assert_eq!(status("/health").await, StatusCode::OK);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::env;
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "X-Razorpay-Signature";
+pub const PAYMENT_CAPTURED: &str = "payment.captured";
+const API_BASE_URL: &str = "https://api.razorpay.com/v1";
+
+/// Checks the hex encoded HMAC-SHA256 Razorpay sends with every webhook,
+/// computed over the raw request body with the webhook secret.
+pub fn verify_webhook_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature.trim()) else {
+        return false;
+    };
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookEvent {
+    pub event: String,
+    pub payload: WebhookPayload,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookPayload {
+    pub payment: Option<PaymentPayload>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PaymentPayload {
+    pub entity: Payment,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Payment {
+    pub id: String,
+    // In paise
+    pub amount: u64,
+    pub currency: String,
+    pub status: String,
+    // Razorpay sends an empty array instead of an object when there are no notes
+    #[serde(default)]
+    pub notes: serde_json::Value,
+}
+
+impl Payment {
+    /// The order is created with the user's id in its notes, which Razorpay
+    /// copies onto the payment.
+    pub fn user_id(&self) -> Option<i32> {
+        match self.notes.get("user_id")? {
+            serde_json::Value::Number(id) => id.as_i64()?.try_into().ok(),
+            serde_json::Value::String(id) => id.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn amount_in_rupees(&self) -> f64 {
+        self.amount as f64 / 100.0
+    }
+}
+
+pub struct RazorpayClient {
+    http: reqwest::Client,
+    base_url: String,
+    key_id: String,
+    key_secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Refund {
+    pub id: String,
+    pub payment_id: String,
+    pub status: String,
+}
+
+impl RazorpayClient {
+    pub fn new(key_id: String, key_secret: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: API_BASE_URL.to_string(),
+            key_id,
+            key_secret,
+        }
+    }
+
+    /// Builds a client from RAZORPAY_KEY_ID and RAZORPAY_KEY_SECRET, if both are set.
+    pub fn from_env() -> Option<Self> {
+        let key_id = env::var("RAZORPAY_KEY_ID").ok()?;
+        let key_secret = env::var("RAZORPAY_KEY_SECRET").ok()?;
+        Some(Self::new(key_id, key_secret))
+    }
+
+    fn refund_request(&self, payment_id: &str, amount: u64) -> Result<reqwest::Request> {
+        let request = self
+            .http
+            .post(format!("{}/payments/{}/refund", self.base_url, payment_id))
+            .basic_auth(&self.key_id, Some(&self.key_secret))
+            .json(&json!({ "amount": amount }))
+            .build()?;
+        Ok(request)
+    }
+
+    /// Refunds `amount` paise of a captured payment. Razorpay treats anything
+    /// less than the captured amount as a partial refund.
+    pub async fn refund_payment(&self, payment_id: &str, amount: u64) -> Result<Refund> {
+        let request = self.refund_request(payment_id, amount)?;
+        let response = self.http.execute(request).await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Razorpay refund failed with {}: {}",
+                status,
+                body
+            ));
+        }
+        Ok(response.json().await?)
+    }
+}
+
+pub fn rupees_to_paise(amount: f64) -> u64 {
+    (amount * 100.0).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn test_verify_webhook_signature() {
+        let body = br#"{"event":"payment.captured"}"#;
+        let signature = sign("secret", body);
+
+        assert!(verify_webhook_signature("secret", body, &signature));
+        assert!(!verify_webhook_signature("other-secret", body, &signature));
+        assert!(!verify_webhook_signature(
+            "secret",
+            br#"{"event":"payment.failed"}"#,
+            &signature
+        ));
+        assert!(!verify_webhook_signature("secret", body, "not-hex"));
+    }
+
+    #[test]
+    fn test_parse_payment_captured() {
+        let body = r#"{
+            "event": "payment.captured",
+            "payload": {
+                "payment": {
+                    "entity": {
+                        "id": "pay_29QQoUBi66xm2f",
+                        "amount": 50000,
+                        "currency": "INR",
+                        "status": "captured",
+                        "notes": { "user_id": "42" }
+                    }
+                }
+            }
+        }"#;
+        let event: WebhookEvent = serde_json::from_str(body).unwrap();
+        assert_eq!(event.event, PAYMENT_CAPTURED);
+
+        let payment = event.payload.payment.unwrap().entity;
+        assert_eq!(payment.user_id(), Some(42));
+        assert_eq!(payment.amount_in_rupees(), 500.0);
+    }
+
+    #[test]
+    fn test_payment_without_notes() {
+        let body = r#"{"id":"pay_1","amount":100,"currency":"INR","status":"captured","notes":[]}"#;
+        let payment: Payment = serde_json::from_str(body).unwrap();
+        assert_eq!(payment.user_id(), None);
+    }
+
+    #[test]
+    fn test_refund_request() {
+        let client = RazorpayClient::new("rzp_test_key".to_string(), "secret".to_string());
+        let request = client.refund_request("pay_29QQoUBi66xm2f", 2550).unwrap();
+
+        assert_eq!(request.method(), reqwest::Method::POST);
+        assert_eq!(
+            request.url().as_str(),
+            "https://api.razorpay.com/v1/payments/pay_29QQoUBi66xm2f/refund"
+        );
+        // base64("rzp_test_key:secret")
+        assert_eq!(
+            request.headers()[reqwest::header::AUTHORIZATION],
+            "Basic cnpwX3Rlc3Rfa2V5OnNlY3JldA=="
+        );
+
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body, json!({ "amount": 2550 }));
+    }
+
+    #[test]
+    fn test_rupees_to_paise() {
+        assert_eq!(rupees_to_paise(25.5), 2550);
+        assert_eq!(rupees_to_paise(0.29), 29);
+    }
+}
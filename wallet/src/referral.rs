@@ -0,0 +1,231 @@
+use std::{env, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use common::{db, utils::Currency};
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+
+use crate::WALLET_CURRENCIES;
+
+/// Bonus paid to a referrer once someone they referred makes their first qualifying
+/// deposit: one of at least `min_deposit` in the bonus currency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReferralBonus {
+    pub amount: f64,
+    pub currency: Currency,
+    pub min_deposit: f64,
+}
+
+impl Default for ReferralBonus {
+    fn default() -> Self {
+        Self {
+            amount: 0.0,
+            currency: Currency::SOL,
+            min_deposit: 0.0,
+        }
+    }
+}
+
+impl ReferralBonus {
+    /// Reads `REFERRAL_BONUS_AMOUNT`, `REFERRAL_BONUS_CURRENCY` and
+    /// `REFERRAL_MIN_DEPOSIT`. Without an amount no bonus is paid.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let default = Self::default();
+        let non_negative = |key: &str, default: f64| match lookup(key) {
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|amount: &f64| amount.is_finite() && *amount >= 0.0)
+                .ok_or_else(|| anyhow!("{} must be a non-negative number, got {:?}", key, value)),
+            None => Ok(default),
+        };
+        let amount = non_negative("REFERRAL_BONUS_AMOUNT", default.amount)?;
+        let min_deposit = non_negative("REFERRAL_MIN_DEPOSIT", default.min_deposit)?;
+        let currency = match lookup("REFERRAL_BONUS_CURRENCY") {
+            Some(value) => Currency::from_str(&value)?,
+            None => default.currency,
+        };
+        // The bonus goes into a wallet every referrer is guaranteed to have
+        if !WALLET_CURRENCIES.contains(&currency) {
+            return Err(anyhow!(
+                "REFERRAL_BONUS_CURRENCY must be one of {:?}, got {}",
+                WALLET_CURRENCIES,
+                currency
+            ));
+        }
+        Ok(Self {
+            amount,
+            currency,
+            min_deposit,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.amount > 0.0
+    }
+
+    /// Whether a deposit of `amount` in `currency` earns the referrer the bonus
+    pub fn qualifies(&self, currency: Currency, amount: f64) -> bool {
+        self.is_enabled() && currency == self.currency && amount >= self.min_deposit
+    }
+}
+
+/// Pays the referrer of `referee_id` if they haven't been paid yet. Called after every
+/// credited deposit, so the first qualifying one pays; smaller ones leave the referral
+/// unclaimed, so a stream of tiny deposits from throwaway accounts earns nothing. Runs
+/// in its own transaction: a failed bonus never undoes the deposit and is retried on
+/// the referee's next qualifying one.
+pub async fn reward_referrer(
+    pool: &Pool<Postgres>,
+    bonus: &ReferralBonus,
+    referee_id: i32,
+    currency: Currency,
+    amount: f64,
+) {
+    if !bonus.qualifies(currency, amount) {
+        return;
+    }
+    let rewarded = async {
+        let mut tx = pool.begin().await?;
+        let referrer_id =
+            db::credit_referral_bonus_tx(&mut tx, referee_id, bonus.currency, bonus.amount).await?;
+        tx.commit().await?;
+        anyhow::Ok(referrer_id)
+    }
+    .await;
+
+    match rewarded {
+        Ok(Some(referrer_id)) => info!(
+            referrer_id,
+            referee_id,
+            amount = bonus.amount,
+            currency = %bonus.currency,
+            "Credited referral bonus"
+        ),
+        Ok(None) => {}
+        Err(err) => error!(referee_id, "Failed to credit referral bonus: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use common::{db::establish_connection, utils::WalletType};
+
+    use super::*;
+
+    fn lookup(vars: &[(&str, &str)]) -> Result<ReferralBonus> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        ReferralBonus::from_lookup(move |key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_from_lookup() {
+        let bonus = lookup(&[]).unwrap();
+        assert_eq!(bonus, ReferralBonus::default());
+        assert!(!bonus.is_enabled());
+
+        assert_eq!(
+            lookup(&[
+                ("REFERRAL_BONUS_AMOUNT", "0.05"),
+                ("REFERRAL_BONUS_CURRENCY", "MON"),
+            ])
+            .unwrap(),
+            ReferralBonus {
+                amount: 0.05,
+                currency: Currency::MON,
+                min_deposit: 0.0,
+            }
+        );
+        assert_eq!(
+            lookup(&[("REFERRAL_MIN_DEPOSIT", "0.5")])
+                .unwrap()
+                .min_deposit,
+            0.5
+        );
+        assert!(lookup(&[("REFERRAL_BONUS_AMOUNT", "-1")]).is_err());
+        assert!(lookup(&[("REFERRAL_MIN_DEPOSIT", "NaN")]).is_err());
+        assert!(lookup(&[("REFERRAL_BONUS_AMOUNT", "lots")]).is_err());
+        // Not every user has an INR wallet
+        assert!(lookup(&[("REFERRAL_BONUS_CURRENCY", "INR")]).is_err());
+    }
+
+    #[test]
+    fn test_only_large_enough_deposits_qualify() {
+        let bonus = ReferralBonus {
+            amount: 0.1,
+            currency: Currency::SOL,
+            min_deposit: 0.5,
+        };
+        assert!(bonus.qualifies(Currency::SOL, 0.5));
+        assert!(!bonus.qualifies(Currency::SOL, 0.01));
+        assert!(!bonus.qualifies(Currency::MON, 5.0));
+        assert!(!ReferralBonus::default().qualifies(Currency::SOL, 5.0));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_first_deposit_rewards_referrer_once() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = establish_connection().await;
+        let unique = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        let mut tx = pool.begin().await?;
+        let mut user_ids = Vec::new();
+        // The referrer, then the referee
+        for _ in 0..2 {
+            let user_id = db::create_test_user(&mut tx).await?;
+            db::provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA)
+                .await?;
+            user_ids.push(user_id);
+        }
+        let (referrer, referee) = (user_ids[0], user_ids[1]);
+        let code: String = sqlx::query_scalar("SELECT referral_code FROM users WHERE id = $1")
+            .bind(referrer)
+            .fetch_one(&mut *tx)
+            .await?;
+        db::record_referral_tx(&mut tx, referee, &code).await?;
+        tx.commit().await?;
+
+        let bonus = ReferralBonus {
+            amount: 0.1,
+            currency: Currency::SOL,
+            min_deposit: 0.5,
+        };
+        // Too small to count, then the first that does and one after it
+        for (deposit, amount) in [0.01, 1.0, 1.0].into_iter().enumerate() {
+            let mut tx = pool.begin().await?;
+            db::credit_deposit_once_tx(
+                &mut tx,
+                referee,
+                Currency::SOL,
+                amount,
+                &format!("tx-{}-{}", unique, deposit),
+            )
+            .await?;
+            tx.commit().await?;
+            reward_referrer(&pool, &bonus, referee, Currency::SOL, amount).await;
+            let paid = db::get_user_wallet(&pool, referrer, Currency::SOL)
+                .await?
+                .balance;
+            assert_eq!(paid, if deposit == 0 { 0.0 } else { 0.1 }, "{}", deposit);
+        }
+
+        assert_eq!(
+            db::get_user_wallet(&pool, referrer, Currency::SOL)
+                .await?
+                .balance,
+            0.1
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::{env, future::Future, io};
+
+use actix_web::dev::Server;
+use anyhow::{anyhow, Result};
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+
+/// Long enough for a withdrawal's database transaction, which never waits on the chain.
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// Reads `SHUTDOWN_TIMEOUT_SECS`, how long in-flight requests get to finish once
+/// shutdown starts, defaulting to [`DEFAULT_SHUTDOWN_TIMEOUT_SECS`].
+pub fn shutdown_timeout_from_env() -> Result<u64> {
+    match env::var("SHUTDOWN_TIMEOUT_SECS") {
+        Ok(value) => value.parse().map_err(|_| {
+            anyhow!(
+                "SHUTDOWN_TIMEOUT_SECS must be a whole number of seconds, got {:?}",
+                value
+            )
+        }),
+        Err(_) => Ok(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+    }
+}
+
+/// Resolves on ctrl-c or, on unix, SIGTERM (what fly sends on deploys).
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for ctrl-c: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Runs `server` until `shutdown` resolves, then stops accepting connections, lets
+/// in-flight requests finish within the server's shutdown timeout and closes `pool`.
+/// The server should have its own signal handling disabled.
+pub async fn run_until(
+    server: Server,
+    shutdown: impl Future<Output = ()>,
+    pool: &Pool<Postgres>,
+) -> io::Result<()> {
+    let handle = server.handle();
+    tokio::pin!(server);
+
+    let result = tokio::select! {
+        result = &mut server => result,
+        _ = shutdown => {
+            info!("Shutdown signal received, finishing in-flight requests");
+            // The stop is only carried out while the server is polled
+            let stopped = handle.stop(true);
+            let result = server.await;
+            stopped.await;
+            result
+        }
+    };
+
+    // Waits for checked out connections, so nothing is cut off mid-transaction
+    pool.close().await;
+    info!("Closed the database pool");
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::{mpsc, oneshot},
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_in_flight_request_finishes_during_shutdown() {
+        let (started, mut requests) = mpsc::unbounded_channel::<()>();
+        let server = HttpServer::new(move || {
+            let started = started.clone();
+            App::new().route(
+                "/slow",
+                web::get().to(move || {
+                    let started = started.clone();
+                    async move {
+                        started.send(()).unwrap();
+                        actix_web::rt::time::sleep(Duration::from_millis(300)).await;
+                        HttpResponse::Ok().body("done")
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .disable_signals()
+        .shutdown_timeout(5)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://127.0.0.1:1/test")
+            .unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = actix_web::rt::spawn({
+            let pool = pool.clone();
+            async move {
+                run_until(
+                    server.run(),
+                    async {
+                        let _ = stopped.await;
+                    },
+                    &pool,
+                )
+                .await
+            }
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        requests.recv().await.unwrap();
+        // Shut down while the request is being handled
+        stop.send(()).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("done"), "{}", response);
+
+        running.await.unwrap().unwrap();
+        assert!(pool.is_closed());
+        // No longer accepting connections
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,584 @@
+use std::{
+    env,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use common::{
+    db::{self, establish_connection},
+    models::PendingWithdrawal,
+    utils::Currency,
+};
+use deposits::{
+    error::DepositError,
+    sol::{self, DepositService},
+};
+use dotenv::dotenv;
+use evm_deposits::{MonadClient, TransferFailure};
+use sqlx::{Pool, Postgres};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+const SOL_TO_LAMPORTS: u64 = 1_000_000_000;
+
+// Long past the point a Solana transfer's blockhash expires and it can no longer land
+const DEFAULT_RECONCILE_AFTER_SECS: u64 = 300;
+// A blockhash lasts about a minute and a half; checking sooner would only find
+// transfers that may still land
+const MIN_RECONCILE_AFTER_SECS: u64 = 120;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv().ok();
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    info!("Starting the withdrawal background worker");
+    let pool = establish_connection().await;
+
+    let program_id = sol::program_id_from_env()?;
+    let treasury = sol::treasury_keypair_from_env()?;
+    let deposit_service = DepositService::new(treasury, program_id);
+
+    let poll_interval = Duration::from_secs(
+        env::var("WITHDRAWAL_POLL_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(5),
+    );
+
+    let reconcile_after =
+        parse_reconcile_after(env::var("WITHDRAWAL_RECONCILE_AFTER_SECS").ok().as_deref())?;
+    // MON withdrawals fail one by one while Monad isn't configured, like any other
+    // withdrawal that can't be sent
+    let monad = MonadClient::from_env()
+        .map_err(|err| warn!("MON withdrawals can't be sent: {}", err))
+        .ok();
+    let chain = OnChain {
+        solana: deposit_service,
+        monad,
+    };
+
+    // Withdrawals are sent one at a time so transfers from the treasury never
+    // race each other for a nonce or blockhash. Stuck ones are reconciled on
+    // startup and then every `reconcile_after`.
+    let mut next_reconcile = Instant::now();
+    loop {
+        if Instant::now() >= next_reconcile {
+            if let Err(err) = reconcile_withdrawals(&pool, &chain, reconcile_after).await {
+                error!("Failed to reconcile withdrawals: {:?}", err);
+            }
+            next_reconcile = Instant::now() + reconcile_after;
+        }
+        match db::claim_next_withdrawal(&pool).await {
+            Ok(Some(withdrawal)) => {
+                process_withdrawal(&pool, &chain, withdrawal).await;
+            }
+            Ok(None) => sleep(poll_interval).await,
+            Err(err) => {
+                error!("Failed to claim withdrawal: {:?}", err);
+                sleep(poll_interval).await;
+            }
+        }
+    }
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// A transfer that is signed but not sent. Sending only starts once `send` is polled,
+/// so its hash can be recorded first.
+struct PreparedTransfer<'a> {
+    tx_hash: String,
+    // Recorded to tell when the transfer can no longer land: the nonce of an EVM
+    // transfer, the last valid block height of a Solana one
+    nonce: Option<u64>,
+    last_valid_block_height: Option<u64>,
+    send: BoxFuture<'a, anyhow::Result<()>>,
+}
+
+/// Pays out withdrawals on the chain their currency lives on.
+trait WithdrawalChain {
+    /// Signs the transfer of `amount - fee` to the withdrawal address.
+    fn prepare<'a>(
+        &'a self,
+        withdrawal: &'a PendingWithdrawal,
+    ) -> BoxFuture<'a, anyhow::Result<PreparedTransfer<'a>>>;
+
+    /// Whether `tx_hash` succeeded and paid the withdrawal address. False while it is
+    /// unknown, unconfirmed or failed.
+    fn is_confirmed<'a>(
+        &'a self,
+        withdrawal: &'a PendingWithdrawal,
+        tx_hash: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// Whether `tx_hash`, which isn't confirmed, can no longer land, so the withdrawal
+    /// can be refunded.
+    fn is_dropped<'a>(
+        &'a self,
+        withdrawal: &'a PendingWithdrawal,
+        tx_hash: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<bool>>;
+}
+
+/// SOL and USDC from the treasury keypair, MON from `MONAD_ACCOUNT_PRIVATE_KEY`.
+struct OnChain {
+    solana: DepositService,
+    monad: Option<MonadClient>,
+}
+
+impl OnChain {
+    fn monad(&self) -> anyhow::Result<&MonadClient> {
+        self.monad
+            .as_ref()
+            .ok_or_else(|| anyhow!("Monad RPC is not configured"))
+    }
+}
+
+impl WithdrawalChain for OnChain {
+    fn prepare<'a>(
+        &'a self,
+        withdrawal: &'a PendingWithdrawal,
+    ) -> BoxFuture<'a, anyhow::Result<PreparedTransfer<'a>>> {
+        Box::pin(async move {
+            let net_amount = withdrawal.amount - withdrawal.fee;
+            let address = withdrawal.withdraw_address.clone();
+
+            let solana = match Currency::from_str(&withdrawal.currency)? {
+                Currency::SOL => {
+                    self.solana
+                        .sign_withdrawal(address, (net_amount * SOL_TO_LAMPORTS as f64) as u64)
+                        .await?
+                }
+                Currency::USDC => {
+                    self.solana
+                        .sign_spl_withdrawal(
+                            address,
+                            self.solana.usdc_mint(),
+                            sol::USDC_DECIMALS,
+                            sol::to_base_units(net_amount, sol::USDC_DECIMALS),
+                        )
+                        .await?
+                }
+                Currency::MON => {
+                    let monad = self.monad()?;
+                    let transfer = monad.sign_transfer(&address, net_amount).await?;
+                    return Ok(PreparedTransfer {
+                        tx_hash: transfer.tx_hash(),
+                        nonce: Some(transfer.nonce()),
+                        last_valid_block_height: None,
+                        send: Box::pin(async move {
+                            monad.send_transfer(&transfer).await?;
+                            Ok(())
+                        }),
+                    });
+                }
+                currency => return Err(anyhow!("Withdrawals are not supported for {}", currency)),
+            };
+            Ok(PreparedTransfer {
+                tx_hash: solana.signature(),
+                nonce: None,
+                last_valid_block_height: Some(solana.last_valid_block_height()),
+                send: Box::pin(async move {
+                    self.solana.send_withdrawal(&solana).await?;
+                    Ok(())
+                }),
+            })
+        })
+    }
+
+    fn is_confirmed<'a>(
+        &'a self,
+        withdrawal: &'a PendingWithdrawal,
+        tx_hash: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let address = &withdrawal.withdraw_address;
+            let received = match Currency::from_str(&withdrawal.currency)? {
+                Currency::SOL => self
+                    .solana
+                    .received_by(tx_hash, &sol::parse_address(address)?)
+                    .await?
+                    .is_some(),
+                Currency::USDC => self
+                    .solana
+                    .tokens_received_by(
+                        tx_hash,
+                        &sol::parse_address(address)?,
+                        &self.solana.usdc_mint(),
+                    )
+                    .await?
+                    .is_some(),
+                Currency::MON => self.monad()?.received_by(tx_hash, address).await?.is_some(),
+                Currency::INR => false,
+            };
+            Ok(received)
+        })
+    }
+
+    fn is_dropped<'a>(
+        &'a self,
+        withdrawal: &'a PendingWithdrawal,
+        tx_hash: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            match Currency::from_str(&withdrawal.currency)? {
+                // A signed transfer can be sent any time until its nonce is spent, by a
+                // replacement or cancellation if not by the transfer itself
+                Currency::MON => match withdrawal.nonce {
+                    Some(nonce) => self.monad()?.transfer_dropped(tx_hash, nonce as u64).await,
+                    None => Err(anyhow!("Nonce of transfer {} was not recorded", tx_hash)),
+                },
+                // Until then the blockhash it was signed with is still valid
+                Currency::SOL | Currency::USDC => match withdrawal.last_valid_block_height {
+                    Some(height) => Ok(self.solana.block_height_passed(height as u64).await?),
+                    None => Err(anyhow!(
+                        "Last valid block height of transfer {} was not recorded",
+                        tx_hash
+                    )),
+                },
+                // Nothing goes on chain
+                Currency::INR => Ok(true),
+            }
+        })
+    }
+}
+
+async fn process_withdrawal(
+    pool: &Pool<Postgres>,
+    chain: &impl WithdrawalChain,
+    withdrawal: PendingWithdrawal,
+) {
+    info!(
+        "Processing withdrawal {} for user {}",
+        withdrawal.id, withdrawal.user_id
+    );
+
+    let prepared = match chain.prepare(&withdrawal).await {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            // Nothing was sent, so the hold can be returned right away. The user sees the
+            // reason on the withdrawal, so it's kept free of treasury and node details.
+            let failure = transfer_failure(&err);
+            match failure {
+                TransferFailure::InsufficientFunds => error!(
+                    "Treasury can't cover withdrawal {}, top it up: {:?}",
+                    withdrawal.id, err
+                ),
+                _ => warn!("Withdrawal {} failed: {:?}", withdrawal.id, err),
+            }
+            if let Err(err) = db::fail_withdrawal(pool, &withdrawal, failure.user_message()).await {
+                error!("Failed to fail withdrawal {}: {:?}", withdrawal.id, err);
+            }
+            return;
+        }
+    };
+
+    // Without the hash on record the transfer couldn't be found after a crash
+    if let Err(err) = db::record_withdrawal_tx_hash(
+        pool,
+        withdrawal.id,
+        &prepared.tx_hash,
+        prepared.nonce.map(|nonce| nonce as i64),
+        prepared.last_valid_block_height.map(|height| height as i64),
+    )
+    .await
+    {
+        error!(
+            "Not sending withdrawal {}, its hash wasn't recorded: {:?}",
+            withdrawal.id, err
+        );
+        return;
+    }
+
+    match prepared.send.await {
+        Ok(()) => {
+            if let Err(err) = db::complete_withdrawal(pool, &withdrawal, &prepared.tx_hash).await {
+                error!(
+                    "Failed to record outcome of withdrawal {}: {:?}",
+                    withdrawal.id, err
+                );
+            }
+        }
+        // The transfer may still land, so it's only refunded once reconciliation finds
+        // it never did
+        Err(err) => warn!(
+            "Withdrawal {} was not confirmed, reconciling it later: {:?}",
+            withdrawal.id, err
+        ),
+    }
+}
+
+fn parse_reconcile_after(secs: Option<&str>) -> anyhow::Result<Duration> {
+    let Some(secs) = secs else {
+        return Ok(Duration::from_secs(DEFAULT_RECONCILE_AFTER_SECS));
+    };
+    match secs.trim().parse() {
+        Ok(secs) if secs >= MIN_RECONCILE_AFTER_SECS => Ok(Duration::from_secs(secs)),
+        _ => Err(anyhow!(
+            "WITHDRAWAL_RECONCILE_AFTER_SECS must be at least {}, got {:?}",
+            MIN_RECONCILE_AFTER_SECS,
+            secs
+        )),
+    }
+}
+
+/// Classifies an error preparing a transfer on either chain.
+fn transfer_failure(err: &anyhow::Error) -> TransferFailure {
+    match err.downcast_ref::<DepositError>() {
+        Some(DepositError::InvalidAddress(_)) => TransferFailure::InvalidAddress,
+        // Signing only asks the node for a blockhash or accounts
+        Some(DepositError::Rpc(_)) => TransferFailure::NodeUnavailable,
+        _ => TransferFailure::of(err),
+    }
+}
+
+/// Settles withdrawals a worker claimed but never recorded the outcome of, e.g. because
+/// it stopped between sending the transfer and recording it. Those that reached the
+/// chain are completed and those that no longer can are failed, returning the hold; the
+/// rest wait for the next run. `settle_after` has to outlast the time a sent transfer
+/// can take to land.
+async fn reconcile_withdrawals(
+    pool: &Pool<Postgres>,
+    chain: &impl WithdrawalChain,
+    settle_after: Duration,
+) -> anyhow::Result<()> {
+    for withdrawal in db::stuck_withdrawals(pool, settle_after).await? {
+        let reconciled = match &withdrawal.tx_hash {
+            // The worker stopped before signing, so nothing was sent
+            None => db::fail_withdrawal(pool, &withdrawal, "Withdrawal was never sent").await,
+            Some(tx_hash) => match chain.is_confirmed(&withdrawal, tx_hash).await {
+                Ok(true) => {
+                    info!(
+                        "Withdrawal {} reached the chain, completing it",
+                        withdrawal.id
+                    );
+                    db::complete_withdrawal(pool, &withdrawal, tx_hash).await
+                }
+                Ok(false) => fail_dropped_withdrawal(pool, chain, &withdrawal, tx_hash).await,
+                // Left for the next run rather than guessed at
+                Err(err) => Err(err),
+            },
+        };
+        if let Err(err) = reconciled {
+            error!(
+                "Failed to reconcile withdrawal {}: {:?}",
+                withdrawal.id, err
+            );
+        }
+    }
+    Ok(())
+}
+
+// Refunds a withdrawal whose transfer didn't confirm, once it can no longer land
+async fn fail_dropped_withdrawal(
+    pool: &Pool<Postgres>,
+    chain: &impl WithdrawalChain,
+    withdrawal: &PendingWithdrawal,
+    tx_hash: &str,
+) -> anyhow::Result<()> {
+    if !chain.is_dropped(withdrawal, tx_hash).await? {
+        info!(
+            "Withdrawal {} may still reach the chain, leaving it processing",
+            withdrawal.id
+        );
+        return Ok(());
+    }
+    db::fail_withdrawal(pool, withdrawal, "Transfer never reached the chain").await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Mutex};
+
+    use common::utils::{WalletType, WithdrawalStatus};
+
+    use super::*;
+
+    // Every send fails as if confirmation timed out, but the withdrawals in `landing`
+    // reach the chain anyway. Those in `sendable` could still land later.
+    #[derive(Default)]
+    struct MockChain {
+        landing: HashSet<i32>,
+        landed: Mutex<HashSet<String>>,
+        sendable: Mutex<HashSet<i32>>,
+    }
+
+    impl WithdrawalChain for MockChain {
+        fn prepare<'a>(
+            &'a self,
+            withdrawal: &'a PendingWithdrawal,
+        ) -> BoxFuture<'a, anyhow::Result<PreparedTransfer<'a>>> {
+            Box::pin(async move {
+                let tx_hash = format!("tx-{}", withdrawal.id);
+                let landed = tx_hash.clone();
+                Ok(PreparedTransfer {
+                    tx_hash,
+                    nonce: None,
+                    last_valid_block_height: None,
+                    send: Box::pin(async move {
+                        if self.landing.contains(&withdrawal.id) {
+                            self.landed.lock().unwrap().insert(landed);
+                        }
+                        Err(anyhow!("confirmation timed out"))
+                    }),
+                })
+            })
+        }
+
+        fn is_confirmed<'a>(
+            &'a self,
+            _withdrawal: &'a PendingWithdrawal,
+            tx_hash: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<bool>> {
+            Box::pin(async move { Ok(self.landed.lock().unwrap().contains(tx_hash)) })
+        }
+
+        fn is_dropped<'a>(
+            &'a self,
+            withdrawal: &'a PendingWithdrawal,
+            _tx_hash: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<bool>> {
+            Box::pin(async move { Ok(!self.sendable.lock().unwrap().contains(&withdrawal.id)) })
+        }
+    }
+
+    #[test]
+    fn test_transfer_failures_are_classified() {
+        let invalid = anyhow::Error::from(DepositError::InvalidAddress("nope".to_string()));
+        assert_eq!(transfer_failure(&invalid), TransferFailure::InvalidAddress);
+        let invalid = anyhow::Error::from(evm_deposits::parse_address("0x12").unwrap_err());
+        assert_eq!(transfer_failure(&invalid), TransferFailure::InvalidAddress);
+        assert_eq!(
+            transfer_failure(&anyhow!("Monad RPC is not configured")),
+            TransferFailure::Other
+        );
+    }
+
+    #[test]
+    fn test_reconcile_after_outlasts_blockhashes() {
+        assert_eq!(
+            parse_reconcile_after(None).unwrap(),
+            Duration::from_secs(DEFAULT_RECONCILE_AFTER_SECS)
+        );
+        assert_eq!(
+            parse_reconcile_after(Some("600")).unwrap(),
+            Duration::from_secs(600)
+        );
+        for secs in ["0", "60", "soon"] {
+            assert!(parse_reconcile_after(Some(secs)).is_err(), "{}", secs);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL pointing at a migrated database"]
+    async fn test_unrecorded_withdrawals_are_reconciled() -> anyhow::Result<()> {
+        dotenv().ok();
+        let pool = establish_connection().await;
+        let mut tx = pool.begin().await?;
+        let user_id = db::create_test_user(&mut tx).await?;
+        db::provision_user_wallets_tx(&mut tx, user_id, &[Currency::SOL], WalletType::PDA).await?;
+        sqlx::query("UPDATE wallet SET balance = 3.0 WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        let mut withdrawals = Vec::new();
+        for amount in [1.0, 0.5, 0.25, 0.125] {
+            let withdrawal =
+                db::enqueue_withdrawal_tx(&mut tx, user_id, Currency::SOL, amount, 0.0, "addr")
+                    .await?;
+            // Claimed by a worker
+            sqlx::query("UPDATE pending_withdrawals SET status = $1 WHERE id = $2")
+                .bind(WithdrawalStatus::PROCESSING.to_string())
+                .bind(withdrawal.id)
+                .execute(&mut *tx)
+                .await?;
+            withdrawals.push(withdrawal.id);
+        }
+        tx.commit().await?;
+        let (sent, lost, unsigned, delayed) = (
+            withdrawals[0],
+            withdrawals[1],
+            withdrawals[2],
+            withdrawals[3],
+        );
+
+        let chain = MockChain {
+            landing: HashSet::from([sent]),
+            sendable: Mutex::new(HashSet::from([delayed])),
+            ..MockChain::default()
+        };
+        // The first transfer lands and the second doesn't, but the worker learns about
+        // neither. The third worker stopped before signing anything. The last transfer
+        // hasn't landed but still could.
+        for id in [sent, lost, delayed] {
+            process_withdrawal(&pool, &chain, db::get_pending_withdrawal(&pool, id).await?).await;
+        }
+        let pool = &pool;
+        let status = |id| async move {
+            let withdrawal = db::get_pending_withdrawal(pool, id).await?;
+            anyhow::Ok((withdrawal.status, withdrawal.tx_hash))
+        };
+        assert_eq!(
+            status(sent).await?,
+            (
+                WithdrawalStatus::PROCESSING.to_string(),
+                Some(format!("tx-{}", sent))
+            )
+        );
+        let balance = || async {
+            anyhow::Ok(
+                db::get_user_wallet(pool, user_id, Currency::SOL)
+                    .await?
+                    .balance,
+            )
+        };
+        assert_eq!(balance().await?, 1.125);
+
+        reconcile_withdrawals(pool, &chain, Duration::ZERO).await?;
+
+        assert_eq!(
+            status(sent).await?.0,
+            WithdrawalStatus::COMPLETED.to_string()
+        );
+        assert_eq!(status(lost).await?.0, WithdrawalStatus::FAILED.to_string());
+        assert_eq!(
+            status(unsigned).await?.0,
+            WithdrawalStatus::FAILED.to_string()
+        );
+        assert_eq!(
+            status(delayed).await?.0,
+            WithdrawalStatus::PROCESSING.to_string()
+        );
+        assert_eq!(balance().await?, 1.875);
+
+        // Refunded once it can no longer land
+        chain.sendable.lock().unwrap().clear();
+        reconcile_withdrawals(pool, &chain, Duration::ZERO).await?;
+        assert_eq!(
+            status(delayed).await?.0,
+            WithdrawalStatus::FAILED.to_string()
+        );
+        // Only the withdrawal that reached the chain stays paid
+        assert_eq!(balance().await?, 2.0);
+        let ledger: Vec<String> = sqlx::query_scalar(
+            "SELECT tx_hash FROM transactions WHERE user_id = $1 AND tx_type = 'WITHDRAWAL'",
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+        assert_eq!(ledger, [format!("tx-{}", sent)]);
+
+        // Nothing is left to reconcile
+        reconcile_withdrawals(pool, &chain, Duration::ZERO).await?;
+        assert_eq!(balance().await?, 2.0);
+        Ok(())
+    }
+}